@@ -0,0 +1,13 @@
+// Any EIR data, as a device in range could send it, must parse without panicking
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use esp32_a2dp_player_host::bluetooth_gap_hal::ScannedDevice;
+
+fuzz_target!(|eir: &[u8]| {
+    let mut device = ScannedDevice::default();
+    device.parse_eir(eir);
+    // the Debug output goes to the log for every device found
+    let _ = format!("{device:?}");
+});
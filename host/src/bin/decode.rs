@@ -0,0 +1,305 @@
+// Renders a file through the playback pipeline to a WAV file or raw PCM, and reports how long the
+// stages took. For checking files before they go on the SD card, for hearing what the equalizer
+// and compressor do, and for profiling the pipeline on a PC.
+//
+//   cargo run --release --bin decode -- music.ogg music.wav --eq bass --night
+
+use std::{
+    cmp::min,
+    env,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use futures::channel::mpsc;
+
+use esp32_a2dp_player_host::{
+    audio::{self, DecodeOutcome, PlaybackSettings, StreamEvent, CHANNELS, SAMPLE_RATE},
+    audio_dynamics::DynamicsSettings,
+    audio_eq::{builtin_preset, BUILTIN_PRESETS},
+    bluetooth_hal::Stream,
+    wav,
+};
+
+// What the Bluetooth layer asks for
+const DEFAULT_READ_SIZE: usize = 256;
+
+struct Options {
+    input: String,
+    output: String,
+    raw: bool,
+    start_ms: u64,
+    length_ms: Option<u64>,
+    read_size: usize,
+    settings: PlaybackSettings,
+}
+
+fn usage() -> String {
+    format!(
+        "Usage: decode <input> <output> [options]
+
+Renders an Ogg Vorbis or WAV file through the playback pipeline to a 16 bit stereo WAV file at
+{} Hz.
+
+Options:
+  --raw             write raw interleaved 16 bit little endian samples instead of WAV
+  --start <ms>      start at this position
+  --length <ms>     stop after this much audio
+  --speed <x>       playback speed, 1.0 is normal speed
+  --gain <dB>       gain before the equalizer
+  --eq <preset>     equalizer preset: {}
+  --night           compressor and limiter of night mode
+  --read-size <n>   samples per read, default {}",
+        SAMPLE_RATE,
+        BUILTIN_PRESETS.join(", "),
+        DEFAULT_READ_SIZE
+    )
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options> {
+    let mut files = Vec::new();
+    let mut options = Options {
+        input: String::new(),
+        output: String::new(),
+        raw: false,
+        start_ms: 0,
+        length_ms: None,
+        read_size: DEFAULT_READ_SIZE,
+        settings: PlaybackSettings::default(),
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("{} needs a value", arg))
+        };
+
+        match arg.as_str() {
+            "--raw" => options.raw = true,
+            "--start" => options.start_ms = value()?.parse()?,
+            "--length" => options.length_ms = Some(value()?.parse()?),
+            "--speed" => options.settings.speed = value()?.parse()?,
+            "--gain" => options.settings.gain_db = value()?.parse()?,
+            "--eq" => {
+                let name = value()?;
+                let Some(bands) = builtin_preset(&name) else {
+                    bail!("Unknown equalizer preset {}", name);
+                };
+                options.settings.equalizer = bands;
+            }
+            "--night" => options.settings.dynamics = DynamicsSettings::night_mode(),
+            "--read-size" => options.read_size = value()?.parse()?,
+            _ if arg.starts_with("--") => bail!("Unknown option {}", arg),
+            _ => files.push(arg),
+        }
+    }
+
+    let [input, output] = <[String; 2]>::try_from(files)
+        .map_err(|_| anyhow::anyhow!("Expected an input and an output file"))?;
+    if options.read_size == 0 || options.read_size % CHANNELS != 0 {
+        bail!("The read size must be a multiple of {}", CHANNELS);
+    }
+    options.input = input;
+    options.output = output;
+
+    Ok(options)
+}
+
+// Adds up the time spent reading from the source, so that it can be told apart from the time
+// spent in the stages after it
+struct TimedStream {
+    source: Box<dyn Stream<i16>>,
+    nanos: Arc<AtomicU64>,
+}
+
+impl Stream<i16> for TimedStream {
+    fn read(&mut self, buf: &mut [i16]) -> Result<usize> {
+        let start = Instant::now();
+        let count = self.source.read(buf)?;
+
+        self.nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        Ok(count)
+    }
+}
+
+struct PcmWriter {
+    writer: BufWriter<File>,
+    wav: bool,
+    data_len: u64,
+}
+
+impl PcmWriter {
+    fn create(path: &str, wav: bool) -> Result<Self> {
+        let mut writer = BufWriter::new(
+            File::create(path).with_context(|| format!("Failed to create {}", path))?,
+        );
+        if wav {
+            // the sizes are filled in by finish()
+            wav::write_header(&mut writer, 0)?;
+        }
+
+        Ok(PcmWriter {
+            writer,
+            wav,
+            data_len: 0,
+        })
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += 2 * samples.len() as u64;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if self.wav {
+            self.writer.seek(SeekFrom::Start(0))?;
+            wav::write_header(&mut self.writer, self.data_len).context("use --raw")?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Prints the event, returns the error if decoding failed
+fn report_event(event: &StreamEvent) -> Option<String> {
+    match event {
+        StreamEvent::LinkStarted {
+            link,
+            channels,
+            rate,
+            comments,
+        } => {
+            let tag = |key| {
+                comments
+                    .as_ref()
+                    .and_then(|c| c.get(key))
+                    .unwrap_or("unknown")
+            };
+            println!(
+                "Link {}: {} channels, {} Hz, {} by {}",
+                link,
+                channels,
+                rate,
+                tag("TITLE"),
+                tag("ARTIST")
+            );
+            None
+        }
+        StreamEvent::DecodeFinished { outcome, .. } => {
+            println!("Decoding ended: {:?}", outcome);
+            match outcome {
+                DecodeOutcome::Failed { error, .. } => Some(error.clone()),
+                _ => None,
+            }
+        }
+    }
+}
+
+fn render(options: &Options) -> Result<()> {
+    let settings = &options.settings;
+    let (events, mut received_events) = mpsc::unbounded();
+    let started = Instant::now();
+
+    // input -> decoder and format conversion -> tempo -> gain -> equalizer -> compressor/limiter
+    let source = audio::open_file(&options.input, options.start_ms, settings, &events)
+        .with_context(|| format!("Failed to open {}", options.input))?;
+    let decode_nanos = Arc::new(AtomicU64::new(0));
+    let (mut dsp, _controls) = audio::processing_pipeline(
+        Box::new(TimedStream {
+            source,
+            nanos: decode_nanos.clone(),
+        }),
+        settings,
+    );
+
+    let mut writer = PcmWriter::create(&options.output, !options.raw)?;
+    let limit = options
+        .length_ms
+        .map(|ms| ms * SAMPLE_RATE as u64 / 1000 * CHANNELS as u64);
+    let mut buf = vec![0i16; options.read_size];
+    let mut samples = 0u64;
+    let mut pipeline_time = Duration::ZERO;
+    let mut write_time = Duration::ZERO;
+    let mut error = None;
+
+    loop {
+        let len = match limit {
+            Some(limit) => min(buf.len() as u64, limit - samples) as usize,
+            None => buf.len(),
+        };
+        if len == 0 {
+            break;
+        }
+
+        let start = Instant::now();
+        let count = dsp.read(&mut buf[..len])?;
+        pipeline_time += start.elapsed();
+
+        while let Ok(event) = received_events.try_recv() {
+            error = error.or(report_event(&event));
+        }
+        if count == 0 {
+            break;
+        }
+
+        let start = Instant::now();
+        writer.write(&buf[..count])?;
+        write_time += start.elapsed();
+        samples += count as u64;
+    }
+    writer.finish()?;
+    drop(dsp); // stops the decoding thread if we stopped before the end
+
+    // For Ogg files decoding runs ahead on its own thread, so this is the time the pipeline had to
+    // wait for it rather than the time it took
+    let decode_time = Duration::from_nanos(decode_nanos.load(Ordering::Relaxed));
+    let audio_seconds = (samples / CHANNELS as u64) as f64 / SAMPLE_RATE as f64;
+    let total = started.elapsed();
+
+    println!(
+        "Rendered {:.2} s of audio to {} in {:.3} s, {:.1}x real time",
+        audio_seconds,
+        options.output,
+        total.as_secs_f64(),
+        audio_seconds / total.as_secs_f64().max(1e-9)
+    );
+    println!("  decoding    {:.3} s", decode_time.as_secs_f64());
+    println!(
+        "  processing  {:.3} s",
+        pipeline_time.saturating_sub(decode_time).as_secs_f64()
+    );
+    println!("  writing     {:.3} s", write_time.as_secs_f64());
+
+    match error {
+        Some(error) => bail!("Decoding failed: {}", error),
+        None => Ok(()),
+    }
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, usage());
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = render(&options) {
+        eprintln!("decode: {:#}", e);
+        process::exit(1);
+    }
+}
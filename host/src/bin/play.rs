@@ -0,0 +1,131 @@
+// Runs the player's playback task over a folder, like the firmware does once a speaker is
+// connected, but plays into a WAV file instead of over Bluetooth.
+//
+//   cargo run --release --bin play -- /media/sdcard/music music.wav
+
+use std::{
+    env,
+    future::Future,
+    pin::pin,
+    process,
+    task::{self, Poll},
+};
+
+use anyhow::{Context, Result};
+use futures::{channel::mpsc, executor::block_on, task::noop_waker_ref};
+
+use esp32_a2dp_player_host::{
+    audio::{self, NowPlaying, PlaybackReport, PlaybackSettings, CHANNELS},
+    audio_mixer::Mixer,
+    audio_sink::{AudioSink, WavFileSink},
+    bluetooth_hal::Stream,
+    playlist::Playlist,
+    wav::WavWriter,
+};
+
+// 10 ms at a time, like the sinks
+const BLOCK_FRAMES: usize = 441;
+
+fn usage() -> &'static str {
+    "Usage: play <folder> <output.wav> [--realtime]
+
+Plays the files in the folder, in the firmware's playlist order, into a 16 bit stereo WAV file.
+
+Options:
+  --realtime        play at the speed of a real output, through a sink like the firmware's,
+                    rather than as fast as possible"
+}
+
+fn play(folder: &str, output: &str, realtime: bool) -> Result<()> {
+    let playlist = Playlist::from_folder(folder, false)
+        .with_context(|| format!("Failed to read playlist from {}", folder))?;
+    let mut mixer = Mixer::new();
+    let mixer_control = mixer.control();
+    let settings = PlaybackSettings::default();
+    let now_playing = NowPlaying::new();
+    // playback stops when all senders are gone, so keep this one for as long as it runs
+    let (_commands, mut receiver) = mpsc::unbounded();
+    let playback = audio::playback_task(
+        &mixer_control,
+        &playlist,
+        &settings,
+        &mut receiver,
+        &now_playing,
+    );
+
+    let report = if realtime {
+        let mut sink = WavFileSink::new(output, true);
+        block_on(async {
+            sink.start(Box::new(mixer)).await?;
+            let report = playback.await;
+            sink.stop().await?;
+            report
+        })?
+    } else {
+        render(&mut mixer, playback, output)?
+    };
+
+    println!("Played {} files to {}", report.files_completed, output);
+    for (file, error) in &report.files_failed {
+        println!("Could not play {}: {}", file, error);
+    }
+    if report.corrupt_packets > 0 {
+        println!("Skipped {} corrupt packets", report.corrupt_packets);
+    }
+    Ok(())
+}
+
+// Reads the mixer in step with the playback task, which gets to act on every block read, e.g. by
+// starting the next file when one has ended, before the next block is read. That way the output
+// is the same every time; a sink's thread would read on regardless, putting a varying amount of
+// silence between the files and after the last one.
+fn render(
+    mixer: &mut Mixer,
+    playback: impl Future<Output = Result<PlaybackReport>>,
+    output: &str,
+) -> Result<PlaybackReport> {
+    let mut writer = WavWriter::create(output)?;
+    let mut playback = pin!(playback);
+    // nothing to wake: the task is polled after every block anyway
+    let mut context = task::Context::from_waker(noop_waker_ref());
+    let mut buf = vec![0i16; BLOCK_FRAMES * CHANNELS];
+
+    loop {
+        if let Poll::Ready(report) = playback.as_mut().poll(&mut context) {
+            writer.finish()?;
+            return report;
+        }
+
+        let count = mixer.read(&mut buf)?;
+        writer.write(&buf[..count])?;
+    }
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let mut files = Vec::new();
+    let mut realtime = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--realtime" => realtime = true,
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option {}\n\n{}", arg, usage());
+                process::exit(2);
+            }
+            _ => files.push(arg),
+        }
+    }
+    let [folder, output] = match <[String; 2]>::try_from(files) {
+        Ok(files) => files,
+        Err(_) => {
+            eprintln!("Expected a folder and an output file\n\n{}", usage());
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = play(&folder, &output, realtime) {
+        eprintln!("play: {:#}", e);
+        process::exit(1);
+    }
+}
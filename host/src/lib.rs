@@ -0,0 +1,44 @@
+// The firmware's decoding, playlists and processing pipeline, built for the host so they can be
+// run and tested on a PC. These are the firmware's own sources; modules which need ESP-IDF are
+// left out.
+
+// FileStream, which the firmware doesn't use either
+#[allow(dead_code)]
+#[path = "../../src/audio.rs"]
+pub mod audio;
+#[path = "../../src/audio_dsp.rs"]
+pub mod audio_dsp;
+#[path = "../../src/audio_dynamics.rs"]
+pub mod audio_dynamics;
+#[path = "../../src/audio_eq.rs"]
+pub mod audio_eq;
+#[path = "../../src/audio_generator.rs"]
+pub mod audio_generator;
+#[path = "../../src/audio_mixer.rs"]
+pub mod audio_mixer;
+#[path = "../../src/audio_resample.rs"]
+pub mod audio_resample;
+#[path = "../../src/audio_sink.rs"]
+pub mod audio_sink;
+#[path = "../../src/audio_tempo.rs"]
+pub mod audio_tempo;
+#[path = "../../src/audiobook.rs"]
+pub mod audiobook;
+#[path = "../../src/bluetooth_gap_hal.rs"]
+pub mod bluetooth_gap_hal;
+#[path = "../../src/bluetooth_hal.rs"]
+pub mod bluetooth_hal;
+#[path = "../../src/cue_sheet.rs"]
+pub mod cue_sheet;
+#[path = "../../src/ogg_chain.rs"]
+pub mod ogg_chain;
+#[path = "../../src/playlist.rs"]
+pub mod playlist;
+#[path = "../../src/sink_selection.rs"]
+pub mod sink_selection;
+#[path = "../../src/uuids.rs"]
+pub mod uuids;
+#[path = "../../src/vorbis_comments.rs"]
+pub mod vorbis_comments;
+#[path = "../../src/wav.rs"]
+pub mod wav;
@@ -0,0 +1,213 @@
+// Class of Device decoding tests: classes as real devices send them, and reserved values, which
+// must decode without failing. See the Assigned Numbers section 2.8 for the tables.
+
+use esp32_a2dp_player_host::bluetooth_gap_hal::{
+    AudioVideoMinor, ClassOfDevice, ComputerMinor, HealthMinor, MajorClass, MinorClass,
+    NetworkLoad, PeripheralMinor, PhoneMinor, ServiceClass, ToyMinor, WearableMinor,
+};
+
+struct Case {
+    value: u32,
+    major: Option<MajorClass>,
+    minor: MinorClass,
+    services: &'static [ServiceClass],
+    audio_sink: bool,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        value: 0x240404,
+        major: Some(MajorClass::AudioVideo),
+        minor: MinorClass::AudioVideo(AudioVideoMinor::WearableHeadset),
+        services: &[ServiceClass::Rendering, ServiceClass::Audio],
+        audio_sink: true,
+    },
+    Case {
+        value: 0x240418,
+        major: Some(MajorClass::AudioVideo),
+        minor: MinorClass::AudioVideo(AudioVideoMinor::Headphones),
+        services: &[ServiceClass::Rendering, ServiceClass::Audio],
+        audio_sink: true,
+    },
+    Case {
+        value: 0x240414,
+        major: Some(MajorClass::AudioVideo),
+        minor: MinorClass::AudioVideo(AudioVideoMinor::Loudspeaker),
+        services: &[ServiceClass::Rendering, ServiceClass::Audio],
+        audio_sink: true,
+    },
+    Case {
+        value: 0x200420,
+        major: Some(MajorClass::AudioVideo),
+        minor: MinorClass::AudioVideo(AudioVideoMinor::CarAudio),
+        services: &[ServiceClass::Audio],
+        audio_sink: true,
+    },
+    Case {
+        value: 0x200410,
+        major: Some(MajorClass::AudioVideo),
+        minor: MinorClass::AudioVideo(AudioVideoMinor::Microphone),
+        services: &[ServiceClass::Audio],
+        audio_sink: false,
+    },
+    // uncategorized audio/video devices are sinks only if they render audio
+    Case {
+        value: 0x240400,
+        major: Some(MajorClass::AudioVideo),
+        minor: MinorClass::AudioVideo(AudioVideoMinor::Uncategorized),
+        services: &[ServiceClass::Rendering, ServiceClass::Audio],
+        audio_sink: true,
+    },
+    Case {
+        value: 0x200400,
+        major: Some(MajorClass::AudioVideo),
+        minor: MinorClass::AudioVideo(AudioVideoMinor::Uncategorized),
+        services: &[ServiceClass::Audio],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x00040c,
+        major: Some(MajorClass::AudioVideo),
+        minor: MinorClass::Other(3),
+        services: &[],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x5a020c,
+        major: Some(MajorClass::Phone),
+        minor: MinorClass::Phone(PhoneMinor::Smartphone),
+        services: &[
+            ServiceClass::Networking,
+            ServiceClass::Capturing,
+            ServiceClass::ObjectTransfer,
+            ServiceClass::Telephony,
+        ],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x00010c,
+        major: Some(MajorClass::Computer),
+        minor: MinorClass::Computer(ComputerMinor::Laptop),
+        services: &[],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x0003e0,
+        major: Some(MajorClass::Network),
+        minor: MinorClass::Network(NetworkLoad::NoServiceAvailable),
+        services: &[],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x000540,
+        major: Some(MajorClass::Peripheral),
+        minor: MinorClass::Peripheral {
+            keyboard: true,
+            pointing: false,
+            device: PeripheralMinor::Uncategorized,
+        },
+        services: &[],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x0025cc,
+        major: Some(MajorClass::Peripheral),
+        minor: MinorClass::Peripheral {
+            keyboard: true,
+            pointing: true,
+            device: PeripheralMinor::RemoteControl,
+        },
+        services: &[ServiceClass::LimitedDiscoverableMode],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x0006a0,
+        major: Some(MajorClass::Imaging),
+        minor: MinorClass::Imaging {
+            display: false,
+            camera: true,
+            scanner: false,
+            printer: true,
+        },
+        services: &[],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x000704,
+        major: Some(MajorClass::Wearable),
+        minor: MinorClass::Wearable(WearableMinor::Wristwatch),
+        services: &[],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x000808,
+        major: Some(MajorClass::Toy),
+        minor: MinorClass::Toy(ToyMinor::Vehicle),
+        services: &[],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x000908,
+        major: Some(MajorClass::Health),
+        minor: MinorClass::Health(HealthMinor::Thermometer),
+        services: &[],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x004000,
+        major: Some(MajorClass::Miscellaneous),
+        minor: MinorClass::Other(0),
+        services: &[ServiceClass::LeAudio],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x001f00,
+        major: Some(MajorClass::Uncategorized),
+        minor: MinorClass::Other(0),
+        services: &[],
+        audio_sink: false,
+    },
+    // reserved major and minor values, and the reserved service class bit
+    Case {
+        value: 0x008d14,
+        major: None,
+        minor: MinorClass::Other(5),
+        services: &[],
+        audio_sink: false,
+    },
+    Case {
+        value: 0x0004fc,
+        major: Some(MajorClass::AudioVideo),
+        minor: MinorClass::Other(0x3f),
+        services: &[],
+        audio_sink: false,
+    },
+];
+
+#[test]
+fn decodes_classes() {
+    for case in CASES {
+        let class = ClassOfDevice { value: case.value };
+        let name = format!("{:06x}", case.value);
+
+        assert_eq!(class.get_major_device_class().ok(), case.major, "{name}");
+        assert_eq!(class.minor_class(), case.minor, "{name}");
+        assert_eq!(class.service_classes(), case.services, "{name}");
+        for service in case.services {
+            assert!(class.has_service_class(*service), "{name}");
+        }
+        assert_eq!(class.is_audio_sink(), case.audio_sink, "{name}");
+    }
+}
+
+#[test]
+fn displays_reserved_values() {
+    assert_eq!(
+        ClassOfDevice { value: 0x240418 }.to_string(),
+        "Major: AudioVideo, Minor: AudioVideo(Headphones), Service Classes: Rendering, Audio"
+    );
+    assert_eq!(
+        ClassOfDevice { value: 0x008d14 }.to_string(),
+        "Major: reserved, Minor: 5, Service Classes: "
+    );
+}
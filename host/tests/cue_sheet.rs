@@ -0,0 +1,182 @@
+// CUE sheet parsing tests: the tracks found in sheets as rippers write them, and sheets with
+// missing or malformed lines, which either skip what can't be played or fail.
+
+use std::path::Path;
+
+use esp32_a2dp_player_host::cue_sheet::CueSheet;
+
+const DIRECTORY: &str = "/sdcard/album";
+
+// number, file below DIRECTORY, title, performer, start and end in ms
+type Track<'a> = (
+    u32,
+    &'a str,
+    Option<&'a str>,
+    Option<&'a str>,
+    u64,
+    Option<u64>,
+);
+
+struct Case {
+    name: &'static str,
+    sheet: &'static str,
+    // None if the sheet can't be read
+    tracks: Option<&'static [Track<'static>]>,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "empty",
+        sheet: "",
+        tracks: Some(&[]),
+    },
+    Case {
+        name: "one file",
+        sheet: r#"REM GENRE Rock
+PERFORMER "The Band"
+TITLE "The Album"
+FILE "album.wav" WAVE
+  TRACK 01 AUDIO
+    TITLE "First Song"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Second Song"
+    PERFORMER "Guest"
+    INDEX 00 03:58:50
+    INDEX 01 04:00:00
+  TRACK 03 AUDIO
+    TITLE "Third Song"
+    INDEX 01 08:30:37
+"#,
+        tracks: Some(&[
+            (1, "album.wav", Some("First Song"), None, 0, Some(240_000)),
+            (
+                2,
+                "album.wav",
+                Some("Second Song"),
+                Some("Guest"),
+                240_000,
+                Some(510_493),
+            ),
+            (3, "album.wav", Some("Third Song"), None, 510_493, None),
+        ]),
+    },
+    Case {
+        name: "a file per track",
+        sheet: r#"FILE "01 First.wav" WAVE
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+FILE "02 Second.wav" WAVE
+  TRACK 02 AUDIO
+    INDEX 01 00:00:00
+"#,
+        tracks: Some(&[
+            (1, "01 First.wav", None, None, 0, None),
+            (2, "02 Second.wav", None, None, 0, None),
+        ]),
+    },
+    Case {
+        name: "quotes, case and whitespace",
+        sheet: "file \"album.wav\" WAVE\r\n\ttrack 1 audio\r\n\t\ttitle   \"  Spaces  inside  \"\r\n\t\tindex 1 00:00:00\r\n  TRACK 2 AUDIO\n    TITLE Unquoted\n    INDEX 01 01:00:00\n",
+        tracks: Some(&[
+            (1, "album.wav", Some("  Spaces  inside  "), None, 0, Some(60_000)),
+            (2, "album.wav", Some("Unquoted"), None, 60_000, None),
+        ]),
+    },
+    Case {
+        name: "unterminated quote",
+        sheet: "FILE \"album.wav\" WAVE\nTRACK 01 AUDIO\nTITLE \"No end\nINDEX 01 00:00:00\n",
+        tracks: Some(&[(1, "album.wav", Some("No end"), None, 0, None)]),
+    },
+    Case {
+        name: "MM:SS:FF",
+        sheet: "FILE a.wav WAVE\nTRACK 1 AUDIO\nINDEX 01 00:00:74\nTRACK 2 AUDIO\nINDEX 01 99:59:00\nTRACK 3 AUDIO\nINDEX 01 120:00:01\n",
+        tracks: Some(&[
+            (1, "a.wav", None, None, 986, Some(5_999_000)),
+            (2, "a.wav", None, None, 5_999_000, Some(7_200_013)),
+            (3, "a.wav", None, None, 7_200_013, None),
+        ]),
+    },
+    Case {
+        name: "missing INDEX 01",
+        sheet: "FILE a.wav WAVE\nTRACK 1 AUDIO\nINDEX 00 00:00:00\nTRACK 2 AUDIO\nINDEX 01 00:10:00\nTRACK 3 AUDIO\n",
+        tracks: Some(&[(2, "a.wav", None, None, 10_000, None)]),
+    },
+    Case {
+        name: "lines without arguments, unknown commands",
+        sheet: "FILE a.wav WAVE\nTRACK 1 AUDIO\nTITLE\nPERFORMER\nINDEX 01\nFLAGS DCP\nISRC ABC\nBOGUS\nINDEX 01 00:01:00\n",
+        tracks: Some(&[(1, "a.wav", None, None, 1000, None)]),
+    },
+    Case {
+        name: "TRACK before FILE",
+        sheet: "TRACK 01 AUDIO\nINDEX 01 00:00:00\n",
+        tracks: None,
+    },
+    Case {
+        name: "track number not a number",
+        sheet: "FILE a.wav WAVE\nTRACK one AUDIO\n",
+        tracks: None,
+    },
+    Case {
+        name: "seconds out of range",
+        sheet: "FILE a.wav WAVE\nTRACK 1 AUDIO\nINDEX 01 00:60:00\n",
+        tracks: None,
+    },
+    Case {
+        name: "frames out of range",
+        sheet: "FILE a.wav WAVE\nTRACK 1 AUDIO\nINDEX 01 00:00:75\n",
+        tracks: None,
+    },
+    Case {
+        name: "time without frames",
+        sheet: "FILE a.wav WAVE\nTRACK 1 AUDIO\nINDEX 01 04:00\n",
+        tracks: None,
+    },
+    Case {
+        name: "time with too many parts",
+        sheet: "FILE a.wav WAVE\nTRACK 1 AUDIO\nINDEX 01 00:04:00:00\n",
+        tracks: None,
+    },
+    Case {
+        name: "time not a number",
+        sheet: "FILE a.wav WAVE\nTRACK 1 AUDIO\nINDEX 01 00:xx:00\n",
+        tracks: None,
+    },
+];
+
+#[test]
+fn parse() {
+    for case in CASES {
+        let sheet = CueSheet::parse(case.sheet, Path::new(DIRECTORY));
+
+        let Some(expected) = case.tracks else {
+            assert!(sheet.is_err(), "{}: read", case.name);
+            continue;
+        };
+        let sheet = sheet.unwrap_or_else(|e| panic!("{}: {}", case.name, e));
+        let tracks: Vec<Track> = sheet
+            .tracks
+            .iter()
+            .map(|track| {
+                let file = Path::new(&track.file).strip_prefix(DIRECTORY).unwrap();
+                (
+                    track.number,
+                    file.to_str().unwrap(),
+                    track.title.as_deref(),
+                    track.performer.as_deref(),
+                    track.start_ms,
+                    track.end_ms,
+                )
+            })
+            .collect();
+        assert_eq!(tracks, expected, "{}", case.name);
+    }
+}
+
+#[test]
+fn album_title_and_performer() {
+    let sheet = CueSheet::parse(CASES[1].sheet, Path::new(DIRECTORY)).unwrap();
+
+    assert_eq!(sheet.title.as_deref(), Some("The Album"));
+    assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+}
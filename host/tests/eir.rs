@@ -0,0 +1,319 @@
+// EIR parsing tests: the records a device sends in its inquiry response, and what is made of
+// them. The data comes from other devices, so besides the table of well formed records, truncated
+// and random data must not make the parser panic. The fuzz target in ../fuzz goes further.
+
+use esp32_a2dp_player_host::{
+    bluetooth_gap_hal::{ClassOfDevice, DeviceId, ManufacturerData, ScannedDevice, ServiceUuid},
+    uuids::Bluetooth16bitUUIDEnum,
+};
+
+// As sent by a pair of headphones: name, services, device ID and TX power, padded with zeros
+const HEADPHONES: &[u8] = &[
+    0x0b, 0x09, b'H', b'e', b'a', b'd', b'p', b'h', b'o', b'n', b'e', b's', //
+    0x07, 0x03, 0x0b, 0x11, 0x0e, 0x11, 0x1e, 0x11, //
+    0x09, 0x10, 0x02, 0x00, 0x5d, 0x00, 0x34, 0x12, 0x01, 0x00, //
+    0x02, 0x0a, 0xf8, //
+    0x00, 0x00, 0x00, 0x00,
+];
+
+struct Case {
+    name: &'static str,
+    eir: &'static [u8],
+    expected: fn(&mut ScannedDevice),
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "empty",
+        eir: &[],
+        expected: |_| {},
+    },
+    Case {
+        name: "padding only",
+        eir: &[0, 0, 0, 0],
+        expected: |_| {},
+    },
+    Case {
+        name: "headphones",
+        eir: HEADPHONES,
+        expected: |device| {
+            device.name = Some("Headphones".to_string());
+            device.service_uuids = vec![
+                ServiceUuid::Known(Bluetooth16bitUUIDEnum::AudioSink),
+                ServiceUuid::Known(Bluetooth16bitUUIDEnum::AVRemoteControl),
+                ServiceUuid::Known(Bluetooth16bitUUIDEnum::Handsfree),
+            ];
+            device.device_id = Some(DeviceId {
+                vendor_id_source: 2,
+                vendor_id: 0x5d,
+                product_id: 0x1234,
+                version: 1,
+            });
+            device.tx_power = Some(-8);
+        },
+    },
+    Case {
+        name: "flags",
+        eir: &[0x02, 0x01, 0x06],
+        expected: |device| device.flags = Some(0x06),
+    },
+    Case {
+        name: "incomplete 16 bit UUIDs",
+        eir: &[0x05, 0x02, 0x0d, 0x11, 0x00, 0xfe],
+        expected: |device| {
+            device.service_uuids = vec![
+                ServiceUuid::Known(Bluetooth16bitUUIDEnum::AdvancedAudioDistribution),
+                ServiceUuid::Uuid16(0xfe00),
+            ];
+            device.service_uuids_incomplete = true;
+        },
+    },
+    Case {
+        name: "32 bit UUIDs",
+        eir: &[0x09, 0x05, 0x0b, 0x11, 0x00, 0x00, 0x78, 0x56, 0x34, 0x12],
+        expected: |device| {
+            device.service_uuids = vec![
+                ServiceUuid::Known(Bluetooth16bitUUIDEnum::AudioSink),
+                ServiceUuid::Uuid32(0x12345678),
+            ];
+        },
+    },
+    Case {
+        name: "128 bit UUIDs",
+        eir: &[
+            0x21, 0x07, //
+            0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0x0b, 0x11,
+            0x00, 0x00, //
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+            0x0f, 0x10,
+        ],
+        expected: |device| {
+            device.service_uuids = vec![
+                ServiceUuid::Known(Bluetooth16bitUUIDEnum::AudioSink),
+                ServiceUuid::Uuid128([
+                    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+                    0x0e, 0x0f, 0x10,
+                ]),
+            ];
+        },
+    },
+    Case {
+        name: "incomplete 128 bit UUIDs",
+        eir: &[
+            0x11, 0x06, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+            0x0d, 0x0e, 0x0f, 0x10,
+        ],
+        expected: |device| {
+            device.service_uuids = vec![ServiceUuid::Uuid128([
+                0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+                0x0f, 0x10,
+            ])];
+            device.service_uuids_incomplete = true;
+        },
+    },
+    Case {
+        name: "partial UUID is dropped",
+        eir: &[0x04, 0x03, 0x0b, 0x11, 0x0e],
+        expected: |device| {
+            device.service_uuids = vec![ServiceUuid::Known(Bluetooth16bitUUIDEnum::AudioSink)];
+        },
+    },
+    Case {
+        name: "solicited services",
+        eir: &[0x03, 0x14, 0x0e, 0x11],
+        expected: |device| {
+            device.solicited_service_uuids =
+                vec![ServiceUuid::Known(Bluetooth16bitUUIDEnum::AVRemoteControl)];
+        },
+    },
+    Case {
+        name: "shortened name",
+        eir: &[0x05, 0x08, b'S', b'p', b'e', b'a'],
+        expected: |device| {
+            device.name = Some("Spea".to_string());
+            device.name_shortened = true;
+        },
+    },
+    Case {
+        name: "complete name wins over a later shortened one",
+        eir: &[
+            0x08, 0x09, b'S', b'p', b'e', b'a', b'k', b'e', b'r', //
+            0x04, 0x08, b'S', b'p', b'e',
+        ],
+        expected: |device| device.name = Some("Speaker".to_string()),
+    },
+    Case {
+        name: "complete name wins over an earlier shortened one",
+        eir: &[
+            0x04, 0x08, b'S', b'p', b'e', //
+            0x08, 0x09, b'S', b'p', b'e', b'a', b'k', b'e', b'r',
+        ],
+        expected: |device| device.name = Some("Speaker".to_string()),
+    },
+    Case {
+        name: "name cut in a character",
+        eir: &[0x04, 0x08, b'B', b'l', 0xc3],
+        expected: |device| {
+            device.name = Some("Bl\u{fffd}".to_string());
+            device.name_shortened = true;
+        },
+    },
+    Case {
+        name: "name with invalid UTF-8 and a terminator",
+        eir: &[0x05, 0x09, b'B', 0xc3, b'l', 0x00],
+        expected: |device| device.name = Some("B\u{fffd}l".to_string()),
+    },
+    Case {
+        name: "class of device",
+        eir: &[0x04, 0x0d, 0x18, 0x04, 0x24],
+        expected: |device| device.class = Some(ClassOfDevice { value: 0x240418 }),
+    },
+    Case {
+        name: "manufacturer data",
+        eir: &[0x05, 0xff, 0x4c, 0x00, 0x01, 0x02],
+        expected: |device| {
+            device.manufacturer_data = vec![ManufacturerData {
+                company_id: 0x004c,
+                data: vec![0x01, 0x02],
+            }];
+        },
+    },
+    Case {
+        name: "manufacturer data without data",
+        eir: &[0x03, 0xff, 0x4c, 0x00],
+        expected: |device| {
+            device.manufacturer_data = vec![ManufacturerData {
+                company_id: 0x004c,
+                data: Vec::new(),
+            }];
+        },
+    },
+    Case {
+        name: "simple pairing hash and randomizer",
+        eir: &[
+            0x11, 0x0e, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, //
+            0x11, 0x0f, 16, 15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1,
+        ],
+        expected: |device| {
+            device.simple_pairing_hash =
+                Some([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+            device.simple_pairing_randomizer =
+                Some([16, 15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        },
+    },
+    Case {
+        name: "security manager flags and connection interval range",
+        eir: &[0x02, 0x11, 0x01, 0x05, 0x12, 0x06, 0x00, 0x80, 0x0c],
+        expected: |device| {
+            device.security_manager_oob_flags = Some(0x01);
+            device.connection_interval_range = Some((0x0006, 0x0c80));
+        },
+    },
+    Case {
+        name: "unknown type is skipped",
+        eir: &[0x03, 0x42, 0x01, 0x02, 0x02, 0x0a, 0x04],
+        expected: |device| device.tx_power = Some(4),
+    },
+    Case {
+        name: "record of the wrong length is skipped",
+        eir: &[0x03, 0x0a, 0x01, 0x02, 0x02, 0x01, 0x06],
+        expected: |device| device.flags = Some(0x06),
+    },
+    Case {
+        name: "record with only a type",
+        eir: &[0x01, 0x09, 0x02, 0x0a, 0x04],
+        expected: |device| {
+            device.name = Some(String::new());
+            device.tx_power = Some(4);
+        },
+    },
+    Case {
+        name: "truncated record ends parsing",
+        eir: &[0x02, 0x0a, 0x04, 0x09, 0x09, b'S', b'p'],
+        expected: |device| device.tx_power = Some(4),
+    },
+    Case {
+        name: "length without a type",
+        eir: &[0x02, 0x0a, 0x04, 0x01],
+        expected: |device| device.tx_power = Some(4),
+    },
+];
+
+fn parse(eir: &[u8]) -> ScannedDevice {
+    let mut device = ScannedDevice::default();
+    device.parse_eir(eir);
+    device
+}
+
+#[test]
+fn parses_records() {
+    for case in CASES {
+        let mut expected = ScannedDevice::default();
+        (case.expected)(&mut expected);
+
+        assert_eq!(parse(case.eir), expected, "{}", case.name);
+    }
+}
+
+#[test]
+fn class_from_inquiry_wins() {
+    let mut device = ScannedDevice {
+        class: Some(ClassOfDevice { value: 0x200404 }),
+        ..Default::default()
+    };
+    device.parse_eir(&[0x04, 0x0d, 0x18, 0x04, 0x24]);
+
+    assert_eq!(device.class, Some(ClassOfDevice { value: 0x200404 }));
+}
+
+#[test]
+fn finds_a2dp_uuid() {
+    let device = parse(&[0x03, 0x03, 0x0d, 0x11]);
+
+    assert!(device.has_16bit_uuid(Bluetooth16bitUUIDEnum::AdvancedAudioDistribution as u16));
+    assert!(!device.has_16bit_uuid(Bluetooth16bitUUIDEnum::Handsfree as u16));
+}
+
+// Every cut of a real packet keeps the records before the cut
+#[test]
+fn truncated_data() {
+    let full = parse(HEADPHONES);
+    for len in 0..HEADPHONES.len() {
+        let device = parse(&HEADPHONES[..len]);
+
+        if len >= 12 {
+            assert_eq!(device.name, full.name, "cut at {len}");
+        } else {
+            assert_eq!(device.name, None, "cut at {len}");
+        }
+        if len >= 33 {
+            assert_eq!(device, full, "cut at {len}");
+        }
+    }
+}
+
+// A quick run of the fuzz target's check, with a fixed seed so that it is the same every time
+#[test]
+fn random_data() {
+    let mut state: u32 = 0x2545_f491;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+
+    for _ in 0..20_000 {
+        let len = (next() % 64) as usize;
+        let eir: Vec<u8> = (0..len)
+            .map(|_| match next() % 4 {
+                // mostly lengths and types which exist, to get past the first record
+                0 => (next() % 20) as u8,
+                1 => [0x03, 0x07, 0x09, 0x10, 0xff][(next() % 5) as usize],
+                _ => next() as u8,
+            })
+            .collect();
+
+        parse(&eir);
+    }
+}
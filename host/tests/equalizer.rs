@@ -0,0 +1,124 @@
+// Equalizer tests: the response of each filter type where it is defined (the center of a peak, the
+// corner of a shelf, which gets half the gain, and far from it), and bands which must not get into
+// the filters.
+
+use esp32_a2dp_player_host::{
+    audio_dsp::AudioProcessor,
+    audio_eq::{builtin_preset, EqBand, Equalizer, FilterType},
+};
+
+const TOLERANCE_DB: f32 = 0.1;
+
+fn band(filter_type: FilterType, frequency: f32, gain_db: f32, q: f32) -> EqBand {
+    EqBand {
+        filter_type,
+        frequency,
+        gain_db,
+        q,
+    }
+}
+
+struct Case {
+    name: &'static str,
+    bands: Vec<EqBand>,
+    // (frequency, gain in dB)
+    response: &'static [(f32, f32)],
+}
+
+fn cases() -> Vec<Case> {
+    vec![
+        Case {
+            name: "flat",
+            bands: Vec::new(),
+            response: &[(20.0, 0.0), (1000.0, 0.0), (20000.0, 0.0)],
+        },
+        Case {
+            name: "peak",
+            bands: vec![band(FilterType::Peaking, 1000.0, 6.0, 1.0)],
+            response: &[(1000.0, 6.0), (20.0, 0.0), (20000.0, 0.0)],
+        },
+        Case {
+            name: "dip",
+            bands: vec![band(FilterType::Peaking, 2500.0, -9.0, 2.0)],
+            response: &[(2500.0, -9.0), (100.0, 0.0)],
+        },
+        Case {
+            name: "low shelf",
+            bands: vec![band(FilterType::LowShelf, 120.0, 6.0, 0.707)],
+            response: &[(10.0, 6.0), (120.0, 3.0), (10000.0, 0.0)],
+        },
+        Case {
+            name: "low shelf cut",
+            bands: vec![band(FilterType::LowShelf, 150.0, -6.0, 0.707)],
+            response: &[(10.0, -6.0), (150.0, -3.0), (10000.0, 0.0)],
+        },
+        Case {
+            name: "high shelf",
+            bands: vec![band(FilterType::HighShelf, 6000.0, 6.0, 0.707)],
+            response: &[(6000.0, 3.0), (50.0, 0.0)],
+        },
+        Case {
+            name: "bands add up",
+            bands: vec![
+                band(FilterType::Peaking, 1000.0, 4.0, 1.0),
+                band(FilterType::Peaking, 1000.0, 2.0, 1.0),
+            ],
+            response: &[(1000.0, 6.0), (20.0, 0.0)],
+        },
+        Case {
+            name: "voice preset",
+            bands: builtin_preset("voice").unwrap(),
+            response: &[(2500.0, 4.0), (10.0, -6.0)],
+        },
+    ]
+}
+
+#[test]
+fn response() {
+    for case in cases() {
+        let equalizer = Equalizer::new(case.bands);
+
+        for &(frequency, expected) in case.response {
+            let response = equalizer.response_db(frequency);
+            assert!(
+                (response - expected).abs() < TOLERANCE_DB,
+                "{}: {} dB at {} Hz, expected {} dB",
+                case.name,
+                response,
+                frequency,
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn rejects_invalid_bands() {
+    let valid = band(FilterType::Peaking, 1000.0, 6.0, 1.0);
+    let invalid = [
+        band(FilterType::Peaking, f32::NAN, 6.0, 1.0),
+        band(FilterType::Peaking, 0.0, 6.0, 1.0),
+        band(FilterType::Peaking, -100.0, 6.0, 1.0),
+        band(FilterType::HighShelf, 22050.0, 6.0, 0.707),
+        band(FilterType::Peaking, 1000.0, f32::NAN, 1.0),
+        band(FilterType::Peaking, 1000.0, f32::INFINITY, 1.0),
+        band(FilterType::Peaking, 1000.0, 100.0, 1.0),
+        band(FilterType::LowShelf, 100.0, 6.0, 0.0),
+        band(FilterType::LowShelf, 100.0, 6.0, -0.707),
+        band(FilterType::LowShelf, 100.0, 6.0, f32::NAN),
+        band(FilterType::LowShelf, 100.0, 6.0, f32::INFINITY),
+    ];
+
+    assert!(valid.validate().is_ok());
+    for band in invalid {
+        assert!(band.validate().is_err(), "{:?}", band);
+
+        // the valid band is kept, the invalid one dropped, and the output stays finite
+        let mut equalizer = Equalizer::new(vec![valid, band]);
+        assert_eq!(equalizer.bands(), &[valid], "{:?}", band);
+
+        let mut samples = vec![0.5f32; 512];
+        equalizer.process(&mut samples);
+        assert!(samples.iter().all(|s| s.is_finite()), "{:?}", band);
+    }
+}
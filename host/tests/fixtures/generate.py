@@ -0,0 +1,276 @@
+#!/usr/bin/env python3
+# Writes the input files of the golden tests.
+#
+# The Ogg Vorbis files come from a minimal encoder: short blocks only, a flat floor, and residue
+# values picked directly instead of from an MDCT of a signal. That still gives the decoder real
+# work, and keeps the files tiny. Changing anything here changes the expected output, so the
+# references have to be regenerated afterwards, see ../golden.rs.
+#
+#   cd host/tests/fixtures && python3 generate.py
+
+import math
+import struct
+
+BLOCKSIZE = 256
+# samples per channel that each audio packet after the first adds
+PACKET_SAMPLES = BLOCKSIZE // 2
+FLOOR_Y = 90
+PARTITION_SIZE = 16
+
+
+class BitWriter:
+    def __init__(self):
+        self.data = bytearray()
+        self.bit = 0
+
+    # least significant bit first, as everywhere in Vorbis
+    def write(self, value, bits):
+        for i in range(bits):
+            if self.bit == 0:
+                self.data.append(0)
+            if (value >> i) & 1:
+                self.data[-1] |= 1 << self.bit
+            self.bit = (self.bit + 1) % 8
+
+    # Huffman codewords are read a bit at a time, most significant bit first
+    def codeword(self, code, length):
+        for i in reversed(range(length)):
+            self.write((code >> i) & 1, 1)
+
+    def write_bytes(self, data):
+        for byte in data:
+            self.write(byte, 8)
+
+
+# Vorbis float32, for integer values only
+def float32(value):
+    sign = 0x80000000 if value < 0 else 0
+    return sign | (788 << 21) | abs(value)
+
+
+def codebook(w, lengths, lookup=None):
+    w.write(0x564342, 24)
+    w.write(1, 16)  # dimensions
+    w.write(len(lengths), 24)
+    w.write(0, 1)  # not ordered
+    w.write(0, 1)  # not sparse
+    for length in lengths:
+        w.write(length - 1, 5)
+    if lookup is None:
+        w.write(0, 4)
+    else:
+        minimum, value_bits = lookup
+        w.write(1, 4)
+        w.write(float32(minimum), 32)
+        w.write(float32(1), 32)  # delta
+        w.write(value_bits - 1, 4)
+        w.write(0, 1)  # not a sequence
+        for multiplicand in range(len(lengths)):
+            w.write(multiplicand, value_bits)
+
+
+def identification_header(channels, rate):
+    exponent = BLOCKSIZE.bit_length() - 1
+    return (
+        b"\x01vorbis"
+        + struct.pack("<IBIiii", 0, channels, rate, 0, 0, 0)
+        + bytes([exponent << 4 | exponent, 1])
+    )
+
+
+def comment_header(comments):
+    vendor = b"esp32-a2dp-player test fixture"
+    data = b"\x03vorbis" + struct.pack("<I", len(vendor)) + vendor
+    data += struct.pack("<I", len(comments))
+    for comment in comments:
+        encoded = comment.encode()
+        data += struct.pack("<I", len(encoded)) + encoded
+    return data + b"\x01"
+
+
+def setup_header():
+    w = BitWriter()
+    w.write_bytes(b"\x05vorbis")
+
+    # book 0 classifies residue partitions, book 1 holds residue values -16..15
+    w.write(2 - 1, 8)
+    codebook(w, [1, 1])
+    codebook(w, [5] * 32, lookup=(-16, 5))
+
+    # time domain transforms, unused
+    w.write(0, 6)
+    w.write(0, 16)
+
+    # one floor 1 with a point in the middle which is never coded, so the floor is flat
+    w.write(0, 6)
+    w.write(1, 16)
+    w.write(1, 5)  # partitions
+    w.write(0, 4)  # class of partition 0
+    w.write(0, 3)  # class dimension 1
+    w.write(0, 2)  # no subclasses
+    w.write(0, 8)  # no subclass book
+    w.write(1, 2)  # multiplier 2
+    w.write(7, 4)  # range bits
+    w.write(64, 7)
+
+    # one residue type 1: class 0 partitions are silent, class 1 ones coded with book 1
+    w.write(0, 6)
+    w.write(1, 16)
+    w.write(0, 24)  # begin
+    w.write(BLOCKSIZE // 2, 24)  # end
+    w.write(PARTITION_SIZE - 1, 24)
+    w.write(2 - 1, 6)  # classifications
+    w.write(0, 8)  # classbook
+    w.write(0, 3)  # class 0 cascade: nothing
+    w.write(0, 1)
+    w.write(1, 3)  # class 1 cascade: pass 0
+    w.write(0, 1)
+    w.write(1, 8)
+
+    # one mapping, one submap, no coupling
+    w.write(0, 6)
+    w.write(0, 16)
+    w.write(0, 1)
+    w.write(0, 1)
+    w.write(0, 2)
+    w.write(0, 8)
+    w.write(0, 8)
+    w.write(0, 8)
+
+    # one mode, short blocks
+    w.write(0, 6)
+    w.write(0, 1)
+    w.write(0, 16)
+    w.write(0, 16)
+    w.write(0, 8)
+
+    w.write(1, 1)  # framing
+    return bytes(w.data)
+
+
+# spectra holds per channel a dict of MDCT bin to residue value, or None for a silent channel
+def audio_packet(spectra):
+    w = BitWriter()
+    w.write(0, 1)
+
+    for spectrum in spectra:
+        if spectrum is None:
+            w.write(0, 1)
+        else:
+            w.write(1, 1)
+            w.write(FLOOR_Y, 7)
+            w.write(FLOOR_Y, 7)
+
+    coded = [spectrum for spectrum in spectra if spectrum is not None]
+    for partition in range(BLOCKSIZE // 2 // PARTITION_SIZE):
+        bins = range(partition * PARTITION_SIZE, (partition + 1) * PARTITION_SIZE)
+        classes = [1 if any(spectrum.get(b) for b in bins) else 0 for spectrum in coded]
+        for c in classes:
+            w.codeword(c, 1)
+        for spectrum, c in zip(coded, classes):
+            if c:
+                for b in bins:
+                    w.codeword(spectrum.get(b, 0) + 16, 5)
+
+    return bytes(w.data)
+
+
+def tone_spectra(channels, index):
+    spectra = []
+    for channel in range(channels):
+        level = round(14 * math.sin(0.21 * index + channel))
+        spectra.append({6 + 5 * channel: level, 40 + 3 * channel: -level // 2, 90: 3})
+    return spectra
+
+
+def crc32(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte << 24
+        for _ in range(8):
+            crc = (crc << 1) ^ 0x04C11DB7 if crc & 0x80000000 else crc << 1
+            crc &= 0xFFFFFFFF
+    return crc
+
+
+def page(serial, sequence, granule, packets, flags):
+    lacing = bytearray()
+    for packet in packets:
+        lacing += bytes([255] * (len(packet) // 255) + [len(packet) % 255])
+    header = (
+        b"OggS"
+        + struct.pack("<BBqII", 0, flags, granule, serial, sequence)
+        + b"\0\0\0\0"
+        + bytes([len(lacing)])
+        + lacing
+    )
+    data = bytearray(header + b"".join(packets))
+    data[22:26] = struct.pack("<I", crc32(data))
+    return bytes(data)
+
+
+# A logical bitstream: headers, then packets_per_page audio packets per page
+def link(serial, channels, rate, comments, packets, packets_per_page=16):
+    data = page(serial, 0, 0, [identification_header(channels, rate)], 0x02)
+    data += page(serial, 1, 0, [comment_header(comments), setup_header()], 0)
+
+    sequence = 2
+    for start in range(0, len(packets), packets_per_page):
+        chunk = packets[start : start + packets_per_page]
+        last = start + len(chunk) - 1
+        flags = 0x04 if last == len(packets) - 1 else 0
+        data += page(serial, sequence, last * PACKET_SAMPLES, chunk, flags)
+        sequence += 1
+    return data
+
+
+def tone_packets(channels, count):
+    return [audio_packet(tone_spectra(channels, i)) for i in range(count)]
+
+
+def wav(channels, samples):
+    data = struct.pack("<%dh" % len(samples), *samples)
+    return (
+        b"RIFF"
+        + struct.pack("<I", 36 + len(data))
+        + b"WAVEfmt "
+        + struct.pack("<IHHIIHH", 16, 1, channels, 44100, 44100 * 2 * channels, 2 * channels, 16)
+        + b"data"
+        + struct.pack("<I", len(data))
+        + data
+    )
+
+
+def sine(frequency, frame, amplitude):
+    return round(amplitude * math.sin(2 * math.pi * frequency * frame / 44100))
+
+
+def main():
+    tone = link(0x1001, 2, 44100, ["TITLE=Tone", "ARTIST=Fixture"], tone_packets(2, 64))
+    open("tone.ogg", "wb").write(tone)
+
+    # a byte of the fourth page changed, so that its checksum fails
+    corrupt = bytearray(tone)
+    pages = [i for i in range(len(corrupt)) if corrupt[i : i + 4] == b"OggS"]
+    corrupt[pages[3] + 40] ^= 0x55
+    open("corrupt.ogg", "wb").write(corrupt)
+
+    chain = link(0x2001, 2, 44100, ["TITLE=First", "ARTIST=Fixture"], tone_packets(2, 32))
+    chain += link(0x2002, 1, 22050, ["TITLE=Second", "ARTIST=Fixture"], tone_packets(1, 32))
+    open("chain.ogg", "wb").write(chain)
+
+    # the tone, then silence: more than the decoding thread buffers ahead
+    silent = [audio_packet([None, None])] * 736
+    long = link(0x3001, 2, 44100, ["TITLE=Long"], tone_packets(2, 64) + silent)
+    open("long.ogg", "wb").write(long)
+
+    stereo = []
+    for frame in range(4410):
+        stereo += [sine(440, frame, 12000), sine(660, frame, 9000)]
+    open("stereo.wav", "wb").write(wav(2, stereo))
+
+    open("mono.wav", "wb").write(wav(1, [sine(1000, frame, 16000) for frame in range(2205)]))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,554 @@
+// Golden output tests: the files in fixtures/ are played through the sources and processing stages
+// of the firmware and the output is compared with reference output in golden/.
+//
+// Outputs that must be bit exact are compared by checksum. Decoded Vorbis and floating point
+// processing are compared sample by sample with a tolerance, since other decoders and platforms
+// round differently; those references are kept as raw PCM, with their checksums.
+//
+// After a change which is meant to change the output, listen to it (failing tests write the output
+// next to the test binaries) and regenerate the references with
+//
+//   UPDATE_GOLDEN=1 cargo test --test golden
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    env, fs,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver},
+    executor::block_on,
+    StreamExt,
+};
+
+use esp32_a2dp_player_host::{
+    audio::{
+        self, DecodeOutcome, DecodeResilience, PlaybackSettings, SourceSlot, StreamEvent, CHANNELS,
+        SAMPLE_RATE,
+    },
+    audio_dsp::{AudioProcessor, DspStream, Gain},
+    audio_dynamics::{Dynamics, DynamicsSettings},
+    audio_eq::{builtin_preset, Equalizer},
+    audio_generator::{SignalGenerator, TestSignal},
+    audio_mixer::{Mixer, PromptClip},
+    audio_resample::FormatConverter,
+    audio_tempo::TempoStream,
+    bluetooth_hal::Stream,
+    wav::WavStream,
+};
+
+// Tremor is a fixed point decoder; this is about -60 dBFS
+const LOSSY_TOLERANCE: i32 = 32;
+const FLOAT_TOLERANCE: i32 = 2;
+
+// What the Bluetooth layer reads
+const READ_SIZE: usize = 256;
+
+// Frames of audio in tone.ogg and long.ogg
+const TONE_FRAMES: usize = 63 * 128;
+const LONG_FRAMES: usize = 799 * 128;
+
+// checksums.txt is rewritten by several tests when updating
+static CHECKSUMS: Mutex<()> = Mutex::new(());
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name)
+}
+
+fn updating() -> bool {
+    env::var_os("UPDATE_GOLDEN").is_some()
+}
+
+// FNV-1a, and the length so that a failure says whether it is the length that differs
+fn checksum(samples: &[i16]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for byte in samples.iter().flat_map(|s| s.to_le_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x} {}", hash, samples.len())
+}
+
+fn golden_checksum(name: &str, actual: &str) -> Option<String> {
+    let _lock = CHECKSUMS.lock().unwrap_or_else(|e| e.into_inner());
+    let path = golden_path("checksums.txt");
+
+    let mut checksums: BTreeMap<String, String> = fs::read_to_string(&path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .map(|(name, checksum)| (name.to_string(), checksum.to_string()))
+        .collect();
+
+    if updating() {
+        checksums.insert(name.to_string(), actual.to_string());
+        let contents: String = checksums
+            .iter()
+            .map(|(name, checksum)| format!("{}: {}\n", name, checksum))
+            .collect();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+    }
+    checksums.remove(name)
+}
+
+fn assert_checksum(name: &str, samples: &[i16]) {
+    let actual = checksum(samples);
+    let expected = golden_checksum(name, &actual)
+        .unwrap_or_else(|| panic!("No checksum for {}, run with UPDATE_GOLDEN=1", name));
+
+    if actual != expected {
+        let path = write_actual(name, samples);
+        panic!(
+            "{}: checksum {} differs from {}, output written to {}",
+            name, actual, expected, path
+        );
+    }
+}
+
+fn assert_close(name: &str, samples: &[i16], tolerance: i32) {
+    let path = golden_path(&format!("{}.pcm", name));
+
+    if updating() {
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        fs::write(&path, bytes).unwrap();
+    }
+    let bytes = fs::read(&path).unwrap_or_else(|e| {
+        panic!(
+            "No reference for {} ({}), run with UPDATE_GOLDEN=1",
+            name, e
+        )
+    });
+    let expected: Vec<i16> = bytes
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect();
+    assert_eq!(
+        golden_checksum(&format!("{}.pcm", name), &checksum(&expected)),
+        Some(checksum(&expected)),
+        "{}: the reference doesn't match its checksum",
+        name
+    );
+
+    let worst = samples
+        .iter()
+        .zip(&expected)
+        .map(|(a, b)| (*a as i32 - *b as i32).abs())
+        .enumerate()
+        .max_by_key(|(_, difference)| *difference);
+
+    if samples.len() != expected.len() || worst.is_some_and(|(_, d)| d > tolerance) {
+        let actual_path = write_actual(name, samples);
+        panic!(
+            "{}: {} samples, expected {}; largest difference {:?} (sample, difference), \
+             tolerance {}; output written to {}",
+            name,
+            samples.len(),
+            expected.len(),
+            worst,
+            tolerance,
+            actual_path
+        );
+    }
+}
+
+fn write_actual(name: &str, samples: &[i16]) -> String {
+    let path = format!("{}/{}.actual.pcm", env!("CARGO_TARGET_TMPDIR"), name);
+    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+
+    fs::write(&path, bytes).unwrap();
+    path
+}
+
+// Reads until the end of the stream
+fn read_all(stream: &mut dyn Stream<i16>, read_size: usize) -> Vec<i16> {
+    let mut output = Vec::new();
+    let mut buf = vec![0i16; read_size];
+
+    loop {
+        let count = stream.read(&mut buf).unwrap();
+        assert!(count <= read_size);
+        if count == 0 {
+            return output;
+        }
+        output.extend_from_slice(&buf[..count]);
+    }
+}
+
+// For streams which don't end
+fn read_frames(stream: &mut dyn Stream<i16>, frames: usize) -> Vec<i16> {
+    let mut output = Vec::new();
+    let mut buf = vec![0i16; READ_SIZE];
+
+    while output.len() < frames * CHANNELS {
+        let len = READ_SIZE.min(frames * CHANNELS - output.len());
+        let count = stream.read(&mut buf[..len]).unwrap();
+        assert_eq!(count, len);
+        output.extend_from_slice(&buf[..count]);
+    }
+    output
+}
+
+fn open(name: &str, position_ms: u64) -> (Box<dyn Stream<i16>>, UnboundedReceiver<StreamEvent>) {
+    open_with(name, position_ms, &PlaybackSettings::default())
+}
+
+fn open_with(
+    name: &str,
+    position_ms: u64,
+    settings: &PlaybackSettings,
+) -> (Box<dyn Stream<i16>>, UnboundedReceiver<StreamEvent>) {
+    let (sender, receiver) = mpsc::unbounded();
+    let stream = audio::open_file(&fixture(name), position_ms, settings, &sender).unwrap();
+
+    (stream, receiver)
+}
+
+fn received(events: &mut UnboundedReceiver<StreamEvent>) -> Vec<StreamEvent> {
+    let mut received = Vec::new();
+
+    while let Ok(event) = events.try_recv() {
+        received.push(event);
+    }
+    received
+}
+
+fn outcome(events: &[StreamEvent]) -> Option<DecodeOutcome> {
+    events.iter().find_map(|event| match event {
+        StreamEvent::DecodeFinished { outcome, .. } => Some(outcome.clone()),
+        _ => None,
+    })
+}
+
+fn wav_stream(name: &str) -> Box<dyn Stream<i16>> {
+    Box::new(WavStream::open(&fixture(name)).unwrap())
+}
+
+fn process(processors: Vec<Box<dyn AudioProcessor>>) -> Vec<i16> {
+    let mut dsp = DspStream::new(wav_stream("stereo.wav"));
+    for processor in processors {
+        dsp.add(processor);
+    }
+    read_all(&mut dsp, READ_SIZE)
+}
+
+#[test]
+fn wav_stereo() {
+    let output = read_all(&mut *wav_stream("stereo.wav"), READ_SIZE);
+
+    assert_eq!(output.len(), 4410 * CHANNELS);
+    assert_checksum("wav_stereo", &output);
+}
+
+#[test]
+fn wav_mono_is_played_on_both_channels() {
+    let output = read_all(&mut *wav_stream("mono.wav"), READ_SIZE);
+
+    assert_eq!(output.len(), 2205 * CHANNELS);
+    assert!(output.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+    assert_checksum("wav_mono", &output);
+}
+
+#[test]
+fn wav_seek() {
+    let mut stream = WavStream::open(&fixture("stereo.wav")).unwrap();
+    stream.seek(50).unwrap();
+    let output = read_all(&mut stream, READ_SIZE);
+
+    let whole = read_all(&mut *wav_stream("stereo.wav"), READ_SIZE);
+    assert_eq!(output, whole[2205 * CHANNELS..]);
+
+    // past the end there is nothing left
+    stream.seek(10_000).unwrap();
+    assert!(read_all(&mut stream, READ_SIZE).is_empty());
+}
+
+#[test]
+fn wav_odd_read_sizes() {
+    let whole = read_all(&mut *wav_stream("stereo.wav"), READ_SIZE);
+
+    // reads are whole frames, so an odd size gets one sample less
+    for read_size in [2, 3, 1000, 9000] {
+        let output = read_all(&mut *wav_stream("stereo.wav"), read_size);
+        assert_eq!(output, whole, "read size {}", read_size);
+    }
+}
+
+#[test]
+fn ogg_tone() {
+    let (mut stream, mut events) = open("tone.ogg", 0);
+    let output = read_all(&mut *stream, READ_SIZE);
+
+    assert_eq!(output.len(), TONE_FRAMES * CHANNELS);
+    assert_close("ogg_tone", &output, LOSSY_TOLERANCE);
+
+    let events = received(&mut events);
+    assert!(matches!(
+        &events[0],
+        StreamEvent::LinkStarted { link: 0, channels: 2, rate: 44100, comments: Some(comments) }
+            if comments.get("TITLE") == Some("Tone")
+    ));
+    assert_eq!(
+        outcome(&events),
+        Some(DecodeOutcome::Completed { corrupt_packets: 0 })
+    );
+}
+
+#[test]
+fn ogg_read_sizes() {
+    let (mut stream, _events) = open("tone.ogg", 0);
+    let reference = read_all(&mut *stream, READ_SIZE);
+
+    // single samples, half frames, and reads larger than the whole file
+    for read_size in [1, 2, 3, 255, 4096, 100_000] {
+        let (mut stream, _events) = open("tone.ogg", 0);
+        let output = read_all(&mut *stream, read_size);
+        assert_eq!(output, reference, "read size {}", read_size);
+    }
+}
+
+#[test]
+fn ogg_end_of_stream() {
+    let (mut stream, _events) = open("tone.ogg", 0);
+    let mut buf = vec![0i16; 5000];
+    let mut total = 0;
+
+    // the read which reaches the end returns what is left, only the next one returns 0
+    loop {
+        let count = stream.read(&mut buf).unwrap();
+        total += count;
+        if count < buf.len() {
+            assert_eq!(total, TONE_FRAMES * CHANNELS);
+            break;
+        }
+    }
+    for _ in 0..3 {
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+}
+
+#[test]
+fn ogg_longer_than_the_decoding_buffer() {
+    let (mut stream, mut events) = open("long.ogg", 0);
+    let output = read_all(&mut *stream, 100_000);
+
+    assert_eq!(output.len(), LONG_FRAMES * CHANNELS);
+    // the same packets as tone.ogg, then the end of the last one fading out, then silence
+    assert_close(
+        "ogg_tone",
+        &output[..TONE_FRAMES * CHANNELS],
+        LOSSY_TOLERANCE,
+    );
+    assert!(output[(TONE_FRAMES + 128) * CHANNELS..]
+        .iter()
+        .all(|s| *s == 0));
+    assert_eq!(
+        outcome(&received(&mut events)),
+        Some(DecodeOutcome::Completed { corrupt_packets: 0 })
+    );
+}
+
+// Skipping to the next track drops the stream; long.ogg is still being decoded then, tone.ogg has
+// been decoded but not played to the end
+#[test]
+fn ogg_stopped() {
+    for name in ["long.ogg", "tone.ogg"] {
+        let (mut stream, mut events) = open(name, 0);
+        read_frames(&mut *stream, 1000);
+        drop(stream);
+
+        // the decoding thread may report after the drop
+        let finished = block_on(async {
+            while let Some(event) = events.next().await {
+                if let StreamEvent::DecodeFinished { outcome, .. } = event {
+                    return Some(outcome);
+                }
+            }
+            None
+        });
+        assert_eq!(
+            finished,
+            Some(DecodeOutcome::Stopped { corrupt_packets: 0 }),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn ogg_start_position() {
+    let (mut stream, _events) = open("tone.ogg", 50);
+    let output = read_all(&mut *stream, READ_SIZE);
+
+    let (mut stream, _events) = open("tone.ogg", 0);
+    let whole = read_all(&mut *stream, READ_SIZE);
+
+    assert_eq!(output.len(), whole.len() - 2205 * CHANNELS);
+    let difference = output
+        .iter()
+        .zip(&whole[2205 * CHANNELS..])
+        .map(|(a, b)| (*a as i32 - *b as i32).abs())
+        .max();
+    assert!(difference <= Some(LOSSY_TOLERANCE), "{:?}", difference);
+}
+
+#[test]
+fn ogg_chain() {
+    let (mut stream, mut events) = open("chain.ogg", 0);
+
+    // events come when the listener gets to them, not when they are decoded
+    let first = read_frames(&mut *stream, 1000);
+    let early_events = received(&mut events);
+    assert_eq!(early_events.len(), 1);
+    assert!(matches!(
+        &early_events[0],
+        StreamEvent::LinkStarted {
+            link: 0,
+            channels: 2,
+            rate: 44100,
+            ..
+        }
+    ));
+
+    let mut output = first;
+    output.extend(read_all(&mut *stream, READ_SIZE));
+    // the second link is mono at 22050 Hz, converted to stereo at 44100 Hz; interpolation starts
+    // at its first frame, so it is one frame short of twice as long
+    assert_eq!(output.len(), (31 * 128 + 31 * 256 - 1) * CHANNELS);
+    assert_close("ogg_chain", &output, LOSSY_TOLERANCE);
+
+    let events = received(&mut events);
+    assert!(matches!(
+        &events[0],
+        StreamEvent::LinkStarted { link: 1, channels: 1, rate: 22050, comments: Some(comments) }
+            if comments.get("TITLE") == Some("Second")
+    ));
+    assert_eq!(
+        outcome(&events),
+        Some(DecodeOutcome::Completed { corrupt_packets: 0 })
+    );
+}
+
+#[test]
+fn ogg_corrupt_page_is_skipped() {
+    let (mut stream, mut events) = open("corrupt.ogg", 0);
+    let output = read_all(&mut *stream, READ_SIZE);
+
+    assert!(!output.is_empty() && output.len() < TONE_FRAMES * CHANNELS);
+    assert!(matches!(
+        outcome(&received(&mut events)),
+        Some(DecodeOutcome::Completed { corrupt_packets }) if corrupt_packets > 0
+    ));
+}
+
+#[test]
+fn ogg_corrupt_page_fails_strict_decoding() {
+    let settings = PlaybackSettings {
+        resilience: DecodeResilience::strict(),
+        ..Default::default()
+    };
+    let (mut stream, mut events) = open_with("corrupt.ogg", 0, &settings);
+    read_all(&mut *stream, READ_SIZE);
+
+    assert!(matches!(
+        outcome(&received(&mut events)),
+        Some(DecodeOutcome::Failed { .. })
+    ));
+}
+
+#[test]
+fn resample_mono_22050() {
+    let mut stream = WavStream::open(&fixture("mono.wav")).unwrap();
+    let stereo = read_all(&mut stream, READ_SIZE);
+    // every other frame of the left channel: the mono signal at half the rate
+    let mono: Vec<i16> = stereo.iter().step_by(4).copied().collect();
+
+    let mut converter = FormatConverter::new(1, SAMPLE_RATE / 2);
+    let mut output = VecDeque::new();
+    for chunk in mono.chunks(100) {
+        converter.convert(chunk, &mut output);
+    }
+    let output: Vec<i16> = output.into();
+
+    assert_close("resample_mono_22050", &output, FLOAT_TOLERANCE);
+}
+
+#[test]
+fn gain() {
+    let output = process(vec![Box::new(Gain::new(-6.0))]);
+    assert_close("gain", &output, FLOAT_TOLERANCE);
+}
+
+#[test]
+fn equalizer() {
+    let output = process(vec![Box::new(Equalizer::new(
+        builtin_preset("loudness").unwrap(),
+    ))]);
+    assert_close("equalizer", &output, FLOAT_TOLERANCE);
+}
+
+#[test]
+fn dynamics() {
+    let output = process(vec![
+        Box::new(Gain::new(6.0)),
+        Box::new(Dynamics::new(DynamicsSettings::night_mode())),
+    ]);
+    assert_close("dynamics", &output, FLOAT_TOLERANCE);
+}
+
+#[test]
+fn tempo() {
+    let mut tempo = TempoStream::new(wav_stream("stereo.wav"));
+    tempo.speed_control().set(1.5);
+    let output = read_all(&mut tempo, READ_SIZE);
+
+    assert_close("tempo", &output, FLOAT_TOLERANCE);
+}
+
+#[test]
+fn signal_generator() {
+    let mut generator = SignalGenerator::new(
+        TestSignal::Sweep {
+            start_frequency: 100.0,
+            end_frequency: 10000.0,
+            duration_ms: 100,
+            level_db: -6.0,
+        },
+        None,
+    );
+    let output = read_all(&mut generator, READ_SIZE);
+
+    assert_eq!(output.len(), 4410 * CHANNELS);
+    assert_close("signal_generator", &output, FLOAT_TOLERANCE);
+}
+
+#[test]
+fn source_slot_pads_with_silence() {
+    let slot = SourceSlot::new();
+    slot.set(wav_stream("mono.wav"), 0);
+    let output = read_frames(&mut slot.clone(), 3000);
+
+    let mono = read_all(&mut *wav_stream("mono.wav"), READ_SIZE);
+    assert_eq!(output[..mono.len()], mono);
+    assert!(output[mono.len()..].iter().all(|s| *s == 0));
+    assert_eq!(slot.position_ms(), 50);
+}
+
+#[test]
+fn mixer_prompt() {
+    let mut mixer = Mixer::new();
+    let control = mixer.control();
+    control.set_music(wav_stream("stereo.wav"));
+    control.play_prompt(PromptClip::from_wav_file(&fixture("mono.wav")).unwrap());
+
+    let output = read_frames(&mut mixer, 6000);
+    assert_close("mixer_prompt", &output, FLOAT_TOLERANCE);
+}
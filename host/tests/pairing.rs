@@ -0,0 +1,51 @@
+// Legacy pairing tests: the PIN given to sinks which ask for one, with and without a PIN set, and
+// which PINs can be set.
+
+use esp32_a2dp_player_host::bluetooth_gap_hal::PairingConfig;
+
+fn pairing(pin: Option<&str>) -> PairingConfig {
+    PairingConfig {
+        legacy_pin: pin.map(str::to_string),
+        ..Default::default()
+    }
+}
+
+// (PIN set, 16 digits asked for, PIN given)
+const PIN_CASES: &[(Option<&str>, bool, &[u8])] = &[
+    // without a PIN set, what the player always answered
+    (None, false, b"1234"),
+    (None, true, &[0; 16]),
+    (Some("0000"), false, b"0000"),
+    (Some("0000"), true, b"0000000000000000"),
+    (Some("123456"), true, b"1234560000000000"),
+    (Some("1234567890123456"), true, b"1234567890123456"),
+    (Some("1234567890123456"), false, b"1234567890123456"),
+];
+
+#[test]
+fn pin_code() {
+    for &(pin, min_16_digits, expected) in PIN_CASES {
+        assert_eq!(
+            pairing(pin).pin_code(min_16_digits),
+            expected,
+            "{:?}, 16 digits: {}",
+            pin,
+            min_16_digits
+        );
+    }
+}
+
+#[test]
+fn validate() {
+    let cases = [
+        (None, true),
+        (Some("1"), true),
+        (Some("1234567890123456"), true),
+        (Some(""), false),
+        (Some("12345678901234567"), false),
+    ];
+
+    for (pin, valid) in cases {
+        assert_eq!(pairing(pin).validate().is_ok(), valid, "{:?}", pin);
+    }
+}
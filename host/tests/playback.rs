@@ -0,0 +1,207 @@
+// Playback task tests: playlists played through the mixer in step with the task, as the play tool
+// does, checking what the task reports and how it moves through the playlist.
+
+use std::{
+    env, fs,
+    future::Future,
+    path::PathBuf,
+    pin::pin,
+    task::{self, Poll},
+};
+
+use futures::{channel::mpsc, task::noop_waker_ref};
+
+use esp32_a2dp_player_host::{
+    audio::{self, NowPlaying, PlaybackCommand, PlaybackReport, PlaybackSettings, CHANNELS},
+    audio_generator::TestSignal,
+    audio_mixer::Mixer,
+    bluetooth_hal::Stream,
+    playlist::{Playlist, PlaylistPosition},
+};
+
+// 10 ms at a time, like the sinks
+const BLOCK_FRAMES: usize = 441;
+// Longer than any of the playlists played
+const MAX_BLOCKS: usize = 10_000;
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+// An empty folder of its own for each test
+fn folder(test: &str) -> PathBuf {
+    let folder = env::temp_dir().join(format!("playback-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&folder);
+    fs::create_dir_all(&folder).unwrap();
+    folder
+}
+
+fn play(playlist: &Playlist) -> PlaybackReport {
+    play_commands(playlist, Vec::new()).report
+}
+
+struct Played {
+    report: PlaybackReport,
+    // the track numbers, in the order played
+    tracks: Vec<u32>,
+}
+
+// Plays the playlist, sending each command before reading the block it goes with
+fn play_commands(playlist: &Playlist, mut commands: Vec<(usize, PlaybackCommand)>) -> Played {
+    let mut mixer = Mixer::new();
+    let mixer_control = mixer.control();
+    let settings = PlaybackSettings::default();
+    let now_playing = NowPlaying::new();
+    let (sender, mut receiver) = mpsc::unbounded();
+    let mut playback = pin!(audio::playback_task(
+        &mixer_control,
+        playlist,
+        &settings,
+        &mut receiver,
+        &now_playing,
+    ));
+    let mut context = task::Context::from_waker(noop_waker_ref());
+    let mut buf = vec![0i16; BLOCK_FRAMES * CHANNELS];
+    let mut tracks = Vec::new();
+    commands.reverse();
+
+    for block in 0..MAX_BLOCKS {
+        if commands.last().is_some_and(|(at, _)| *at == block) {
+            let (_, command) = commands.pop().unwrap();
+            sender.unbounded_send(command).unwrap();
+        }
+        if let Poll::Ready(report) = playback.as_mut().poll(&mut context) {
+            return Played {
+                report: report.unwrap(),
+                tracks,
+            };
+        }
+        if let Some((_, track)) = now_playing.track() {
+            let number = track.track_number.unwrap();
+            if tracks.last() != Some(&number) {
+                tracks.push(number);
+            }
+        }
+        mixer.read(&mut buf).unwrap();
+    }
+    panic!("Playback didn't end");
+}
+
+// A file that can't be opened is reported, and the files after it still play
+#[test]
+fn unplayable_files_are_skipped() {
+    let folder = folder("unplayable");
+    let stereo = fs::read(fixture("stereo.wav")).unwrap();
+    // the same file, claiming to be 48 kHz, which WavStream doesn't play
+    let mut wrong_rate = stereo.clone();
+    wrong_rate[24..28].copy_from_slice(&48000u32.to_le_bytes());
+
+    fs::write(folder.join("a.wav"), &stereo).unwrap();
+    fs::write(folder.join("b.wav"), &wrong_rate).unwrap();
+    fs::copy(fixture("tone.ogg"), folder.join("c.ogg")).unwrap();
+    fs::write(folder.join("d.wav"), &wrong_rate).unwrap();
+
+    let playlist = Playlist::from_folder(folder.to_str().unwrap(), false).unwrap();
+    let report = play(&playlist);
+
+    assert_eq!(report.files_completed, 2);
+    let failed: Vec<_> = report
+        .files_failed
+        .iter()
+        .map(|(file, _)| PathBuf::from(file).file_name().unwrap().to_owned())
+        .collect();
+    assert_eq!(failed, ["b.wav", "d.wav"]);
+
+    fs::remove_dir_all(folder).unwrap();
+}
+
+#[test]
+fn nothing_playable() {
+    let folder = folder("nothing");
+    let mut wrong_rate = fs::read(fixture("stereo.wav")).unwrap();
+    wrong_rate[24..28].copy_from_slice(&48000u32.to_le_bytes());
+    fs::write(folder.join("a.wav"), &wrong_rate).unwrap();
+
+    let playlist = Playlist::from_folder(folder.to_str().unwrap(), false).unwrap();
+    let report = play(&playlist);
+
+    assert_eq!(report.files_completed, 0);
+    assert_eq!(report.files_failed.len(), 1);
+
+    fs::remove_dir_all(folder).unwrap();
+}
+
+fn sine(frequency: f32) -> (TestSignal, Option<u64>) {
+    let signal = TestSignal::Sine {
+        frequency,
+        level_db: -20.0,
+    };
+    (signal, Some(1000))
+}
+
+const fn at(index: usize, position_ms: u64) -> Option<PlaylistPosition> {
+    Some(PlaylistPosition { index, position_ms })
+}
+
+// (from, seek by in ms, to) in two signals of 1 s and one without an end
+const SEEK_CASES: &[(Option<PlaylistPosition>, i64, Option<PlaylistPosition>)] = &[
+    (at(0, 200), 500, at(0, 700)),
+    (at(0, 200), 799, at(0, 999)),
+    // past the end is the next track
+    (at(0, 200), 800, at(1, 0)),
+    (at(0, 200), 60_000, at(1, 0)),
+    (at(1, 0), 1000, at(2, 0)),
+    // before the start is the start
+    (at(0, 200), -60_000, at(0, 0)),
+    (at(1, 500), -500, at(1, 0)),
+    // a signal without a duration has no end, the last track has nothing after it
+    (at(2, 200), 60_000, at(2, 60_200)),
+    (at(2, 200), -60_000, at(2, 0)),
+];
+
+#[test]
+fn seek() {
+    let endless = (
+        TestSignal::Sine {
+            frequency: 440.0,
+            level_db: -20.0,
+        },
+        None,
+    );
+    let playlist = Playlist::test_signals(vec![sine(440.0), sine(880.0), endless]);
+
+    for &(from, delta_ms, expected) in SEEK_CASES {
+        assert_eq!(
+            playlist.seek(&from.unwrap(), delta_ms),
+            expected,
+            "{:?} by {}",
+            from,
+            delta_ms
+        );
+    }
+
+    let last = Playlist::test_signals(vec![sine(440.0)]);
+    assert_eq!(last.seek(&at(0, 200).unwrap(), 60_000), None);
+}
+
+// Files don't know their duration, so they end by themselves when seeked past it
+#[test]
+fn seek_past_the_end_of_a_file() {
+    let folder = folder("seek");
+    fs::copy(fixture("tone.ogg"), folder.join("a.ogg")).unwrap();
+    fs::copy(fixture("stereo.wav"), folder.join("b.wav")).unwrap();
+    fs::copy(fixture("tone.ogg"), folder.join("c.ogg")).unwrap();
+
+    let playlist = Playlist::from_folder(folder.to_str().unwrap(), false).unwrap();
+    let commands = vec![
+        (5, PlaybackCommand::SeekBy(3_600_000)),
+        (10, PlaybackCommand::SeekBy(3_600_000)),
+    ];
+    let played = play_commands(&playlist, commands);
+
+    assert_eq!(played.tracks, [1, 2, 3]);
+    assert_eq!(played.report.files_completed, 3);
+    assert!(played.report.files_failed.is_empty());
+
+    fs::remove_dir_all(folder).unwrap();
+}
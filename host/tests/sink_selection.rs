@@ -0,0 +1,362 @@
+// Sink selection tests: the allow and deny patterns, how they are read and shown, which devices a
+// policy accepts, and which of the accepted ones is picked.
+
+use esp32_a2dp_player_host::{
+    bluetooth_gap_hal::{AudioVideoMinor, ClassOfDevice, ScannedDevice},
+    bluetooth_hal::BDAddr,
+    sink_selection::{DeviceMatch, SelectionPolicy},
+};
+
+const SPEAKER: BDAddr = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55];
+const HEADPHONES: BDAddr = [0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb];
+
+const LOUDSPEAKER_CLASS: u32 = 0x240414;
+const HEADPHONES_CLASS: u32 = 0x240418;
+const MICROPHONE_CLASS: u32 = 0x200410;
+const COMPUTER_CLASS: u32 = 0x00010c;
+
+fn name(pattern: &str) -> DeviceMatch {
+    DeviceMatch::Name(pattern.to_string())
+}
+
+fn device(
+    address: BDAddr,
+    name: Option<&str>,
+    class: Option<u32>,
+    rssi: Option<i8>,
+) -> ScannedDevice {
+    ScannedDevice {
+        address,
+        name: name.map(str::to_string),
+        class: class.map(|value| ClassOfDevice { value }),
+        rssi,
+        ..Default::default()
+    }
+}
+
+fn speaker() -> ScannedDevice {
+    device(
+        SPEAKER,
+        Some("JBL Flip 5"),
+        Some(LOUDSPEAKER_CLASS),
+        Some(-60),
+    )
+}
+
+// (pattern, address, name, matches)
+fn match_cases() -> Vec<(DeviceMatch, BDAddr, Option<&'static str>, bool)> {
+    vec![
+        (DeviceMatch::Address(SPEAKER), SPEAKER, None, true),
+        (DeviceMatch::Address(SPEAKER), SPEAKER, Some("Other"), true),
+        (
+            DeviceMatch::Address(SPEAKER),
+            HEADPHONES,
+            Some("JBL Flip 5"),
+            false,
+        ),
+        (name("JBL Flip 5"), HEADPHONES, Some("JBL Flip 5"), true),
+        (name("jbl flip 5"), SPEAKER, Some("JBL FLIP 5"), true),
+        (name("JBL Flip"), SPEAKER, Some("JBL Flip 5"), false),
+        (name("JBL*"), SPEAKER, Some("JBL Flip 5"), true),
+        (name("JBL*"), SPEAKER, Some("JBL"), true),
+        (name("JBL*"), SPEAKER, Some("My JBL"), false),
+        (name("JBL*"), SPEAKER, None, false),
+        (name("*kitchen*"), SPEAKER, Some("Kitchen"), true),
+        (name("*kitchen*"), SPEAKER, Some("The kitchen radio"), true),
+        (name("*kitchen*"), SPEAKER, Some("Living room"), false),
+        (name("*5"), SPEAKER, Some("JBL Flip 5"), true),
+        (name("*5"), SPEAKER, Some("JBL Flip 5 Pro"), false),
+        (name("a*b*c"), SPEAKER, Some("abc"), true),
+        (name("a*b*c"), SPEAKER, Some("aXXbYYc"), true),
+        (name("a*b*c"), SPEAKER, Some("acb"), false),
+        (name("*"), SPEAKER, Some(""), true),
+        (name("*"), SPEAKER, None, false),
+        (name(""), SPEAKER, Some(""), true),
+        // the star ends in the middle of the text, not of a character
+        (name("k*che"), SPEAKER, Some("KÜCHE"), true),
+        (name("*ü*"), SPEAKER, Some("Küche"), true),
+    ]
+}
+
+#[test]
+fn device_match() {
+    for (pattern, address, name, expected) in match_cases() {
+        assert_eq!(
+            pattern.matches(&address, name),
+            expected,
+            "{:?} on {:?}",
+            pattern,
+            name
+        );
+    }
+}
+
+// (text, parsed, shown)
+fn parse_cases() -> Vec<(&'static str, DeviceMatch, &'static str)> {
+    vec![
+        (
+            "00:11:22:33:44:55",
+            DeviceMatch::Address(SPEAKER),
+            "00:11:22:33:44:55",
+        ),
+        (
+            "66:77:88:99:aa:bb",
+            DeviceMatch::Address(HEADPHONES),
+            "66:77:88:99:AA:BB",
+        ),
+        (
+            "001122334455",
+            DeviceMatch::Address(SPEAKER),
+            "00:11:22:33:44:55",
+        ),
+        ("JBL*", name("JBL*"), "JBL*"),
+        (
+            "Kitchen speaker",
+            name("Kitchen speaker"),
+            "Kitchen speaker",
+        ),
+        // not quite addresses
+        ("00:11:22:33:44", name("00:11:22:33:44"), "00:11:22:33:44"),
+        (
+            "00:11:22:33:44:5G",
+            name("00:11:22:33:44:5G"),
+            "00:11:22:33:44:5G",
+        ),
+        (
+            "0011:22:33:44:55",
+            name("0011:22:33:44:55"),
+            "0011:22:33:44:55",
+        ),
+        ("00112233445", name("00112233445"), "00112233445"),
+    ]
+}
+
+#[test]
+fn device_match_parse() {
+    for (text, parsed, shown) in parse_cases() {
+        let device = DeviceMatch::parse(text);
+        assert_eq!(device, parsed, "{}", text);
+        assert_eq!(device.to_string(), shown, "{}", text);
+        // what is shown is read back the same
+        assert_eq!(DeviceMatch::parse(shown), parsed, "{}", text);
+    }
+}
+
+struct Case {
+    name: &'static str,
+    policy: SelectionPolicy,
+    device: ScannedDevice,
+    accepted: bool,
+}
+
+fn accept_cases() -> Vec<Case> {
+    vec![
+        Case {
+            name: "default policy, speaker",
+            policy: SelectionPolicy::default(),
+            device: speaker(),
+            accepted: true,
+        },
+        Case {
+            name: "default policy, headphones",
+            policy: SelectionPolicy::default(),
+            device: device(HEADPHONES, None, Some(HEADPHONES_CLASS), None),
+            accepted: true,
+        },
+        Case {
+            name: "default policy, microphone",
+            policy: SelectionPolicy::default(),
+            device: device(SPEAKER, None, Some(MICROPHONE_CLASS), Some(-60)),
+            accepted: false,
+        },
+        Case {
+            name: "default policy, computer",
+            policy: SelectionPolicy::default(),
+            device: device(SPEAKER, None, Some(COMPUTER_CLASS), Some(-60)),
+            accepted: false,
+        },
+        Case {
+            name: "default policy, no class",
+            policy: SelectionPolicy::default(),
+            device: device(SPEAKER, None, None, Some(-60)),
+            accepted: false,
+        },
+        Case {
+            name: "any class",
+            policy: SelectionPolicy {
+                audio_sinks_only: false,
+                ..Default::default()
+            },
+            device: device(SPEAKER, None, None, None),
+            accepted: true,
+        },
+        Case {
+            name: "allowed by name",
+            policy: SelectionPolicy {
+                allow: vec![name("jbl*")],
+                ..Default::default()
+            },
+            device: speaker(),
+            accepted: true,
+        },
+        Case {
+            name: "not allowed",
+            policy: SelectionPolicy {
+                allow: vec![name("Sony*"), DeviceMatch::Address(HEADPHONES)],
+                ..Default::default()
+            },
+            device: speaker(),
+            accepted: false,
+        },
+        Case {
+            name: "allowed by address",
+            policy: SelectionPolicy {
+                allow: vec![name("Sony*"), DeviceMatch::Address(SPEAKER)],
+                ..Default::default()
+            },
+            device: speaker(),
+            accepted: true,
+        },
+        Case {
+            name: "denied",
+            policy: SelectionPolicy {
+                deny: vec![name("*flip*")],
+                ..Default::default()
+            },
+            device: speaker(),
+            accepted: false,
+        },
+        Case {
+            name: "denied wins over allowed",
+            policy: SelectionPolicy {
+                allow: vec![DeviceMatch::Address(SPEAKER)],
+                deny: vec![DeviceMatch::Address(SPEAKER)],
+                ..Default::default()
+            },
+            device: speaker(),
+            accepted: false,
+        },
+        Case {
+            name: "strong enough",
+            policy: SelectionPolicy {
+                min_rssi: Some(-60),
+                ..Default::default()
+            },
+            device: speaker(),
+            accepted: true,
+        },
+        Case {
+            name: "too far",
+            policy: SelectionPolicy {
+                min_rssi: Some(-59),
+                ..Default::default()
+            },
+            device: speaker(),
+            accepted: false,
+        },
+        Case {
+            name: "no signal strength",
+            policy: SelectionPolicy {
+                min_rssi: Some(-90),
+                ..Default::default()
+            },
+            device: device(SPEAKER, None, Some(LOUDSPEAKER_CLASS), None),
+            accepted: false,
+        },
+        Case {
+            name: "wanted minor class",
+            policy: SelectionPolicy {
+                minor_classes: vec![AudioVideoMinor::Loudspeaker],
+                ..Default::default()
+            },
+            device: speaker(),
+            accepted: true,
+        },
+        Case {
+            name: "other minor class",
+            policy: SelectionPolicy {
+                minor_classes: vec![AudioVideoMinor::Loudspeaker],
+                ..Default::default()
+            },
+            device: device(HEADPHONES, None, Some(HEADPHONES_CLASS), Some(-40)),
+            accepted: false,
+        },
+    ]
+}
+
+#[test]
+fn accepts() {
+    for case in accept_cases() {
+        assert_eq!(
+            case.policy.accepts(&case.device),
+            case.accepted,
+            "{}",
+            case.name
+        );
+    }
+}
+
+// name, rssi in dBm, class
+type Candidate = (&'static str, Option<i8>, u32);
+
+// the candidates, in the order found, and the name of the best
+const BEST_CASES: &[(&[Candidate], Option<&str>)] = &[
+    (&[], None),
+    (&[("near", Some(-40), LOUDSPEAKER_CLASS)], Some("near")),
+    (
+        &[
+            ("far", Some(-80), LOUDSPEAKER_CLASS),
+            ("near", Some(-40), LOUDSPEAKER_CLASS),
+            ("middle", Some(-60), HEADPHONES_CLASS),
+        ],
+        Some("near"),
+    ),
+    // of equals, the first found
+    (
+        &[
+            ("first", Some(-50), LOUDSPEAKER_CLASS),
+            ("second", Some(-50), LOUDSPEAKER_CLASS),
+        ],
+        Some("first"),
+    ),
+    // a signal strength beats none
+    (
+        &[
+            ("unknown", None, LOUDSPEAKER_CLASS),
+            ("far", Some(-90), LOUDSPEAKER_CLASS),
+        ],
+        Some("far"),
+    ),
+    // the strongest isn't picked when the policy doesn't accept it
+    (
+        &[
+            ("computer", Some(-30), COMPUTER_CLASS),
+            ("far", Some(-80), LOUDSPEAKER_CLASS),
+        ],
+        Some("far"),
+    ),
+    (&[("computer", Some(-30), COMPUTER_CLASS)], None),
+];
+
+#[test]
+fn best() {
+    let policy = SelectionPolicy::default();
+
+    for &(found, expected) in BEST_CASES {
+        let candidates: Vec<ScannedDevice> = found
+            .iter()
+            .enumerate()
+            .map(|(index, &(name, rssi, class))| {
+                device([index as u8; 6], Some(name), Some(class), rssi)
+            })
+            .collect();
+
+        let best = policy.best(&candidates);
+        assert_eq!(
+            best.and_then(|device| device.name.as_deref()),
+            expected,
+            "{:?}",
+            found
+        );
+    }
+}
@@ -0,0 +1,64 @@
+// Volume taper tests: full scale at MAX_VOLUME, silence at 0, and VOLUME_RANGE_DB (50 dB) spread
+// evenly in dB over the steps in between.
+
+use esp32_a2dp_player_host::audio_dsp::{AudioProcessor, Volume, MAX_VOLUME};
+
+const TOLERANCE_DB: f32 = 0.01;
+
+// (volume in percent, gain in dB)
+const CASES: &[(u8, f32)] = &[
+    (100, 0.0),
+    (90, -5.0),
+    (70, -15.0),
+    (50, -25.0),
+    (20, -40.0),
+    (1, -49.5),
+    // above the maximum is the maximum
+    (101, 0.0),
+    (255, 0.0),
+];
+
+fn gain(volume: u8) -> f32 {
+    let mut samples = [1.0f32, -1.0];
+    Volume::new(volume).process(&mut samples);
+    assert_eq!(samples[0], -samples[1], "volume {volume}");
+
+    samples[0]
+}
+
+#[test]
+fn taper() {
+    for &(volume, expected_db) in CASES {
+        let gain_db = 20.0 * gain(volume).log10();
+        assert!(
+            (gain_db - expected_db).abs() < TOLERANCE_DB,
+            "volume {}: {} dB, expected {} dB",
+            volume,
+            gain_db,
+            expected_db
+        );
+    }
+}
+
+#[test]
+fn zero_is_silence() {
+    assert_eq!(gain(0), 0.0);
+}
+
+#[test]
+fn every_step_is_louder() {
+    for volume in 1..=MAX_VOLUME {
+        assert!(gain(volume) > gain(volume - 1), "volume {volume}");
+    }
+}
+
+#[test]
+fn control_changes_the_volume() {
+    let mut volume = Volume::new(MAX_VOLUME);
+    let control = volume.control();
+    control.set(50);
+
+    let mut samples = [1.0f32; 4];
+    volume.process(&mut samples);
+    assert!((20.0 * samples[0].log10() + 25.0).abs() < TOLERANCE_DB);
+}
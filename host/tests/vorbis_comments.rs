@@ -0,0 +1,130 @@
+// Vorbis chapter tests: the CHAPTERxxx and CHAPTERxxxNAME comments of the chapter extension, as
+// audiobooks have them, with bad timestamps and gaps in the numbering. See
+// https://wiki.xiph.org/Chapter_Extension
+
+use esp32_a2dp_player_host::vorbis_comments::VorbisComments;
+
+struct Case {
+    name: &'static str,
+    comments: &'static [(&'static str, &'static str)],
+    // start and title of each chapter
+    chapters: &'static [(u64, Option<&'static str>)],
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "no chapters",
+        comments: &[("TITLE", "Dracula"), ("ARTIST", "Bram Stoker")],
+        chapters: &[],
+    },
+    Case {
+        name: "named chapters",
+        comments: &[
+            ("TITLE", "Dracula"),
+            ("CHAPTER001", "00:00:00.000"),
+            ("CHAPTER001NAME", "Jonathan Harker's Journal"),
+            ("CHAPTER002", "00:25:13.500"),
+            ("CHAPTER002NAME", "Jonathan Harker's Journal, continued"),
+            ("CHAPTER003", "01:02:03.004"),
+            ("CHAPTER003NAME", "Mina Murray's Journal"),
+        ],
+        chapters: &[
+            (0, Some("Jonathan Harker's Journal")),
+            (1_513_500, Some("Jonathan Harker's Journal, continued")),
+            (3_723_004, Some("Mina Murray's Journal")),
+        ],
+    },
+    Case {
+        name: "names missing and out of order",
+        comments: &[
+            ("CHAPTER002NAME", "Second"),
+            ("CHAPTER002", "00:10:00"),
+            ("CHAPTER001", "00:00:00"),
+        ],
+        chapters: &[(0, None), (600_000, Some("Second"))],
+    },
+    Case {
+        name: "sorted by start, not number",
+        comments: &[
+            ("CHAPTER01", "00:20:00"),
+            ("CHAPTER02", "00:10:00"),
+            ("CHAPTER02NAME", "Earlier"),
+        ],
+        chapters: &[(600_000, Some("Earlier")), (1_200_000, None)],
+    },
+    Case {
+        name: "gaps in the numbering",
+        comments: &[
+            ("CHAPTER001", "00:00:00"),
+            ("CHAPTER003", "00:05:00"),
+            ("CHAPTER003NAME", "Third"),
+            ("CHAPTER010", "00:50:00"),
+        ],
+        chapters: &[(0, None), (300_000, Some("Third")), (3_000_000, None)],
+    },
+    Case {
+        name: "fractions",
+        comments: &[
+            ("CHAPTER1", "00:00:01.5"),
+            ("CHAPTER2", "00:00:02.25"),
+            ("CHAPTER3", "00:00:03.123456"),
+            ("CHAPTER4", "00:00:04."),
+            ("CHAPTER5", " 00:00:05.000 "),
+            ("CHAPTER6", "100:00:00"),
+        ],
+        chapters: &[
+            (1_500, None),
+            (2_250, None),
+            (3_123, None),
+            (4_000, None),
+            (5_000, None),
+            (360_000_000, None),
+        ],
+    },
+    Case {
+        name: "bad timestamps are skipped",
+        comments: &[
+            ("CHAPTER001", "00:00:00"),
+            ("CHAPTER002", "00:10"),
+            ("CHAPTER003", "00:00:00:10"),
+            ("CHAPTER004", "aa:bb:cc"),
+            ("CHAPTER005", "00:10:00.5x"),
+            ("CHAPTER006", "-1:00:00"),
+            ("CHAPTER007", ""),
+            ("CHAPTER008", "00:30:00"),
+        ],
+        chapters: &[(0, None), (1_800_000, None)],
+    },
+    Case {
+        name: "not chapter markers",
+        comments: &[
+            ("CHAPTER", "00:00:00"),
+            ("CHAPTERS", "00:01:00"),
+            ("CHAPTER1A", "00:02:00"),
+            ("CHAPTER1NAME", "Name without a chapter"),
+            ("MYCHAPTER1", "00:03:00"),
+        ],
+        chapters: &[],
+    },
+];
+
+#[test]
+fn chapters() {
+    for case in CASES {
+        let comments = VorbisComments {
+            vendor: "test".to_string(),
+            comments: case
+                .comments
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        };
+
+        let chapters = comments.chapters();
+        let chapters: Vec<(u64, Option<&str>)> = chapters
+            .iter()
+            .map(|chapter| (chapter.start_ms, chapter.title.as_deref()))
+            .collect();
+        assert_eq!(chapters, case.chapters, "{}", case.name);
+    }
+}
@@ -1,15 +1,294 @@
-use crate::bluetooth_hal::Bluetooth;
+use crate::audio_dsp::{DspStream, Gain};
+use crate::audio_dynamics::{Dynamics, DynamicsControl, DynamicsSettings};
+use crate::audio_eq::{EqBand, Equalizer, EqualizerControl};
+use crate::audio_generator::SignalGenerator;
+use crate::audio_mixer::MixerControl;
+use crate::audio_resample::FormatConverter;
+use crate::audio_tempo::{SpeedControl, TempoStream};
 use crate::bluetooth_hal::Stream;
+use crate::ogg_chain;
+use crate::playlist::{Playlist, PlaylistEntry, PlaylistPosition};
+use crate::vorbis_comments::VorbisComments;
+use crate::wav::WavStream;
 use anyhow::Result;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, select, Either},
+    StreamExt,
+};
 
 use std::{
     cmp::min,
     collections::VecDeque,
     fs::File,
     io::Read,
+    path::Path,
     sync::{Arc, Condvar, Mutex},
     thread::{self, JoinHandle},
+    time::Duration,
 };
+
+// A2DP sources always send 44.1 kHz stereo, and all streams are interleaved samples in that format
+pub const SAMPLE_RATE: u32 = 44100;
+pub const CHANNELS: usize = 2;
+
+// How often the bookmark of an audiobook is saved while it plays, so that not much is lost when
+// the power goes
+const BOOKMARK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub enum PlaybackCommand {
+    Stop,
+    NextTrack,
+    PreviousTrack,
+    NextChapter,
+    PreviousChapter,
+    // Jumps within the track, back for negative values
+    SeekBy(i64),
+    // Playback speed, 1.0 is normal speed. Pitch is kept.
+    SetSpeed(f32),
+    SetEqualizer(Vec<EqBand>),
+    // Compressor and limiter, e.g. DynamicsSettings::night_mode()
+    SetDynamics(DynamicsSettings),
+}
+
+// Initial settings of the processing pipeline; they can be changed with PlaybackCommands
+#[derive(Debug, Clone)]
+pub struct PlaybackSettings {
+    pub speed: f32,
+    // before the equalizer, negative values give headroom for its boosts
+    pub gain_db: f32,
+    pub equalizer: Vec<EqBand>,
+    pub dynamics: DynamicsSettings,
+    pub resilience: DecodeResilience,
+}
+
+impl Default for PlaybackSettings {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            gain_db: 0.0,
+            equalizer: Vec::new(),
+            dynamics: DynamicsSettings::default(),
+            resilience: DecodeResilience::default(),
+        }
+    }
+}
+
+// How many corrupt packets the decoder skips before giving up on a file. Skipped packets are
+// heard as a short dropout, which beats not hearing the rest of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeResilience {
+    // in a row, i.e. before the decoder has found its way back into the stream
+    pub max_consecutive_errors: u32,
+    pub max_errors: u32,
+}
+
+impl Default for DecodeResilience {
+    fn default() -> Self {
+        Self {
+            max_consecutive_errors: 10,
+            max_errors: 100,
+        }
+    }
+}
+
+impl DecodeResilience {
+    // Give up at the first error, e.g. for checking files
+    #[allow(dead_code)]
+    pub fn strict() -> Self {
+        Self {
+            max_consecutive_errors: 0,
+            max_errors: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeOutcome {
+    // Decoded to the end, skipping corrupt_packets along the way
+    Completed { corrupt_packets: u32 },
+    // The stream was closed before the end, e.g. when skipping to the next track
+    Stopped { corrupt_packets: u32 },
+    Failed { error: String, corrupt_packets: u32 },
+}
+
+// What happened to the files played, returned to the Playback state by playback_task
+#[derive(Debug, Default)]
+pub struct PlaybackReport {
+    pub files_completed: u32,
+    // (file name, error)
+    pub files_failed: Vec<(String, String)>,
+    pub corrupt_packets: u32,
+}
+
+impl PlaybackReport {
+    fn add(&mut self, filename: &str, outcome: &DecodeOutcome) {
+        match outcome {
+            DecodeOutcome::Completed { corrupt_packets } => {
+                self.files_completed += 1;
+                self.corrupt_packets += corrupt_packets;
+            }
+            DecodeOutcome::Stopped { corrupt_packets } => self.corrupt_packets += corrupt_packets,
+            DecodeOutcome::Failed {
+                error,
+                corrupt_packets,
+            } => {
+                self.files_failed
+                    .push((filename.to_string(), error.clone()));
+                self.corrupt_packets += corrupt_packets;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlayStatus {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+// What is playing, from the playlist and the file's tags. ESP-IDF's AVRCP target can't answer
+// GetElementAttributes, so controllers don't get it yet; see bluetooth_esp32_avrcp.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackInfo {
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+    // only known for CUE tracks and test signals
+    pub duration_ms: Option<u64>,
+}
+
+impl TrackInfo {
+    fn from_entry(entry: &PlaylistEntry, index: usize) -> Self {
+        let track_number = match entry {
+            PlaylistEntry::CueTrack(track) => track.number,
+            _ => index as u32 + 1,
+        };
+
+        TrackInfo {
+            title: entry.title(),
+            artist: entry.performer().map(str::to_string),
+            album: None,
+            track_number: Some(track_number),
+            duration_ms: entry.duration_ms(),
+        }
+    }
+
+    // The tags of a file know better than its name, but not better than a CUE sheet
+    fn add_comments(&mut self, comments: &VorbisComments) {
+        if let Some(title) = comments.get("TITLE") {
+            self.title = title.to_string();
+        }
+        if let Some(artist) = comments.get("ARTIST") {
+            self.artist = Some(artist.to_string());
+        }
+        if let Some(album) = comments.get("ALBUM") {
+            self.album = Some(album.to_string());
+        }
+        // may be written as "3/12"
+        if let Some(number) = comments
+            .get("TRACKNUMBER")
+            .and_then(|n| n.split('/').next())
+            .and_then(|n| n.trim().parse().ok())
+        {
+            self.track_number = Some(number);
+        }
+    }
+}
+
+// What is playing and where, for remote controls and displays. Every track that starts gets a new
+// id, also when it is the same one again.
+#[derive(Clone, Default)]
+pub struct NowPlaying {
+    state: Arc<Mutex<NowPlayingState>>,
+}
+
+#[derive(Default)]
+struct NowPlayingState {
+    track: Option<TrackInfo>,
+    track_id: u64,
+    status: PlayStatus,
+    // link of a chained Ogg file, each is a track of its own
+    link: usize,
+    slot: Option<SourceSlot>,
+}
+
+impl NowPlaying {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The track, and its id
+    pub fn track(&self) -> Option<(u64, TrackInfo)> {
+        let state = self.state.lock().expect("Failed to lock now playing");
+        state.track.clone().map(|track| (state.track_id, track))
+    }
+
+    pub fn status(&self) -> PlayStatus {
+        self.state
+            .lock()
+            .expect("Failed to lock now playing")
+            .status
+    }
+
+    // Pausing is up to the outputs, so they set it
+    pub fn set_status(&self, status: PlayStatus) {
+        let mut state = self.state.lock().expect("Failed to lock now playing");
+        if state.track.is_some() {
+            state.status = status;
+        }
+    }
+
+    // Within the current track, None when nothing is playing
+    pub fn position_ms(&self) -> Option<u64> {
+        let state = self.state.lock().expect("Failed to lock now playing");
+        state
+            .track
+            .as_ref()
+            .and(state.slot.as_ref())
+            .map(|slot| slot.position_ms())
+    }
+
+    fn start_track(&self, track: TrackInfo, slot: &SourceSlot) {
+        log::info!("Now playing {:?}", track);
+        let mut state = self.state.lock().expect("Failed to lock now playing");
+        state.track = Some(track);
+        state.track_id += 1;
+        state.link = 0;
+        state.slot = Some(slot.clone());
+        if state.status == PlayStatus::Stopped {
+            state.status = PlayStatus::Playing;
+        }
+    }
+
+    fn link_started(&self, link: usize, comments: Option<&VorbisComments>, from_cue: bool) {
+        let mut state = self.state.lock().expect("Failed to lock now playing");
+        if link != state.link {
+            state.link = link;
+            state.track_id += 1;
+        }
+        if let (Some(track), Some(comments), false) = (&mut state.track, comments, from_cue) {
+            track.add_comments(comments);
+        }
+    }
+
+    fn stop(&self) {
+        let mut state = self.state.lock().expect("Failed to lock now playing");
+        state.track = None;
+        state.status = PlayStatus::Stopped;
+        state.slot = None;
+    }
+}
+
+#[derive(Default)]
+struct DecodeProgress {
+    samples_buffered: u64,
+    corrupt_packets: u32,
+}
 struct FileStream {
     file: File,
 }
@@ -29,93 +308,330 @@ impl Stream<i16> for FileStream {
     }
 }
 
+// Events from a source. They are sent when the listener gets to the point in the stream where
+// they happened, not when the decoder gets there.
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    // A logical bitstream of an Ogg file has started: the first one, or the next link of a chained
+    // file. The format is the one of the file, before conversion to stereo at SAMPLE_RATE.
+    LinkStarted {
+        link: usize,
+        channels: u16,
+        rate: u32,
+        comments: Option<VorbisComments>,
+    },
+    // Decoding has ended, and the listener has heard everything that was decoded
+    DecodeFinished {
+        filename: String,
+        outcome: DecodeOutcome,
+    },
+}
+
 struct OggBluetoothStream {
     filename: String,
-    thread: Option<JoinHandle<Result<()>>>,
+    thread: Option<JoinHandle<()>>,
     buffer_condvar: Arc<Condvar>,
     buffer: Arc<Mutex<VecDeque<i16>>>,
     end_of_file: Arc<Mutex<bool>>,
+    stop: Arc<Mutex<bool>>,
+    // events, and how many samples into the stream they happen
+    markers: Arc<Mutex<VecDeque<(u64, StreamEvent)>>>,
+    samples_read: u64,
+    events: Option<UnboundedSender<StreamEvent>>,
+    resilience: DecodeResilience,
 }
 
 impl OggBluetoothStream {
-    pub fn new(filename: &str) -> Result<Self> {
+    pub fn new(
+        filename: &str,
+        events: Option<UnboundedSender<StreamEvent>>,
+        resilience: DecodeResilience,
+    ) -> Result<Self> {
         Ok(OggBluetoothStream {
             filename: filename.to_string(),
             thread: None,
             buffer_condvar: Arc::new(Condvar::new()),
             buffer: Arc::new(Mutex::new(VecDeque::new())),
             end_of_file: Arc::new(Mutex::new(false)),
+            stop: Arc::new(Mutex::new(false)),
+            markers: Arc::new(Mutex::new(VecDeque::new())),
+            samples_read: 0,
+            events,
+            resilience,
         })
     }
 
-    pub fn start(&mut self) -> Result<()> {
+    pub fn start(&mut self, position_ms: u64) -> Result<()> {
         // start thread decoding file to buffer
         let filename = self.filename.clone();
         let buffer = self.buffer.clone();
         let condvar = self.buffer_condvar.clone();
         let eos = self.end_of_file.clone();
+        let stop = self.stop.clone();
+        let markers = self.markers.clone();
+        let events = self.events.clone();
+        let resilience = self.resilience;
 
         self.thread = Some(
             thread::Builder::new()
                 .name("decoding_thread".to_owned())
                 .stack_size(28000) // 4096 as too small a stack. May need 14000-28000 which was main
                 .spawn(move || {
-                    OggBluetoothStream::decoding_thread(filename, buffer, condvar, &eos)
+                    OggBluetoothStream::decoding_thread(
+                        filename,
+                        position_ms,
+                        resilience,
+                        buffer,
+                        condvar,
+                        &eos,
+                        &stop,
+                        &markers,
+                        events,
+                    )
                 })?,
         );
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn decoding_thread(
         filename: String,
+        position_ms: u64,
+        resilience: DecodeResilience,
         buffer_mutex: Arc<Mutex<VecDeque<i16>>>,
         condvar: Arc<Condvar>,
         eos_mutex: &Mutex<bool>,
+        stop_mutex: &Mutex<bool>,
+        markers: &Mutex<VecDeque<(u64, StreamEvent)>>,
+        events: Option<UnboundedSender<StreamEvent>>,
+    ) {
+        let mut progress = DecodeProgress::default();
+        let result = OggBluetoothStream::decode_file(
+            &filename,
+            position_ms,
+            resilience,
+            &buffer_mutex,
+            &condvar,
+            stop_mutex,
+            markers,
+            &mut progress,
+        );
+
+        // Locked before looking at stop, so that the stream can't be dropped in between
+        let mut markers = markers.lock().expect("Failed to lock markers");
+        let stopped = *stop_mutex.lock().expect("Failed to lock stop");
+
+        let corrupt_packets = progress.corrupt_packets;
+        let outcome = match result {
+            Ok(()) if stopped => DecodeOutcome::Stopped { corrupt_packets },
+            Ok(()) => DecodeOutcome::Completed { corrupt_packets },
+            Err(e) => DecodeOutcome::Failed {
+                error: e.to_string(),
+                corrupt_packets,
+            },
+        };
+        match &outcome {
+            DecodeOutcome::Failed { error, .. } => {
+                log::error!("Decoding {} failed: {}", filename, error)
+            }
+            _ => log::info!("Decoding {} ended: {:?}", filename, outcome),
+        }
+
+        // The outcome is reported once everything decoded has been played, or right away when
+        // the stream is gone and nothing more will be played
+        let event = StreamEvent::DecodeFinished { filename, outcome };
+        if stopped {
+            if let Some(events) = &events {
+                let _ = events.unbounded_send(event);
+            }
+        } else {
+            markers.push_back((progress.samples_buffered, event));
+        }
+        drop(markers);
+
+        // Whatever happened, the reader must not wait for more samples
+        let buffer = buffer_mutex.lock().expect("Failed to lock");
+        *eos_mutex.lock().expect("Failed to lock eos") = true;
+        condvar.notify_all();
+        drop(buffer);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn decode_file(
+        filename: &str,
+        position_ms: u64,
+        resilience: DecodeResilience,
+        buffer_mutex: &Mutex<VecDeque<i16>>,
+        condvar: &Arc<Condvar>,
+        stop_mutex: &Mutex<bool>,
+        markers: &Mutex<VecDeque<(u64, StreamEvent)>>,
+        progress: &mut DecodeProgress,
     ) -> Result<()> {
+        // Chained files have comments per link, for others the comments of the file are used
+        let links = ogg_chain::scan_links(filename).unwrap_or_else(|e| {
+            log::warn!("Failed to look for chained streams in {}: {}", filename, e);
+            Vec::new()
+        });
+        let file_comments = if links.is_empty() {
+            VorbisComments::read_file(filename).ok()
+        } else {
+            None
+        };
+
         // this task
         let file = File::open(filename)?;
         log::info!("Opened file, creating StreamReader");
 
         let mut decoder = librespot_tremor::Decoder::new(file)?;
+        if position_ms > 0 {
+            match decoder.time_seek(position_ms as i64) {
+                Ok(()) => {}
+                // Tremor's OV_EINVAL, which after good headers means past the end of the file:
+                // nothing is left to play, like a WAV seeked past its end
+                Err(librespot_tremor::VorbisError::InitialFileHeadersCorrupt) => {
+                    log::info!("Seek to {} ms is past the end of {}", position_ms, filename);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
         let packets = decoder.packets();
 
+        let mut link = links
+            .iter()
+            .rposition(|l| l.start_ms <= position_ms)
+            .unwrap_or(0);
+        let mut link_started = true;
+        let mut decoded_ms = position_ms as f64;
+        let mut converter: Option<FormatConverter> = None;
+        let mut consecutive_errors = 0;
+
         for packet_result in packets {
             let mut packet = match packet_result {
-                Ok(packet) => packet,
-                Err(e) => return Err(anyhow::anyhow!("Vorbis error {}", e)),
+                Ok(packet) => {
+                    consecutive_errors = 0;
+                    packet
+                }
+                Err(librespot_tremor::VorbisError::ReadError(e)) => return Err(e.into()),
+                Err(e) => {
+                    // Tremor resynchronises on the next page by itself, so all we do is count
+                    progress.corrupt_packets += 1;
+                    consecutive_errors += 1;
+                    log::warn!("Skipping corrupt packet in {}: {}", filename, e);
+
+                    if consecutive_errors > resilience.max_consecutive_errors
+                        || progress.corrupt_packets > resilience.max_errors
+                    {
+                        anyhow::bail!(
+                            "Giving up after {} corrupt packets, last error {}",
+                            progress.corrupt_packets,
+                            e
+                        );
+                    }
+                    continue;
+                }
             };
             let mut buffer = buffer_mutex.lock().expect("Failed to lock"); // not sure why ? doesn't work here
 
-            while buffer.len() >= 88200 {
+            while buffer.len() >= 88200 && !*stop_mutex.lock().expect("Failed to lock stop") {
                 buffer = condvar.wait(buffer).expect("Condvar wait failed");
             }
 
             drop(buffer); // = Release lock
 
-            OggBluetoothStream::buffer_packet(&mut packet, &buffer_mutex, &condvar, eos_mutex)?;
+            if *stop_mutex.lock().expect("Failed to lock stop") {
+                log::info!("Decoding stopped");
+                return Ok(());
+            }
+
+            if links
+                .get(link + 1)
+                .is_some_and(|next| decoded_ms >= next.start_ms as f64)
+            {
+                link += 1;
+                link_started = true;
+            }
+
+            // Tremor's packets keep the format of the first link throughout a chained file, so
+            // there it comes from the link's own headers
+            let (channels, rate) = match links.get(link) {
+                Some(chain_link) => (chain_link.channels as usize, chain_link.rate),
+                None => (packet.channels as usize, packet.rate as u32),
+            };
+            if channels == 0 || rate == 0 {
+                continue;
+            }
+            let format_changed = converter
+                .as_ref()
+                .map_or(true, |c| c.channels() != channels || c.rate() != rate);
+            if format_changed {
+                converter = Some(FormatConverter::new(channels, rate));
+            }
+
+            if link_started || format_changed {
+                let comments = match links.get(link) {
+                    Some(chain_link) => chain_link.comments.clone(),
+                    None => file_comments.clone(),
+                };
+                log::info!("Link {}: {} channels, {} Hz", link, channels, rate);
+                markers.lock().expect("Failed to lock markers").push_back((
+                    progress.samples_buffered,
+                    StreamEvent::LinkStarted {
+                        link,
+                        channels: channels as u16,
+                        rate,
+                        comments,
+                    },
+                ));
+                link_started = false;
+            }
+            decoded_ms += (packet.data.len() / channels) as f64 * 1000.0 / rate as f64;
+
+            if let Some(converter) = &mut converter {
+                progress.samples_buffered += OggBluetoothStream::buffer_packet(
+                    &mut packet,
+                    converter,
+                    buffer_mutex,
+                    condvar,
+                )? as u64;
+            }
         }
         Ok(())
     }
 
-    // returns true on end of stream
+    // returns the number of samples added to the buffer
     fn buffer_packet(
-        // &mut self,
         packet: &mut librespot_tremor::Packet,
+        converter: &mut FormatConverter,
         buffer_mutex: &Mutex<VecDeque<i16>>,
         condvar: &Arc<Condvar>,
-        _eos_mutex: &Mutex<bool>,
-    ) -> Result<bool> {
+    ) -> Result<usize> {
         // Performance:
         // Packets are 2048 samples = 1024 frames. Representing 1024 / 44100 seconds = ca 23 ms of audio
         let mut buffer = buffer_mutex.lock().expect("xyzyz");
-        // this seems very inefficient, but optmize later
-        for sample in &packet.data {
-            buffer.push_back(*sample);
-        }
+        let length_before = buffer.len();
+
+        converter.convert(&packet.data, &mut buffer);
+        let added = buffer.len() - length_before;
         condvar.notify_all();
         drop(buffer);
 
-        Ok(false)
+        Ok(added)
+    }
+
+    // Sends the events of the samples which have now been read
+    fn send_events(&mut self) {
+        let mut markers = self.markers.lock().expect("Failed to lock markers");
+
+        while markers
+            .front()
+            .is_some_and(|(position, _)| *position <= self.samples_read)
+        {
+            if let (Some((_, event)), Some(events)) = (markers.pop_front(), &self.events) {
+                // the receiver is gone when playback is stopping, nothing to do then
+                let _ = events.unbounded_send(event);
+            }
+        }
     }
 }
 
@@ -129,13 +645,14 @@ impl Stream<i16> for OggBluetoothStream {
             let mut end_of_stream = *self.end_of_file.lock().expect("Failed to lock eos");
             let mut buffer = self.buffer.lock().expect("Failed to lock");
 
-            while buffer.len() == 0 && !end_of_stream {
+            while buffer.is_empty() && !end_of_stream {
                 buffer = self.buffer_condvar.wait(buffer).expect("Wait 17");
                 end_of_stream = *self.end_of_file.lock().expect("Failde to lock eos");
             }
 
-            if buffer.len() == 0 && end_of_stream {
-                return Ok(0); // not sure what we return at EOS. TODO
+            if buffer.is_empty() && end_of_stream {
+                // return what we have; the next read returns 0 for the end of the stream
+                break;
             }
 
             let copy_len = min(buf.len() - copy_count, buffer.len());
@@ -150,16 +667,566 @@ impl Stream<i16> for OggBluetoothStream {
             self.buffer_condvar.notify_all();
         }
 
+        self.samples_read += copy_count as u64;
+        self.send_events();
+
         Ok(copy_count)
     }
 }
 
-pub async fn playback_task<'a>(bluetooth: &mut dyn Bluetooth<'a>) -> Result<()> {
-    // Open audio file
-    log::info!("Creating OggBluetoothStream");
-    let mut stream = OggBluetoothStream::new("/sdcard/sun.ogg")?;
-    stream.start()?;
-    log::info!("Created ogg Bluetooth stream");
+impl Drop for OggBluetoothStream {
+    // Make the decoding thread exit if it is waiting for buffer space, and report the outcome of
+    // a file that wasn't played to the end as stopped. If the thread is still decoding, it reports
+    // the outcome itself when it sees stop.
+    fn drop(&mut self) {
+        let mut markers = self.markers.lock().expect("Failed to lock markers");
+        let buffer = self.buffer.lock().expect("Failed to lock");
+        *self.stop.lock().expect("Failed to lock stop") = true;
+        self.buffer_condvar.notify_all();
+        drop(buffer);
+
+        for (_, event) in markers.drain(..) {
+            let StreamEvent::DecodeFinished { filename, outcome } = event else {
+                continue;
+            };
+            let outcome = match outcome {
+                DecodeOutcome::Completed { corrupt_packets } => {
+                    DecodeOutcome::Stopped { corrupt_packets }
+                }
+                outcome => outcome,
+            };
+            if let Some(events) = &self.events {
+                let _ = events.unbounded_send(StreamEvent::DecodeFinished { filename, outcome });
+            }
+        }
+    }
+}
+
+struct SlotState {
+    stream: Option<Box<dyn Stream<i16>>>,
+    start_ms: u64,
+    frames_read: u64,
+    finished: bool,
+}
+
+// Holds the source currently being played. The slot is the start of the processing pipeline, so
+// sources can be switched without rebuilding the pipeline or restarting the A2DP media stream.
+// When there is no source, or the source has ended, the slot plays silence.
+#[derive(Clone)]
+pub struct SourceSlot {
+    state: Arc<Mutex<SlotState>>,
+    finished_event: Arc<event_listener::Event>,
+}
+
+impl SourceSlot {
+    pub fn new() -> Self {
+        SourceSlot {
+            state: Arc::new(Mutex::new(SlotState {
+                stream: None,
+                start_ms: 0,
+                frames_read: 0,
+                finished: false,
+            })),
+            finished_event: Arc::new(event_listener::Event::new()),
+        }
+    }
+
+    // start_ms is where in the source the stream starts, for position reporting
+    pub fn set(&self, stream: Box<dyn Stream<i16>>, start_ms: u64) {
+        let mut state = self.state.lock().expect("Failed to lock slot");
+
+        state.stream = Some(stream);
+        state.start_ms = start_ms;
+        state.frames_read = 0;
+        state.finished = false;
+    }
+
+    pub fn clear(&self) {
+        let old_stream = self
+            .state
+            .lock()
+            .expect("Failed to lock slot")
+            .stream
+            .take();
+
+        drop(old_stream); // outside the lock, dropping may have to wait for a decoding thread
+    }
+
+    pub fn position_ms(&self) -> u64 {
+        let state = self.state.lock().expect("Failed to lock slot");
+
+        state.start_ms + state.frames_read * 1000 / SAMPLE_RATE as u64
+    }
+
+    pub async fn wait_finished(&self) {
+        loop {
+            let listener = self.finished_event.listen();
+
+            if self.state.lock().expect("Failed to lock slot").finished {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
+
+impl Default for SourceSlot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream<i16> for SourceSlot {
+    fn read(&mut self, buf: &mut [i16]) -> Result<usize> {
+        let mut state = self.state.lock().expect("Failed to lock slot");
+        let mut count = 0;
+
+        if let Some(stream) = &mut state.stream {
+            count = match stream.read(buf) {
+                Ok(count) => count,
+                Err(e) => {
+                    log::error!("SourceSlot: error reading from source: {}", e);
+                    0
+                }
+            };
+            state.frames_read += (count / CHANNELS) as u64;
+
+            if count == 0 {
+                state.stream = None;
+                state.finished = true;
+                self.finished_event.notify(usize::MAX);
+            }
+        }
+        drop(state);
+
+        buf[count..].fill(0);
+
+        Ok(buf.len())
+    }
+}
+
+// Stops a source after the given number of samples, e.g. at the end of a CUE track
+struct LimitedStream {
+    source: Box<dyn Stream<i16>>,
+    remaining: u64,
+}
+
+impl Stream<i16> for LimitedStream {
+    fn read(&mut self, buf: &mut [i16]) -> Result<usize> {
+        let len = min(buf.len() as u64, self.remaining) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let count = self.source.read(&mut buf[..len])?;
+        self.remaining -= count as u64;
+        Ok(count)
+    }
+}
+
+// Reports the outcome of a source which doesn't do so itself, like a WAV file: completed when it
+// has been read to the end, failed at a read error, and stopped when dropped before either
+struct OutcomeStream {
+    source: Box<dyn Stream<i16>>,
+    filename: String,
+    events: UnboundedSender<StreamEvent>,
+    reported: bool,
+}
+
+impl OutcomeStream {
+    fn report(&mut self, outcome: DecodeOutcome) {
+        if !self.reported {
+            self.reported = true;
+            // the receiver is gone when playback is stopping, nothing to do then
+            let _ = self.events.unbounded_send(StreamEvent::DecodeFinished {
+                filename: self.filename.clone(),
+                outcome,
+            });
+        }
+    }
+}
+
+impl Stream<i16> for OutcomeStream {
+    fn read(&mut self, buf: &mut [i16]) -> Result<usize> {
+        match self.source.read(buf) {
+            Ok(0) => {
+                self.report(DecodeOutcome::Completed { corrupt_packets: 0 });
+                Ok(0)
+            }
+            Ok(count) => Ok(count),
+            Err(e) => {
+                self.report(DecodeOutcome::Failed {
+                    error: e.to_string(),
+                    corrupt_packets: 0,
+                });
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for OutcomeStream {
+    fn drop(&mut self) {
+        self.report(DecodeOutcome::Stopped { corrupt_packets: 0 });
+    }
+}
+
+// Opens an Ogg Vorbis or WAV file, decoding to stereo at SAMPLE_RATE
+pub fn open_file(
+    filename: &str,
+    position_ms: u64,
+    settings: &PlaybackSettings,
+    events: &UnboundedSender<StreamEvent>,
+) -> Result<Box<dyn Stream<i16>>> {
+    let is_wav = Path::new(filename)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"));
+
+    if is_wav {
+        log::info!("Creating WavStream for {} at {} ms", filename, position_ms);
+        let mut stream = WavStream::open(filename)?;
+        stream.seek(position_ms)?;
+
+        Ok(Box::new(OutcomeStream {
+            source: Box::new(stream),
+            filename: filename.to_string(),
+            events: events.clone(),
+            reported: false,
+        }))
+    } else {
+        log::info!(
+            "Creating OggBluetoothStream for {} at {} ms",
+            filename,
+            position_ms
+        );
+        let mut stream =
+            OggBluetoothStream::new(filename, Some(events.clone()), settings.resilience)?;
+        stream.start(position_ms)?;
+
+        Ok(Box::new(stream))
+    }
+}
+
+fn open_source(
+    entry: &PlaylistEntry,
+    position_ms: u64,
+    settings: &PlaybackSettings,
+    events: &UnboundedSender<StreamEvent>,
+) -> Result<Box<dyn Stream<i16>>> {
+    log::info!(
+        "Playing {} by {}",
+        entry.title(),
+        entry.performer().unwrap_or("unknown")
+    );
+
+    match entry {
+        PlaylistEntry::File(filename) => open_file(filename, position_ms, settings, events),
+        PlaylistEntry::CueTrack(track) => {
+            let stream = open_file(&track.file, track.start_ms + position_ms, settings, events)?;
+
+            match track.end_ms {
+                Some(end_ms) => {
+                    let frames = end_ms.saturating_sub(track.start_ms + position_ms)
+                        * SAMPLE_RATE as u64
+                        / 1000;
+                    Ok(Box::new(LimitedStream {
+                        source: stream,
+                        remaining: frames * CHANNELS as u64,
+                    }))
+                }
+                None => Ok(stream),
+            }
+        }
+        PlaylistEntry::TestSignal {
+            signal,
+            duration_ms,
+        } => {
+            log::info!("Generating test signal {:?}", signal);
+            let mut generator = SignalGenerator::new(*signal, *duration_ms);
+            generator.seek(position_ms);
+
+            Ok(Box::new(generator))
+        }
+    }
+}
+
+// Opens the entry at position, or if it can't be opened, e.g. a WAV file in a format we don't
+// play, the next one that can. The ones skipped are reported as failed. None if there is none.
+fn open_playable(
+    playlist: &Playlist,
+    mut position: PlaylistPosition,
+    settings: &PlaybackSettings,
+    events: &UnboundedSender<StreamEvent>,
+    report: &mut PlaybackReport,
+) -> Option<(PlaylistPosition, Box<dyn Stream<i16>>)> {
+    loop {
+        let entry = playlist.entry(position.index);
+
+        match open_source(entry, position.position_ms, settings, events) {
+            Ok(stream) => return Some((position, stream)),
+            Err(e) => {
+                let name = match entry {
+                    PlaylistEntry::File(filename) => filename.clone(),
+                    PlaylistEntry::CueTrack(track) => track.file.clone(),
+                    PlaylistEntry::TestSignal { .. } => entry.title(),
+                };
+                log::error!("Skipping {}: {}", name, e);
+                report.files_failed.push((name, e.to_string()));
+                position = playlist.next_track(&position)?;
+            }
+        }
+    }
+}
+
+enum SourceEvent {
+    Finished,
+    Stream(StreamEvent),
+}
+
+async fn next_source_event(
+    slot: &SourceSlot,
+    events: &mut UnboundedReceiver<StreamEvent>,
+) -> SourceEvent {
+    // events first, so that the outcome of a file is handled before moving on from it
+    match select(events.next(), Box::pin(slot.wait_finished())).await {
+        Either::Left((Some(event), _)) => SourceEvent::Stream(event),
+        // the sender is kept by the playback task, so the event stream doesn't end
+        Either::Left((None, _)) | Either::Right(_) => SourceEvent::Finished,
+    }
+}
+
+fn handle_stream_event(event: &StreamEvent, report: &mut PlaybackReport) {
+    match event {
+        StreamEvent::LinkStarted {
+            link,
+            channels,
+            rate,
+            comments,
+        } => {
+            let tag = |key| {
+                comments
+                    .as_ref()
+                    .and_then(|c| c.get(key))
+                    .unwrap_or("unknown")
+            };
+            log::info!(
+                "Now playing {} by {} (link {}, {} channels, {} Hz)",
+                tag("TITLE"),
+                tag("ARTIST"),
+                link,
+                channels,
+                rate
+            );
+        }
+        StreamEvent::DecodeFinished { filename, outcome } => report.add(filename, outcome),
+    }
+}
+
+// For changing the processing while it plays
+pub struct ProcessingControls {
+    pub speed: SpeedControl,
+    pub equalizer: EqualizerControl,
+    pub dynamics: DynamicsControl,
+}
+
+// What is done to the music after decoding, set up as in settings:
+// tempo -> gain -> equalizer -> compressor/limiter
+pub fn processing_pipeline(
+    source: Box<dyn Stream<i16>>,
+    settings: &PlaybackSettings,
+) -> (DspStream, ProcessingControls) {
+    let tempo = TempoStream::new(source);
+    let speed = tempo.speed_control();
+    speed.set(settings.speed);
+
+    let equalizer = Equalizer::new(settings.equalizer.clone());
+    let dynamics = Dynamics::new(settings.dynamics);
+    let controls = ProcessingControls {
+        speed,
+        equalizer: equalizer.control(),
+        dynamics: dynamics.control(),
+    };
+
+    let mut dsp = DspStream::new(Box::new(tempo));
+    dsp.add(Box::new(Gain::new(settings.gain_db)));
+    dsp.add(Box::new(equalizer));
+    dsp.add(Box::new(dynamics));
+
+    (dsp, controls)
+}
+
+// Plays the playlist through the mixer until it ends or a Stop command is received.
+pub async fn playback_task(
+    mixer: &MixerControl,
+    playlist: &Playlist,
+    settings: &PlaybackSettings,
+    commands: &mut UnboundedReceiver<PlaybackCommand>,
+    now_playing: &NowPlaying,
+) -> Result<PlaybackReport> {
+    let mut report = PlaybackReport::default();
+    let Some(mut start) = playlist.start() else {
+        log::info!("Playlist is empty");
+        return Ok(report);
+    };
+
+    let (event_sender, mut stream_events) = mpsc::unbounded();
+    let slot = SourceSlot::new();
+    let Some((first, stream)) =
+        open_playable(playlist, start, settings, &event_sender, &mut report)
+    else {
+        log::info!("Nothing in the playlist can be played");
+        return Ok(report);
+    };
+    start = first;
+    slot.set(stream, start.position_ms);
+    now_playing.start_track(
+        TrackInfo::from_entry(playlist.entry(start.index), start.index),
+        &slot,
+    );
+
+    // slot -> processing -> mixer -> Bluetooth
+    let (dsp, controls) = processing_pipeline(Box::new(slot.clone()), settings);
+    mixer.set_music(Box::new(dsp));
+
+    // only audiobooks have bookmarks
+    let mut bookmark_ticks = match playlist.audiobook() {
+        Some(_) => Some(ticker(BOOKMARK_INTERVAL)?),
+        None => None,
+    };
+    let mut bookmark = start;
+
+    loop {
+        let bookmark_tick = async {
+            match bookmark_ticks.as_mut() {
+                Some(ticks) => ticks.next().await,
+                None => future::pending().await,
+            }
+        };
+        let event = select(
+            select(
+                Box::pin(next_source_event(&slot, &mut stream_events)),
+                commands.next(),
+            ),
+            Box::pin(bookmark_tick),
+        )
+        .await;
+        let current = PlaylistPosition {
+            index: start.index,
+            position_ms: slot.position_ms(),
+        };
+
+        let event = match event {
+            Either::Left((event, _)) => event,
+            Either::Right(_) => {
+                // not while paused
+                if current != bookmark {
+                    store_position(playlist, &current);
+                    bookmark = current;
+                }
+                continue;
+            }
+        };
+        // a seek stays in the track, also when it ends up at the start
+        let mut seeking = false;
+        let next = match event {
+            Either::Left((SourceEvent::Finished, _)) => playlist.next_track(&current),
+            Either::Left((SourceEvent::Stream(stream_event), _)) => {
+                if let StreamEvent::LinkStarted { link, comments, .. } = &stream_event {
+                    let from_cue =
+                        matches!(playlist.entry(start.index), PlaylistEntry::CueTrack(_));
+                    now_playing.link_started(*link, comments.as_ref(), from_cue);
+                }
+                handle_stream_event(&stream_event, &mut report);
+                continue;
+            }
+            Either::Right((Some(command), _)) => {
+                log::info!("Playback command {:?}", command);
+                match command {
+                    PlaybackCommand::Stop => None,
+                    PlaybackCommand::NextTrack => playlist.next_track(&current),
+                    PlaybackCommand::PreviousTrack => playlist.previous_track(&current),
+                    PlaybackCommand::NextChapter => playlist.next_chapter(&current),
+                    PlaybackCommand::PreviousChapter => playlist.previous_chapter(&current),
+                    PlaybackCommand::SeekBy(delta_ms) => {
+                        seeking = true;
+                        playlist.seek(&current, delta_ms)
+                    }
+                    PlaybackCommand::SetSpeed(new_speed) => {
+                        controls.speed.set(new_speed);
+                        continue;
+                    }
+                    PlaybackCommand::SetEqualizer(bands) => {
+                        controls.equalizer.set_bands(bands);
+                        continue;
+                    }
+                    PlaybackCommand::SetDynamics(dynamics) => {
+                        controls.dynamics.set(dynamics);
+                        continue;
+                    }
+                }
+            }
+            Either::Right((None, _)) => None,
+        };
+
+        match next {
+            Some(position) => {
+                slot.clear();
+                let Some((position, stream)) =
+                    open_playable(playlist, position, settings, &event_sender, &mut report)
+                else {
+                    store_position(playlist, &current);
+                    break;
+                };
+                store_position(playlist, &position);
+                bookmark = position;
+                slot.set(stream, position.position_ms);
+                // previous track restarts the same one, which is a new track for remote controls
+                if position.index != start.index || (position.position_ms == 0 && !seeking) {
+                    now_playing.start_track(
+                        TrackInfo::from_entry(playlist.entry(position.index), position.index),
+                        &slot,
+                    );
+                }
+                start = position;
+            }
+            None => {
+                store_position(playlist, &current);
+                break;
+            }
+        }
+    }
+
+    mixer.clear_music();
+    slot.clear();
+    now_playing.stop();
+    while let Ok(stream_event) = stream_events.try_recv() {
+        handle_stream_event(&stream_event, &mut report);
+    }
+    log::info!("Playback finished: {:?}", report);
+
+    Ok(report)
+}
+
+// A bookmark that can't be written, e.g. on a full SD card, is no reason to stop playing
+fn store_position(playlist: &Playlist, position: &PlaylistPosition) {
+    if let Err(e) = playlist.store_position(position) {
+        log::error!("Failed to save bookmark: {}", e);
+    }
+}
+
+// Sends every interval, until the receiver is dropped
+fn ticker(interval: Duration) -> Result<UnboundedReceiver<()>> {
+    let (sender, receiver) = mpsc::unbounded();
+
+    thread::Builder::new()
+        .name("ticker".to_owned())
+        .stack_size(4096)
+        .spawn(move || loop {
+            thread::sleep(interval);
+            if sender.unbounded_send(()).is_err() {
+                break;
+            }
+        })?;
 
-    bluetooth.a2dp_play(Box::new(stream)).await
+    Ok(receiver)
 }
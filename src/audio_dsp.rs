@@ -0,0 +1,142 @@
+// Floating point processing between the decoder and the Bluetooth layer.
+// Samples are converted to f32 once, run through all the processors in order and converted back,
+// so a boost in one stage doesn't clip before a later stage gets to deal with it.
+//
+// Classic Bluetooth, and so this player, only exists on the original ESP32, which has a single
+// precision FPU. f32 is about as fast as fixed point there, and much simpler to get right.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::bluetooth_hal::Stream;
+
+pub trait AudioProcessor: Send {
+    // samples are interleaved stereo, full scale is -1.0..1.0
+    fn process(&mut self, samples: &mut [f32]);
+}
+
+pub struct DspStream {
+    source: Box<dyn Stream<i16>>,
+    processors: Vec<Box<dyn AudioProcessor>>,
+    samples: Vec<f32>,
+}
+
+impl DspStream {
+    pub fn new(source: Box<dyn Stream<i16>>) -> Self {
+        DspStream {
+            source,
+            processors: Vec::new(),
+            samples: Vec::new(),
+        }
+    }
+
+    // Processors run in the order they are added
+    pub fn add(&mut self, processor: Box<dyn AudioProcessor>) {
+        self.processors.push(processor);
+    }
+}
+
+impl Stream<i16> for DspStream {
+    fn read(&mut self, buf: &mut [i16]) -> Result<usize> {
+        let count = self.source.read(buf)?;
+
+        if self.processors.is_empty() {
+            return Ok(count);
+        }
+
+        self.samples.clear();
+        self.samples
+            .extend(buf[..count].iter().map(|s| *s as f32 / 32768.0));
+
+        for processor in &mut self.processors {
+            processor.process(&mut self.samples);
+        }
+
+        for (dest, sample) in buf.iter_mut().zip(&self.samples) {
+            *dest = (sample * 32768.0).clamp(-32768.0, 32767.0) as i16;
+        }
+
+        Ok(count)
+    }
+}
+
+// Fixed gain, e.g. to make headroom for equalizer boosts
+pub struct Gain {
+    factor: f32,
+}
+
+impl Gain {
+    pub fn new(gain_db: f32) -> Self {
+        Gain {
+            factor: 10f32.powf(gain_db / 20.0),
+        }
+    }
+}
+
+impl AudioProcessor for Gain {
+    fn process(&mut self, samples: &mut [f32]) {
+        if self.factor == 1.0 {
+            return;
+        }
+        for sample in samples {
+            *sample *= self.factor;
+        }
+    }
+}
+
+// Player volume in percent. The taper is logarithmic so that each step sounds about as loud as the
+// next, down to VOLUME_RANGE_DB below full scale; 0 is silence.
+pub const MAX_VOLUME: u8 = 100;
+const VOLUME_RANGE_DB: f32 = 50.0;
+
+#[derive(Clone)]
+pub struct VolumeControl {
+    factor: Arc<Mutex<f32>>,
+}
+
+impl VolumeControl {
+    pub fn set(&self, volume: u8) {
+        *self.factor.lock().expect("Failed to lock volume") = volume_factor(volume);
+    }
+}
+
+pub struct Volume {
+    factor: Arc<Mutex<f32>>,
+}
+
+impl Volume {
+    pub fn new(volume: u8) -> Self {
+        Volume {
+            factor: Arc::new(Mutex::new(volume_factor(volume))),
+        }
+    }
+
+    pub fn control(&self) -> VolumeControl {
+        VolumeControl {
+            factor: self.factor.clone(),
+        }
+    }
+}
+
+impl AudioProcessor for Volume {
+    fn process(&mut self, samples: &mut [f32]) {
+        let factor = *self.factor.lock().expect("Failed to lock volume");
+        if factor == 1.0 {
+            return;
+        }
+        for sample in samples {
+            *sample *= factor;
+        }
+    }
+}
+
+fn volume_factor(volume: u8) -> f32 {
+    match volume.min(MAX_VOLUME) {
+        0 => 0.0,
+        volume => {
+            let gain_db = (volume as f32 / MAX_VOLUME as f32 - 1.0) * VOLUME_RANGE_DB;
+            10f32.powf(gain_db / 20.0)
+        }
+    }
+}
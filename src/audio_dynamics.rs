@@ -0,0 +1,264 @@
+// Dynamics processing, last in the DSP chain: an optional downward compressor followed by a
+// look-ahead peak limiter which keeps the output below the ceiling, so that equalizer boosts and
+// compressor makeup gain don't clip when converted back to 16 bits.
+//
+// The limiter delays the audio by LOOKAHEAD_FRAMES. The gain needed for each incoming frame is
+// held over the look-ahead window (sliding minimum), released exponentially and then averaged
+// over the window. The average is never above the gain needed for the frame leaving the delay line,
+// and there are no steps in the gain.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    audio::{CHANNELS, SAMPLE_RATE},
+    audio_dsp::AudioProcessor,
+};
+
+// 5 ms
+const LOOKAHEAD_FRAMES: usize = 220;
+const LIMITER_RELEASE_MS: f32 = 80.0;
+// The compressor gain is computed once per block, and interpolated in between
+const COMPRESSOR_BLOCK_FRAMES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressorSettings {
+    pub threshold_db: f32,
+    // e.g. 4.0 for 4:1
+    pub ratio: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    pub makeup_db: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DynamicsSettings {
+    pub compressor: Option<CompressorSettings>,
+    pub limiter_ceiling_db: f32,
+}
+
+impl Default for DynamicsSettings {
+    fn default() -> Self {
+        Self {
+            compressor: None,
+            limiter_ceiling_db: -1.0,
+        }
+    }
+}
+
+impl DynamicsSettings {
+    // For quiet listening: loud passages are brought down and quiet ones up, so that dialogue
+    // can be heard without explosions waking anyone up.
+    pub fn night_mode() -> Self {
+        Self {
+            compressor: Some(CompressorSettings {
+                threshold_db: -30.0,
+                ratio: 4.0,
+                attack_ms: 5.0,
+                release_ms: 200.0,
+                makeup_db: 12.0,
+            }),
+            limiter_ceiling_db: -1.0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DynamicsControl {
+    pending: Arc<Mutex<Option<DynamicsSettings>>>,
+}
+
+impl DynamicsControl {
+    pub fn set(&self, settings: DynamicsSettings) {
+        *self
+            .pending
+            .lock()
+            .expect("Failed to lock dynamics settings") = Some(settings);
+    }
+}
+
+struct Compressor {
+    settings: CompressorSettings,
+    attack_coefficient: f32,
+    release_coefficient: f32,
+    envelope: f32,
+    gain: f32,
+}
+
+impl Compressor {
+    fn new(settings: CompressorSettings) -> Self {
+        Compressor {
+            settings,
+            attack_coefficient: time_coefficient(settings.attack_ms),
+            release_coefficient: time_coefficient(settings.release_ms),
+            envelope: 0.0,
+            gain: db_to_linear(settings.makeup_db),
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        for block in samples.chunks_mut(COMPRESSOR_BLOCK_FRAMES * CHANNELS) {
+            for frame in block.chunks_exact(CHANNELS) {
+                let peak = frame.iter().fold(0.0f32, |max, s| max.max(s.abs()));
+                let coefficient = if peak > self.envelope {
+                    self.attack_coefficient
+                } else {
+                    self.release_coefficient
+                };
+                self.envelope += (peak - self.envelope) * coefficient;
+            }
+
+            let level_db = linear_to_db(self.envelope);
+            let over_db = (level_db - self.settings.threshold_db).max(0.0);
+            let gain_db = self.settings.makeup_db - over_db * (1.0 - 1.0 / self.settings.ratio);
+            let target = db_to_linear(gain_db);
+
+            // ramp from the previous block's gain to avoid zipper noise
+            let frames = (block.len() / CHANNELS).max(1);
+            let step = (target - self.gain) / frames as f32;
+            for frame in block.chunks_exact_mut(CHANNELS) {
+                self.gain += step;
+                for sample in frame {
+                    *sample *= self.gain;
+                }
+            }
+            self.gain = target;
+        }
+    }
+}
+
+struct Limiter {
+    ceiling: f32,
+    release_coefficient: f32,
+    delay: VecDeque<f32>,
+    // (frame number, required gain), increasing gains: the front is the window minimum
+    window_minimum: VecDeque<(u64, f32)>,
+    released_gain: f32,
+    // the released gains of the last LOOKAHEAD_FRAMES frames, and their sum
+    average_buffer: VecDeque<f32>,
+    average_sum: f32,
+    frame_number: u64,
+}
+
+impl Limiter {
+    fn new(ceiling_db: f32) -> Self {
+        Limiter {
+            ceiling: db_to_linear(ceiling_db),
+            release_coefficient: time_coefficient(LIMITER_RELEASE_MS),
+            delay: VecDeque::from(vec![0.0; LOOKAHEAD_FRAMES * CHANNELS]),
+            window_minimum: VecDeque::new(),
+            released_gain: 1.0,
+            average_buffer: VecDeque::from(vec![1.0; LOOKAHEAD_FRAMES]),
+            average_sum: LOOKAHEAD_FRAMES as f32,
+            frame_number: 0,
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(CHANNELS) {
+            let peak = frame.iter().fold(0.0f32, |max, s| max.max(s.abs()));
+            let required = if peak > self.ceiling {
+                self.ceiling / peak
+            } else {
+                1.0
+            };
+
+            // sliding minimum over the look-ahead window
+            while matches!(self.window_minimum.back(), Some((_, gain)) if *gain >= required) {
+                self.window_minimum.pop_back();
+            }
+            self.window_minimum.push_back((self.frame_number, required));
+            while matches!(self.window_minimum.front(), Some((frame_number, _))
+                if *frame_number + (LOOKAHEAD_FRAMES as u64) < self.frame_number)
+            {
+                self.window_minimum.pop_front();
+            }
+            let held = self.window_minimum.front().map_or(1.0, |(_, gain)| *gain);
+
+            // instant attack, exponential release
+            self.released_gain = if held < self.released_gain {
+                held
+            } else {
+                self.released_gain + (held - self.released_gain) * self.release_coefficient
+            };
+
+            self.average_sum += self.released_gain - self.average_buffer.pop_front().unwrap_or(1.0);
+            self.average_buffer.push_back(self.released_gain);
+            self.frame_number += 1;
+            if self.frame_number % LOOKAHEAD_FRAMES as u64 == 0 {
+                // don't let rounding errors accumulate
+                self.average_sum = self.average_buffer.iter().sum();
+            }
+            let gain = self.average_sum / LOOKAHEAD_FRAMES as f32;
+
+            for sample in frame {
+                let delayed = self.delay.pop_front().unwrap_or(0.0);
+                self.delay.push_back(*sample);
+                // the clamp only catches rounding errors
+                *sample = (delayed * gain).clamp(-self.ceiling, self.ceiling);
+            }
+        }
+    }
+}
+
+pub struct Dynamics {
+    compressor: Option<Compressor>,
+    limiter: Limiter,
+    control: DynamicsControl,
+}
+
+impl Dynamics {
+    pub fn new(settings: DynamicsSettings) -> Self {
+        Dynamics {
+            compressor: settings.compressor.map(Compressor::new),
+            limiter: Limiter::new(settings.limiter_ceiling_db),
+            control: DynamicsControl {
+                pending: Arc::new(Mutex::new(None)),
+            },
+        }
+    }
+
+    pub fn control(&self) -> DynamicsControl {
+        self.control.clone()
+    }
+
+    fn apply(&mut self, settings: DynamicsSettings) {
+        log::info!("Dynamics: new settings {:?}", settings);
+        self.compressor = settings.compressor.map(Compressor::new);
+        // keep the limiter's delay line and gain state, only the ceiling changes
+        self.limiter.ceiling = db_to_linear(settings.limiter_ceiling_db);
+    }
+}
+
+impl AudioProcessor for Dynamics {
+    fn process(&mut self, samples: &mut [f32]) {
+        // never wait for the control here, we may be in the Bluetooth callback
+        let pending = match self.control.pending.try_lock() {
+            Ok(mut pending) => pending.take(),
+            Err(_) => None,
+        };
+        if let Some(settings) = pending {
+            self.apply(settings);
+        }
+
+        if let Some(compressor) = &mut self.compressor {
+            compressor.process(samples);
+        }
+        self.limiter.process(samples);
+    }
+}
+
+// One-pole smoothing coefficient reaching 1 - 1/e of a step in the given time
+fn time_coefficient(ms: f32) -> f32 {
+    1.0 - (-1000.0 / (ms.max(0.01) * SAMPLE_RATE as f32)).exp()
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn linear_to_db(linear: f32) -> f32 {
+    20.0 * linear.max(1e-6).log10()
+}
@@ -0,0 +1,263 @@
+// Parametric equalizer: a cascade of biquad filters, one per band.
+// Coefficients from Robert Bristow-Johnson's Audio EQ Cookbook,
+// https://www.w3.org/TR/audio-eq-cookbook/
+
+use std::{
+    f32::consts::PI,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    audio::{CHANNELS, SAMPLE_RATE},
+    audio_dsp::AudioProcessor,
+};
+
+pub const MAX_BANDS: usize = 8;
+// Boost or cut of a band; far more is a mistake, and would overflow the coefficients
+pub const MAX_GAIN_DB: f32 = 24.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterType {
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EqBand {
+    pub filter_type: FilterType,
+    // Center frequency for peaking filters, corner frequency for shelves
+    pub frequency: f32,
+    pub gain_db: f32,
+    // For shelves, 0.707 gives the steepest slope without overshoot
+    pub q: f32,
+}
+
+impl EqBand {
+    // A band outside these limits gives an unstable filter or NaN, which then stays in the filter
+    // state and silences the output
+    pub fn validate(&self) -> Result<()> {
+        let nyquist = SAMPLE_RATE as f32 / 2.0;
+
+        if self.frequency.is_nan() || self.frequency <= 0.0 || self.frequency >= nyquist {
+            bail!(
+                "Band frequency {} Hz not between 0 and {} Hz",
+                self.frequency,
+                nyquist
+            );
+        }
+        if self.gain_db.is_nan() || self.gain_db.abs() > MAX_GAIN_DB {
+            bail!("Band gain {} dB beyond {} dB", self.gain_db, MAX_GAIN_DB);
+        }
+        if self.q.is_nan() || self.q <= 0.0 || self.q.is_infinite() {
+            bail!("Band Q {} not positive", self.q);
+        }
+        Ok(())
+    }
+}
+
+// Coefficients normalized so that a0 = 1
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Biquad {
+    fn new(band: &EqBand) -> Self {
+        let a = 10f32.powf(band.gain_db / 40.0);
+        let w0 = 2.0 * PI * band.frequency / SAMPLE_RATE as f32;
+        let cos_w0 = w0.cos();
+        let alpha = w0.sin() / (2.0 * band.q);
+
+        let (b0, b1, b2, a0, a1, a2) = match band.filter_type {
+            FilterType::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos_w0,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos_w0,
+                1.0 - alpha / a,
+            ),
+            FilterType::LowShelf => {
+                let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha),
+                    (a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha,
+                )
+            }
+            FilterType::HighShelf => {
+                let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha),
+                    (a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha,
+                )
+            }
+        };
+
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    // Magnitude of the transfer function at the given frequency
+    fn magnitude(&self, frequency: f32) -> f32 {
+        let w = 2.0 * PI * frequency / SAMPLE_RATE as f32;
+        let (cos1, sin1) = (w.cos(), w.sin());
+        let (cos2, sin2) = ((2.0 * w).cos(), (2.0 * w).sin());
+
+        let num_re = self.b0 + self.b1 * cos1 + self.b2 * cos2;
+        let num_im = -(self.b1 * sin1 + self.b2 * sin2);
+        let den_re = 1.0 + self.a1 * cos1 + self.a2 * cos2;
+        let den_im = -(self.a1 * sin1 + self.a2 * sin2);
+
+        ((num_re * num_re + num_im * num_im) / (den_re * den_re + den_im * den_im)).sqrt()
+    }
+}
+
+// Changes made through the control are picked up by the equalizer at its next block
+#[derive(Clone)]
+pub struct EqualizerControl {
+    pending: Arc<Mutex<Option<Vec<EqBand>>>>,
+}
+
+impl EqualizerControl {
+    pub fn set_bands(&self, bands: Vec<EqBand>) {
+        *self.pending.lock().expect("Failed to lock equalizer bands") = Some(bands);
+    }
+}
+
+pub struct Equalizer {
+    bands: Vec<EqBand>,
+    filters: Vec<Biquad>,
+    // transposed direct form II state, per filter and channel
+    state: Vec<[[f32; 2]; CHANNELS]>,
+    control: EqualizerControl,
+}
+
+impl Equalizer {
+    pub fn new(bands: Vec<EqBand>) -> Self {
+        let mut eq = Equalizer {
+            bands: Vec::new(),
+            filters: Vec::new(),
+            state: Vec::new(),
+            control: EqualizerControl {
+                pending: Arc::new(Mutex::new(None)),
+            },
+        };
+        eq.set_bands(bands);
+        eq
+    }
+
+    pub fn control(&self) -> EqualizerControl {
+        self.control.clone()
+    }
+
+    pub fn bands(&self) -> &[EqBand] {
+        &self.bands
+    }
+
+    fn set_bands(&mut self, mut bands: Vec<EqBand>) {
+        if bands.len() > MAX_BANDS {
+            log::warn!("Equalizer: ignoring bands beyond {}", MAX_BANDS);
+            bands.truncate(MAX_BANDS);
+        }
+        bands.retain(|band| match band.validate() {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Equalizer: ignoring band {:?}: {}", band, e);
+                false
+            }
+        });
+        // a 0 dB band does nothing, so don't spend CPU on it
+        self.filters = bands
+            .iter()
+            .filter(|band| band.gain_db != 0.0)
+            .map(Biquad::new)
+            .collect();
+        self.state = vec![[[0.0; 2]; CHANNELS]; self.filters.len()];
+        self.bands = bands;
+    }
+
+    // Combined gain of all bands at the given frequency, in dB
+    pub fn response_db(&self, frequency: f32) -> f32 {
+        let magnitude: f32 = self
+            .filters
+            .iter()
+            .map(|f| f.magnitude(frequency))
+            .product();
+
+        20.0 * magnitude.log10()
+    }
+}
+
+impl AudioProcessor for Equalizer {
+    fn process(&mut self, samples: &mut [f32]) {
+        // never wait for the control here, we may be in the Bluetooth callback
+        let pending = match self.control.pending.try_lock() {
+            Ok(mut pending) => pending.take(),
+            Err(_) => None,
+        };
+        if let Some(bands) = pending {
+            log::info!("Equalizer: new bands {:?}", bands);
+            self.set_bands(bands);
+        }
+
+        for (filter, state) in self.filters.iter().zip(self.state.iter_mut()) {
+            for frame in samples.chunks_exact_mut(CHANNELS) {
+                for (sample, s) in frame.iter_mut().zip(state.iter_mut()) {
+                    let input = *sample;
+                    let output = filter.b0 * input + s[0];
+
+                    s[0] = filter.b1 * input - filter.a1 * output + s[1];
+                    s[1] = filter.b2 * input - filter.a2 * output;
+                    *sample = output;
+                }
+            }
+        }
+    }
+}
+
+pub const BUILTIN_PRESETS: [&str; 5] = ["flat", "bass", "treble", "voice", "loudness"];
+
+pub fn builtin_preset(name: &str) -> Option<Vec<EqBand>> {
+    let band = |filter_type, frequency, gain_db, q| EqBand {
+        filter_type,
+        frequency,
+        gain_db,
+        q,
+    };
+
+    match name {
+        "flat" => Some(Vec::new()),
+        "bass" => Some(vec![band(FilterType::LowShelf, 120.0, 6.0, 0.707)]),
+        "treble" => Some(vec![band(FilterType::HighShelf, 6000.0, 6.0, 0.707)]),
+        // speech clarity for audiobooks and podcasts: less rumble, more presence
+        "voice" => Some(vec![
+            band(FilterType::LowShelf, 150.0, -6.0, 0.707),
+            band(FilterType::Peaking, 2500.0, 4.0, 1.0),
+        ]),
+        "loudness" => Some(vec![
+            band(FilterType::LowShelf, 100.0, 6.0, 0.707),
+            band(FilterType::HighShelf, 10000.0, 4.0, 0.707),
+        ]),
+        _ => None,
+    }
+}
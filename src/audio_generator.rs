@@ -0,0 +1,234 @@
+// Built in test signals, for checking out headphones and speakers without needing any files on
+// an SD card. All signals are generated at SAMPLE_RATE, in stereo.
+
+use std::f32::consts::PI;
+
+use anyhow::Result;
+
+use crate::{
+    audio::{CHANNELS, SAMPLE_RATE},
+    bluetooth_hal::Stream,
+};
+
+// Channel identification: a beep on the left, then two beeps on the right, repeating.
+// Two beeps on the right tells left from right even when both are heard in the same ear.
+const IDENTIFICATION_CYCLE_MS: u64 = 4000;
+const IDENTIFICATION_FREQUENCY: f32 = 1000.0;
+// (start ms within the cycle, length ms, channel)
+const IDENTIFICATION_BEEPS: [(u64, u64, usize); 3] = [(0, 500, 0), (2000, 200, 1), (2300, 200, 1)];
+// Beeps are faded in and out to avoid clicks
+const FADE_FRAMES: u64 = 220;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestSignal {
+    // level_db is relative to full scale, so 0.0 is the loudest possible
+    Sine {
+        frequency: f32,
+        level_db: f32,
+    },
+    // Logarithmic sweep, ends after duration_ms
+    Sweep {
+        start_frequency: f32,
+        end_frequency: f32,
+        duration_ms: u64,
+        level_db: f32,
+    },
+    WhiteNoise {
+        level_db: f32,
+    },
+    PinkNoise {
+        level_db: f32,
+    },
+    ChannelIdentification,
+    Silence,
+}
+
+pub struct SignalGenerator {
+    signal: TestSignal,
+    amplitude: f32,
+    // frame number, and the frame number to stop at
+    frame: u64,
+    end_frame: Option<u64>,
+    phase: f32,
+    frequency: f32,
+    sweep_ratio: f32,
+    random: u32,
+    pink: [f32; 3],
+}
+
+impl SignalGenerator {
+    // Signals other than sweeps go on forever unless given a duration
+    pub fn new(signal: TestSignal, duration_ms: Option<u64>) -> Self {
+        let (amplitude, frequency, sweep_ratio, signal_duration_ms) = match signal {
+            TestSignal::Sine {
+                frequency,
+                level_db,
+            } => (db_to_amplitude(level_db), frequency, 1.0, None),
+            TestSignal::Sweep {
+                start_frequency,
+                end_frequency,
+                duration_ms,
+                level_db,
+            } => {
+                let frames = ms_to_frames(duration_ms).max(1);
+                let ratio = (end_frequency / start_frequency).powf(1.0 / frames as f32);
+                (
+                    db_to_amplitude(level_db),
+                    start_frequency,
+                    ratio,
+                    Some(duration_ms),
+                )
+            }
+            TestSignal::WhiteNoise { level_db } | TestSignal::PinkNoise { level_db } => {
+                (db_to_amplitude(level_db), 0.0, 1.0, None)
+            }
+            TestSignal::ChannelIdentification => {
+                (db_to_amplitude(-12.0), IDENTIFICATION_FREQUENCY, 1.0, None)
+            }
+            TestSignal::Silence => (0.0, 0.0, 1.0, None),
+        };
+
+        SignalGenerator {
+            signal,
+            amplitude,
+            frame: 0,
+            end_frame: duration_ms.or(signal_duration_ms).map(ms_to_frames),
+            phase: 0.0,
+            frequency,
+            sweep_ratio,
+            random: 0x1234_5678,
+            pink: [0.0; 3],
+        }
+    }
+
+    // Jumps to the given position, e.g. to resume a long sweep
+    pub fn seek(&mut self, position_ms: u64) {
+        self.frame = ms_to_frames(position_ms);
+
+        if let TestSignal::Sweep {
+            start_frequency, ..
+        } = self.signal
+        {
+            self.frequency = start_frequency * self.sweep_ratio.powf(self.frame as f32);
+        }
+        // the phase of a sine doesn't matter, but keep it continuous from here on
+        self.phase = 0.0;
+    }
+
+    fn next_random(&mut self) -> f32 {
+        // xorshift32
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+
+        (self.random as i32) as f32 / 2147483648.0
+    }
+
+    fn next_sine(&mut self) -> f32 {
+        let value = self.phase.sin();
+
+        self.phase += 2.0 * PI * self.frequency / SAMPLE_RATE as f32;
+        if self.phase > 2.0 * PI {
+            self.phase -= 2.0 * PI;
+        }
+        value
+    }
+
+    // Returns the samples of the next frame, left and right
+    fn next_frame(&mut self) -> [f32; CHANNELS] {
+        let value = match self.signal {
+            TestSignal::Sine { .. } => self.next_sine(),
+            TestSignal::Sweep { .. } => {
+                let value = self.next_sine();
+                self.frequency *= self.sweep_ratio;
+                value
+            }
+            TestSignal::WhiteNoise { .. } => self.next_random(),
+            TestSignal::PinkNoise { .. } => {
+                // Paul Kellet's economy pink noise filter, scaled to stay roughly within -1..1
+                let white = self.next_random();
+                self.pink[0] = 0.99765 * self.pink[0] + white * 0.0990460;
+                self.pink[1] = 0.96300 * self.pink[1] + white * 0.2965164;
+                self.pink[2] = 0.57000 * self.pink[2] + white * 1.0526913;
+                (self.pink[0] + self.pink[1] + self.pink[2] + white * 0.1848) * 0.25
+            }
+            TestSignal::ChannelIdentification => return self.next_identification_frame(),
+            TestSignal::Silence => 0.0,
+        };
+
+        [value; CHANNELS]
+    }
+
+    fn next_identification_frame(&mut self) -> [f32; CHANNELS] {
+        let value = self.next_sine();
+        let position = self.frame % ms_to_frames(IDENTIFICATION_CYCLE_MS);
+        let mut frame = [0.0; CHANNELS];
+
+        for (start_ms, length_ms, channel) in IDENTIFICATION_BEEPS {
+            let start = ms_to_frames(start_ms);
+            let end = start + ms_to_frames(length_ms);
+
+            if (start..end).contains(&position) {
+                let fade = (position - start).min(end - 1 - position).min(FADE_FRAMES);
+                frame[channel] = value * fade as f32 / FADE_FRAMES as f32;
+            }
+        }
+
+        frame
+    }
+}
+
+impl Stream<i16> for SignalGenerator {
+    fn read(&mut self, buf: &mut [i16]) -> Result<usize> {
+        let mut count = 0;
+
+        for frame in buf.chunks_exact_mut(CHANNELS) {
+            if self.end_frame.is_some_and(|end| self.frame >= end) {
+                break;
+            }
+
+            let values = self.next_frame();
+            for (sample, value) in frame.iter_mut().zip(values) {
+                *sample = (value * self.amplitude).clamp(-32768.0, 32767.0) as i16;
+            }
+            self.frame += 1;
+            count += CHANNELS;
+        }
+
+        Ok(count)
+    }
+}
+
+// A run through of all the signals, useful when trying out a new headphone model
+pub fn diagnostic_sequence() -> Vec<(TestSignal, Option<u64>)> {
+    vec![
+        (TestSignal::ChannelIdentification, Some(12000)),
+        (
+            TestSignal::Sine {
+                frequency: 1000.0,
+                level_db: -12.0,
+            },
+            Some(5000),
+        ),
+        (
+            TestSignal::Sweep {
+                start_frequency: 20.0,
+                end_frequency: 20000.0,
+                duration_ms: 10000,
+                level_db: -12.0,
+            },
+            None,
+        ),
+        (TestSignal::PinkNoise { level_db: -12.0 }, Some(10000)),
+        (TestSignal::WhiteNoise { level_db: -18.0 }, Some(5000)),
+        (TestSignal::Silence, Some(2000)),
+    ]
+}
+
+fn db_to_amplitude(level_db: f32) -> f32 {
+    10f32.powf(level_db.min(0.0) / 20.0) * 32767.0
+}
+
+fn ms_to_frames(ms: u64) -> u64 {
+    ms * SAMPLE_RATE as u64 / 1000
+}
@@ -0,0 +1,224 @@
+// Output to an I2S DAC or amplifier, e.g. the MAX98357A of the kitchen units. Uses the legacy I2S
+// driver: 16 bit stereo at SAMPLE_RATE, Philips I2S format, which is what the MAX98357A expects.
+// i2s_write blocks until there is room in the DMA buffers, which paces the pipeline.
+
+use esp_idf_sys::{
+    esp, i2s_bits_per_sample_t_I2S_BITS_PER_SAMPLE_16BIT,
+    i2s_channel_fmt_t_I2S_CHANNEL_FMT_RIGHT_LEFT, i2s_channel_t_I2S_CHANNEL_STEREO,
+    i2s_comm_format_t_I2S_COMM_FORMAT_STAND_I2S, i2s_config_t, i2s_driver_install,
+    i2s_driver_uninstall, i2s_mode_t_I2S_MODE_MASTER, i2s_mode_t_I2S_MODE_TX, i2s_pin_config_t,
+    i2s_port_t, i2s_port_t_I2S_NUM_0, i2s_set_clk, i2s_set_pin, i2s_start, i2s_stop, i2s_write,
+    i2s_zero_dma_buffer, TickType_t, ESP_INTR_FLAG_LEVEL1,
+};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+
+use crate::{
+    audio::{CHANNELS, SAMPLE_RATE},
+    audio_sink::{AudioSink, PullThread},
+    bluetooth_hal::Stream,
+};
+
+// Limits of the legacy driver
+const MAX_DMA_FRAMES: usize = 1024;
+const MAX_DMA_BUFFERS: usize = 128;
+const I2S_PIN_NO_CHANGE: i32 = -1;
+
+// Enough to ride out an SD card read or a busy decoder, without making pause and skip sluggish
+const DEFAULT_LATENCY_MS: u32 = 100;
+
+#[derive(Debug, Clone, Copy)]
+pub struct I2sConfig {
+    pub port: i2s_port_t,
+    pub pin_bclk: i32,
+    pub pin_lrclk: i32,
+    pub pin_data: i32,
+    pub dma_buffers: usize,
+    // frames per DMA buffer
+    pub dma_frames: usize,
+}
+
+impl I2sConfig {
+    /* MAX98357A on the kitchen units' Lolin D32 Pro, clear of the SD card pins:
+    BCLK: IO26
+    LRC: IO25
+    DIN: IO22
+    */
+    pub fn max98357a() -> Self {
+        I2sConfig {
+            port: i2s_port_t_I2S_NUM_0,
+            pin_bclk: 26,
+            pin_lrclk: 25,
+            pin_data: 22,
+            dma_buffers: 0,
+            dma_frames: 0,
+        }
+        .with_latency(DEFAULT_LATENCY_MS)
+    }
+
+    // DMA buffers holding at least latency_ms of audio, in as few buffers as the driver allows
+    pub fn with_latency(mut self, latency_ms: u32) -> Self {
+        let frames = (SAMPLE_RATE * latency_ms / 1000) as usize;
+
+        self.dma_buffers = frames.div_ceil(MAX_DMA_FRAMES).clamp(2, MAX_DMA_BUFFERS);
+        self.dma_frames = frames.div_ceil(self.dma_buffers).clamp(8, MAX_DMA_FRAMES);
+        self
+    }
+
+    pub fn latency_ms(&self) -> u32 {
+        (self.dma_buffers * self.dma_frames * 1000) as u32 / SAMPLE_RATE
+    }
+}
+
+pub struct I2sSink {
+    config: I2sConfig,
+    installed: bool,
+    thread: Option<PullThread>,
+}
+
+impl I2sSink {
+    pub fn new(config: I2sConfig) -> Self {
+        I2sSink {
+            config,
+            installed: false,
+            thread: None,
+        }
+    }
+
+    fn install(&mut self) -> Result<()> {
+        let config = &self.config;
+        if config.dma_buffers < 2 || !(8..=MAX_DMA_FRAMES).contains(&config.dma_frames) {
+            bail!(
+                "Invalid I2S DMA buffers: {} of {} frames",
+                config.dma_buffers,
+                config.dma_frames
+            );
+        }
+
+        let driver_config = i2s_config_t {
+            mode: i2s_mode_t_I2S_MODE_MASTER | i2s_mode_t_I2S_MODE_TX,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: i2s_bits_per_sample_t_I2S_BITS_PER_SAMPLE_16BIT,
+            channel_format: i2s_channel_fmt_t_I2S_CHANNEL_FMT_RIGHT_LEFT,
+            communication_format: i2s_comm_format_t_I2S_COMM_FORMAT_STAND_I2S,
+            intr_alloc_flags: ESP_INTR_FLAG_LEVEL1 as i32,
+            dma_buf_count: config.dma_buffers as i32,
+            dma_buf_len: config.dma_frames as i32,
+            use_apll: true, // 44.1 kHz can't be divided from the 160 MHz clock exactly
+            tx_desc_auto_clear: true, // silence rather than repeating old samples on underrun
+            ..Default::default()
+        };
+        let pin_config = i2s_pin_config_t {
+            mck_io_num: I2S_PIN_NO_CHANGE,
+            bck_io_num: config.pin_bclk,
+            ws_io_num: config.pin_lrclk,
+            data_out_num: config.pin_data,
+            data_in_num: I2S_PIN_NO_CHANGE,
+        };
+
+        unsafe {
+            esp!(i2s_driver_install(
+                config.port,
+                &driver_config,
+                0,
+                std::ptr::null_mut()
+            ))?;
+            if let Err(e) = esp!(i2s_set_pin(config.port, &pin_config)) {
+                i2s_driver_uninstall(config.port);
+                bail!(e);
+            }
+        }
+        self.installed = true;
+
+        log::info!(
+            "I2S installed: {} DMA buffers of {} frames, {} ms",
+            config.dma_buffers,
+            config.dma_frames,
+            config.latency_ms()
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AudioSink for I2sSink {
+    fn name(&self) -> &'static str {
+        "I2S"
+    }
+
+    async fn start(&mut self, stream: Box<dyn Stream<i16>>) -> Result<()> {
+        self.stop().await?;
+
+        let port = self.config.port;
+        if !self.installed {
+            self.install()?;
+        }
+        unsafe {
+            // also restarts the clocks after i2s_stop
+            esp!(i2s_set_clk(
+                port,
+                SAMPLE_RATE,
+                i2s_bits_per_sample_t_I2S_BITS_PER_SAMPLE_16BIT,
+                i2s_channel_t_I2S_CHANNEL_STEREO
+            ))?;
+            esp!(i2s_start(port))?;
+        }
+
+        self.thread = Some(PullThread::spawn(
+            "i2s_sink",
+            stream,
+            self.config.dma_frames,
+            move |samples| {
+                let mut bytes_written = 0;
+                unsafe {
+                    esp!(i2s_write(
+                        port,
+                        samples.as_ptr() as *const _,
+                        samples.len() * 2,
+                        &mut bytes_written,
+                        TickType_t::MAX
+                    ))?;
+                }
+                if bytes_written != samples.len() * 2 {
+                    bail!("I2S wrote {} of {} bytes", bytes_written, samples.len() * 2);
+                }
+                Ok(())
+            },
+        )?);
+        log::info!(
+            "Playing to I2S at {} Hz, {} channels",
+            SAMPLE_RATE,
+            CHANNELS
+        );
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<Option<Box<dyn Stream<i16>>>> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(None);
+        };
+        let stream = thread.stop()?;
+
+        unsafe {
+            esp!(i2s_zero_dma_buffer(self.config.port))?;
+            esp!(i2s_stop(self.config.port))?;
+        }
+        Ok(Some(stream))
+    }
+}
+
+impl Drop for I2sSink {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if thread.stop().is_err() {
+                log::error!("I2S thread panicked");
+            }
+        }
+        if self.installed {
+            unsafe {
+                i2s_driver_uninstall(self.config.port);
+            }
+        }
+    }
+}
@@ -0,0 +1,310 @@
+// Level metering and spectrum analysis of what is sent to the Bluetooth layer, for displays and
+// dashboards.
+//
+// The tap sits last in the pipeline and only copies samples, and only when it can do so without
+// waiting: if the analysis thread holds the buffer, those samples are simply not measured. The
+// analysis runs on its own low priority thread and publishes snapshots which can be read at any
+// time through a LevelMeter.
+
+use std::{
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::{
+    audio::{CHANNELS, SAMPLE_RATE},
+    bluetooth_hal::Stream,
+    esp32,
+};
+
+// Below the Bluetooth and decoding tasks
+const METER_TASK_PRIORITY: u32 = 1;
+const UPDATE_INTERVAL_MS: u64 = 50;
+// A little more than one update interval, older samples are dropped
+const MAX_PENDING_SAMPLES: usize = 4096 * CHANNELS;
+
+// 1024 points gives 43 Hz resolution, enough for the lowest band
+const FFT_SIZE: usize = 1024;
+pub const SPECTRUM_BANDS: usize = 16;
+const SPECTRUM_LOW_HZ: f32 = 60.0;
+const SPECTRUM_HIGH_HZ: f32 = 16000.0;
+
+// What silence is reported as
+pub const MIN_DB: f32 = -96.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelSnapshot {
+    // dBFS per channel, over the last update interval
+    pub peak_db: [f32; CHANNELS],
+    pub rms_db: [f32; CHANNELS],
+    // dBFS per band, lowest band first; empty when the spectrum is turned off
+    pub spectrum_db: Vec<f32>,
+    // increases with every update, so readers can tell whether anything has changed
+    pub sequence: u64,
+}
+
+impl Default for LevelSnapshot {
+    fn default() -> Self {
+        Self {
+            peak_db: [MIN_DB; CHANNELS],
+            rms_db: [MIN_DB; CHANNELS],
+            spectrum_db: Vec::new(),
+            sequence: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LevelMeter {
+    snapshot: Arc<Mutex<LevelSnapshot>>,
+    spectrum_enabled: Arc<AtomicBool>,
+}
+
+impl LevelMeter {
+    pub fn snapshot(&self) -> LevelSnapshot {
+        self.snapshot
+            .lock()
+            .expect("Failed to lock level snapshot")
+            .clone()
+    }
+
+    // The spectrum costs some CPU, so it is off until something wants to show it
+    pub fn set_spectrum_enabled(&self, enabled: bool) {
+        self.spectrum_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    // Center frequencies of the spectrum bands
+    pub fn band_frequencies() -> [f32; SPECTRUM_BANDS] {
+        let mut frequencies = [0.0; SPECTRUM_BANDS];
+
+        for (band, frequency) in frequencies.iter_mut().enumerate() {
+            *frequency = (band_edge(band) * band_edge(band + 1)).sqrt();
+        }
+        frequencies
+    }
+}
+
+pub struct MeterTap {
+    source: Box<dyn Stream<i16>>,
+    pending: Arc<Mutex<Vec<i16>>>,
+    meter: LevelMeter,
+}
+
+impl MeterTap {
+    // Starts the analysis thread, which stops by itself when the tap is dropped
+    pub fn new(source: Box<dyn Stream<i16>>) -> Result<Self> {
+        let pending = Arc::new(Mutex::new(Vec::with_capacity(MAX_PENDING_SAMPLES)));
+        let meter = LevelMeter {
+            snapshot: Arc::new(Mutex::new(LevelSnapshot::default())),
+            spectrum_enabled: Arc::new(AtomicBool::new(false)),
+        };
+
+        let thread_pending = Arc::downgrade(&pending);
+        let thread_meter = meter.clone();
+        thread::Builder::new()
+            .name("meter".to_owned())
+            .stack_size(8192)
+            .spawn(move || analysis_thread(thread_pending, thread_meter))?;
+
+        Ok(MeterTap {
+            source,
+            pending,
+            meter,
+        })
+    }
+
+    pub fn meter(&self) -> LevelMeter {
+        self.meter.clone()
+    }
+}
+
+impl Stream<i16> for MeterTap {
+    fn read(&mut self, buf: &mut [i16]) -> Result<usize> {
+        let count = self.source.read(buf)?;
+
+        // never wait here, we are in the Bluetooth callback
+        if let Ok(mut pending) = self.pending.try_lock() {
+            let space = MAX_PENDING_SAMPLES - pending.len().min(MAX_PENDING_SAMPLES);
+            let copy = count.min(space) / CHANNELS * CHANNELS;
+            pending.extend_from_slice(&buf[..copy]);
+        }
+
+        Ok(count)
+    }
+}
+
+fn analysis_thread(pending: Weak<Mutex<Vec<i16>>>, meter: LevelMeter) {
+    esp32::set_current_thread_priority(METER_TASK_PRIORITY);
+
+    let mut samples = Vec::with_capacity(MAX_PENDING_SAMPLES);
+    let mut analyzer = SpectrumAnalyzer::new();
+    let mut sequence = 0;
+
+    log::info!("Level meter started");
+    loop {
+        thread::sleep(Duration::from_millis(UPDATE_INTERVAL_MS));
+
+        let Some(pending) = pending.upgrade() else {
+            break;
+        };
+        samples.clear();
+        std::mem::swap(
+            &mut samples,
+            &mut *pending.lock().expect("Failed to lock meter buffer"),
+        );
+        drop(pending);
+
+        let mut snapshot = measure_levels(&samples);
+        if meter.spectrum_enabled.load(Ordering::Relaxed) {
+            analyzer.push(&samples);
+            snapshot.spectrum_db = analyzer.spectrum_db();
+        }
+        sequence += 1;
+        snapshot.sequence = sequence;
+
+        *meter
+            .snapshot
+            .lock()
+            .expect("Failed to lock level snapshot") = snapshot;
+    }
+    log::info!("Level meter stopped");
+}
+
+fn measure_levels(samples: &[i16]) -> LevelSnapshot {
+    let mut peak = [0.0f32; CHANNELS];
+    let mut sum_of_squares = [0.0f32; CHANNELS];
+
+    for frame in samples.chunks_exact(CHANNELS) {
+        for (channel, sample) in frame.iter().enumerate() {
+            let value = *sample as f32 / 32768.0;
+            peak[channel] = peak[channel].max(value.abs());
+            sum_of_squares[channel] += value * value;
+        }
+    }
+
+    let frames = (samples.len() / CHANNELS).max(1) as f32;
+    LevelSnapshot {
+        peak_db: peak.map(amplitude_to_db),
+        rms_db: sum_of_squares.map(|sum| amplitude_to_db((sum / frames).sqrt())),
+        ..Default::default()
+    }
+}
+
+// Spectrum of the latest FFT_SIZE frames, mixed to mono
+struct SpectrumAnalyzer {
+    history: Vec<f32>,
+    window: Vec<f32>,
+    real: Vec<f32>,
+    imaginary: Vec<f32>,
+}
+
+impl SpectrumAnalyzer {
+    fn new() -> Self {
+        SpectrumAnalyzer {
+            history: vec![0.0; FFT_SIZE],
+            // Hann window
+            window: (0..FFT_SIZE)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos())
+                .collect(),
+            real: vec![0.0; FFT_SIZE],
+            imaginary: vec![0.0; FFT_SIZE],
+        }
+    }
+
+    fn push(&mut self, samples: &[i16]) {
+        let frames = samples.len() / CHANNELS;
+        let new_frames = frames.min(FFT_SIZE);
+
+        self.history.drain(..new_frames);
+        for frame in samples[(frames - new_frames) * CHANNELS..].chunks_exact(CHANNELS) {
+            let sum: f32 = frame.iter().map(|s| *s as f32).sum();
+            self.history.push(sum / (CHANNELS as f32 * 32768.0));
+        }
+    }
+
+    fn spectrum_db(&mut self) -> Vec<f32> {
+        for i in 0..FFT_SIZE {
+            self.real[i] = self.history[i] * self.window[i];
+            self.imaginary[i] = 0.0;
+        }
+        fft(&mut self.real, &mut self.imaginary);
+
+        // a full scale sine gives 0 dB in its band: the Hann window halves the amplitude
+        let scale = 4.0 / FFT_SIZE as f32;
+        let bin_hz = SAMPLE_RATE as f32 / FFT_SIZE as f32;
+
+        (0..SPECTRUM_BANDS)
+            .map(|band| {
+                let low = (band_edge(band) / bin_hz).round() as usize;
+                let high = ((band_edge(band + 1) / bin_hz).round() as usize).max(low + 1);
+
+                let peak = (low..high.min(FFT_SIZE / 2))
+                    .map(|bin| {
+                        (self.real[bin] * self.real[bin]
+                            + self.imaginary[bin] * self.imaginary[bin])
+                            .sqrt()
+                    })
+                    .fold(0.0f32, f32::max);
+                amplitude_to_db(peak * scale)
+            })
+            .collect()
+    }
+}
+
+// Logarithmically spaced, band_edge(0) is SPECTRUM_LOW_HZ and band_edge(SPECTRUM_BANDS) is
+// SPECTRUM_HIGH_HZ
+fn band_edge(index: usize) -> f32 {
+    SPECTRUM_LOW_HZ
+        * (SPECTRUM_HIGH_HZ / SPECTRUM_LOW_HZ).powf(index as f32 / SPECTRUM_BANDS as f32)
+}
+
+// In place iterative radix-2 FFT, the length must be a power of two
+fn fft(real: &mut [f32], imaginary: &mut [f32]) {
+    let n = real.len();
+
+    // bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            real.swap(i, j);
+            imaginary.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n {
+        let angle = -2.0 * PI / length as f32;
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + length / 2;
+                let t_real = real[b] * cos - imaginary[b] * sin;
+                let t_imaginary = real[b] * sin + imaginary[b] * cos;
+
+                real[b] = real[a] - t_real;
+                imaginary[b] = imaginary[a] - t_imaginary;
+                real[a] += t_real;
+                imaginary[a] += t_imaginary;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+fn amplitude_to_db(amplitude: f32) -> f32 {
+    (20.0 * amplitude.log10()).max(MIN_DB)
+}
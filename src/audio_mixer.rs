@@ -0,0 +1,216 @@
+// Last stage before the Bluetooth layer: overlays short prompt clips ("connected",
+// "battery low", ...) on the music, and turns the music down while a prompt is playing.
+//
+// The mixer is handed to the Bluetooth layer once, when the connection is up, and keeps playing
+// for as long as the connection lasts. Music streams come and go through the MixerControl, and
+// with no music the prompts are mixed with silence.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{Cursor, Read},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use crate::{
+    audio::{CHANNELS, SAMPLE_RATE},
+    bluetooth_hal::Stream,
+    wav,
+};
+
+// How far the music is turned down under a prompt
+const DUCK_GAIN_DB: f32 = -15.0;
+// 10 ms down, 300 ms back up
+const DUCK_ATTACK_FRAMES: usize = 441;
+const DUCK_RELEASE_FRAMES: usize = 13230;
+
+// Longer than any prompt needs to be, so a bad file can't eat all the memory
+const MAX_PROMPT_FRAMES: usize = 10 * SAMPLE_RATE as usize;
+
+// Interleaved stereo samples at SAMPLE_RATE, shared so that a prompt can be played many times
+// without copying it.
+#[derive(Clone)]
+pub struct PromptClip {
+    samples: Arc<Vec<i16>>,
+}
+
+impl PromptClip {
+    pub fn from_samples(samples: Vec<i16>) -> Self {
+        PromptClip {
+            samples: Arc::new(samples),
+        }
+    }
+
+    // Works both for files read from the SD card and for clips compiled into the firmware with
+    // include_bytes!.
+    pub fn from_wav(data: &[u8]) -> Result<Self> {
+        let mut reader = Cursor::new(data);
+        let format = wav::read_header(&mut reader)?;
+
+        let start = format.data_offset as usize;
+        let len = (format.data_len as usize)
+            .min(data.len() - start)
+            .min(MAX_PROMPT_FRAMES * format.frame_size());
+        let mut samples = Vec::new();
+        wav::convert_frames(&data[start..start + len], format.channels, &mut samples);
+
+        Ok(Self::from_samples(samples))
+    }
+
+    pub fn from_wav_file(path: &str) -> Result<Self> {
+        let mut data = Vec::new();
+        let limit = (1024 + MAX_PROMPT_FRAMES * CHANNELS * 2) as u64;
+
+        File::open(path)?.take(limit).read_to_end(&mut data)?;
+        Self::from_wav(&data)
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        (self.samples.len() / CHANNELS) as u64 * 1000 / SAMPLE_RATE as u64
+    }
+}
+
+struct MixerState {
+    music: Option<Box<dyn Stream<i16>>>,
+    // prompts play one after the other; the position is into the front clip
+    prompts: VecDeque<PromptClip>,
+    prompt_position: usize,
+}
+
+#[derive(Clone)]
+pub struct MixerControl {
+    state: Arc<Mutex<MixerState>>,
+}
+
+impl MixerControl {
+    pub fn set_music(&self, stream: Box<dyn Stream<i16>>) {
+        let old_stream = self
+            .state
+            .lock()
+            .expect("Failed to lock mixer")
+            .music
+            .replace(stream);
+
+        drop(old_stream); // outside the lock, dropping may have to wait for a decoding thread
+    }
+
+    pub fn clear_music(&self) {
+        let old_stream = self
+            .state
+            .lock()
+            .expect("Failed to lock mixer")
+            .music
+            .take();
+
+        drop(old_stream);
+    }
+
+    // The prompt starts as soon as the ones queued before it have finished
+    pub fn play_prompt(&self, clip: PromptClip) {
+        if clip.samples.len() < CHANNELS {
+            log::warn!("Mixer: ignoring empty prompt");
+            return;
+        }
+        log::info!("Mixer: queueing {} ms prompt", clip.duration_ms());
+        self.state
+            .lock()
+            .expect("Failed to lock mixer")
+            .prompts
+            .push_back(clip);
+    }
+}
+
+pub struct Mixer {
+    control: MixerControl,
+    duck_gain: f32,
+    duck_attack_step: f32,
+    duck_release_step: f32,
+    gain: f32,
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let duck_gain = 10f32.powf(DUCK_GAIN_DB / 20.0);
+
+        Mixer {
+            control: MixerControl {
+                state: Arc::new(Mutex::new(MixerState {
+                    music: None,
+                    prompts: VecDeque::new(),
+                    prompt_position: 0,
+                })),
+            },
+            duck_gain,
+            duck_attack_step: (1.0 - duck_gain) / DUCK_ATTACK_FRAMES as f32,
+            duck_release_step: (1.0 - duck_gain) / DUCK_RELEASE_FRAMES as f32,
+            gain: 1.0,
+        }
+    }
+
+    pub fn control(&self) -> MixerControl {
+        self.control.clone()
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream<i16> for Mixer {
+    fn read(&mut self, buf: &mut [i16]) -> Result<usize> {
+        let mut state = self.control.state.lock().expect("Failed to lock mixer");
+        let state = &mut *state;
+
+        let mut count = 0;
+        if let Some(music) = &mut state.music {
+            count = match music.read(buf) {
+                Ok(count) => count,
+                Err(e) => {
+                    log::error!("Mixer: error reading music: {}", e);
+                    0
+                }
+            };
+        }
+        buf[count..].fill(0);
+
+        for frame in buf.chunks_exact_mut(CHANNELS) {
+            let prompt = state.prompts.front();
+
+            // ramp the music gain down while there is a prompt, and back up afterwards
+            self.gain = if prompt.is_some() {
+                (self.gain - self.duck_attack_step).max(self.duck_gain)
+            } else {
+                (self.gain + self.duck_release_step).min(1.0)
+            };
+
+            let prompt_frame = prompt
+                .map(|clip| &clip.samples[state.prompt_position..state.prompt_position + CHANNELS]);
+
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut value = *sample as f32 * self.gain;
+                // the prompt only starts once the music is down
+                if let Some(prompt_frame) = prompt_frame {
+                    if self.gain <= self.duck_gain {
+                        value += prompt_frame[channel] as f32;
+                    }
+                }
+                *sample = value.clamp(-32768.0, 32767.0) as i16;
+            }
+
+            if prompt_frame.is_some() && self.gain <= self.duck_gain {
+                state.prompt_position += CHANNELS;
+                if state.prompt_position + CHANNELS > state.prompts[0].samples.len() {
+                    state.prompts.pop_front();
+                    state.prompt_position = 0;
+                }
+            }
+        }
+
+        // Always a full buffer, the mixer never ends
+        Ok(buf.len())
+    }
+}
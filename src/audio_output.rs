@@ -0,0 +1,121 @@
+// Which output plays, stored in NVS so that units without a Bluetooth speaker, like the kitchen
+// units with their I2S amplifier, don't go looking for one at every boot. The volume is kept
+// along with it, so that the player comes back as loud as it was left, and so is what to do when
+// the Bluetooth sink goes away.
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+
+use crate::audio_dsp::MAX_VOLUME;
+
+const NVS_NAMESPACE: &str = "output";
+const OUTPUT_KEY: &str = "output";
+const VOLUME_KEY: &str = "volume";
+const DISCONNECT_POLICY_KEY: &str = "disconnect";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Bluetooth,
+    I2s,
+}
+
+impl Output {
+    // Name of the AudioSink which plays this output
+    pub fn sink_name(&self) -> &'static str {
+        match self {
+            Output::Bluetooth => "A2DP",
+            Output::I2s => "I2S",
+        }
+    }
+}
+
+// What to do when the Bluetooth sink disconnects during playback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectPolicy {
+    // End playback, and let the state machine start over with a new search
+    Stop,
+    // Keep playing on I2S, and go back to Bluetooth when the sink reconnects; suits units with a
+    // speaker of their own
+    ContinueLocally,
+    // Hold the position, and carry on when the sink reconnects
+    PauseAndWait,
+}
+
+pub struct OutputStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl OutputStore {
+    pub fn new(partition: EspNvsPartition<NvsDefault>) -> Result<Self> {
+        Ok(OutputStore {
+            nvs: EspNvs::new(partition, NVS_NAMESPACE, true)?,
+        })
+    }
+
+    // Bluetooth unless something else has been chosen
+    pub fn load(&self) -> Result<Output> {
+        let mut buf = [0u8; 1];
+
+        match self.nvs.get_raw(OUTPUT_KEY, &mut buf)? {
+            Some([1]) => Ok(Output::I2s),
+            Some([0]) | None => Ok(Output::Bluetooth),
+            Some(data) => {
+                log::warn!("Unknown output {:?}, using Bluetooth", data);
+                Ok(Output::Bluetooth)
+            }
+        }
+    }
+
+    pub fn save(&mut self, output: Output) -> Result<()> {
+        let value = match output {
+            Output::Bluetooth => 0u8,
+            Output::I2s => 1,
+        };
+        self.nvs.set_raw(OUTPUT_KEY, &[value])?;
+        Ok(())
+    }
+
+    // In percent, None until one has been saved
+    pub fn load_volume(&self) -> Result<Option<u8>> {
+        let mut buf = [0u8; 1];
+
+        match self.nvs.get_raw(VOLUME_KEY, &mut buf)? {
+            Some([volume]) => Ok(Some((*volume).min(MAX_VOLUME))),
+            Some(data) => {
+                log::warn!("Bad volume {:?}, ignoring it", data);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_volume(&mut self, volume: u8) -> Result<()> {
+        self.nvs.set_raw(VOLUME_KEY, &[volume])?;
+        Ok(())
+    }
+
+    // PauseAndWait unless something else has been chosen
+    pub fn load_disconnect_policy(&self) -> Result<DisconnectPolicy> {
+        let mut buf = [0u8; 1];
+
+        match self.nvs.get_raw(DISCONNECT_POLICY_KEY, &mut buf)? {
+            Some([0]) => Ok(DisconnectPolicy::Stop),
+            Some([1]) => Ok(DisconnectPolicy::ContinueLocally),
+            Some([2]) | None => Ok(DisconnectPolicy::PauseAndWait),
+            Some(data) => {
+                log::warn!("Unknown disconnect policy {:?}, pausing", data);
+                Ok(DisconnectPolicy::PauseAndWait)
+            }
+        }
+    }
+
+    pub fn save_disconnect_policy(&mut self, policy: DisconnectPolicy) -> Result<()> {
+        let value = match policy {
+            DisconnectPolicy::Stop => 0u8,
+            DisconnectPolicy::ContinueLocally => 1,
+            DisconnectPolicy::PauseAndWait => 2,
+        };
+        self.nvs.set_raw(DISCONNECT_POLICY_KEY, &[value])?;
+        Ok(())
+    }
+}
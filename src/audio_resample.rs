@@ -0,0 +1,123 @@
+// Converts decoded audio to what the rest of the pipeline expects: stereo at SAMPLE_RATE.
+// Most files already are, and pass straight through. Others are mixed down to stereo and
+// resampled with linear interpolation, which is not hi-fi but cheap, and much better than
+// playing them at the wrong speed.
+
+use std::collections::VecDeque;
+
+use crate::audio::SAMPLE_RATE;
+
+// -3 dB, for mixing centre and surround channels into left and right
+const MIX_LEVEL: f32 = 0.707;
+
+pub struct FormatConverter {
+    channels: usize,
+    rate: u32,
+    // input frames per output frame
+    step: f64,
+    // position of the next output frame, in input frames after `previous`
+    position: f64,
+    previous: [f32; 2],
+    frame: Vec<[f32; 2]>,
+}
+
+impl FormatConverter {
+    pub fn new(channels: usize, rate: u32) -> Self {
+        FormatConverter {
+            channels: channels.max(1),
+            rate,
+            step: rate as f64 / SAMPLE_RATE as f64,
+            position: 1.0,
+            previous: [0.0; 2],
+            frame: Vec::new(),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    // input is interleaved with self.channels channels, the output interleaved stereo
+    pub fn convert(&mut self, input: &[i16], output: &mut VecDeque<i16>) {
+        if self.channels == 2 && self.rate == SAMPLE_RATE {
+            output.extend(input);
+            return;
+        }
+
+        self.frame.clear();
+        self.frame
+            .extend(input.chunks_exact(self.channels).map(downmix));
+
+        if self.rate == SAMPLE_RATE {
+            for frame in &self.frame {
+                output.extend(frame.map(|s| s as i16));
+            }
+            return;
+        }
+
+        // index 0 is the last frame of the previous call, index i the frame i - 1 of this one
+        let frames = self.frame.len() as f64;
+        while self.position <= frames {
+            let index = self.position as usize;
+            let fraction = (self.position - index as f64) as f32;
+            let a = if index == 0 {
+                self.previous
+            } else {
+                self.frame[index - 1]
+            };
+            let b = self.frame.get(index).copied().unwrap_or(a);
+
+            for channel in 0..2 {
+                let value = a[channel] + (b[channel] - a[channel]) * fraction;
+                output.push_back(value.clamp(-32768.0, 32767.0) as i16);
+            }
+            self.position += self.step;
+        }
+
+        if let Some(last) = self.frame.last() {
+            self.previous = *last;
+            self.position -= frames;
+        }
+    }
+}
+
+// Vorbis channel order, see https://xiph.org/vorbis/doc/Vorbis_I_spec.html section 4.3.9.
+// Levels are scaled so that the mix can't clip.
+fn downmix(frame: &[i16]) -> [f32; 2] {
+    let s = |index: usize| frame[index] as f32;
+
+    match frame.len() {
+        1 => [s(0), s(0)],
+        2 => [s(0), s(1)],
+        // left, centre, right
+        3 => {
+            let scale = 1.0 / (1.0 + MIX_LEVEL);
+            [
+                (s(0) + MIX_LEVEL * s(1)) * scale,
+                (s(2) + MIX_LEVEL * s(1)) * scale,
+            ]
+        }
+        // front left, front right, rear left, rear right
+        4 => {
+            let scale = 1.0 / (1.0 + MIX_LEVEL);
+            [
+                (s(0) + MIX_LEVEL * s(2)) * scale,
+                (s(1) + MIX_LEVEL * s(3)) * scale,
+            ]
+        }
+        // front left, centre, front right, rear left, rear right, and maybe LFE which is dropped
+        5 | 6 => {
+            let scale = 1.0 / (1.0 + 2.0 * MIX_LEVEL);
+            [
+                (s(0) + MIX_LEVEL * (s(1) + s(3))) * scale,
+                (s(2) + MIX_LEVEL * (s(1) + s(4))) * scale,
+            ]
+        }
+        // 7.1 and beyond are rare enough to just take the front left and right
+        _ => [s(0), s(2)],
+    }
+}
@@ -0,0 +1,261 @@
+// Outputs for the playback pipeline. A sink is given the stream to play and reads from it at its
+// own pace: the A2DP source when the Bluetooth stack asks for data, other sinks from a thread of
+// their own. When stopped, a sink hands the stream back, so that playback can carry on through
+// another sink where it left off.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+
+use crate::{
+    audio::{CHANNELS, SAMPLE_RATE},
+    bluetooth_hal::Stream,
+    wav::WavWriter,
+};
+
+// 10 ms at a time, like the A2DP source
+const PULL_FRAMES: usize = 441;
+
+#[async_trait]
+pub trait AudioSink: Send {
+    fn name(&self) -> &'static str;
+
+    // Starts playing the stream; returns once the sink is running
+    async fn start(&mut self, stream: Box<dyn Stream<i16>>) -> Result<()>;
+
+    // Stops reading and returns the stream, or None if the sink wasn't started
+    async fn stop(&mut self) -> Result<Option<Box<dyn Stream<i16>>>>;
+}
+
+// A thread which reads from a stream and passes the samples on to write, until stopped or until
+// the stream ends. The write function sets the pace, e.g. by blocking until there is room.
+pub struct PullThread {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Box<dyn Stream<i16>>>,
+}
+
+impl PullThread {
+    pub fn spawn<W>(
+        name: &str,
+        mut stream: Box<dyn Stream<i16>>,
+        block_frames: usize,
+        mut write: W,
+    ) -> Result<Self>
+    where
+        W: FnMut(&[i16]) -> Result<()> + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread_name = name.to_owned();
+
+        let thread = thread::Builder::new()
+            .name(thread_name.clone())
+            .stack_size(8192)
+            .spawn(move || {
+                let mut buf = vec![0i16; block_frames * CHANNELS];
+
+                while !thread_stop.load(Ordering::Relaxed) {
+                    let count = match stream.read(&mut buf) {
+                        Ok(count) => count,
+                        Err(e) => {
+                            log::error!("{}: error reading stream: {}", thread_name, e);
+                            0
+                        }
+                    };
+                    if count == 0 {
+                        log::info!("{}: end of stream", thread_name);
+                        break;
+                    }
+                    if let Err(e) = write(&buf[..count]) {
+                        log::error!("{}: error writing: {}", thread_name, e);
+                        break;
+                    }
+                }
+                stream
+            })?;
+
+        Ok(PullThread { stop, thread })
+    }
+
+    pub fn stop(self) -> Result<Box<dyn Stream<i16>>> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread
+            .join()
+            .map_err(|_| anyhow!("Audio sink thread panicked"))
+    }
+}
+
+// Records to a WAV file, e.g. to hear on a PC what the player would have played. As fast as the
+// pipeline can go, or in real time to behave like a real output.
+#[allow(dead_code)]
+pub struct WavFileSink {
+    path: String,
+    realtime: bool,
+    writer: Arc<Mutex<Option<WavWriter>>>,
+    thread: Option<PullThread>,
+}
+
+#[allow(dead_code)]
+impl WavFileSink {
+    pub fn new(path: &str, realtime: bool) -> Self {
+        WavFileSink {
+            path: path.to_owned(),
+            realtime,
+            writer: Arc::new(Mutex::new(None)),
+            thread: None,
+        }
+    }
+}
+
+#[async_trait]
+impl AudioSink for WavFileSink {
+    fn name(&self) -> &'static str {
+        "WAV file"
+    }
+
+    async fn start(&mut self, stream: Box<dyn Stream<i16>>) -> Result<()> {
+        self.stop().await?;
+        *self.writer.lock().expect("Failed to lock WAV writer") =
+            Some(WavWriter::create(&self.path)?);
+        log::info!("Recording to {}", self.path);
+
+        let writer = self.writer.clone();
+        let realtime = self.realtime;
+        let started = Instant::now();
+        let mut frames = 0u64;
+
+        self.thread = Some(PullThread::spawn(
+            "wav_sink",
+            stream,
+            PULL_FRAMES,
+            move |samples| {
+                if let Some(writer) = &mut *writer.lock().expect("Failed to lock WAV writer") {
+                    writer.write(samples)?;
+                }
+                frames += (samples.len() / CHANNELS) as u64;
+
+                if realtime {
+                    let due = Duration::from_micros(frames * 1_000_000 / SAMPLE_RATE as u64);
+                    if let Some(wait) = due.checked_sub(started.elapsed()) {
+                        thread::sleep(wait);
+                    }
+                }
+                Ok(())
+            },
+        )?);
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<Option<Box<dyn Stream<i16>>>> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(None);
+        };
+        let stream = thread.stop()?;
+
+        if let Some(writer) = self
+            .writer
+            .lock()
+            .expect("Failed to lock WAV writer")
+            .take()
+        {
+            writer.finish()?;
+        }
+        Ok(Some(stream))
+    }
+}
+
+// Plays through one of several sinks, and moves playback from one to another where it is. It can
+// also hold on to the stream without any sink playing it, which pauses everything before it.
+pub struct SinkSelector<'a> {
+    sinks: Vec<&'a mut dyn AudioSink>,
+    active: Option<usize>,
+    paused: Option<Box<dyn Stream<i16>>>,
+}
+
+impl<'a> SinkSelector<'a> {
+    pub fn new() -> Self {
+        SinkSelector {
+            sinks: Vec::new(),
+            active: None,
+            paused: None,
+        }
+    }
+
+    pub fn add(&mut self, sink: &'a mut dyn AudioSink) {
+        self.sinks.push(sink);
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.find(name).is_ok()
+    }
+
+    // Name of the sink playing, if any
+    pub fn active(&self) -> Option<&'static str> {
+        self.active.map(|index| self.sinks[index].name())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    pub async fn start(&mut self, name: &str, stream: Box<dyn Stream<i16>>) -> Result<()> {
+        self.stop().await?;
+
+        let index = self.find(name)?;
+        self.sinks[index].start(stream).await?;
+        self.active = Some(index);
+        log::info!("Playing to {}", name);
+        Ok(())
+    }
+
+    // Moves playback to the sink, also when paused. If starting the new sink fails, the stream is
+    // lost and nothing plays.
+    pub async fn select(&mut self, name: &str) -> Result<()> {
+        let index = self.find(name)?;
+        if self.active == Some(index) {
+            return Ok(());
+        }
+
+        match self.stop().await? {
+            Some(stream) => self.start(name, stream).await,
+            None => bail!("Nothing is playing"),
+        }
+    }
+
+    pub async fn pause(&mut self) -> Result<()> {
+        if let Some(index) = self.active.take() {
+            self.paused = self.sinks[index].stop().await?;
+            log::info!("Paused playback to {}", self.sinks[index].name());
+        }
+        Ok(())
+    }
+
+    // Returns the stream, also when paused
+    pub async fn stop(&mut self) -> Result<Option<Box<dyn Stream<i16>>>> {
+        match self.active.take() {
+            Some(index) => self.sinks[index].stop().await,
+            None => Ok(self.paused.take()),
+        }
+    }
+
+    fn find(&self, name: &str) -> Result<usize> {
+        match self.sinks.iter().position(|sink| sink.name() == name) {
+            Some(index) => Ok(index),
+            None => bail!("No audio sink {}", name),
+        }
+    }
+}
+
+impl Default for SinkSelector<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,256 @@
+// Playback speed change without pitch change, by WSOLA (waveform similarity overlap-add), roughly
+// as done by SoundTouch: the input is cut into overlapping sequences which are spaced further apart
+// (faster) or closer together (slower) than in the output. Each sequence is placed where its start
+// best matches the end of the previous one, and the overlaps are cross-faded.
+//
+// To stay within the ESP32's CPU budget the similarity search is done on a mono mix, decimated
+// and with a coarse step refined afterwards. At 1x the stream is passed through untouched.
+
+use std::{
+    cmp::min,
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use crate::{audio::CHANNELS, bluetooth_hal::Stream};
+
+pub const MIN_SPEED: f32 = 0.75;
+pub const MAX_SPEED: f32 = 2.0;
+
+// In frames at 44.1 kHz: 40 ms sequences, 8 ms overlap, searched over 15 ms
+const SEQUENCE_FRAMES: usize = 1764;
+const OVERLAP_FRAMES: usize = 352;
+const SEEK_FRAMES: usize = 660;
+// The search only looks at every SEARCH_DECIMATION:th frame of the overlap, and every
+// SEARCH_STEP:th offset before refining around the best one.
+const SEARCH_DECIMATION: usize = 4;
+const SEARCH_STEP: usize = 2;
+
+#[derive(Clone)]
+pub struct SpeedControl {
+    speed: Arc<Mutex<f32>>,
+}
+
+impl SpeedControl {
+    pub fn get(&self) -> f32 {
+        *self.speed.lock().expect("Failed to lock speed")
+    }
+
+    pub fn set(&self, speed: f32) {
+        let clamped = speed.clamp(MIN_SPEED, MAX_SPEED);
+
+        if clamped != speed {
+            log::warn!("Speed {} out of range, using {}", speed, clamped);
+        }
+        *self.speed.lock().expect("Failed to lock speed") = clamped;
+    }
+}
+
+pub struct TempoStream {
+    source: Box<dyn Stream<i16>>,
+    control: SpeedControl,
+    speed: f32,
+    // interleaved samples not yet consumed
+    input: Vec<i16>,
+    output: VecDeque<i16>,
+    // end of the previous sequence, to be cross-faded with the start of the next one
+    overlap: Vec<i16>,
+    have_overlap: bool,
+    // frames of input that were already played as part of the overlap
+    overlap_end: usize,
+    skip_fraction: f32,
+}
+
+impl TempoStream {
+    pub fn new(source: Box<dyn Stream<i16>>) -> Self {
+        TempoStream {
+            source,
+            control: SpeedControl {
+                speed: Arc::new(Mutex::new(1.0)),
+            },
+            speed: 1.0,
+            input: Vec::new(),
+            output: VecDeque::new(),
+            overlap: vec![0; OVERLAP_FRAMES * CHANNELS],
+            have_overlap: false,
+            overlap_end: 0,
+            skip_fraction: 0.0,
+        }
+    }
+
+    pub fn speed_control(&self) -> SpeedControl {
+        self.control.clone()
+    }
+
+    // Reads from the source until there is enough input for one sequence.
+    // Returns false if the source ended first.
+    fn fill_input(&mut self) -> Result<bool> {
+        let skip_frames = (self.speed * (SEQUENCE_FRAMES - OVERLAP_FRAMES) as f32) as usize + 1;
+        let needed = (SEEK_FRAMES + SEQUENCE_FRAMES).max(skip_frames) * CHANNELS;
+
+        while self.input.len() < needed {
+            let old_len = self.input.len();
+
+            self.input.resize(needed, 0);
+            let count = self.source.read(&mut self.input[old_len..])?;
+            self.input.truncate(old_len + count);
+
+            if count == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn process_sequence(&mut self) {
+        let offset = if self.have_overlap {
+            self.best_offset()
+        } else {
+            0
+        };
+        let start = offset * CHANNELS;
+        let overlap_len = OVERLAP_FRAMES * CHANNELS;
+
+        if self.have_overlap {
+            for i in 0..overlap_len {
+                let weight = (i / CHANNELS) as i32;
+                let faded = (self.overlap[i] as i32 * (OVERLAP_FRAMES as i32 - weight)
+                    + self.input[start + i] as i32 * weight)
+                    / OVERLAP_FRAMES as i32;
+                self.output.push_back(faded as i16);
+            }
+        } else {
+            self.output
+                .extend(&self.input[start..(start + overlap_len)]);
+        }
+
+        let overlap_start = start + (SEQUENCE_FRAMES - OVERLAP_FRAMES) * CHANNELS;
+        self.output
+            .extend(&self.input[(start + overlap_len)..overlap_start]);
+        self.overlap
+            .copy_from_slice(&self.input[overlap_start..(overlap_start + overlap_len)]);
+        self.have_overlap = true;
+
+        let skip = self.speed * (SEQUENCE_FRAMES - OVERLAP_FRAMES) as f32 + self.skip_fraction;
+        let skip_frames = skip as usize;
+        self.skip_fraction = skip - skip_frames as f32;
+        self.input
+            .drain(..min(skip_frames * CHANNELS, self.input.len()));
+        self.overlap_end = (offset + SEQUENCE_FRAMES).saturating_sub(skip_frames);
+    }
+
+    // Where in the search window the input best continues the previous sequence
+    fn best_offset(&self) -> usize {
+        let mut best_offset = 0;
+        let mut best_score = f32::MIN;
+
+        for offset in (0..SEEK_FRAMES).step_by(SEARCH_STEP) {
+            let score = self.similarity(offset);
+            if score > best_score {
+                best_score = score;
+                best_offset = offset;
+            }
+        }
+
+        let coarse_best = best_offset;
+        for offset in [coarse_best.saturating_sub(1), coarse_best + 1] {
+            if offset < SEEK_FRAMES && self.similarity(offset) > best_score {
+                best_score = self.similarity(offset);
+                best_offset = offset;
+            }
+        }
+
+        best_offset
+    }
+
+    // Normalized cross-correlation of the mono mixes
+    fn similarity(&self, offset: usize) -> f32 {
+        let mut correlation: i32 = 0;
+        let mut energy: i32 = 0;
+
+        for frame in (0..OVERLAP_FRAMES).step_by(SEARCH_DECIMATION) {
+            let a = mono(&self.overlap, frame);
+            let b = mono(&self.input, offset + frame);
+
+            // scaled down so that the sums can't overflow
+            correlation += (a * b) >> 8;
+            energy += (b * b) >> 8;
+        }
+
+        correlation as f32 / ((energy as f32).sqrt() + 1.0)
+    }
+
+    // At the end of the source, play what is left as it is
+    fn flush(&mut self) {
+        if self.have_overlap {
+            self.output.extend(&self.overlap);
+            self.input
+                .drain(..min(self.overlap_end * CHANNELS, self.input.len()));
+            self.have_overlap = false;
+        }
+        self.output.extend(self.input.drain(..));
+    }
+
+    fn take_output(&mut self, buf: &mut [i16]) -> usize {
+        let count = min(buf.len(), self.output.len());
+
+        for (dest, sample) in buf.iter_mut().zip(self.output.drain(..count)) {
+            *dest = sample;
+        }
+        count
+    }
+}
+
+fn mono(samples: &[i16], frame: usize) -> i32 {
+    (samples[frame * CHANNELS] as i32 + samples[frame * CHANNELS + 1] as i32) >> 1
+}
+
+impl Stream<i16> for TempoStream {
+    fn read(&mut self, buf: &mut [i16]) -> Result<usize> {
+        let speed = self.control.get();
+        if speed != self.speed {
+            log::info!("Changing speed from {} to {}", self.speed, speed);
+            if self.speed == 1.0 {
+                // starting time stretching: there is no previous sequence to fade from
+                self.have_overlap = false;
+                self.skip_fraction = 0.0;
+            } else if speed == 1.0 {
+                self.flush();
+            }
+            self.speed = speed;
+        }
+
+        let mut count = self.take_output(buf);
+
+        while count < buf.len() {
+            if self.speed == 1.0 {
+                // pass through, starting with any input left over from time stretching
+                if !self.input.is_empty() {
+                    self.output.extend(self.input.drain(..));
+                    count += self.take_output(&mut buf[count..]);
+                    continue;
+                }
+
+                let read = self.source.read(&mut buf[count..])?;
+                if read == 0 {
+                    break;
+                }
+                count += read;
+            } else {
+                if self.fill_input()? {
+                    self.process_sequence();
+                } else {
+                    self.flush();
+                    if self.output.is_empty() {
+                        break;
+                    }
+                }
+                count += self.take_output(&mut buf[count..]);
+            }
+        }
+
+        Ok(count)
+    }
+}
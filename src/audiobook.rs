@@ -0,0 +1,220 @@
+// Audiobook mode. Every folder below AUDIOBOOK_ROOT is one book, played in order (never shuffled),
+// with a bookmark stored in the book's folder on the SD card so playback resumes where it was left.
+// Chapters come from a CUE sheet in the folder if there is one, otherwise from the CHAPTERxx
+// Vorbis comments of each file, otherwise every file is a chapter.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::{cue_sheet::CueSheet, playlist::PlaylistPosition, vorbis_comments::VorbisComments};
+
+pub const AUDIOBOOK_ROOT: &str = "/sdcard/audiobooks";
+const BOOKMARK_FILENAME: &str = "bookmark.txt";
+
+// When resuming, go back a bit so the listener gets some context
+const RESUME_REWIND_MS: u64 = 5000;
+// Previous chapter within this time of the start of a chapter goes to the chapter before,
+// otherwise to the start of the current chapter.
+const RESTART_CHAPTER_THRESHOLD_MS: u64 = 3000;
+
+#[derive(Debug, Clone)]
+pub struct Chapter {
+    pub file_index: usize,
+    pub start_ms: u64,
+    pub title: Option<String>,
+}
+
+pub struct Audiobook {
+    folder: String,
+    files: Vec<String>,
+    chapters: Vec<Chapter>,
+}
+
+impl Audiobook {
+    pub fn is_audiobook_folder(folder: &str) -> bool {
+        let folder = Path::new(folder);
+
+        folder.starts_with(AUDIOBOOK_ROOT) && folder != Path::new(AUDIOBOOK_ROOT)
+    }
+
+    // files are the audio files of the book, in playback order
+    pub fn open(folder: &str, files: Vec<String>) -> Result<Self> {
+        let mut book = Audiobook {
+            folder: folder.to_string(),
+            files,
+            chapters: Vec::new(),
+        };
+
+        book.chapters = match book.find_cue_sheet() {
+            Some(cue_file) => book.chapters_from_cue_sheet(&cue_file)?,
+            None => book.chapters_from_comments(),
+        };
+        log::info!(
+            "Audiobook {} has {} files, {} chapters",
+            folder,
+            book.files.len(),
+            book.chapters.len()
+        );
+
+        Ok(book)
+    }
+
+    pub fn chapters(&self) -> &[Chapter] {
+        &self.chapters
+    }
+
+    // Where to start playing: a little before the bookmark, or the beginning of the book
+    pub fn resume_position(&self) -> PlaylistPosition {
+        match self.load_bookmark() {
+            Some(bookmark) => PlaylistPosition {
+                index: bookmark.index,
+                position_ms: bookmark.position_ms.saturating_sub(RESUME_REWIND_MS),
+            },
+            None => PlaylistPosition::default(),
+        }
+    }
+
+    pub fn load_bookmark(&self) -> Option<PlaylistPosition> {
+        let contents = match std::fs::read_to_string(self.bookmark_path()) {
+            Ok(contents) => contents,
+            Err(e) => {
+                log::info!("No bookmark for {}: {}", self.folder, e);
+                return None;
+            }
+        };
+
+        // The file name is stored rather than the index, so the bookmark survives files being added
+        let mut lines = contents.lines();
+        let filename = lines.next()?;
+        let position_ms = lines.next()?.trim().parse().ok()?;
+
+        match self.files.iter().position(|f| file_name(f) == filename) {
+            Some(index) => Some(PlaylistPosition { index, position_ms }),
+            None => {
+                log::warn!("Bookmarked file {} no longer in {}", filename, self.folder);
+                None
+            }
+        }
+    }
+
+    pub fn save_bookmark(&self, position: &PlaylistPosition) -> Result<()> {
+        let Some(file) = self.files.get(position.index) else {
+            anyhow::bail!("Bookmark index {} out of range", position.index);
+        };
+
+        std::fs::write(
+            self.bookmark_path(),
+            format!("{}\n{}\n", file_name(file), position.position_ms),
+        )?;
+        Ok(())
+    }
+
+    pub fn next_chapter(&self, position: &PlaylistPosition) -> Option<PlaylistPosition> {
+        self.chapters
+            .iter()
+            .find(|c| (c.file_index, c.start_ms) > (position.index, position.position_ms))
+            .map(Chapter::position)
+    }
+
+    pub fn previous_chapter(&self, position: &PlaylistPosition) -> Option<PlaylistPosition> {
+        let current = self
+            .chapters
+            .iter()
+            .rposition(|c| (c.file_index, c.start_ms) <= (position.index, position.position_ms))?;
+        let chapter = &self.chapters[current];
+
+        if current == 0
+            || position.index != chapter.file_index
+            || position.position_ms - chapter.start_ms > RESTART_CHAPTER_THRESHOLD_MS
+        {
+            Some(chapter.position())
+        } else {
+            Some(self.chapters[current - 1].position())
+        }
+    }
+
+    fn bookmark_path(&self) -> String {
+        format!("{}/{}", self.folder, BOOKMARK_FILENAME)
+    }
+
+    fn find_cue_sheet(&self) -> Option<String> {
+        let entries = std::fs::read_dir(&self.folder).ok()?;
+
+        entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+            })
+            .map(|path| path.to_string_lossy().into_owned())
+    }
+
+    fn chapters_from_cue_sheet(&self, cue_file: &str) -> Result<Vec<Chapter>> {
+        let sheet = CueSheet::read_file(cue_file)?;
+        let mut chapters = Vec::new();
+
+        for track in sheet.tracks {
+            match self.files.iter().position(|f| *f == track.file) {
+                Some(file_index) => chapters.push(Chapter {
+                    file_index,
+                    start_ms: track.start_ms,
+                    title: track.title,
+                }),
+                None => log::warn!("{}: no file {} in book", cue_file, track.file),
+            }
+        }
+        chapters.sort_by_key(|c| (c.file_index, c.start_ms));
+
+        Ok(chapters)
+    }
+
+    fn chapters_from_comments(&self) -> Vec<Chapter> {
+        let mut chapters = Vec::new();
+
+        for (file_index, file) in self.files.iter().enumerate() {
+            let file_chapters = match VorbisComments::read_file(file) {
+                Ok(comments) => comments.chapters(),
+                Err(e) => {
+                    log::warn!("Failed to read comments of {}: {}", file, e);
+                    Vec::new()
+                }
+            };
+
+            if file_chapters.is_empty() {
+                chapters.push(Chapter {
+                    file_index,
+                    start_ms: 0,
+                    title: None,
+                });
+            }
+
+            for chapter in file_chapters {
+                chapters.push(Chapter {
+                    file_index,
+                    start_ms: chapter.start_ms,
+                    title: chapter.title,
+                });
+            }
+        }
+
+        chapters
+    }
+}
+
+impl Chapter {
+    fn position(&self) -> PlaylistPosition {
+        PlaylistPosition {
+            index: self.file_index,
+            position_ms: self.start_ms,
+        }
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
@@ -1,4 +1,5 @@
-use std::ffi::CString;
+use std::collections::HashMap;
+use std::ffi::{c_void, CString};
 use std::sync::Arc;
 
 use async_trait::async_trait;
@@ -7,26 +8,38 @@ use esp_idf_sys::{
     esp_bt_controller_config_t, esp_bt_controller_enable, esp_bt_controller_init,
     esp_bt_controller_mem_release, esp_bt_dev_set_device_name,
     esp_bt_discovery_mode_t_ESP_BT_GENERAL_DISCOVERABLE, esp_bt_gap_cancel_discovery,
-    esp_bt_gap_register_callback, esp_bt_gap_set_scan_mode, esp_bt_gap_start_discovery,
-    esp_bt_inq_mode_t_ESP_BT_INQ_MODE_GENERAL_INQUIRY, esp_bt_mode_t,
-    esp_bt_mode_t_ESP_BT_MODE_BLE, esp_bt_mode_t_ESP_BT_MODE_BTDM,
-    esp_bt_mode_t_ESP_BT_MODE_CLASSIC_BT, BTDM_CTRL_AUTO_LATENCY_EFF, BTDM_CTRL_HLI,
-    BTDM_CTRL_LEGACY_AUTH_VENDOR_EVT_EFF, BT_HCI_UART_BAUDRATE_DEFAULT, BT_HCI_UART_NO_DEFAULT,
+    esp_bt_gap_get_bond_device_list, esp_bt_gap_get_bond_device_num,
+    esp_bt_gap_get_remote_service_record, esp_bt_gap_get_remote_services,
+    esp_bt_gap_register_callback, esp_bt_gap_set_pin, esp_bt_gap_set_scan_mode,
+    esp_bt_gap_set_security_param, esp_bt_gap_ssp_confirm_reply, esp_bt_gap_ssp_passkey_reply,
+    esp_bt_gap_start_discovery, esp_bt_inq_mode_t_ESP_BT_INQ_MODE_GENERAL_INQUIRY, esp_bt_io_cap_t,
+    esp_bt_mode_t, esp_bt_mode_t_ESP_BT_MODE_BLE, esp_bt_mode_t_ESP_BT_MODE_BTDM,
+    esp_bt_mode_t_ESP_BT_MODE_CLASSIC_BT, esp_bt_pin_code_t,
+    esp_bt_pin_type_t_ESP_BT_PIN_TYPE_VARIABLE, esp_bt_sp_param_t_ESP_BT_SP_IOCAP_MODE,
+    esp_bt_uuid_t, BTDM_CTRL_AUTO_LATENCY_EFF, BTDM_CTRL_HLI, BTDM_CTRL_LEGACY_AUTH_VENDOR_EVT_EFF,
+    BT_HCI_UART_BAUDRATE_DEFAULT, BT_HCI_UART_NO_DEFAULT,
     CONFIG_BTDM_BLE_SLEEP_CLOCK_ACCURACY_INDEX_EFF, CONFIG_BTDM_CTRL_BLE_MAX_CONN_EFF,
     CONFIG_BTDM_CTRL_BR_EDR_MAX_ACL_CONN_EFF, CONFIG_BTDM_CTRL_BR_EDR_MAX_SYNC_CONN_EFF,
     CONFIG_BTDM_CTRL_BR_EDR_SCO_DATA_PATH_EFF, CONFIG_BTDM_CTRL_PCM_POLAR_EFF,
     CONFIG_BTDM_CTRL_PCM_ROLE_EFF, CONTROLLER_ADV_LOST_DEBUG_BIT,
-    ESP_BT_CONTROLLER_CONFIG_MAGIC_VAL, ESP_TASK_BT_CONTROLLER_PRIO, ESP_TASK_BT_CONTROLLER_STACK,
+    ESP_BT_CONTROLLER_CONFIG_MAGIC_VAL, ESP_BT_IO_CAP_IN, ESP_BT_IO_CAP_IO, ESP_BT_IO_CAP_NONE,
+    ESP_BT_IO_CAP_OUT, ESP_TASK_BT_CONTROLLER_PRIO, ESP_TASK_BT_CONTROLLER_STACK,
     MESH_DUPLICATE_SCAN_CACHE_SIZE, NORMAL_SCAN_DUPLICATE_CACHE_SIZE, SCAN_DUPLICATE_MODE,
     SCAN_DUPLICATE_TYPE_VALUE, SCAN_DUPL_CACHE_REFRESH_PERIOD, SCAN_SEND_ADV_RESERVED_SIZE,
 };
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use lazy_static::lazy_static;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
+use crate::audio::NowPlaying;
+use crate::audio_sink::AudioSink;
 use crate::bluetooth_esp32_a2dp::ESP32A2DP;
+use crate::bluetooth_esp32_avrcp::{NowPlayingNotifier, ESP32AVRCP};
 use crate::bluetooth_gap_esp32::bt_app_gap_cb;
-use crate::bluetooth_gap_hal::ScannedDevice;
+use crate::bluetooth_gap_hal::{
+    Authentication, IoCapability, PairingConfig, PairingEvent, ScannedDevice, ServiceUuid,
+};
 use crate::bluetooth_hal::AsyncCall;
 use crate::bluetooth_hal::*;
 use crate::esp32::Esp32;
@@ -39,15 +52,44 @@ pub struct Esp32BluetoothGlobals {
         )>,
     >,
     pub connection: Arc<AsyncCall<Result<()>>>,
+    pub remote_services: AsyncCall<Result<Vec<ServiceUuid>>>,
+    pub remote_service_record: AsyncCall<Result<bool>>,
+    pub pairing: std::sync::Mutex<PairingConfig>,
+    pub pairing_events: std::sync::Mutex<Option<UnboundedSender<PairingEvent>>>,
+    pub authentications: std::sync::Mutex<HashMap<BDAddr, Authentication>>,
+    pub events: std::sync::Mutex<Option<UnboundedSender<Result<BluetoothEvent>>>>,
 }
 
 lazy_static! {
     pub static ref ESP32_BLUETOOTH_GLOBALS: Esp32BluetoothGlobals = Esp32BluetoothGlobals {
         discovery: std::sync::Mutex::new(None),
-        connection: Arc::new(AsyncCall::<Result<()>>::new())
+        connection: Arc::new(AsyncCall::<Result<()>>::new()),
+        remote_services: AsyncCall::new(),
+        remote_service_record: AsyncCall::new(),
+        pairing: std::sync::Mutex::new(PairingConfig::default()),
+        pairing_events: std::sync::Mutex::new(None),
+        authentications: std::sync::Mutex::new(HashMap::new()),
+        events: std::sync::Mutex::new(None),
     };
 }
 
+// Called from the stack's callbacks, which must not panic or block
+pub fn send_event(event: Result<BluetoothEvent>) {
+    if let Err(e) = &event {
+        log::error!("Bluetooth: {}", e);
+    }
+
+    match ESP32_BLUETOOTH_GLOBALS.events.lock() {
+        Ok(sender) => {
+            if let Some(sender) = sender.as_ref() {
+                // the receiver may have been dropped, nothing to do about it
+                let _ = sender.unbounded_send(event);
+            }
+        }
+        Err(e) => log::error!("Failed to lock Bluetooth events: {}", e),
+    }
+}
+
 pub struct ESP32Bluetooth {
     classic: bool,
     low_energy: bool,
@@ -145,6 +187,8 @@ impl<'a> Bluetooth<'a> for ESP32Bluetooth {
             ))?;
         };
 
+        ESP32AVRCP::init()?;
+
         let a2dp = crate::bluetooth_esp32_a2dp::A2DP.lock().unwrap();
 
         a2dp.init()?; // should await / block on?
@@ -154,6 +198,16 @@ impl<'a> Bluetooth<'a> for ESP32Bluetooth {
         Ok(())
     }
 
+    fn events(&self) -> Result<UnboundedReceiver<Result<BluetoothEvent>>> {
+        let (sender, receiver) = mpsc::unbounded();
+        ESP32_BLUETOOTH_GLOBALS
+            .events
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock: {e}"))?
+            .replace(sender);
+        Ok(receiver)
+    }
+
     fn gap_start_discovery(&self) -> Result<async_broadcast::Receiver<ScannedDevice>> {
         let mut discovery_lock = ESP32_BLUETOOTH_GLOBALS
             .discovery
@@ -196,12 +250,118 @@ impl<'a> Bluetooth<'a> for ESP32Bluetooth {
         }
     }
 
+    fn gap_bonded_devices(&self) -> Result<Vec<BDAddr>> {
+        let mut count = unsafe { esp_bt_gap_get_bond_device_num() };
+        if count < 0 {
+            bail!("Failed to get the number of bonded devices");
+        }
+
+        let mut devices = vec![BDAddr::default(); count as usize];
+        unsafe {
+            esp!(esp_bt_gap_get_bond_device_list(
+                &mut count,
+                devices.as_mut_ptr()
+            ))?;
+        }
+        devices.truncate(count as usize);
+
+        Ok(devices)
+    }
+
+    fn gap_set_pairing(&mut self, config: PairingConfig) -> Result<()> {
+        config.validate()?;
+
+        let mut io_capability = match config.io_capability {
+            IoCapability::DisplayOnly => ESP_BT_IO_CAP_OUT,
+            IoCapability::DisplayYesNo => ESP_BT_IO_CAP_IO,
+            IoCapability::KeyboardOnly => ESP_BT_IO_CAP_IN,
+            IoCapability::NoInputNoOutput => ESP_BT_IO_CAP_NONE,
+        } as esp_bt_io_cap_t;
+        // the PIN is given when a legacy sink asks for it, see bt_app_gap_cb
+        let mut pin_code: esp_bt_pin_code_t = [0; 16];
+        unsafe {
+            esp!(esp_bt_gap_set_security_param(
+                esp_bt_sp_param_t_ESP_BT_SP_IOCAP_MODE,
+                &mut io_capability as *mut esp_bt_io_cap_t as *mut c_void,
+                std::mem::size_of::<esp_bt_io_cap_t>() as u8
+            ))?;
+            esp!(esp_bt_gap_set_pin(
+                esp_bt_pin_type_t_ESP_BT_PIN_TYPE_VARIABLE,
+                0,
+                pin_code.as_mut_ptr()
+            ))?;
+        }
+
+        log::info!("Pairing with IO capability {:?}", config.io_capability);
+        *ESP32_BLUETOOTH_GLOBALS
+            .pairing
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock: {e}"))? = config;
+        Ok(())
+    }
+
+    fn gap_pairing_events(&self) -> Result<UnboundedReceiver<PairingEvent>> {
+        let (sender, receiver) = mpsc::unbounded();
+        ESP32_BLUETOOTH_GLOBALS
+            .pairing_events
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock: {e}"))?
+            .replace(sender);
+        Ok(receiver)
+    }
+
+    fn gap_confirm_pairing(&self, addr: &BDAddr, accept: bool) -> Result<()> {
+        Self::gap_answer_confirmation(addr, accept)
+    }
+
+    fn gap_enter_passkey(&self, addr: &BDAddr, passkey: Option<u32>) -> Result<()> {
+        Self::gap_answer_passkey(addr, passkey)
+    }
+
+    fn gap_authentication(&self, addr: &BDAddr) -> Option<Authentication> {
+        ESP32_BLUETOOTH_GLOBALS
+            .authentications
+            .lock()
+            .ok()?
+            .get(addr)
+            .cloned()
+    }
+
+    async fn gap_get_remote_services(&self, addr: &BDAddr) -> Result<Vec<ServiceUuid>> {
+        let mut address = *addr;
+        let call = &ESP32_BLUETOOTH_GLOBALS.remote_services;
+        call.do_and_wait(|| unsafe {
+            // no event will come, so complete the call ourselves
+            if let Err(e) = esp!(esp_bt_gap_get_remote_services(address.as_mut_ptr())) {
+                call.complete(Err(anyhow::anyhow!("Failed to get remote services: {e}")));
+            }
+        })
+        .await
+    }
+
+    async fn gap_has_remote_service(&self, addr: &BDAddr, uuid: ServiceUuid) -> Result<bool> {
+        let mut address = *addr;
+        let mut uuid = esp_uuid(uuid);
+        let call = &ESP32_BLUETOOTH_GLOBALS.remote_service_record;
+        call.do_and_wait(|| unsafe {
+            if let Err(e) = esp!(esp_bt_gap_get_remote_service_record(
+                address.as_mut_ptr(),
+                &mut uuid
+            )) {
+                call.complete(Err(anyhow::anyhow!(
+                    "Failed to get remote service record: {e}"
+                )));
+            }
+        })
+        .await
+    }
+
     async fn a2dp_connect(&mut self, addr: &BDAddr) -> Result<()> {
         ESP32A2DP::connect(addr).await
     }
 
-    async fn a2dp_play(&mut self, stream: Box<dyn Stream<i16>>) -> Result<()> {
-        ESP32A2DP::play(stream).await
+    fn avrcp_commands(&self) -> Result<UnboundedReceiver<RemoteCommand>> {
+        Ok(ESP32AVRCP::commands())
     }
 
     fn deinit(&mut self) -> Result<()> {
@@ -209,6 +369,22 @@ impl<'a> Bluetooth<'a> for ESP32Bluetooth {
     }
 }
 
+// Plays to the connected A2DP sink, which asks for data when it needs it
+#[async_trait]
+impl AudioSink for ESP32Bluetooth {
+    fn name(&self) -> &'static str {
+        "A2DP"
+    }
+
+    async fn start(&mut self, stream: Box<dyn Stream<i16>>) -> Result<()> {
+        ESP32A2DP::play(stream).await
+    }
+
+    async fn stop(&mut self) -> Result<Option<Box<dyn Stream<i16>>>> {
+        ESP32A2DP::stop().await
+    }
+}
+
 impl ESP32Bluetooth {
     pub fn new(classic: bool, low_energy: bool) -> Self {
         ESP32Bluetooth {
@@ -217,6 +393,52 @@ impl ESP32Bluetooth {
         }
     }
 
+    // Not a method, so that it can be waited for while the Bluetooth sink is playing
+    pub async fn a2dp_wait_for_connection(connected: bool) {
+        ESP32A2DP::wait_for_connection(connected).await
+    }
+
+    // Connects to a sink which went away, also while playback holds the Bluetooth sink. Not a
+    // method for the same reason.
+    pub async fn a2dp_reconnect(addr: &BDAddr) -> Result<()> {
+        ESP32A2DP::connect(addr).await
+    }
+
+    // gap_confirm_pairing, for answering while a2dp_connect sets up the connection that needs
+    // the pairing. Not a method for the same reason.
+    pub fn gap_answer_confirmation(addr: &BDAddr, accept: bool) -> Result<()> {
+        let mut address = *addr;
+        unsafe {
+            esp!(esp_bt_gap_ssp_confirm_reply(address.as_mut_ptr(), accept))?;
+        }
+        Ok(())
+    }
+
+    // gap_enter_passkey, not a method for the same reason
+    pub fn gap_answer_passkey(addr: &BDAddr, passkey: Option<u32>) -> Result<()> {
+        let mut address = *addr;
+        unsafe {
+            esp!(esp_bt_gap_ssp_passkey_reply(
+                address.as_mut_ptr(),
+                passkey.is_some(),
+                passkey.unwrap_or(0)
+            ))?;
+        }
+        Ok(())
+    }
+
+    // The player's volume in percent, passed on to sinks which support absolute volume. Not a
+    // method for the same reason.
+    pub fn avrcp_set_volume(volume: u8) -> Result<()> {
+        ESP32AVRCP::set_volume(volume)
+    }
+
+    // Tells controllers connected to our AVRCP target what is playing, until the notifier is
+    // dropped
+    pub fn avrcp_follow(now_playing: &NowPlaying) -> Result<NowPlayingNotifier> {
+        ESP32AVRCP::follow(now_playing)
+    }
+
     pub fn pre_init(&self, esp32: &mut Esp32) -> Result<()> {
         // Initialize NVS.
         // All examples do this, but I have yet to see the docs that say that this needs to be done
@@ -232,3 +454,26 @@ impl ESP32Bluetooth {
         Ok(())
     }
 }
+
+fn esp_uuid(uuid: ServiceUuid) -> esp_bt_uuid_t {
+    let mut result = esp_bt_uuid_t::default();
+    match uuid {
+        ServiceUuid::Known(known) => {
+            result.len = 2;
+            result.uuid.uuid16 = known as u16;
+        }
+        ServiceUuid::Uuid16(value) => {
+            result.len = 2;
+            result.uuid.uuid16 = value;
+        }
+        ServiceUuid::Uuid32(value) => {
+            result.len = 4;
+            result.uuid.uuid32 = value;
+        }
+        ServiceUuid::Uuid128(value) => {
+            result.len = 16;
+            result.uuid.uuid128 = value;
+        }
+    }
+    result
+}
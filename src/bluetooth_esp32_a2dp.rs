@@ -1,12 +1,13 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 
 use futures::executor::block_on;
 use lazy_static::lazy_static;
 
 use esp_idf_sys::{
-    esp, esp_a2d_cb_event_t, esp_a2d_cb_event_t_ESP_A2D_AUDIO_CFG_EVT,
-    esp_a2d_cb_event_t_ESP_A2D_AUDIO_STATE_EVT, esp_a2d_cb_event_t_ESP_A2D_CONNECTION_STATE_EVT,
-    esp_a2d_cb_event_t_ESP_A2D_MEDIA_CTRL_ACK_EVT, esp_a2d_cb_event_t_ESP_A2D_PROF_STATE_EVT,
+    esp, esp_a2d_audio_state_t_ESP_A2D_AUDIO_STATE_STARTED, esp_a2d_cb_event_t,
+    esp_a2d_cb_event_t_ESP_A2D_AUDIO_CFG_EVT, esp_a2d_cb_event_t_ESP_A2D_AUDIO_STATE_EVT,
+    esp_a2d_cb_event_t_ESP_A2D_CONNECTION_STATE_EVT, esp_a2d_cb_event_t_ESP_A2D_MEDIA_CTRL_ACK_EVT,
+    esp_a2d_cb_event_t_ESP_A2D_PROF_STATE_EVT,
     /* esp_a2d_cb_event_t_ESP_A2D_SNK_PSC_CFG_EVT,
     esp_a2d_cb_event_t_ESP_A2D_SNK_GET_DELAY_VALUE_EVT,
     esp_a2d_cb_event_t_ESP_A2D_SNK_SET_DELAY_VALUE_EVT */
@@ -15,16 +16,19 @@ use esp_idf_sys::{
     esp_a2d_connection_state_t_ESP_A2D_CONNECTION_STATE_CONNECTING,
     esp_a2d_connection_state_t_ESP_A2D_CONNECTION_STATE_DISCONNECTED,
     esp_a2d_connection_state_t_ESP_A2D_CONNECTION_STATE_DISCONNECTING, esp_a2d_disc_rsn_t,
-    esp_a2d_disc_rsn_t_ESP_A2D_DISC_RSN_NORMAL, esp_a2d_media_ctrl,
-    esp_a2d_media_ctrl_ack_t_ESP_A2D_MEDIA_CTRL_ACK_SUCCESS,
-    esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_CHECK_SRC_RDY,
-    esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_START, esp_a2d_register_callback,
-    /* esp_a2d_cb_event_t, */ esp_a2d_source_connect, esp_a2d_source_init,
-    esp_a2d_source_register_data_callback,
+    esp_a2d_disc_rsn_t_ESP_A2D_DISC_RSN_NORMAL, esp_a2d_init_state_t_ESP_A2D_INIT_SUCCESS,
+    esp_a2d_media_ctrl, esp_a2d_media_ctrl_ack_t_ESP_A2D_MEDIA_CTRL_ACK_SUCCESS,
+    esp_a2d_media_ctrl_t, esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_CHECK_SRC_RDY,
+    esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_START, esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_SUSPEND,
+    esp_a2d_register_callback, /* esp_a2d_cb_event_t, */
+    esp_a2d_source_connect, esp_a2d_source_init, esp_a2d_source_register_data_callback,
 };
 
+use crate::bluetooth_esp32::send_event;
 use crate::bluetooth_gap_hal::ScannedDevice;
-use crate::bluetooth_hal::{AsyncCall, BDAddr, Stream};
+use crate::bluetooth_hal::{
+    A2dpConnectionState, AsyncCall, BDAddr, BluetoothEvent, MediaControl, Stream,
+};
 
 pub struct ESP32A2DP {}
 
@@ -134,7 +138,25 @@ impl ESP32A2DP {
                 Err(anyhow::anyhow!(result.disconnect_reason))
             }
 
-            _ => panic!("Invalid state"),
+            state => bail!("Invalid A2DP connection state {}", state),
+        }
+    }
+
+    // Waits until the sink is connected, or until it isn't, e.g. when headphones are switched off
+    // or walk out of range
+    pub async fn wait_for_connection(connected: bool) {
+        loop {
+            let listener = CONNECTION_STATE_EVENT.listen();
+            let state = CONNECTION_STATE
+                .lock()
+                .expect("Failed to lock connection state")
+                .state;
+
+            if (state == esp_a2d_connection_state_t_ESP_A2D_CONNECTION_STATE_CONNECTED) == connected
+            {
+                return;
+            }
+            listener.await;
         }
     }
 
@@ -149,10 +171,12 @@ impl ESP32A2DP {
 
         SRC_READY_CALL
             .do_and_wait(|| unsafe {
-                esp!(esp_a2d_media_ctrl(
+                // no acknowledgement will come, so complete the call ourselves
+                if let Err(e) = esp!(esp_a2d_media_ctrl(
                     esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_CHECK_SRC_RDY
-                ))
-                .expect("CHECK_SRC_READY failed");
+                )) {
+                    SRC_READY_CALL.complete(Err(anyhow!("CHECK_SRC_READY failed: {}", e)));
+                }
             })
             .await?;
 
@@ -167,50 +191,85 @@ impl ESP32A2DP {
         // somehow wait until playback is finished
         Ok(())
     }
+
+    // Suspends the media stream and takes back the stream that was being played
+    pub async fn stop() -> Result<Option<Box<dyn Stream<i16>>>> {
+        unsafe {
+            if let Err(e) = esp!(esp_a2d_media_ctrl(
+                esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_SUSPEND
+            )) {
+                // e.g. when the sink has already disconnected
+                log::warn!("Failed to suspend media: {}", e);
+            }
+        }
+
+        Ok(PLAY_STATE.lock().await.stream.take())
+    }
     extern "C" fn bt_app_a2d_cb(event: esp_a2d_cb_event_t, param: *mut esp_a2d_cb_param_t) {
         #[allow(non_upper_case_globals)]
         match event {
             // connection state changed event
-            esp_a2d_cb_event_t_ESP_A2D_CONNECTION_STATE_EVT => {
-                let me = A2DP.lock().unwrap();
-                me.process_connection_state_evt(param)
-            }
+            esp_a2d_cb_event_t_ESP_A2D_CONNECTION_STATE_EVT => match A2DP.lock() {
+                Ok(me) => me.process_connection_state_evt(param),
+                Err(e) => log::error!("Failed to lock A2DP: {}", e),
+            },
+            // audio stream transmission state changed event
             esp_a2d_cb_event_t_ESP_A2D_AUDIO_STATE_EVT => unsafe {
+                let audio_state = &(*param).audio_stat;
+                log::info!("Got ESP_A2D_AUDIO_STATE_EVT, state={}", audio_state.state);
+                send_event(Ok(BluetoothEvent::A2dpAudioState {
+                    address: audio_state.remote_bda,
+                    started: audio_state.state == esp_a2d_audio_state_t_ESP_A2D_AUDIO_STATE_STARTED,
+                }));
+            },
+            // used only for sink
+            esp_a2d_cb_event_t_ESP_A2D_AUDIO_CFG_EVT => unsafe {
+                log::warn!("Got ESP_A2D_AUDIO_CFG_EVT as a source");
+                send_event(Ok(BluetoothEvent::A2dpAudioConfig {
+                    address: (*param).audio_cfg.remote_bda,
+                }));
+            },
+            // acknowledge event in response to media control commands
+            esp_a2d_cb_event_t_ESP_A2D_MEDIA_CTRL_ACK_EVT => unsafe {
+                let stat = &(*param).media_ctrl_stat;
+                let success =
+                    stat.status == esp_a2d_media_ctrl_ack_t_ESP_A2D_MEDIA_CTRL_ACK_SUCCESS;
                 log::info!(
-                    "Got ESP_A2D_AUDIO_STATE_EVT, state={}",
-                    &(*param).audio_stat.state
+                    "Got ESP_A2D_MEDIA_CTRL_ACK_EVT, command {}, status = {}",
+                    stat.cmd,
+                    stat.status
                 );
-            }, // audio stream transmission state changed event
-            esp_a2d_cb_event_t_ESP_A2D_AUDIO_CFG_EVT => todo!(), // used only for sink
-            esp_a2d_cb_event_t_ESP_A2D_MEDIA_CTRL_ACK_EVT => {
-                log::info!("Got ESP_A2D_MEDIA_CTRL_ACK_EVT");
-
-                unsafe {
-                    let stat = &(*param).media_ctrl_stat;
-                    match stat.cmd {
-                        esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_CHECK_SRC_RDY => {
-                            #[allow(non_upper_case_globals)]
-                            let result = match stat.status {
-                                esp_a2d_media_ctrl_ack_t_ESP_A2D_MEDIA_CTRL_ACK_SUCCESS => Ok(()),
-                                _ => Err(anyhow::anyhow!(format!("Media error {}", stat.status))),
-                            };
-                            log::info!("Got ESP_A2D_MEDIA_CTRL_ACK_EVT: calling complete");
-                            SRC_READY_CALL.complete(result);
-                            log::info!("Got ESP_A2D_MEDIA_CTRL_ACK_EVT: after complete");
-                        }
-                        esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_START => {
-                            log::info!("Media start ACK, status = {}", stat.status);
+
+                match media_control(stat.cmd) {
+                    Some(command) => {
+                        if command == MediaControl::CheckSourceReady {
+                            SRC_READY_CALL.complete(if success {
+                                Ok(())
+                            } else {
+                                Err(anyhow!("Media error {}", stat.status))
+                            });
                         }
-                        _ => todo!(),
+                        send_event(Ok(BluetoothEvent::A2dpMediaControlAck { command, success }));
                     }
+                    None => send_event(Err(anyhow!(
+                        "Acknowledgement of unknown media control {}",
+                        stat.cmd
+                    ))),
                 }
-            } // acknowledge event in response to media control commands
-            esp_a2d_cb_event_t_ESP_A2D_PROF_STATE_EVT => todo!(), // indicate a2dp init&deinit complete
+            },
+            // indicate a2dp init&deinit complete
+            esp_a2d_cb_event_t_ESP_A2D_PROF_STATE_EVT => unsafe {
+                let init_state = (*param).a2d_prof_stat.init_state;
+                log::info!("A2DP profile state {}", init_state);
+                send_event(Ok(BluetoothEvent::A2dpProfileState {
+                    initialised: init_state == esp_a2d_init_state_t_ESP_A2D_INIT_SUCCESS,
+                }));
+            },
             // esp_a2d_cb_event_t_ESP_A2D_SNK_PSC_CFG_EVT => todo!(), // protocol service capabilities configured，only used for A2DP SINK
             // esp_a2d_cb_event_t_ESP_A2D_SNK_SET_DELAY_VALUE_EVT => todo!(), // indicate a2dp sink set delay report value complete, only used for A2DP SINK
             // esp_a2d_cb_event_t_ESP_A2D_SNK_GET_DELAY_VALUE_EVT => todo!(), // indicate a2dp sink get delay report value complete, only used for A2DP SINK
             // esp_a2d_cb_event_t_ESP_A2D_REPORT_SNK_DELAY_VALUE_EVT => todo!(), // report delay value, only used for A2DP SRC
-            _ => panic!("Unknown A2DP callback event {event}"),
+            _ => send_event(Err(anyhow!("Unknown A2DP callback event {}", event))),
         }
     }
 
@@ -224,31 +283,51 @@ impl ESP32A2DP {
                 conn_state.disc_rsn
             );
 
-            let mut state = CONNECTION_STATE
-                .lock()
-                .expect("Failed to lock connection state");
-
             #[allow(non_upper_case_globals)]
-            match conn_state.state {
+            let new_state = match conn_state.state {
                 esp_a2d_connection_state_t_ESP_A2D_CONNECTION_STATE_CONNECTING => {
                     log::info!("A2DP: Connecting");
-                    state.state = conn_state.state;
-                    CONNECTION_STATE_EVENT.notify(usize::MAX);
+                    A2dpConnectionState::Connecting
                 }
-                esp_a2d_connection_state_t_ESP_A2D_CONNECTION_STATE_DISCONNECTED
-                | esp_a2d_connection_state_t_ESP_A2D_CONNECTION_STATE_CONNECTED => {
-                    state.state = conn_state.state;
-                    state.disconnect_reason = conn_state.disc_rsn;
-
-                    CONNECTION_STATE_EVENT.notify(usize::MAX);
+                esp_a2d_connection_state_t_ESP_A2D_CONNECTION_STATE_DISCONNECTED => {
+                    A2dpConnectionState::Disconnected
+                }
+                esp_a2d_connection_state_t_ESP_A2D_CONNECTION_STATE_CONNECTED => {
+                    A2dpConnectionState::Connected
                 }
                 esp_a2d_connection_state_t_ESP_A2D_CONNECTION_STATE_DISCONNECTING => {
                     log::info!("A2DP: Disconnecting");
+                    A2dpConnectionState::Disconnecting
+                }
+                _ => {
+                    send_event(Err(anyhow!(
+                        "Invalid A2DP connection state: {}",
+                        conn_state.state
+                    )));
+                    return;
+                }
+            };
+
+            match CONNECTION_STATE.lock() {
+                Ok(mut state) => {
                     state.state = conn_state.state;
-                    CONNECTION_STATE_EVENT.notify(usize::MAX);
+                    if matches!(
+                        new_state,
+                        A2dpConnectionState::Disconnected | A2dpConnectionState::Connected
+                    ) {
+                        state.disconnect_reason = conn_state.disc_rsn;
+                    }
                 }
-                _ => panic!("Invalid connection state: {}", conn_state.state),
+                Err(e) => log::error!("Failed to lock connection state: {}", e),
             }
+            CONNECTION_STATE_EVENT.notify(usize::MAX);
+
+            send_event(Ok(BluetoothEvent::A2dpConnectionState {
+                address: conn_state.remote_bda,
+                state: new_state,
+                normal_disconnect: conn_state.disc_rsn
+                    == esp_a2d_disc_rsn_t_ESP_A2D_DISC_RSN_NORMAL,
+            }));
         }
     }
 
@@ -284,3 +363,15 @@ impl ESP32A2DP {
         }
     }
 }
+
+#[allow(non_upper_case_globals)]
+fn media_control(command: esp_a2d_media_ctrl_t) -> Option<MediaControl> {
+    match command {
+        esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_CHECK_SRC_RDY => {
+            Some(MediaControl::CheckSourceReady)
+        }
+        esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_START => Some(MediaControl::Start),
+        esp_a2d_media_ctrl_t_ESP_A2D_MEDIA_CTRL_SUSPEND => Some(MediaControl::Suspend),
+        _ => None,
+    }
+}
@@ -0,0 +1,570 @@
+// AVRCP, the remote control profile that comes along with A2DP. As the audio source we are the
+// target for the buttons of the headphones, which send passthrough commands (play, pause, next,
+// ...). The controller role is for commands we send to the sink.
+//
+// Sinks with AVRCP 1.4 or later do absolute volume: they apply the volume themselves, and we keep
+// the player's volume in sync by registering for their volume changes and setting theirs when
+// ours changes.
+//
+// Car head units and watches register with our target for changes of the play status, the track
+// and the position, which are followed from the NowPlaying of the playback task.
+//
+// Track metadata is not sent. NowPlaying has the title, artist, album, track number and duration,
+// but ESP-IDF's target only handles capabilities, notifications and absolute volume: it has no
+// call for answering GetElementAttributes or GetPlayStatus, and Bluedroid rejects them itself as
+// "not implemented". Sending metadata needs an ESP-IDF with a response API for them (or a patched
+// Bluedroid); until then controllers see the track change, but not what it is.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use lazy_static::lazy_static;
+
+use esp_idf_sys::{
+    esp, esp_avrc_bit_mask_op_t_ESP_AVRC_BIT_MASK_OP_SET,
+    esp_avrc_bit_mask_op_t_ESP_AVRC_BIT_MASK_OP_TEST, esp_avrc_ct_cb_event_t,
+    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_CHANGE_NOTIFY_EVT,
+    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_CONNECTION_STATE_EVT,
+    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_GET_RN_CAPABILITIES_RSP_EVT,
+    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_PASSTHROUGH_RSP_EVT,
+    esp_avrc_ct_cb_event_t_ESP_AVRC_CT_SET_ABSOLUTE_VOLUME_RSP_EVT, esp_avrc_ct_cb_param_t,
+    esp_avrc_ct_init, esp_avrc_ct_register_callback, esp_avrc_ct_send_get_rn_capabilities_cmd,
+    esp_avrc_ct_send_register_notification_cmd, esp_avrc_ct_send_set_absolute_volume_cmd,
+    esp_avrc_playback_stat_t, esp_avrc_playback_stat_t_ESP_AVRC_PLAYBACK_PAUSED,
+    esp_avrc_playback_stat_t_ESP_AVRC_PLAYBACK_PLAYING,
+    esp_avrc_playback_stat_t_ESP_AVRC_PLAYBACK_STOPPED, esp_avrc_psth_bit_mask_operation,
+    esp_avrc_psth_bit_mask_t, esp_avrc_psth_filter_t_ESP_AVRC_PSTH_FILTER_SUPPORTED_CMD,
+    esp_avrc_pt_cmd_state_t_ESP_AVRC_PT_CMD_STATE_PRESSED, esp_avrc_pt_cmd_t,
+    esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_BACKWARD, esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_FAST_FORWARD,
+    esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_FORWARD, esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_PAUSE,
+    esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_PLAY, esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_REWIND,
+    esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_STOP, esp_avrc_rn_event_ids_t,
+    esp_avrc_rn_event_ids_t_ESP_AVRC_RN_PLAY_POS_CHANGED,
+    esp_avrc_rn_event_ids_t_ESP_AVRC_RN_PLAY_STATUS_CHANGE,
+    esp_avrc_rn_event_ids_t_ESP_AVRC_RN_TRACK_CHANGE,
+    esp_avrc_rn_event_ids_t_ESP_AVRC_RN_VOLUME_CHANGE, esp_avrc_rn_evt_bit_mask_operation,
+    esp_avrc_rn_evt_cap_mask_t, esp_avrc_rn_param_t, esp_avrc_rn_rsp_t,
+    esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_CHANGED, esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_INTERIM,
+    esp_avrc_tg_cb_event_t, esp_avrc_tg_cb_event_t_ESP_AVRC_TG_CONNECTION_STATE_EVT,
+    esp_avrc_tg_cb_event_t_ESP_AVRC_TG_PASSTHROUGH_CMD_EVT,
+    esp_avrc_tg_cb_event_t_ESP_AVRC_TG_REGISTER_NOTIFICATION_EVT, esp_avrc_tg_cb_param_t,
+    esp_avrc_tg_init, esp_avrc_tg_register_callback, esp_avrc_tg_send_rn_rsp,
+    esp_avrc_tg_set_psth_cmd_filter, esp_avrc_tg_set_rn_evt_cap,
+};
+
+use crate::{
+    audio::{NowPlaying, PlayStatus},
+    audio_dsp::MAX_VOLUME,
+    bluetooth_hal::{bdaddr_to_string, RemoteCommand},
+};
+
+const PASSTHROUGH_COMMANDS: [(esp_avrc_pt_cmd_t, RemoteCommand); 7] = [
+    (esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_PLAY, RemoteCommand::Play),
+    (
+        esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_PAUSE,
+        RemoteCommand::Pause,
+    ),
+    (esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_STOP, RemoteCommand::Stop),
+    (
+        esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_FORWARD,
+        RemoteCommand::Next,
+    ),
+    (
+        esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_BACKWARD,
+        RemoteCommand::Previous,
+    ),
+    (
+        esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_FAST_FORWARD,
+        RemoteCommand::FastForward,
+    ),
+    (
+        esp_avrc_pt_cmd_t_ESP_AVRC_PT_CMD_REWIND,
+        RemoteCommand::Rewind,
+    ),
+];
+
+// Transaction labels of the commands we send as controller
+const TL_GET_CAPABILITIES: u8 = 0;
+const TL_VOLUME_CHANGE: u8 = 1;
+const TL_SET_VOLUME: u8 = 2;
+
+// Absolute volume goes from 0 to 127
+const AVRCP_MAX_VOLUME: u32 = 127;
+
+// The player's volume, in AVRCP units
+static VOLUME: AtomicU8 = AtomicU8::new(0);
+// The sink supports volume change notifications, and so absolute volume
+static SINK_VOLUME: AtomicBool = AtomicBool::new(false);
+
+// Notifications our target sends
+const NOTIFICATIONS: [esp_avrc_rn_event_ids_t; 3] = [
+    esp_avrc_rn_event_ids_t_ESP_AVRC_RN_PLAY_STATUS_CHANGE,
+    esp_avrc_rn_event_ids_t_ESP_AVRC_RN_TRACK_CHANGE,
+    esp_avrc_rn_event_ids_t_ESP_AVRC_RN_PLAY_POS_CHANGED,
+];
+// How often the NowPlaying is checked for changes to notify
+const NOTIFY_POLL_MS: u64 = 250;
+// Without browsing, a track is identified as "selected" or "none"
+const TRACK_SELECTED: [u8; 8] = [0; 8];
+const NO_TRACK: [u8; 8] = [0xff; 8];
+const NO_POSITION: u32 = 0xffff_ffff;
+
+// Notifications a controller has registered for, with what it was told. Each registration is
+// answered once right away, and once more when the value changes, after which the controller
+// registers again.
+#[derive(Default)]
+struct Registrations {
+    play_status: Option<PlayStatus>,
+    // track id, 0 for none
+    track: Option<u64>,
+    position: Option<PositionRegistration>,
+}
+
+struct PositionRegistration {
+    interval: Duration,
+    reported: Instant,
+    status: PlayStatus,
+    track: u64,
+}
+
+lazy_static! {
+    static ref REMOTE_COMMANDS: Mutex<Option<UnboundedSender<RemoteCommand>>> = Mutex::new(None);
+    static ref NOW_PLAYING: Mutex<Option<NowPlaying>> = Mutex::new(None);
+    static ref REGISTRATIONS: Mutex<Registrations> = Mutex::new(Registrations::default());
+}
+
+// Sends the notifications for a NowPlaying, until dropped
+pub struct NowPlayingNotifier {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for NowPlayingNotifier {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("AVRCP notification thread panicked");
+            }
+        }
+        match NOW_PLAYING.lock() {
+            Ok(mut now_playing) => {
+                now_playing.take();
+            }
+            Err(e) => log::error!("Failed to lock now playing: {}", e),
+        }
+    }
+}
+
+pub struct ESP32AVRCP {}
+
+impl ESP32AVRCP {
+    // Must come before A2DP is initialised
+    pub fn init() -> Result<()> {
+        unsafe {
+            esp!(esp_avrc_ct_init())?;
+            esp!(esp_avrc_ct_register_callback(Some(
+                ESP32AVRCP::bt_app_rc_ct_cb
+            )))?;
+
+            esp!(esp_avrc_tg_init())?;
+            esp!(esp_avrc_tg_register_callback(Some(
+                ESP32AVRCP::bt_app_rc_tg_cb
+            )))?;
+
+            // Commands we don't list are rejected with "not implemented"
+            let mut commands = esp_avrc_psth_bit_mask_t::default();
+            for (command, _) in PASSTHROUGH_COMMANDS {
+                esp_avrc_psth_bit_mask_operation(
+                    esp_avrc_bit_mask_op_t_ESP_AVRC_BIT_MASK_OP_SET,
+                    &mut commands,
+                    command,
+                );
+            }
+            esp!(esp_avrc_tg_set_psth_cmd_filter(
+                esp_avrc_psth_filter_t_ESP_AVRC_PSTH_FILTER_SUPPORTED_CMD,
+                &commands
+            ))?;
+
+            let mut events = esp_avrc_rn_evt_cap_mask_t::default();
+            for event in NOTIFICATIONS {
+                esp_avrc_rn_evt_bit_mask_operation(
+                    esp_avrc_bit_mask_op_t_ESP_AVRC_BIT_MASK_OP_SET,
+                    &mut events,
+                    event,
+                );
+            }
+            esp!(esp_avrc_tg_set_rn_evt_cap(&events))?;
+        }
+        Ok(())
+    }
+
+    // Notifies registered controllers of what the NowPlaying does, from a thread which checks it
+    // every NOTIFY_POLL_MS
+    pub fn follow(now_playing: &NowPlaying) -> Result<NowPlayingNotifier> {
+        NOW_PLAYING
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock now playing: {}", e))?
+            .replace(now_playing.clone());
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let now_playing = now_playing.clone();
+        let thread = thread::Builder::new()
+            .name("avrcp_notify".to_owned())
+            .stack_size(8192)
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Relaxed) {
+                    ESP32AVRCP::notify_changes(&now_playing);
+                    thread::sleep(Duration::from_millis(NOTIFY_POLL_MS));
+                }
+            })
+            .map_err(|e| anyhow!("Failed to start AVRCP notifications: {}", e))?;
+
+        Ok(NowPlayingNotifier {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    fn notify_changes(now_playing: &NowPlaying) {
+        let status = now_playing.status();
+        let track = now_playing.track().map_or(0, |(id, _)| id);
+        let mut registrations = match REGISTRATIONS.lock() {
+            Ok(registrations) => registrations,
+            Err(e) => {
+                log::error!("Failed to lock registrations: {}", e);
+                return;
+            }
+        };
+
+        if registrations
+            .play_status
+            .is_some_and(|registered| registered != status)
+        {
+            registrations.play_status = None;
+            ESP32AVRCP::send_play_status(esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_CHANGED, status);
+        }
+        if registrations
+            .track
+            .is_some_and(|registered| registered != track)
+        {
+            registrations.track = None;
+            ESP32AVRCP::send_track(esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_CHANGED, track);
+        }
+        // also when the track or status changes, the controller may have to correct its own count
+        if registrations.position.as_ref().is_some_and(|registered| {
+            registered.track != track
+                || registered.status != status
+                || (status == PlayStatus::Playing
+                    && registered.reported.elapsed() >= registered.interval)
+        }) {
+            registrations.position = None;
+            ESP32AVRCP::send_position(
+                esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_CHANGED,
+                now_playing.position_ms(),
+            );
+        }
+    }
+
+    // Answers a registration with the current value, and remembers it to notify the change
+    fn register_notification(event: esp_avrc_rn_event_ids_t, parameter: u32) {
+        let now_playing = match NOW_PLAYING.lock() {
+            Ok(now_playing) => now_playing.clone(),
+            Err(e) => {
+                log::error!("Failed to lock now playing: {}", e);
+                return;
+            }
+        };
+        let status = now_playing
+            .as_ref()
+            .map_or(PlayStatus::Stopped, |n| n.status());
+        let track = now_playing
+            .as_ref()
+            .and_then(|n| n.track())
+            .map_or(0, |(id, _)| id);
+        let interim = esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_INTERIM;
+        let mut registrations = match REGISTRATIONS.lock() {
+            Ok(registrations) => registrations,
+            Err(e) => {
+                log::error!("Failed to lock registrations: {}", e);
+                return;
+            }
+        };
+
+        #[allow(non_upper_case_globals)]
+        match event {
+            esp_avrc_rn_event_ids_t_ESP_AVRC_RN_PLAY_STATUS_CHANGE => {
+                registrations.play_status = Some(status);
+                ESP32AVRCP::send_play_status(interim, status);
+            }
+            esp_avrc_rn_event_ids_t_ESP_AVRC_RN_TRACK_CHANGE => {
+                registrations.track = Some(track);
+                ESP32AVRCP::send_track(interim, track);
+            }
+            esp_avrc_rn_event_ids_t_ESP_AVRC_RN_PLAY_POS_CHANGED => {
+                // the parameter is the interval in seconds
+                registrations.position = Some(PositionRegistration {
+                    interval: Duration::from_secs(parameter.max(1) as u64),
+                    reported: Instant::now(),
+                    status,
+                    track,
+                });
+                ESP32AVRCP::send_position(
+                    interim,
+                    now_playing.as_ref().and_then(|n| n.position_ms()),
+                );
+            }
+            _ => log::info!("Ignoring AVRCP notification registration {}", event),
+        }
+    }
+
+    fn send_play_status(response: esp_avrc_rn_rsp_t, status: PlayStatus) {
+        let playback: esp_avrc_playback_stat_t = match status {
+            PlayStatus::Stopped => esp_avrc_playback_stat_t_ESP_AVRC_PLAYBACK_STOPPED,
+            PlayStatus::Playing => esp_avrc_playback_stat_t_ESP_AVRC_PLAYBACK_PLAYING,
+            PlayStatus::Paused => esp_avrc_playback_stat_t_ESP_AVRC_PLAYBACK_PAUSED,
+        };
+        ESP32AVRCP::send_notification(
+            esp_avrc_rn_event_ids_t_ESP_AVRC_RN_PLAY_STATUS_CHANGE,
+            response,
+            esp_avrc_rn_param_t { playback },
+        );
+    }
+
+    fn send_track(response: esp_avrc_rn_rsp_t, track: u64) {
+        let elm_id = if track == 0 { NO_TRACK } else { TRACK_SELECTED };
+        ESP32AVRCP::send_notification(
+            esp_avrc_rn_event_ids_t_ESP_AVRC_RN_TRACK_CHANGE,
+            response,
+            esp_avrc_rn_param_t { elm_id },
+        );
+    }
+
+    fn send_position(response: esp_avrc_rn_rsp_t, position_ms: Option<u64>) {
+        let play_pos = position_ms.map_or(NO_POSITION, |ms| ms.min(NO_POSITION as u64 - 1) as u32);
+        ESP32AVRCP::send_notification(
+            esp_avrc_rn_event_ids_t_ESP_AVRC_RN_PLAY_POS_CHANGED,
+            response,
+            esp_avrc_rn_param_t { play_pos },
+        );
+    }
+
+    fn send_notification(
+        event: esp_avrc_rn_event_ids_t,
+        response: esp_avrc_rn_rsp_t,
+        mut param: esp_avrc_rn_param_t,
+    ) {
+        if let Err(e) = unsafe { esp!(esp_avrc_tg_send_rn_rsp(event, response, &mut param)) } {
+            log::error!("Failed to send AVRCP notification {}: {}", event, e);
+        }
+    }
+
+    // Volume in percent, sent to the sink if it does absolute volume
+    pub fn set_volume(volume: u8) -> Result<()> {
+        let volume = to_avrcp_volume(volume);
+        VOLUME.store(volume, Ordering::Relaxed);
+
+        if SINK_VOLUME.load(Ordering::Relaxed) {
+            unsafe {
+                esp!(esp_avrc_ct_send_set_absolute_volume_cmd(
+                    TL_SET_VOLUME,
+                    volume
+                ))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Takes on the volume of the sink, and tells the player
+    fn volume_changed(volume: u8) {
+        VOLUME.store(volume, Ordering::Relaxed);
+        let volume = from_avrcp_volume(volume);
+        log::info!("AVRCP volume {}%", volume);
+        send_command(RemoteCommand::Volume(volume));
+    }
+
+    fn register_volume_change() {
+        let result = unsafe {
+            esp!(esp_avrc_ct_send_register_notification_cmd(
+                TL_VOLUME_CHANGE,
+                esp_avrc_rn_event_ids_t_ESP_AVRC_RN_VOLUME_CHANGE as u8,
+                0
+            ))
+        };
+        if let Err(e) = result {
+            log::error!("Failed to register for volume changes: {}", e);
+        }
+    }
+
+    // Replaces the receiver of an earlier call, which then ends
+    pub fn commands() -> UnboundedReceiver<RemoteCommand> {
+        let (sender, receiver) = mpsc::unbounded();
+        REMOTE_COMMANDS
+            .lock()
+            .expect("Failed to lock remote commands")
+            .replace(sender);
+        receiver
+    }
+
+    extern "C" fn bt_app_rc_tg_cb(
+        event: esp_avrc_tg_cb_event_t,
+        param: *mut esp_avrc_tg_cb_param_t,
+    ) {
+        #[allow(non_upper_case_globals)]
+        match event {
+            esp_avrc_tg_cb_event_t_ESP_AVRC_TG_CONNECTION_STATE_EVT => unsafe {
+                let state = &(*param).conn_stat;
+                match REGISTRATIONS.lock() {
+                    Ok(mut registrations) => *registrations = Registrations::default(),
+                    Err(e) => log::error!("Failed to lock registrations: {}", e),
+                }
+                log::info!(
+                    "AVRCP target {} {}",
+                    bdaddr_to_string(state.remote_bda),
+                    if state.connected {
+                        "connected"
+                    } else {
+                        "disconnected"
+                    }
+                );
+            },
+            esp_avrc_tg_cb_event_t_ESP_AVRC_TG_PASSTHROUGH_CMD_EVT => unsafe {
+                let command = &(*param).psth_cmd;
+                // each button press comes as pressed and released, act on the press
+                if command.key_state as u32 != esp_avrc_pt_cmd_state_t_ESP_AVRC_PT_CMD_STATE_PRESSED
+                {
+                    return;
+                }
+
+                match PASSTHROUGH_COMMANDS
+                    .iter()
+                    .find(|(code, _)| *code == command.key_code as esp_avrc_pt_cmd_t)
+                {
+                    Some((_, remote_command)) => {
+                        log::info!("AVRCP passthrough {:?}", remote_command);
+                        send_command(*remote_command);
+                    }
+                    None => log::info!("Ignoring AVRCP passthrough 0x{:02x}", command.key_code),
+                }
+            },
+            esp_avrc_tg_cb_event_t_ESP_AVRC_TG_REGISTER_NOTIFICATION_EVT => unsafe {
+                let registration = &(*param).reg_ntf;
+                ESP32AVRCP::register_notification(
+                    registration.event_id as esp_avrc_rn_event_ids_t,
+                    registration.event_parameter,
+                );
+            },
+            _ => log::info!("AVRCP target event {}", event),
+        }
+    }
+
+    extern "C" fn bt_app_rc_ct_cb(
+        event: esp_avrc_ct_cb_event_t,
+        param: *mut esp_avrc_ct_cb_param_t,
+    ) {
+        #[allow(non_upper_case_globals)]
+        match event {
+            esp_avrc_ct_cb_event_t_ESP_AVRC_CT_CONNECTION_STATE_EVT => unsafe {
+                let state = &(*param).conn_stat;
+                log::info!(
+                    "AVRCP controller {} {}",
+                    bdaddr_to_string(state.remote_bda),
+                    if state.connected {
+                        "connected"
+                    } else {
+                        "disconnected"
+                    }
+                );
+
+                SINK_VOLUME.store(false, Ordering::Relaxed);
+                if state.connected {
+                    // the answer tells whether the sink does absolute volume
+                    if let Err(e) = esp!(esp_avrc_ct_send_get_rn_capabilities_cmd(
+                        TL_GET_CAPABILITIES
+                    )) {
+                        log::error!("Failed to get AVRCP capabilities: {}", e);
+                    }
+                }
+            },
+            esp_avrc_ct_cb_event_t_ESP_AVRC_CT_GET_RN_CAPABILITIES_RSP_EVT => unsafe {
+                let mut events = (*param).get_rn_caps_rsp.evt_set;
+                if !esp_avrc_rn_evt_bit_mask_operation(
+                    esp_avrc_bit_mask_op_t_ESP_AVRC_BIT_MASK_OP_TEST,
+                    &mut events,
+                    esp_avrc_rn_event_ids_t_ESP_AVRC_RN_VOLUME_CHANGE,
+                ) {
+                    log::info!("Sink has no absolute volume, the player applies the volume");
+                    return;
+                }
+
+                log::info!("Sink has absolute volume");
+                SINK_VOLUME.store(true, Ordering::Relaxed);
+                ESP32AVRCP::register_volume_change();
+                // the sink starts at the player's volume, its answer confirms it
+                if let Err(e) = esp!(esp_avrc_ct_send_set_absolute_volume_cmd(
+                    TL_SET_VOLUME,
+                    VOLUME.load(Ordering::Relaxed)
+                )) {
+                    log::error!("Failed to set the sink's volume: {}", e);
+                }
+            },
+            esp_avrc_ct_cb_event_t_ESP_AVRC_CT_CHANGE_NOTIFY_EVT => unsafe {
+                let notification = &(*param).change_ntf;
+                if notification.event_id as esp_avrc_rn_event_ids_t
+                    == esp_avrc_rn_event_ids_t_ESP_AVRC_RN_VOLUME_CHANGE
+                {
+                    ESP32AVRCP::volume_changed(notification.event_parameter.volume);
+                    ESP32AVRCP::register_volume_change();
+                }
+            },
+            esp_avrc_ct_cb_event_t_ESP_AVRC_CT_SET_ABSOLUTE_VOLUME_RSP_EVT => unsafe {
+                ESP32AVRCP::volume_changed((*param).set_volume_rsp.volume);
+            },
+            esp_avrc_ct_cb_event_t_ESP_AVRC_CT_PASSTHROUGH_RSP_EVT => unsafe {
+                let response = &(*param).psth_rsp;
+                log::info!(
+                    "AVRCP passthrough response: key 0x{:02x}, state {}",
+                    response.key_code,
+                    response.key_state
+                );
+            },
+            _ => log::info!("AVRCP controller event {}", event),
+        }
+    }
+}
+
+// Called from the target callback, which must not panic
+fn send_command(command: RemoteCommand) {
+    let sender = match REMOTE_COMMANDS.lock() {
+        Ok(sender) => sender,
+        Err(e) => {
+            log::error!("Failed to lock remote commands: {}", e);
+            return;
+        }
+    };
+    if sender
+        .as_ref()
+        .map_or(true, |sender| sender.unbounded_send(command).is_err())
+    {
+        log::info!("Nothing is listening to remote commands");
+    }
+}
+
+fn to_avrcp_volume(volume: u8) -> u8 {
+    let volume = volume.min(MAX_VOLUME) as u32;
+    ((volume * AVRCP_MAX_VOLUME + MAX_VOLUME as u32 / 2) / MAX_VOLUME as u32) as u8
+}
+
+fn from_avrcp_volume(volume: u8) -> u8 {
+    let volume = (volume as u32).min(AVRCP_MAX_VOLUME);
+    ((volume * MAX_VOLUME as u32 + AVRCP_MAX_VOLUME / 2) / AVRCP_MAX_VOLUME) as u8
+}
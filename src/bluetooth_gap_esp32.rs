@@ -4,13 +4,17 @@
  * link establishment, link termination, initiation of security features, and device configuration.
  */
 
-use anyhow::anyhow;
+use std::ffi::CStr;
+
+use anyhow::{anyhow, Result};
 use esp_idf_sys::{
     esp, esp_bt_gap_cb_event_t, esp_bt_gap_cb_event_t_ESP_BT_GAP_ACL_CONN_CMPL_STAT_EVT,
-    esp_bt_gap_cb_event_t_ESP_BT_GAP_AUTH_CMPL_EVT,
+    esp_bt_gap_cb_event_t_ESP_BT_GAP_ACL_DISCONN_CMPL_STAT_EVT,
+    esp_bt_gap_cb_event_t_ESP_BT_GAP_AUTH_CMPL_EVT, esp_bt_gap_cb_event_t_ESP_BT_GAP_CFM_REQ_EVT,
     esp_bt_gap_cb_event_t_ESP_BT_GAP_CONFIG_EIR_DATA_EVT,
     esp_bt_gap_cb_event_t_ESP_BT_GAP_DISC_RES_EVT,
     esp_bt_gap_cb_event_t_ESP_BT_GAP_DISC_STATE_CHANGED_EVT,
+    esp_bt_gap_cb_event_t_ESP_BT_GAP_KEY_NOTIF_EVT, esp_bt_gap_cb_event_t_ESP_BT_GAP_KEY_REQ_EVT,
     esp_bt_gap_cb_event_t_ESP_BT_GAP_MODE_CHG_EVT, esp_bt_gap_cb_event_t_ESP_BT_GAP_PIN_REQ_EVT,
     esp_bt_gap_cb_event_t_ESP_BT_GAP_RMT_SRVCS_EVT,
     esp_bt_gap_cb_event_t_ESP_BT_GAP_RMT_SRVC_REC_EVT, esp_bt_gap_cb_param_t,
@@ -21,15 +25,22 @@ use esp_idf_sys::{
     esp_bt_gap_dev_prop_type_t_ESP_BT_GAP_DEV_PROP_RSSI,
     esp_bt_gap_discovery_state_t_ESP_BT_GAP_DISCOVERY_STARTED,
     esp_bt_gap_discovery_state_t_ESP_BT_GAP_DISCOVERY_STOPPED, esp_bt_gap_pin_reply,
-    esp_bt_pin_code_t, esp_bt_status_t_ESP_BT_STATUS_HCI_SUCCESS,
-    esp_bt_status_t_ESP_BT_STATUS_SUCCESS,
+    esp_bt_gap_ssp_confirm_reply, esp_bt_gap_ssp_passkey_reply, esp_bt_pin_code_t, esp_bt_status_t,
+    esp_bt_status_t_ESP_BT_STATUS_HCI_SUCCESS, esp_bt_status_t_ESP_BT_STATUS_SUCCESS,
+    esp_bt_uuid_t,
+};
+
+use crate::bluetooth_esp32::{send_event, ESP32_BLUETOOTH_GLOBALS};
+use crate::bluetooth_gap_hal::{
+    parse_name, Authentication, ClassOfDevice, DeviceProperty, IoCapability, PairingEvent,
+    ScannedDevice, ServiceUuid,
 };
+use crate::bluetooth_hal::{bdaddr_to_string, BluetoothEvent};
 
-use crate::bluetooth_esp32::ESP32_BLUETOOTH_GLOBALS;
-use crate::bluetooth_gap_hal::{ClassOfDevice, DeviceProperty, ScannedDevice};
+impl TryFrom<*const esp_bt_gap_dev_prop_t> for DeviceProperty {
+    type Error = anyhow::Error;
 
-impl From<*const esp_bt_gap_dev_prop_t> for DeviceProperty {
-    fn from(property: *const esp_bt_gap_dev_prop_t) -> Self {
+    fn try_from(property: *const esp_bt_gap_dev_prop_t) -> Result<Self> {
         unsafe {
             let t: esp_bt_gap_dev_prop_type_t = (*property).type_;
             #[allow(non_upper_case_globals)]
@@ -39,31 +50,28 @@ impl From<*const esp_bt_gap_dev_prop_t> for DeviceProperty {
                         (*property).val as *const u8,
                         (*property).len as usize,
                     );
-                    let mut name = String::new();
-                    for byte in slice {
-                        name.push_str(&format!("{:02x}", *byte));
-                    }
+                    let name = parse_name(slice);
 
                     log::info!("name: {}", name);
 
-                    DeviceProperty::Name(name)
+                    Ok(DeviceProperty::Name(name))
                 }
                 esp_bt_gap_dev_prop_type_t_ESP_BT_GAP_DEV_PROP_COD => {
-                    DeviceProperty::Class(ClassOfDevice {
+                    Ok(DeviceProperty::Class(ClassOfDevice {
                         value: *((*property).val as *const u32),
-                    })
+                    }))
                 }
                 esp_bt_gap_dev_prop_type_t_ESP_BT_GAP_DEV_PROP_RSSI => {
-                    DeviceProperty::Rssi(*((*property).val as *const i8))
+                    Ok(DeviceProperty::Rssi(*((*property).val as *const i8)))
                 }
                 esp_bt_gap_dev_prop_type_t_ESP_BT_GAP_DEV_PROP_EIR => {
                     let slice = std::slice::from_raw_parts(
                         (*property).val as *const u8,
                         (*property).len as usize,
                     );
-                    DeviceProperty::Eir(slice.to_vec())
+                    Ok(DeviceProperty::Eir(slice.to_vec()))
                 }
-                _ => panic!(),
+                _ => Err(anyhow!("Unknown device property type {}", t)),
             }
         }
     }
@@ -83,17 +91,25 @@ pub extern "C" fn bt_app_gap_cb(event: esp_bt_gap_cb_event_t, param: *mut esp_bt
             };
 
             for i in 0..(result.num_prop) {
-                scanned_device.add_property(DeviceProperty::from(
+                match DeviceProperty::try_from(
                     result.prop.add(i as usize) as *const esp_bt_gap_dev_prop_t
-                ));
+                ) {
+                    Ok(property) => scanned_device.add_property(property),
+                    Err(e) => send_event(Err(e)),
+                }
             }
 
-            // TODO: Error handling
-            let sender_lock = ESP32_BLUETOOTH_GLOBALS.discovery.lock().unwrap();
-            if let Some((sender, _)) = &*sender_lock {
-                sender.try_broadcast(scanned_device).unwrap();
-            };
-            drop(sender_lock); // ensure we keep it locked as short a time as possible
+            match ESP32_BLUETOOTH_GLOBALS.discovery.lock() {
+                Ok(sender_lock) => {
+                    if let Some((sender, _)) = &*sender_lock {
+                        if let Err(e) = sender.try_broadcast(scanned_device) {
+                            log::warn!("Dropped discovery result: {}", e);
+                        }
+                    };
+                    drop(sender_lock); // ensure we keep it locked as short a time as possible
+                }
+                Err(e) => log::error!("Failed to lock discovery: {}", e),
+            }
         },
         esp_bt_gap_cb_event_t_ESP_BT_GAP_DISC_STATE_CHANGED_EVT => {
             let state = unsafe { (*param).disc_st_chg.state };
@@ -101,17 +117,23 @@ pub extern "C" fn bt_app_gap_cb(event: esp_bt_gap_cb_event_t, param: *mut esp_bt
                 esp_bt_gap_discovery_state_t_ESP_BT_GAP_DISCOVERY_STOPPED => {
                     log::info!("Discovery stopped");
 
-                    let mut sender_lock = ESP32_BLUETOOTH_GLOBALS.discovery.lock().unwrap();
-                    if let Some((sender, _)) = &*sender_lock {
-                        sender.close();
-                        log::info!("bt_app_gap_cb: discovery stopped, removing sender ");
-                        sender_lock.take(); // = None;
+                    match ESP32_BLUETOOTH_GLOBALS.discovery.lock() {
+                        Ok(mut sender_lock) => {
+                            if let Some((sender, _)) = &*sender_lock {
+                                sender.close();
+                                log::info!("bt_app_gap_cb: discovery stopped, removing sender ");
+                                sender_lock.take(); // = None;
+                            }
+                        }
+                        Err(e) => log::error!("Failed to lock discovery: {}", e),
                     }
+                    send_event(Ok(BluetoothEvent::DiscoveryStopped));
                 }
                 esp_bt_gap_discovery_state_t_ESP_BT_GAP_DISCOVERY_STARTED => {
                     log::info!("GAP Discovery started.");
+                    send_event(Ok(BluetoothEvent::DiscoveryStarted));
                 }
-                _ => panic!(),
+                _ => send_event(Err(anyhow!("Unknown discovery state {}", state))),
             }
         }
         esp_bt_gap_cb_event_t_ESP_BT_GAP_ACL_CONN_CMPL_STAT_EVT => {
@@ -132,50 +154,253 @@ pub extern "C" fn bt_app_gap_cb(event: esp_bt_gap_cb_event_t, param: *mut esp_bt
                     status.stat,
                     status.handle
                 );
+                send_event(Ok(BluetoothEvent::AclConnected {
+                    address: status.bda,
+                    success: is_success(status.stat),
+                }));
             }
         }
-        esp_bt_gap_cb_event_t_ESP_BT_GAP_RMT_SRVCS_EVT
-        | esp_bt_gap_cb_event_t_ESP_BT_GAP_RMT_SRVC_REC_EVT => todo!(),
+        esp_bt_gap_cb_event_t_ESP_BT_GAP_ACL_DISCONN_CMPL_STAT_EVT => unsafe {
+            let status = (*param).acl_disconn_cmpl_stat;
+            log::info!(
+                "Disconnected from {}, reason {}",
+                bdaddr_to_string(status.bda),
+                status.reason
+            );
+            send_event(Ok(BluetoothEvent::AclDisconnected {
+                address: status.bda,
+                reason: status.reason,
+            }));
+        },
+        esp_bt_gap_cb_event_t_ESP_BT_GAP_RMT_SRVCS_EVT => unsafe {
+            let result = &(*param).rmt_srvcs;
+            let mut uuids = Vec::new();
+            if !result.uuid_list.is_null() {
+                for i in 0..result.num_uuids.max(0) as usize {
+                    match service_uuid(&*result.uuid_list.add(i)) {
+                        Ok(uuid) => uuids.push(uuid),
+                        Err(e) => send_event(Err(e)),
+                    }
+                }
+            }
+            log::info!(
+                "Services of {}: status {}, {:?}",
+                bdaddr_to_string(result.bda),
+                result.stat,
+                uuids
+            );
+            ESP32_BLUETOOTH_GLOBALS
+                .remote_services
+                .complete(if is_success(result.stat) {
+                    Ok(uuids.clone())
+                } else {
+                    Err(anyhow!(
+                        "Getting remote services failed, status {}",
+                        result.stat
+                    ))
+                });
+            send_event(Ok(BluetoothEvent::RemoteServices {
+                address: result.bda,
+                success: is_success(result.stat),
+                uuids,
+            }));
+        },
+        esp_bt_gap_cb_event_t_ESP_BT_GAP_RMT_SRVC_REC_EVT => unsafe {
+            let result = &(*param).rmt_srvc_rec;
+            log::info!(
+                "Service record of {}: status {}",
+                bdaddr_to_string(result.bda),
+                result.stat
+            );
+            // the stack fails the query when the device has no such record
+            ESP32_BLUETOOTH_GLOBALS
+                .remote_service_record
+                .complete(Ok(is_success(result.stat)));
+            send_event(Ok(BluetoothEvent::RemoteServiceRecord {
+                address: result.bda,
+                success: is_success(result.stat),
+            }));
+        },
 
-        esp_bt_gap_cb_event_t_ESP_BT_GAP_AUTH_CMPL_EVT => todo!(),
+        esp_bt_gap_cb_event_t_ESP_BT_GAP_AUTH_CMPL_EVT => unsafe {
+            let result = &(*param).auth_cmpl;
+            let authentication = Authentication {
+                address: result.bda,
+                name: CStr::from_bytes_until_nul(&result.device_name)
+                    .ok()
+                    .and_then(|name| name.to_str().ok())
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string),
+                success: result.stat == esp_bt_status_t_ESP_BT_STATUS_SUCCESS,
+            };
+            if authentication.success {
+                log::info!(
+                    "Authenticated with {} ({})",
+                    bdaddr_to_string(result.bda),
+                    authentication.name.as_deref().unwrap_or("no name")
+                );
+            } else {
+                log::error!(
+                    "Authentication with {} failed, status {}",
+                    bdaddr_to_string(result.bda),
+                    result.stat
+                );
+            }
 
+            match ESP32_BLUETOOTH_GLOBALS.authentications.lock() {
+                Ok(mut authentications) => {
+                    authentications.insert(result.bda, authentication.clone());
+                }
+                Err(e) => log::error!("Failed to record authentication: {}", e),
+            }
+            send_event(Ok(BluetoothEvent::Authenticated(authentication.clone())));
+            send_pairing_event(PairingEvent::Completed(authentication));
+        },
+
+        // Legacy pairing
         esp_bt_gap_cb_event_t_ESP_BT_GAP_PIN_REQ_EVT => unsafe {
-            let mut param = (*param).pin_req;
+            let mut request = (*param).pin_req;
+            log::info!(
+                "PIN requested by {}, 16 digits: {}",
+                bdaddr_to_string(request.bda),
+                request.min_16_digit
+            );
+
+            let pin = match ESP32_BLUETOOTH_GLOBALS.pairing.lock() {
+                Ok(pairing) => pairing.pin_code(request.min_16_digit),
+                Err(e) => {
+                    log::error!("Failed to lock pairing config: {}", e);
+                    Vec::new()
+                }
+            };
+            let mut pin_code: esp_bt_pin_code_t = [0; 16];
+            pin_code[..pin.len()].copy_from_slice(&pin);
+            if let Err(e) = esp!(esp_bt_gap_pin_reply(
+                request.bda.as_mut_ptr(),
+                !pin.is_empty(),
+                pin.len() as u8,
+                pin_code.as_mut_ptr()
+            )) {
+                log::error!("Failed to reply with the PIN: {}", e);
+            }
+        },
+
+        // Secure Simple Pairing. Numeric comparison needs a yes or no from the user, without a
+        // way to answer the stack uses just works and only wants it accepted.
+        esp_bt_gap_cb_event_t_ESP_BT_GAP_CFM_REQ_EVT => unsafe {
+            let mut request = (*param).cfm_req;
             log::info!(
-                "ESP_BT_GAP_PIN_REQ_EVT min_16_digit: {}",
-                param.min_16_digit
+                "Pairing with {}, passkey {:06}",
+                bdaddr_to_string(request.bda),
+                request.num_val
             );
-            if param.min_16_digit {
-                log::info!("Input pin code: 0000 0000 0000 0000");
-                let mut pin_code: esp_bt_pin_code_t = [0; 16];
-                esp!(esp_bt_gap_pin_reply(
-                    param.bda.as_mut_ptr(),
-                    true,
-                    16,
-                    pin_code.as_mut_ptr()
-                ))
-                .unwrap();
+
+            let accept = if io_capability() == Some(IoCapability::DisplayYesNo) {
+                if send_pairing_event(PairingEvent::ConfirmPasskey {
+                    address: request.bda,
+                    passkey: request.num_val,
+                }) {
+                    // answered through gap_confirm_pairing
+                    return;
+                }
+                false
             } else {
-                log::info!("Input pin code: 1234");
-                #[allow(clippy::char_lit_as_u8)]
-                let mut pin_code: esp_bt_pin_code_t = [
-                    '1' as u8, '2' as u8, '3' as u8, '4' as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                ];
-                esp!(esp_bt_gap_pin_reply(
-                    param.bda.as_mut_ptr(),
-                    true,
-                    4,
-                    pin_code.as_mut_ptr()
-                ))
-                .unwrap();
+                true
+            };
+            if let Err(e) = esp!(esp_bt_gap_ssp_confirm_reply(
+                request.bda.as_mut_ptr(),
+                accept
+            )) {
+                log::error!("Failed to answer pairing: {}", e);
+            }
+        },
+        esp_bt_gap_cb_event_t_ESP_BT_GAP_KEY_REQ_EVT => unsafe {
+            let mut request = (*param).key_req;
+            log::info!("Passkey requested by {}", bdaddr_to_string(request.bda));
+
+            if !send_pairing_event(PairingEvent::EnterPasskey {
+                address: request.bda,
+            }) {
+                if let Err(e) = esp!(esp_bt_gap_ssp_passkey_reply(
+                    request.bda.as_mut_ptr(),
+                    false,
+                    0
+                )) {
+                    log::error!("Failed to reject pairing: {}", e);
+                }
             }
         },
+        esp_bt_gap_cb_event_t_ESP_BT_GAP_KEY_NOTIF_EVT => unsafe {
+            let notification = (*param).key_notif;
+            log::info!(
+                "Enter passkey {:06} on {}",
+                notification.passkey,
+                bdaddr_to_string(notification.bda)
+            );
+            send_pairing_event(PairingEvent::ShowPasskey {
+                address: notification.bda,
+                passkey: notification.passkey,
+            });
+        },
         esp_bt_gap_cb_event_t_ESP_BT_GAP_MODE_CHG_EVT => unsafe {
-            log::info!("ESP_BT_GAP_MODE_CHG_EVT mode: {}", (*param).mode_chg.mode);
+            let change = (*param).mode_chg;
+            log::info!("ESP_BT_GAP_MODE_CHG_EVT mode: {}", change.mode);
+            send_event(Ok(BluetoothEvent::PowerModeChanged {
+                address: change.bda,
+                mode: change.mode,
+            }));
         },
-        esp_bt_gap_cb_event_t_ESP_BT_GAP_CONFIG_EIR_DATA_EVT => {
-            log::info!("GAP event CONFIG_EIR_DATA_EVT");
+        esp_bt_gap_cb_event_t_ESP_BT_GAP_CONFIG_EIR_DATA_EVT => unsafe {
+            let stat = (*param).config_eir_data.stat;
+            log::info!("GAP event CONFIG_EIR_DATA_EVT, status {}", stat);
+            send_event(Ok(BluetoothEvent::EirConfigured {
+                success: is_success(stat),
+            }));
+        },
+        _ => send_event(Err(anyhow!("Unexpected GAP event {}", event))),
+    }
+}
+
+#[allow(non_upper_case_globals)]
+fn is_success(status: esp_bt_status_t) -> bool {
+    matches!(
+        status,
+        esp_bt_status_t_ESP_BT_STATUS_SUCCESS | esp_bt_status_t_ESP_BT_STATUS_HCI_SUCCESS
+    )
+}
+
+fn service_uuid(uuid: &esp_bt_uuid_t) -> Result<ServiceUuid> {
+    unsafe {
+        match uuid.len {
+            2 => Ok(ServiceUuid::from_u16(uuid.uuid.uuid16)),
+            4 => Ok(ServiceUuid::from_u32(uuid.uuid.uuid32)),
+            16 => Ok(ServiceUuid::from_uuid128(uuid.uuid.uuid128)),
+            len => Err(anyhow!("Invalid service UUID length {}", len)),
+        }
+    }
+}
+
+fn io_capability() -> Option<IoCapability> {
+    ESP32_BLUETOOTH_GLOBALS
+        .pairing
+        .lock()
+        .ok()
+        .map(|pairing| pairing.io_capability)
+}
+
+// False if nothing is listening, so that the pairing can be rejected rather than left hanging
+fn send_pairing_event(event: PairingEvent) -> bool {
+    let sent = match ESP32_BLUETOOTH_GLOBALS.pairing_events.lock() {
+        Ok(sender) => sender
+            .as_ref()
+            .is_some_and(|sender| sender.unbounded_send(event).is_ok()),
+        Err(e) => {
+            log::error!("Failed to lock pairing events: {}", e);
+            false
         }
-        _ => log::info!("GAP event {}", event),
+    };
+    if !sent {
+        log::warn!("Nothing is listening to pairing events");
     }
+    sent
 }
@@ -5,14 +5,15 @@ use std::fmt::Display;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use crate::bluetooth_hal::{bdaddr_to_string, BDAddr};
+use crate::uuids::Bluetooth16bitUUIDEnum;
 
 // See https://btprodspecificationrefs.blob.core.windows.net/assigned-numbers/Assigned%20Number%20Types/Assigned%20Numbers.pdf
 // section 2.
 #[repr(u8)]
-#[derive(Debug, FromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
 pub enum CommonDataType {
     Flags = 0x01,
     Incomplete16bitServiceClassUUIDs = 0x02,
@@ -27,7 +28,7 @@ pub enum CommonDataType {
     ClassOfDevice = 0x0d,
     SimplePairingHashC192 = 0x0e,
     SimplePairingRandomizerR192 = 0x0f,
-    DeviceID = 0x10, // or Security Manager TK Value ?
+    DeviceID = 0x10, // Security Manager TK Value in LE advertising
     SecurityManagerOutOfBandFlags = 0x11,
     PeripheralConnectionIntervalRange = 0x12,
     ListOf16BitServiceSolicitation = 0x14,
@@ -35,7 +36,8 @@ pub enum CommonDataType {
     ManufacturerSpecificData = 0xff,
 }
 
-#[derive(Debug, FromPrimitive)]
+// Class of Device, see the Assigned Numbers section 2.8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
 pub enum MajorClass {
     Miscellaneous = 0,
     Computer = 1,
@@ -50,132 +52,340 @@ pub enum MajorClass {
     Uncategorized = 0x1f,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum ComputerMinor {
+    Uncategorized = 0,
+    Desktop = 1,
+    Server = 2,
+    Laptop = 3,
+    HandheldPda = 4,
+    PalmSizePda = 5,
+    Wearable = 6,
+    Tablet = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum PhoneMinor {
+    Uncategorized = 0,
+    Cellular = 1,
+    Cordless = 2,
+    Smartphone = 3,
+    WiredModem = 4,
+    IsdnAccess = 5,
+}
+
+// How busy a network access point is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum NetworkLoad {
+    FullyAvailable = 0,
+    Utilised1To17 = 1,
+    Utilised17To33 = 2,
+    Utilised33To50 = 3,
+    Utilised50To67 = 4,
+    Utilised67To83 = 5,
+    Utilised83To99 = 6,
+    NoServiceAvailable = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum AudioVideoMinor {
+    Uncategorized = 0,
+    WearableHeadset = 1,
+    HandsFree = 2,
+    Microphone = 4,
+    Loudspeaker = 5,
+    Headphones = 6,
+    PortableAudio = 7,
+    CarAudio = 8,
+    SetTopBox = 9,
+    HifiAudio = 10,
+    Vcr = 11,
+    VideoCamera = 12,
+    Camcorder = 13,
+    VideoMonitor = 14,
+    VideoDisplayAndLoudspeaker = 15,
+    VideoConferencing = 16,
+    GamingToy = 18,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum PeripheralMinor {
+    Uncategorized = 0,
+    Joystick = 1,
+    Gamepad = 2,
+    RemoteControl = 3,
+    SensingDevice = 4,
+    DigitizerTablet = 5,
+    CardReader = 6,
+    DigitalPen = 7,
+    HandheldScanner = 8,
+    HandheldGesturalInput = 9,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum WearableMinor {
+    Wristwatch = 1,
+    Pager = 2,
+    Jacket = 3,
+    Helmet = 4,
+    Glasses = 5,
+    Pin = 6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum ToyMinor {
+    Robot = 1,
+    Vehicle = 2,
+    Doll = 3,
+    Controller = 4,
+    Game = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum HealthMinor {
+    Undefined = 0,
+    BloodPressureMonitor = 1,
+    Thermometer = 2,
+    WeighingScale = 3,
+    GlucoseMeter = 4,
+    PulseOximeter = 5,
+    HeartRateMonitor = 6,
+    HealthDataDisplay = 7,
+    StepCounter = 8,
+    BodyCompositionAnalyzer = 9,
+    PeakFlowMonitor = 10,
+    MedicationMonitor = 11,
+    KneeProsthesis = 12,
+    AnkleProsthesis = 13,
+    GenericHealthManager = 14,
+    PersonalMobilityDevice = 15,
+}
+
+// The minor class, which means something different for each major class. Values which aren't
+// assigned, and the minors of the miscellaneous and uncategorized majors, are Other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinorClass {
+    Computer(ComputerMinor),
+    Phone(PhoneMinor),
+    Network(NetworkLoad),
+    AudioVideo(AudioVideoMinor),
+    Peripheral {
+        keyboard: bool,
+        pointing: bool,
+        device: PeripheralMinor,
+    },
+    // any combination
+    Imaging {
+        display: bool,
+        camera: bool,
+        scanner: bool,
+        printer: bool,
+    },
+    Wearable(WearableMinor),
+    Toy(ToyMinor),
+    Health(HealthMinor),
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceClass {
+    LimitedDiscoverableMode,
+    LeAudio,
+    Positioning,
+    Networking,
+    Rendering,
+    Capturing,
+    ObjectTransfer,
+    Audio,
+    Telephony,
+    Information,
+}
+
+impl ServiceClass {
+    const ALL: [(u8, ServiceClass); 10] = [
+        (0, ServiceClass::LimitedDiscoverableMode),
+        (1, ServiceClass::LeAudio),
+        (3, ServiceClass::Positioning),
+        (4, ServiceClass::Networking),
+        (5, ServiceClass::Rendering),
+        (6, ServiceClass::Capturing),
+        (7, ServiceClass::ObjectTransfer),
+        (8, ServiceClass::Audio),
+        (9, ServiceClass::Telephony),
+        (10, ServiceClass::Information),
+    ];
+
+    fn bit(self) -> u8 {
+        ServiceClass::ALL
+            .iter()
+            .find(|(_, class)| *class == self)
+            .map_or(0, |(bit, _)| *bit)
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct ClassOfDevice {
     pub value: u32,
 }
 
 impl ClassOfDevice {
-    fn get_minor_device_class(&self) -> u8 {
-        (self.value & 0xf6) as u8 >> 2
+    pub fn get_minor_device_class(&self) -> u8 {
+        (self.value & 0xfc) as u8 >> 2
     }
-    fn get_major_device_class(&self) -> Result<MajorClass> {
+    pub fn get_major_device_class(&self) -> Result<MajorClass> {
         let value = (self.value >> 8) as u8 & 0x1f;
         let opt_class: Option<MajorClass> = FromPrimitive::from_u8(value);
 
         match opt_class {
             Some(mc) => Ok(mc),
-            // None => Err(std::error::Error::new((format!("Invalid major {}", value)))),
             None => Err(anyhow::anyhow!(format!("Invalid major {value}"))),
         }
     }
 
-    fn get_service_classes(&self) -> u16 {
-        (self.value >> 13) as u16 & 0x7ff
-    }
-    /*
-       fn major_device_class_to_string(class: u8) -> &'static str {
-           match class {
-               0 => "Miscellaneous",
-               1 => "Computer",
-               2 => "Phone",
-               3 => "LAN /Network Access point",
-               4 => "Audio/Video",
-               5 => "Peripheral",
-               6 => "Imaging",
-               7 => "Wearable",
-               8 => "Toy",
-               9 => "Health",
-               0x1f => "Uncategorized",
-               _ => "Invalid",
-           }
-       }
-    */
-    fn minor_device_class_to_string(major: u8, minor: u8) -> String {
-        match major {
-            2 => {
-                // Phone
-                match minor {
-                    1 => "Cellular".to_string(),
-                    _ => format!("Phone major, minor {minor} NIY"),
-                }
+    pub fn minor_class(&self) -> MinorClass {
+        let minor = self.get_minor_device_class();
+        let other = MinorClass::Other(minor);
+
+        match self.get_major_device_class() {
+            Ok(MajorClass::Computer) => {
+                FromPrimitive::from_u8(minor).map_or(other, MinorClass::Computer)
             }
-            4 => {
-                // Audio/Video
-                match minor {
-                    1 => "Wearable Headset Device".to_string(),
-                    _ => format!("Audio major, minor {minor} NIY"),
-                }
+            Ok(MajorClass::Phone) => FromPrimitive::from_u8(minor).map_or(other, MinorClass::Phone),
+            // the lower bits are reserved
+            Ok(MajorClass::Network) => {
+                FromPrimitive::from_u8(minor >> 3).map_or(other, MinorClass::Network)
+            }
+            Ok(MajorClass::AudioVideo) => {
+                FromPrimitive::from_u8(minor).map_or(other, MinorClass::AudioVideo)
+            }
+            Ok(MajorClass::Peripheral) => match FromPrimitive::from_u8(minor & 0x0f) {
+                Some(device) => MinorClass::Peripheral {
+                    keyboard: minor & 0x10 != 0,
+                    pointing: minor & 0x20 != 0,
+                    device,
+                },
+                None => other,
+            },
+            Ok(MajorClass::Imaging) => MinorClass::Imaging {
+                display: minor & 0x04 != 0,
+                camera: minor & 0x08 != 0,
+                scanner: minor & 0x10 != 0,
+                printer: minor & 0x20 != 0,
+            },
+            Ok(MajorClass::Wearable) => {
+                FromPrimitive::from_u8(minor).map_or(other, MinorClass::Wearable)
             }
-            _ => format!("Unimplemented major {major}"),
+            Ok(MajorClass::Toy) => FromPrimitive::from_u8(minor).map_or(other, MinorClass::Toy),
+            Ok(MajorClass::Health) => {
+                FromPrimitive::from_u8(minor).map_or(other, MinorClass::Health)
+            }
+            Ok(MajorClass::Miscellaneous | MajorClass::Uncategorized) | Err(_) => other,
         }
-        /*        match class {
-                   0 => "Uncategorized",
-                   1 => "Desktop Workstation",
-                   2 => "Server-class computer",
-                   3 => "Laptop",
-        */
-        // format!("Minor: 0x{:02x}-0x{:02x}", major, minor)
     }
 
-    fn service_classes_to_string(service: u16) -> String {
-        const BITS: [(u8, &str); 9] = [
-            (0, "Limited Discoverable Mode"),
-            (3, "Positioning"),
-            (4, "Networking"),
-            (5, "Rendering"),
-            (6, "Capturing"),
-            (7, "Object Transfer"),
-            (8, "Audio"),
-            (9, "Telephony"),
-            (10, "Information"),
-        ];
+    fn get_service_classes(&self) -> u16 {
+        (self.value >> 13) as u16 & 0x7ff
+    }
 
-        let mut str = String::new();
-        let mut first = true;
+    pub fn service_classes(&self) -> Vec<ServiceClass> {
+        ServiceClass::ALL
+            .iter()
+            .filter(|(bit, _)| self.get_service_classes() & (1 << bit) != 0)
+            .map(|(_, class)| *class)
+            .collect()
+    }
 
-        for bit in BITS {
-            if service & (1 << bit.0) != 0 {
-                str.push_str(bit.1);
-                if first {
-                    first = false
-                } else {
-                    str.push_str(", ");
-                }
+    pub fn has_service_class(&self, class: ServiceClass) -> bool {
+        self.get_service_classes() & (1 << class.bit()) != 0
+    }
+
+    // Devices which play audio we send them. An uncategorized audio/video device counts if it
+    // says it renders audio.
+    pub fn is_audio_sink(&self) -> bool {
+        match self.minor_class() {
+            MinorClass::AudioVideo(AudioVideoMinor::Uncategorized) => {
+                self.has_service_class(ServiceClass::Rendering)
+                    && self.has_service_class(ServiceClass::Audio)
             }
+            MinorClass::AudioVideo(minor) => matches!(
+                minor,
+                AudioVideoMinor::WearableHeadset
+                    | AudioVideoMinor::HandsFree
+                    | AudioVideoMinor::Loudspeaker
+                    | AudioVideoMinor::Headphones
+                    | AudioVideoMinor::PortableAudio
+                    | AudioVideoMinor::CarAudio
+                    | AudioVideoMinor::HifiAudio
+                    | AudioVideoMinor::VideoDisplayAndLoudspeaker
+            ),
+            _ => false,
         }
-        str
     }
 }
 
 impl Display for ClassOfDevice {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let major = self.get_major_device_class();
-        write!(
-            f,
-            "Major: {major:?}",
-            // ClassOfDevice::major_device_class_to_string(major)
-        )?;
-        write!(
-            f,
-            ", Minor: {}",
-            ClassOfDevice::minor_device_class_to_string(
-                self.get_major_device_class().unwrap() as u8,
-                self.get_minor_device_class()
-            )
-        )?;
-        write!(
-            f,
-            ", Service Classes: {}",
-            ClassOfDevice::service_classes_to_string(self.get_service_classes())
-        )
+        match self.get_major_device_class() {
+            Ok(major) => write!(f, "Major: {major:?}")?,
+            Err(_) => write!(f, "Major: reserved")?,
+        }
+        match self.minor_class() {
+            MinorClass::Other(minor) => write!(f, ", Minor: {minor}")?,
+            minor => write!(f, ", Minor: {minor:?}")?,
+        }
+
+        let services: Vec<String> = self
+            .service_classes()
+            .iter()
+            .map(|class| format!("{class:?}"))
+            .collect();
+        write!(f, ", Service Classes: {}", services.join(", "))
     }
 }
 
 impl Debug for ClassOfDevice {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{self}")
-        // f.debug_struct("ClassOfDevice").field("value", &self.value).finish()
+        write!(f, "{self} ({:06x})", self.value)
+    }
+}
+
+// A service class a device has, as found by its SDP records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceUuid {
+    Known(Bluetooth16bitUUIDEnum),
+    Uuid16(u16),
+    Uuid32(u32),
+    Uuid128([u8; 16]),
+}
+
+impl ServiceUuid {
+    pub fn from_u16(value: u16) -> Self {
+        match FromPrimitive::from_u16(value) {
+            Some(known) => ServiceUuid::Known(known),
+            None => ServiceUuid::Uuid16(value),
+        }
+    }
+
+    // 32 bit UUIDs with the upper half zero are 16 bit ones
+    pub fn from_u32(value: u32) -> Self {
+        match u16::try_from(value) {
+            Ok(value) => ServiceUuid::from_u16(value),
+            Err(_) => ServiceUuid::Uuid32(value),
+        }
+    }
+
+    // Little endian, as sent. Those based on the Bluetooth base UUID are shorter ones.
+    pub fn from_uuid128(bytes: [u8; 16]) -> Self {
+        const BASE_UUID: [u8; 12] = [
+            0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00,
+        ];
+        if bytes[..12] == BASE_UUID {
+            ServiceUuid::from_u32(u32::from_le_bytes([
+                bytes[12], bytes[13], bytes[14], bytes[15],
+            ]))
+        } else {
+            ServiceUuid::Uuid128(bytes)
+        }
     }
 }
 
@@ -187,14 +397,43 @@ pub enum DeviceProperty {
     Eir(Vec<u8>),
 }
 
-#[derive(Default, Clone)]
+// The Device ID record, see the Device ID profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId {
+    pub vendor_id_source: u16,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub version: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManufacturerData {
+    pub company_id: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default, Clone, PartialEq)]
 pub struct ScannedDevice {
     pub address: BDAddr,
     pub class: Option<ClassOfDevice>,
     pub name: Option<String>,
+    // only the start of the name was sent
+    pub name_shortened: bool,
     pub rssi: Option<i8>,
-    pub complete_16_bit_service_class_uuids: Option<Vec<u16>>,
-    pub complete_32_bit_service_class_uuids: Option<Vec<u32>>,
+    pub flags: Option<u8>,
+    pub tx_power: Option<i8>,
+    // The service classes in the EIR data, of any length. Incomplete when the device has more
+    // than it had room for.
+    pub service_uuids: Vec<ServiceUuid>,
+    pub service_uuids_incomplete: bool,
+    pub solicited_service_uuids: Vec<ServiceUuid>,
+    pub device_id: Option<DeviceId>,
+    pub manufacturer_data: Vec<ManufacturerData>,
+    pub simple_pairing_hash: Option<[u8; 16]>,
+    pub simple_pairing_randomizer: Option<[u8; 16]>,
+    pub security_manager_oob_flags: Option<u8>,
+    // minimum and maximum, in units of 1.25 ms
+    pub connection_interval_range: Option<(u16, u16)>,
 }
 
 impl ScannedDevice {
@@ -203,85 +442,132 @@ impl ScannedDevice {
             DeviceProperty::Name(name) => self.name = Some(name),
             DeviceProperty::Class(class) => self.class = Some(class),
             DeviceProperty::Rssi(rssi) => self.rssi = Some(rssi),
-            DeviceProperty::Eir(eir) => self.parse_eir(eir),
+            DeviceProperty::Eir(eir) => self.parse_eir(&eir),
         }
     }
 
-    fn parse_eir(&mut self, eir: Vec<u8>) {
-        let mut index = 0;
-        while index < eir.len() {
-            let len: usize = eir[index] as usize;
-
+    // The data comes from other devices, so nothing in it is trusted. A record which doesn't fit
+    // ends the parsing; records of unknown types or with bad lengths are skipped.
+    pub fn parse_eir(&mut self, eir: &[u8]) {
+        let mut rest = eir;
+        while let Some((&len, after)) = rest.split_first() {
+            let len = len as usize;
+            // the rest is padding
             if len == 0 {
                 break;
             }
-            index += 1;
-            let t_val = eir[index];
-            let t: Option<CommonDataType> = FromPrimitive::from_u8(eir[index]);
-            let record_start = index;
-
-            if let Some(typ) = t {
-                index += 1;
-                match typ {
-                    CommonDataType::Complete16bitServiceClassUUIDs => {
-                        let mut uuids = Vec::new();
-
-                        while index < (record_start + len) {
-                            let uuid = (eir[index] as u16) | (eir[index + 1] as u16) << 8;
-                            index += 2;
-                            uuids.push(uuid);
-                        }
-                        // log::info!("uuids={:?}", uuids);
-                        self.complete_16_bit_service_class_uuids = Some(uuids);
-                    }
-                    CommonDataType::Complete32bitServiceClassUUIDs => {
-                        let mut uuids = Vec::new();
-
-                        while index < (record_start + len) {
-                            let uuid = (eir[index] as u32)
-                                | (eir[index + 1] as u32) << 8
-                                | (eir[index + 2] as u32) << 16
-                                | (eir[index + 3] as u32) << 24;
-                            index += 4;
-                            uuids.push(uuid);
-                        }
-                        // log::info!("uuids={:?}", uuids);
-                        self.complete_32_bit_service_class_uuids = Some(uuids);
-                    }
-                    CommonDataType::CompleteLocalName => {
-                        // log::info!("Name len={}", len);
-                        let s = String::from_utf8(eir[index..(index + len - 1)].to_vec());
-                        match s {
-                            Ok(s) => {
-                                log::info!("name={}", s);
-                                self.name = Some(s)
-                            }
-                            Err(e) => log::error!("Failed to parse CompleteLocalName: {}", e),
-                        }
-                        index += len - 1;
-                    }
-                    _ => {
-                        log::warn!("Parsing eir, got type: {:?}", typ);
-                        index += len - 1
-                    }
-                } // end of match
-            }
-            // end of if let
+            let Some((&t_val, data)) = after.get(..len).and_then(|record| record.split_first())
             else {
-                log::warn!("Parsing eir, got type: {:?}", t_val);
-                index += len;
+                log::warn!("Parsing eir, record of {} bytes is truncated", len);
+                break;
+            };
+            rest = &after[len..];
+
+            match FromPrimitive::from_u8(t_val) {
+                Some(typ) => self.add_eir_record(typ, data),
+                None => log::warn!("Parsing eir, got type: {:?}", t_val),
             }
-        } // end of while else
+        }
+    }
+
+    fn add_eir_record(&mut self, typ: CommonDataType, data: &[u8]) {
+        match typ {
+            CommonDataType::Flags => match data {
+                [flags] => self.flags = Some(*flags),
+                _ => log::warn!("Parsing eir, flags of {} bytes", data.len()),
+            },
+            CommonDataType::Incomplete16bitServiceClassUUIDs
+            | CommonDataType::Complete16bitServiceClassUUIDs
+            | CommonDataType::Incomplete32bitServiceClassUUIDs
+            | CommonDataType::Complete32bitServiceClassUUIDs
+            | CommonDataType::Incomplete128bitServiceClassUUIDs
+            | CommonDataType::Complete128bitServiceClassUUIDs => {
+                let incomplete = matches!(
+                    typ,
+                    CommonDataType::Incomplete16bitServiceClassUUIDs
+                        | CommonDataType::Incomplete32bitServiceClassUUIDs
+                        | CommonDataType::Incomplete128bitServiceClassUUIDs
+                );
+                self.service_uuids_incomplete |= incomplete;
+                self.service_uuids.extend(parse_uuids(typ, data));
+            }
+            CommonDataType::ListOf16BitServiceSolicitation
+            | CommonDataType::ListOf128BitServiceSolicitation => {
+                self.solicited_service_uuids.extend(parse_uuids(typ, data));
+            }
+            CommonDataType::ShortenedLocalName => {
+                // the complete name wins, whichever comes first
+                if self.name.is_none() || self.name_shortened {
+                    self.name = Some(parse_name(data));
+                    self.name_shortened = true;
+                }
+            }
+            CommonDataType::CompleteLocalName => {
+                self.name = Some(parse_name(data));
+                self.name_shortened = false;
+            }
+            CommonDataType::TxPowerLevel => match data {
+                [power] => self.tx_power = Some(*power as i8),
+                _ => log::warn!("Parsing eir, TX power of {} bytes", data.len()),
+            },
+            CommonDataType::ClassOfDevice => match data {
+                // the one from the inquiry is the same, and it wins
+                [a, b, c] => {
+                    self.class.get_or_insert(ClassOfDevice {
+                        value: u32::from_le_bytes([*a, *b, *c, 0]),
+                    });
+                }
+                _ => log::warn!("Parsing eir, class of device of {} bytes", data.len()),
+            },
+            CommonDataType::SimplePairingHashC192 => match data.try_into() {
+                Ok(hash) => self.simple_pairing_hash = Some(hash),
+                Err(_) => log::warn!("Parsing eir, pairing hash of {} bytes", data.len()),
+            },
+            CommonDataType::SimplePairingRandomizerR192 => match data.try_into() {
+                Ok(randomizer) => self.simple_pairing_randomizer = Some(randomizer),
+                Err(_) => log::warn!("Parsing eir, pairing randomizer of {} bytes", data.len()),
+            },
+            CommonDataType::DeviceID => match data {
+                [a, b, c, d, e, f, g, h] => {
+                    self.device_id = Some(DeviceId {
+                        vendor_id_source: u16::from_le_bytes([*a, *b]),
+                        vendor_id: u16::from_le_bytes([*c, *d]),
+                        product_id: u16::from_le_bytes([*e, *f]),
+                        version: u16::from_le_bytes([*g, *h]),
+                    })
+                }
+                _ => log::warn!("Parsing eir, device ID of {} bytes", data.len()),
+            },
+            CommonDataType::SecurityManagerOutOfBandFlags => match data {
+                [flags] => self.security_manager_oob_flags = Some(*flags),
+                _ => log::warn!("Parsing eir, OOB flags of {} bytes", data.len()),
+            },
+            CommonDataType::PeripheralConnectionIntervalRange => match data {
+                [a, b, c, d] => {
+                    self.connection_interval_range =
+                        Some((u16::from_le_bytes([*a, *b]), u16::from_le_bytes([*c, *d])))
+                }
+                _ => log::warn!("Parsing eir, interval range of {} bytes", data.len()),
+            },
+            CommonDataType::ManufacturerSpecificData => match data {
+                [a, b, data @ ..] => self.manufacturer_data.push(ManufacturerData {
+                    company_id: u16::from_le_bytes([*a, *b]),
+                    data: data.to_vec(),
+                }),
+                _ => log::warn!("Parsing eir, manufacturer data of {} bytes", data.len()),
+            },
+        }
     }
 
     pub fn has_16bit_uuid(&self, uuid: u16) -> bool {
-        if let Some(uuids) = &self.complete_16_bit_service_class_uuids {
-            // log::info!("uuids={:?}, uuid={}", uuids, uuid);
-            uuids.contains(&uuid)
-        } else {
-            log::info!("Device doesn't have 16 bit uuids");
-            false
+        if self.service_uuids.is_empty() {
+            log::info!("Device doesn't have service uuids");
         }
+        self.service_uuids.iter().any(|service| match service {
+            ServiceUuid::Known(known) => *known as u16 == uuid,
+            ServiceUuid::Uuid16(value) => *value == uuid,
+            _ => false,
+        })
     }
     /*
     pub fn get_name(&self) -> &str {
@@ -290,6 +576,42 @@ impl ScannedDevice {
      */
 }
 
+// The size of the UUIDs comes from the type, a partial UUID at the end is dropped
+fn parse_uuids(typ: CommonDataType, data: &[u8]) -> Vec<ServiceUuid> {
+    let size = match typ {
+        CommonDataType::Incomplete16bitServiceClassUUIDs
+        | CommonDataType::Complete16bitServiceClassUUIDs
+        | CommonDataType::ListOf16BitServiceSolicitation => 2,
+        CommonDataType::Incomplete32bitServiceClassUUIDs
+        | CommonDataType::Complete32bitServiceClassUUIDs => 4,
+        _ => 16,
+    };
+    let uuids = data.chunks_exact(size);
+    if !uuids.remainder().is_empty() {
+        log::warn!("Parsing eir, {:?} of {} bytes", typ, data.len());
+    }
+
+    uuids
+        .map(|uuid| match *uuid {
+            [a, b] => ServiceUuid::from_u16(u16::from_le_bytes([a, b])),
+            [a, b, c, d] => ServiceUuid::from_u32(u32::from_le_bytes([a, b, c, d])),
+            _ => {
+                let mut bytes = [0; 16];
+                bytes.copy_from_slice(uuid);
+                ServiceUuid::from_uuid128(bytes)
+            }
+        })
+        .collect()
+}
+
+// Names are UTF-8, in EIR data and the BDNAME property alike, but a shortened one may end in the
+// middle of a character
+pub fn parse_name(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
 impl Debug for ScannedDevice {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Address: {} ", bdaddr_to_string(self.address))?;
@@ -299,15 +621,27 @@ impl Debug for ScannedDevice {
         }
 
         if let Some(name) = &self.name {
-            write!(f, "Name: {name} ")?;
+            write!(f, "Name: {name}")?;
+            if self.name_shortened {
+                write!(f, "...")?;
+            }
+            write!(f, " ")?;
         }
 
-        if let Some(uuids) = &self.complete_16_bit_service_class_uuids {
-            write!(f, "Complete 16 bit UUIDS: [")?;
-            for uuid in uuids {
-                write!(f, "{uuid:04x} ")?;
+        if !self.service_uuids.is_empty() {
+            write!(f, "UUIDs: {:?}", self.service_uuids)?;
+            if self.service_uuids_incomplete {
+                write!(f, " and more")?;
             }
-            write!(f, "] ")?;
+            write!(f, " ")?;
+        }
+
+        if let Some(device_id) = &self.device_id {
+            write!(f, "{device_id:?} ")?;
+        }
+
+        if let Some(tx_power) = &self.tx_power {
+            write!(f, "TX power: {tx_power} ")?;
         }
 
         if let Some(rssi) = &self.rssi {
@@ -317,3 +651,81 @@ impl Debug for ScannedDevice {
         }
     }
 }
+
+// What the player can show and enter during Secure Simple Pairing, which decides between just
+// works, numeric comparison and passkey entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoCapability {
+    DisplayOnly,
+    DisplayYesNo,
+    KeyboardOnly,
+    NoInputNoOutput,
+}
+
+#[derive(Debug, Clone)]
+pub struct PairingConfig {
+    pub io_capability: IoCapability,
+    // for sinks which only do legacy pairing, usually 0000 or 1234. Without one, a sink asking
+    // for 16 digits gets 16 zero bytes, others get 1234.
+    pub legacy_pin: Option<String>,
+}
+
+impl Default for PairingConfig {
+    // A headless player pairs with just works
+    fn default() -> Self {
+        PairingConfig {
+            io_capability: IoCapability::NoInputNoOutput,
+            legacy_pin: None,
+        }
+    }
+}
+
+impl PairingConfig {
+    pub const MAX_PIN_LEN: usize = 16;
+
+    pub fn validate(&self) -> Result<()> {
+        match &self.legacy_pin {
+            Some(pin) if pin.is_empty() || pin.len() > Self::MAX_PIN_LEN => bail!(
+                "PIN must be 1 to {} characters, not {}",
+                Self::MAX_PIN_LEN,
+                pin.len()
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    // Sinks may ask for a 16 digit PIN, shorter ones set are padded with zeros
+    pub fn pin_code(&self, min_16_digits: bool) -> Vec<u8> {
+        match &self.legacy_pin {
+            Some(pin) => {
+                let mut pin = pin.as_bytes().to_vec();
+                if min_16_digits {
+                    pin.resize(Self::MAX_PIN_LEN, b'0');
+                }
+                pin
+            }
+            None if min_16_digits => vec![0; Self::MAX_PIN_LEN],
+            None => b"1234".to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Authentication {
+    pub address: BDAddr,
+    pub name: Option<String>,
+    pub success: bool,
+}
+
+// Pairing steps which need the user, and how pairing ended
+#[derive(Debug, Clone)]
+pub enum PairingEvent {
+    // Numeric comparison: answer with gap_confirm_pairing once the user has checked that the sink
+    // shows the same number
+    ConfirmPasskey { address: BDAddr, passkey: u32 },
+    // Answer with gap_enter_passkey, with the passkey the sink shows
+    EnterPasskey { address: BDAddr },
+    // The user enters this passkey on the sink
+    ShowPasskey { address: BDAddr, passkey: u32 },
+    Completed(Authentication),
+}
@@ -1,7 +1,10 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedReceiver;
 
-use crate::bluetooth_gap_hal::ScannedDevice;
+use crate::bluetooth_gap_hal::{
+    Authentication, PairingConfig, PairingEvent, ScannedDevice, ServiceUuid,
+};
 
 pub type BDAddr = [u8; 6];
 
@@ -10,11 +13,121 @@ pub trait Bluetooth<'a>: Send {
     fn init(&mut self, device_name: &str) -> Result<()>;
     fn deinit(&mut self) -> Result<()>;
 
+    // Everything the stack reports, events the player doesn't expect as errors. A new receiver
+    // replaces the earlier one.
+    fn events(&self) -> Result<UnboundedReceiver<Result<BluetoothEvent>>>;
+
     fn gap_start_discovery(&self) -> Result<async_broadcast::Receiver<ScannedDevice>>;
     fn gap_cancel_discovery(&self) -> Result<()>;
+    // Devices we have a link key for
+    fn gap_bonded_devices(&self) -> Result<Vec<BDAddr>>;
+
+    // IO capability for Secure Simple Pairing, and the PIN for legacy pairing. After init, before
+    // connecting.
+    fn gap_set_pairing(&mut self, config: PairingConfig) -> Result<()>;
+    // Pairing steps which need the user, and pairing results. A new receiver replaces the earlier
+    // one. Without a receiver, pairing which needs the user is rejected.
+    fn gap_pairing_events(&self) -> Result<UnboundedReceiver<PairingEvent>>;
+    fn gap_confirm_pairing(&self, addr: &BDAddr, accept: bool) -> Result<()>;
+    // None rejects the pairing
+    fn gap_enter_passkey(&self, addr: &BDAddr, passkey: Option<u32>) -> Result<()>;
+    // The last authentication with the device since boot
+    fn gap_authentication(&self, addr: &BDAddr) -> Option<Authentication>;
+    // The services in the device's SDP records, which also identify devices without EIR data.
+    // The device is paged, so not while discovering; one query at a time.
+    async fn gap_get_remote_services(&self, addr: &BDAddr) -> Result<Vec<ServiceUuid>>;
+    // Whether the device has an SDP record for the service
+    async fn gap_has_remote_service(&self, addr: &BDAddr, uuid: ServiceUuid) -> Result<bool>;
 
     async fn a2dp_connect(&mut self, addr: &BDAddr) -> Result<()>;
-    async fn a2dp_play(&mut self, stream: Box<dyn Stream<i16>>) -> Result<()>;
+
+    // Buttons pressed on the connected device, and its volume changes. A new receiver replaces the
+    // earlier one.
+    fn avrcp_commands(&self) -> Result<UnboundedReceiver<RemoteCommand>>;
+}
+
+// AVRCP passthrough commands, and absolute volume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteCommand {
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Previous,
+    FastForward,
+    Rewind,
+    // The volume of the sink changed, in percent. Only sent by sinks which support absolute
+    // volume, and they apply it themselves.
+    Volume(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum A2dpConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+    Disconnecting,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaControl {
+    CheckSourceReady,
+    Start,
+    Suspend,
+}
+
+// GAP and A2DP callbacks of the stack, as they happen
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub enum BluetoothEvent {
+    DiscoveryStarted,
+    DiscoveryStopped,
+    AclConnected {
+        address: BDAddr,
+        success: bool,
+    },
+    AclDisconnected {
+        address: BDAddr,
+        reason: u32,
+    },
+    RemoteServices {
+        address: BDAddr,
+        success: bool,
+        uuids: Vec<ServiceUuid>,
+    },
+    RemoteServiceRecord {
+        address: BDAddr,
+        success: bool,
+    },
+    Authenticated(Authentication),
+    PowerModeChanged {
+        address: BDAddr,
+        mode: u32,
+    },
+    EirConfigured {
+        success: bool,
+    },
+    A2dpConnectionState {
+        address: BDAddr,
+        state: A2dpConnectionState,
+        // only meaningful when disconnected
+        normal_disconnect: bool,
+    },
+    A2dpAudioState {
+        address: BDAddr,
+        started: bool,
+    },
+    // only A2DP sinks should get it
+    A2dpAudioConfig {
+        address: BDAddr,
+    },
+    A2dpMediaControlAck {
+        command: MediaControl,
+        success: bool,
+    },
+    A2dpProfileState {
+        initialised: bool,
+    },
 }
 
 pub trait Stream<T>: Send {
@@ -0,0 +1,303 @@
+// A command line on the serial console, the UART the log goes to, for controlling the player until
+// it has buttons or a web interface. Every line typed is one command:
+//
+//   play [folder]    play a folder, absolute or below the SD card, e.g. play audiobooks/dracula;
+//                    without a folder the whole card plays
+//   signals          play the test signals
+//   volume <0-100>   set the volume, in percent
+//   output <bluetooth|i2s>
+//                    play through the Bluetooth sink or the I2S amplifier, also after a restart
+//   disconnect <stop|continue|wait>
+//                    when the Bluetooth sink goes away, stop, continue on the I2S amplifier, or
+//                    pause until it is back
+//   eq <preset>      use an equalizer preset, also after a restart: flat, bass, treble, voice,
+//                    loudness, or one saved
+//   eq save <name> <band>...
+//                    save the bands as a preset and use it. A band is type:Hz:dB:Q, with type
+//                    low or high for a shelf, or peak, e.g. eq save warm low:200:3:0.707
+//   night <on|off>   compress loud passages for quiet listening, until a restart
+//   speed <0.75-2>   playback speed, 1 is normal, until a restart; the pitch stays the same
+//   allow <sink>     connect only to the sinks allowed, from the next connection; a sink is an
+//                    address, e.g. 00:11:22:33:44:55, or a name, with * for any text, e.g. JBL*.
+//                    allow any forgets them
+//   deny <sink>      never connect to the sink; deny none forgets the sinks denied
+//   pin <PIN|default>
+//                    the PIN for sinks which only do legacy pairing, from the next start of
+//                    Bluetooth
+//   pairing <none|display|yesno|keyboard>
+//                    what the player can show and enter when pairing, from the next start of
+//                    Bluetooth; none pairs without asking
+//   pair <yes|no|passkey>
+//                    answer a sink asking to pair: yes or no if it shows the number logged, or
+//                    the passkey it shows
+//
+// The console is started the first time the player connects to it, and sends the commands to
+// whichever player connected last. Pairing answers go to the pairing handler connected last.
+
+use std::{
+    io::BufRead,
+    ptr,
+    sync::{Mutex, Once},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Result};
+use esp_idf_sys::{
+    esp, esp_line_endings_t_ESP_LINE_ENDINGS_CR, esp_vfs_dev_uart_port_set_rx_line_endings,
+    esp_vfs_dev_uart_use_driver, uart_driver_install, CONFIG_ESP_CONSOLE_UART_NUM,
+};
+use futures::channel::mpsc::UnboundedSender;
+use lazy_static::lazy_static;
+
+use crate::{
+    audio_dsp::MAX_VOLUME,
+    audio_eq::{EqBand, FilterType},
+    audio_output::{DisconnectPolicy, Output},
+    audio_tempo::{MAX_SPEED, MIN_SPEED},
+    bluetooth_gap_hal::IoCapability,
+    playback_state::{ControlRequest, PairingReply},
+    sink_selection::DeviceMatch,
+};
+
+const RX_BUFFER_SIZE: i32 = 256;
+
+lazy_static! {
+    static ref REQUESTS: Mutex<Option<UnboundedSender<ControlRequest>>> = Mutex::new(None);
+    static ref PAIRING_REPLIES: Mutex<Option<UnboundedSender<PairingReply>>> = Mutex::new(None);
+}
+static START: Once = Once::new();
+
+// Sends the commands typed from now on to requests
+pub fn connect(requests: UnboundedSender<ControlRequest>) {
+    start_once();
+    match REQUESTS.lock() {
+        Ok(mut sender) => *sender = Some(requests),
+        Err(e) => log::error!("Failed to lock console requests: {}", e),
+    }
+}
+
+// Sends the pairing answers typed from now on to replies
+pub fn connect_pairing(replies: UnboundedSender<PairingReply>) {
+    start_once();
+    match PAIRING_REPLIES.lock() {
+        Ok(mut sender) => *sender = Some(replies),
+        Err(e) => log::error!("Failed to lock console pairing replies: {}", e),
+    }
+}
+
+fn start_once() {
+    START.call_once(|| {
+        if let Err(e) = start() {
+            log::error!("No console: {}", e);
+        }
+    });
+}
+
+fn start() -> Result<()> {
+    // Without the driver, reading stdin doesn't wait for input
+    unsafe {
+        esp!(uart_driver_install(
+            CONFIG_ESP_CONSOLE_UART_NUM as _,
+            RX_BUFFER_SIZE,
+            0,
+            0,
+            ptr::null_mut(),
+            0
+        ))?;
+        esp_vfs_dev_uart_use_driver(CONFIG_ESP_CONSOLE_UART_NUM as _);
+        // terminals send CR for enter
+        esp_vfs_dev_uart_port_set_rx_line_endings(
+            CONFIG_ESP_CONSOLE_UART_NUM as _,
+            esp_line_endings_t_ESP_LINE_ENDINGS_CR,
+        );
+    }
+
+    thread::Builder::new()
+        .name("console".to_owned())
+        .stack_size(8192)
+        .spawn(read_commands)
+        .map_err(|e| anyhow!("Failed to start console: {}", e))?;
+    Ok(())
+}
+
+fn read_commands() {
+    for line in std::io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Failed to read console: {}", e);
+                continue;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let result = match line.trim().strip_prefix("pair ") {
+            Some(answer) => parse_pairing_reply(answer.trim())
+                .map(|reply| send(&PAIRING_REPLIES, reply, "Not pairing")),
+            None => parse(&line).map(|request| send(&REQUESTS, request, "Not playing")),
+        };
+        if let Err(e) = result {
+            log::error!("{}", e);
+        }
+    }
+    log::warn!("Console closed");
+}
+
+fn send<T: std::fmt::Debug>(channel: &Mutex<Option<UnboundedSender<T>>>, message: T, nobody: &str) {
+    log::info!("Console: {:?}", message);
+
+    let sent = match channel.lock() {
+        Ok(sender) => sender
+            .as_ref()
+            .is_some_and(|sender| sender.unbounded_send(message).is_ok()),
+        Err(e) => {
+            log::error!("Failed to lock console channel: {}", e);
+            return;
+        }
+    };
+    if !sent {
+        log::warn!("{}, command ignored", nobody);
+    }
+}
+
+fn parse(line: &str) -> Result<ControlRequest> {
+    let line = line.trim();
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+    let argument = argument.trim();
+
+    match command {
+        "play" => Ok(ControlRequest::Play(argument.to_string())),
+        "signals" => Ok(ControlRequest::PlayTestSignals),
+        "volume" => match argument.parse() {
+            Ok(volume) if volume <= MAX_VOLUME => Ok(ControlRequest::Volume(volume)),
+            _ => bail!("Volume must be 0 to {}, not {:?}", MAX_VOLUME, argument),
+        },
+        "output" => match argument {
+            "bluetooth" => Ok(ControlRequest::Output(Output::Bluetooth)),
+            "i2s" => Ok(ControlRequest::Output(Output::I2s)),
+            _ => bail!("Output must be bluetooth or i2s, not {:?}", argument),
+        },
+        "disconnect" => match argument {
+            "stop" => Ok(ControlRequest::DisconnectPolicy(DisconnectPolicy::Stop)),
+            "continue" => Ok(ControlRequest::DisconnectPolicy(
+                DisconnectPolicy::ContinueLocally,
+            )),
+            "wait" => Ok(ControlRequest::DisconnectPolicy(
+                DisconnectPolicy::PauseAndWait,
+            )),
+            _ => bail!(
+                "Disconnect must be stop, continue or wait, not {:?}",
+                argument
+            ),
+        },
+        "eq" => {
+            let mut words = argument.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("save"), Some(name)) => {
+                    let bands = words.map(parse_band).collect::<Result<_>>()?;
+                    Ok(ControlRequest::SaveEqualizer(name.to_string(), bands))
+                }
+                (Some("save"), None) => bail!("Usage: eq save <name> <type:Hz:dB:Q>..."),
+                (Some(preset), None) => Ok(ControlRequest::Equalizer(preset.to_string())),
+                _ => bail!("Usage: eq <preset>"),
+            }
+        }
+        "night" => match argument {
+            "on" => Ok(ControlRequest::NightMode(true)),
+            "off" => Ok(ControlRequest::NightMode(false)),
+            _ => bail!("Night must be on or off, not {:?}", argument),
+        },
+        "speed" => match argument.parse() {
+            Ok(speed) if (MIN_SPEED..=MAX_SPEED).contains(&speed) => {
+                Ok(ControlRequest::Speed(speed))
+            }
+            _ => bail!(
+                "Speed must be {} to {}, not {:?}",
+                MIN_SPEED,
+                MAX_SPEED,
+                argument
+            ),
+        },
+        "allow" => match argument {
+            "" => bail!("Usage: allow <address|name>, or allow any"),
+            "any" => Ok(ControlRequest::AllowSink(None)),
+            _ => Ok(ControlRequest::AllowSink(Some(DeviceMatch::parse(
+                argument,
+            )))),
+        },
+        "deny" => match argument {
+            "" => bail!("Usage: deny <address|name>, or deny none"),
+            "none" => Ok(ControlRequest::DenySink(None)),
+            _ => Ok(ControlRequest::DenySink(Some(DeviceMatch::parse(argument)))),
+        },
+        // answers with an argument don't get here
+        "pair" => bail!("Usage: pair <yes|no|passkey>"),
+        "pin" => match argument {
+            "" => bail!("Usage: pin <PIN>, or pin default"),
+            "default" => Ok(ControlRequest::Pin(None)),
+            _ => Ok(ControlRequest::Pin(Some(argument.to_string()))),
+        },
+        "pairing" => match argument {
+            "none" => Ok(ControlRequest::IoCapability(IoCapability::NoInputNoOutput)),
+            "display" => Ok(ControlRequest::IoCapability(IoCapability::DisplayOnly)),
+            "yesno" => Ok(ControlRequest::IoCapability(IoCapability::DisplayYesNo)),
+            "keyboard" => Ok(ControlRequest::IoCapability(IoCapability::KeyboardOnly)),
+            _ => bail!(
+                "Pairing must be none, display, yesno or keyboard, not {:?}",
+                argument
+            ),
+        },
+        _ => bail!(
+            "Unknown command {:?}, try: play [folder], signals, volume <0-100>, \
+             output <bluetooth|i2s>, disconnect <stop|continue|wait>, eq <preset>, \
+             eq save <name> <band>..., night <on|off>, \
+             speed <0.75-2>, allow <sink|any>, deny <sink|none>, \
+             pin <PIN|default>, pairing <none|display|yesno|keyboard>, pair <yes|no|passkey>",
+            command
+        ),
+    }
+}
+
+// type:Hz:dB:Q, e.g. peak:2500:4:1.0
+fn parse_band(band: &str) -> Result<EqBand> {
+    let fields: Vec<&str> = band.split(':').collect();
+    let [filter_type, frequency, gain_db, q] = fields[..] else {
+        bail!("Band {:?} is not type:Hz:dB:Q", band);
+    };
+    let filter_type = match filter_type {
+        "low" => FilterType::LowShelf,
+        "high" => FilterType::HighShelf,
+        "peak" => FilterType::Peaking,
+        _ => bail!("Band type must be low, high or peak, not {:?}", filter_type),
+    };
+    let number = |value: &str| {
+        value
+            .parse::<f32>()
+            .map_err(|_| anyhow!("Band {:?}: {:?} is not a number", band, value))
+    };
+
+    let band = EqBand {
+        filter_type,
+        frequency: number(frequency)?,
+        gain_db: number(gain_db)?,
+        q: number(q)?,
+    };
+    band.validate()?;
+    Ok(band)
+}
+
+fn parse_pairing_reply(answer: &str) -> Result<PairingReply> {
+    match answer {
+        "yes" => Ok(PairingReply::Accept),
+        "no" => Ok(PairingReply::Reject),
+        _ => match answer.parse() {
+            Ok(passkey) if passkey <= 999_999 => Ok(PairingReply::Passkey(passkey)),
+            _ => bail!(
+                "Answer pair yes, no, or the 6 digit passkey, not {:?}",
+                answer
+            ),
+        },
+    }
+}
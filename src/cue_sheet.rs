@@ -0,0 +1,164 @@
+// Parser for CUE sheets, see https://en.wikipedia.org/wiki/Cue_sheet_(computing)
+// Only the commands needed to find the tracks within the audio files are interpreted,
+// everything else (REM, FLAGS, ISRC, ...) is ignored.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+// INDEX times are given as mm:ss:ff where there are 75 frames per second
+const CUE_FRAMES_PER_SECOND: u64 = 75;
+
+#[derive(Debug, Clone)]
+pub struct CueTrack {
+    pub number: u32,
+    // Path of the audio file, resolved relative to the directory of the CUE sheet
+    pub file: String,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    // Position of INDEX 01 within the file
+    pub start_ms: u64,
+    // Where the next track in the same file starts, None if the track lasts to the end of the file
+    pub end_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+impl CueSheet {
+    pub fn read_file(filename: &str) -> Result<Self> {
+        let contents = std::fs::read(filename)?;
+        let directory = Path::new(filename)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+
+        // CUE sheets in the wild are often Latin-1 rather than UTF-8
+        CueSheet::parse(&String::from_utf8_lossy(&contents), directory)
+    }
+
+    pub fn parse(contents: &str, directory: &Path) -> Result<Self> {
+        let mut sheet = CueSheet::default();
+        let mut file: Option<String> = None;
+        let mut track: Option<CueTrack> = None;
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let tokens = tokenize(line);
+            let Some(command) = tokens.first() else {
+                continue;
+            };
+
+            match (command.to_uppercase().as_str(), &tokens[1..]) {
+                ("FILE", [name, ..]) => {
+                    file = Some(directory.join(name).to_string_lossy().into_owned());
+                }
+                ("TRACK", [number, ..]) => {
+                    let Some(file) = &file else {
+                        bail!("Line {}: TRACK before FILE", line_number + 1);
+                    };
+                    if let Some(track) = track.take() {
+                        sheet.push_track(track);
+                    }
+                    track = Some(CueTrack {
+                        number: number.parse()?,
+                        file: file.clone(),
+                        title: None,
+                        performer: None,
+                        start_ms: u64::MAX,
+                        end_ms: None,
+                    });
+                }
+                ("TITLE", [title, ..]) => match &mut track {
+                    Some(track) => track.title = Some(title.clone()),
+                    None => sheet.title = Some(title.clone()),
+                },
+                ("PERFORMER", [performer, ..]) => match &mut track {
+                    Some(track) => track.performer = Some(performer.clone()),
+                    None => sheet.performer = Some(performer.clone()),
+                },
+                ("INDEX", [number, time, ..]) => {
+                    if let (Some(track), Ok(1)) = (&mut track, number.parse::<u32>()) {
+                        match parse_time(time) {
+                            Some(ms) => track.start_ms = ms,
+                            None => bail!("Line {}: bad INDEX time {}", line_number + 1, time),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(track) = track.take() {
+            sheet.push_track(track);
+        }
+
+        // Pregaps (INDEX 00) are played as the end of the track before, like CD players do
+        for i in 1..sheet.tracks.len() {
+            let (before, after) = sheet.tracks.split_at_mut(i);
+            let previous = &mut before[i - 1];
+            if previous.file == after[0].file && after[0].start_ms > previous.start_ms {
+                previous.end_ms = Some(after[0].start_ms);
+            }
+        }
+
+        Ok(sheet)
+    }
+
+    fn push_track(&mut self, track: CueTrack) {
+        if track.start_ms == u64::MAX {
+            log::warn!("Ignoring CUE track {} without INDEX 01", track.number);
+        } else {
+            self.tracks.push(track);
+        }
+    }
+}
+
+// mm:ss:ff
+fn parse_time(time: &str) -> Option<u64> {
+    let mut parts = time.split(':');
+
+    let minutes: u32 = parts.next()?.parse().ok()?;
+    let seconds: u32 = parts.next()?.parse().ok()?;
+    let frames: u32 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || seconds >= 60 || frames as u64 >= CUE_FRAMES_PER_SECOND {
+        return None;
+    }
+
+    Some(
+        (minutes as u64 * 60 + seconds as u64) * 1000
+            + frames as u64 * 1000 / CUE_FRAMES_PER_SECOND,
+    )
+}
+
+// Splits a line on whitespace, keeping "quoted strings" together
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+
+        let mut token = String::new();
+        if c == '"' {
+            for c in chars.by_ref() {
+                if c == '"' {
+                    break;
+                }
+                token.push(c);
+            }
+        } else {
+            token.push(c);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                token.push(c);
+            }
+        }
+        tokens.push(token);
+    }
+
+    tokens
+}
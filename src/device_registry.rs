@@ -0,0 +1,285 @@
+// Bluetooth sinks we have played to, stored in NVS so that the next boot can connect to them
+// directly instead of running an inquiry. The list is kept in order of the last connection, most
+// recent first, and the oldest device is forgotten when it is full.
+//
+// The allow and deny lists of the sink selection policy are kept here too, and how we pair.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+
+use crate::{
+    bluetooth_gap_hal::{ClassOfDevice, IoCapability, PairingConfig},
+    bluetooth_hal::{bdaddr_to_string, BDAddr},
+    sink_selection::{DeviceMatch, SelectionPolicy},
+};
+
+const NVS_NAMESPACE: &str = "devices";
+// addresses of the known devices, 6 bytes each, most recent first
+const LIST_KEY: &str = "list";
+pub const MAX_DEVICES: usize = 8;
+// the allow and deny lists, one address or name pattern per line
+const ALLOW_KEY: &str = "allow";
+const DENY_KEY: &str = "deny";
+const MAX_LIST_LEN: usize = 1024;
+// the IO capability as a byte, and the legacy PIN if one is set
+const IO_CAPABILITY_KEY: &str = "iocap";
+const PIN_KEY: &str = "pin";
+
+const FORMAT_VERSION: u8 = 1;
+// longer names are cut, they are only for the logs and for choosing in a UI
+const MAX_NAME_LEN: usize = 64;
+const HEADER_SIZE: usize = 21;
+
+const FLAG_LINK_KEY: u8 = 0x01;
+const FLAG_CLASS: u8 = 0x02;
+
+#[derive(Debug, Clone)]
+pub struct KnownDevice {
+    pub address: BDAddr,
+    pub name: Option<String>,
+    pub class: Option<ClassOfDevice>,
+    // seconds since 1970, or since boot if the clock had not been set
+    pub last_connected: u64,
+    // whether we are bonded, so that reconnecting won't need pairing
+    pub link_key: bool,
+}
+
+pub struct DeviceRegistry {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl DeviceRegistry {
+    pub fn new(partition: EspNvsPartition<NvsDefault>) -> Result<Self> {
+        Ok(DeviceRegistry {
+            nvs: EspNvs::new(partition, NVS_NAMESPACE, true)?,
+        })
+    }
+
+    // Most recently connected first. Devices which can't be read are skipped.
+    pub fn devices(&self) -> Result<Vec<KnownDevice>> {
+        let mut devices = Vec::new();
+
+        for address in self.addresses()? {
+            let mut buf = [0u8; HEADER_SIZE + MAX_NAME_LEN];
+            match self.nvs.get_raw(&device_key(&address), &mut buf) {
+                Ok(Some(data)) => match decode_device(data) {
+                    Ok(device) => devices.push(device),
+                    Err(e) => log::warn!("Known device {}: {}", bdaddr_to_string(address), e),
+                },
+                Ok(None) => log::warn!("Known device {} is missing", bdaddr_to_string(address)),
+                Err(e) => log::warn!("Known device {}: {}", bdaddr_to_string(address), e),
+            }
+        }
+
+        Ok(devices)
+    }
+
+    // Stores the device as the most recently connected one
+    pub fn record_connection(
+        &mut self,
+        address: &BDAddr,
+        name: Option<&str>,
+        class: Option<ClassOfDevice>,
+        link_key: bool,
+    ) -> Result<()> {
+        let previous = self.devices()?.into_iter().find(|d| d.address == *address);
+        let last_connected = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        // a reconnection to a known device doesn't always come with its name and class
+        let device = KnownDevice {
+            address: *address,
+            name: name
+                .map(str::to_string)
+                .or_else(|| previous.as_ref().and_then(|d| d.name.clone())),
+            class: class.or_else(|| previous.as_ref().and_then(|d| d.class)),
+            last_connected,
+            link_key,
+        };
+        self.nvs
+            .set_raw(&device_key(address), &encode_device(&device))?;
+
+        let mut addresses = self.addresses()?;
+        addresses.retain(|a| a != address);
+        addresses.insert(0, *address);
+        for forgotten in addresses.split_off(addresses.len().min(MAX_DEVICES)) {
+            log::info!("Forgetting device {}", bdaddr_to_string(forgotten));
+            self.nvs.remove(&device_key(&forgotten))?;
+        }
+        self.set_addresses(&addresses)?;
+
+        log::info!(
+            "Remembered device {} ({})",
+            bdaddr_to_string(*address),
+            device.name.as_deref().unwrap_or("no name")
+        );
+        Ok(())
+    }
+
+    // The default policy, with the allow and deny lists stored
+    pub fn load_policy(&self) -> Result<SelectionPolicy> {
+        Ok(SelectionPolicy {
+            allow: self.device_list(ALLOW_KEY)?,
+            deny: self.device_list(DENY_KEY)?,
+            ..Default::default()
+        })
+    }
+
+    pub fn save_policy(&mut self, policy: &SelectionPolicy) -> Result<()> {
+        self.set_device_list(ALLOW_KEY, &policy.allow)?;
+        self.set_device_list(DENY_KEY, &policy.deny)
+    }
+
+    // The default pairing, with what has been set
+    pub fn load_pairing(&self) -> Result<PairingConfig> {
+        let mut config = PairingConfig::default();
+
+        let mut buf = [0u8; PairingConfig::MAX_PIN_LEN];
+        if let Some(pin) = self.nvs.get_raw(PIN_KEY, &mut buf)? {
+            config.legacy_pin = Some(String::from_utf8_lossy(pin).into_owned());
+        }
+        let mut buf = [0u8; 1];
+        if let Some(io_capability) = self.nvs.get_raw(IO_CAPABILITY_KEY, &mut buf)? {
+            config.io_capability = match io_capability {
+                [0] => IoCapability::NoInputNoOutput,
+                [1] => IoCapability::DisplayOnly,
+                [2] => IoCapability::DisplayYesNo,
+                [3] => IoCapability::KeyboardOnly,
+                _ => bail!("Unknown IO capability {:?}", io_capability),
+            };
+        }
+
+        Ok(config)
+    }
+
+    pub fn save_pairing(&mut self, config: &PairingConfig) -> Result<()> {
+        config.validate()?;
+
+        let io_capability = match config.io_capability {
+            IoCapability::NoInputNoOutput => 0,
+            IoCapability::DisplayOnly => 1,
+            IoCapability::DisplayYesNo => 2,
+            IoCapability::KeyboardOnly => 3,
+        };
+        self.nvs.set_raw(IO_CAPABILITY_KEY, &[io_capability])?;
+        match &config.legacy_pin {
+            Some(pin) => {
+                self.nvs.set_raw(PIN_KEY, pin.as_bytes())?;
+            }
+            None => {
+                self.nvs.remove(PIN_KEY)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn device_list(&self, key: &str) -> Result<Vec<DeviceMatch>> {
+        let mut buf = [0u8; MAX_LIST_LEN];
+
+        match self.nvs.get_raw(key, &mut buf)? {
+            Some(data) => Ok(String::from_utf8_lossy(data)
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(DeviceMatch::parse)
+                .collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn set_device_list(&mut self, key: &str, list: &[DeviceMatch]) -> Result<()> {
+        let lines: Vec<String> = list.iter().map(DeviceMatch::to_string).collect();
+        let data = lines.join("\n");
+        if data.len() > MAX_LIST_LEN {
+            bail!("The {} list is longer than {} bytes", key, MAX_LIST_LEN);
+        }
+        self.nvs.set_raw(key, data.as_bytes())?;
+        Ok(())
+    }
+
+    fn addresses(&self) -> Result<Vec<BDAddr>> {
+        let mut buf = [0u8; 6 * MAX_DEVICES];
+
+        match self.nvs.get_raw(LIST_KEY, &mut buf)? {
+            Some(data) => Ok(data
+                .chunks_exact(6)
+                .map(|chunk| {
+                    let mut address = BDAddr::default();
+                    address.copy_from_slice(chunk);
+                    address
+                })
+                .collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn set_addresses(&mut self, addresses: &[BDAddr]) -> Result<()> {
+        self.nvs.set_raw(LIST_KEY, &addresses.concat())?;
+        Ok(())
+    }
+}
+
+// NVS keys are at most 15 characters, the address in hex is 12
+fn device_key(address: &BDAddr) -> String {
+    bdaddr_to_string(*address)
+}
+
+// version, address, flags, class and last connection as little endian, name length, name
+fn encode_device(device: &KnownDevice) -> Vec<u8> {
+    let mut flags = 0;
+    if device.link_key {
+        flags |= FLAG_LINK_KEY;
+    }
+    if device.class.is_some() {
+        flags |= FLAG_CLASS;
+    }
+
+    let name = device.name.as_deref().unwrap_or("").as_bytes();
+    let mut name_len = name.len().min(MAX_NAME_LEN);
+    // don't cut a character in half
+    while std::str::from_utf8(&name[..name_len]).is_err() {
+        name_len -= 1;
+    }
+
+    let mut data = vec![FORMAT_VERSION];
+    data.extend_from_slice(&device.address);
+    data.push(flags);
+    data.extend_from_slice(&device.class.map(|c| c.value).unwrap_or(0).to_le_bytes());
+    data.extend_from_slice(&device.last_connected.to_le_bytes());
+    data.push(name_len as u8);
+    data.extend_from_slice(&name[..name_len]);
+
+    data
+}
+
+fn decode_device(data: &[u8]) -> Result<KnownDevice> {
+    if data.len() < HEADER_SIZE || data[0] != FORMAT_VERSION {
+        bail!("Unknown device format");
+    }
+    let name_len = data[HEADER_SIZE - 1] as usize;
+    if data.len() != HEADER_SIZE + name_len {
+        bail!("Corrupt device");
+    }
+
+    let mut address = BDAddr::default();
+    address.copy_from_slice(&data[1..7]);
+    let flags = data[7];
+    let mut class = [0u8; 4];
+    class.copy_from_slice(&data[8..12]);
+    let mut last_connected = [0u8; 8];
+    last_connected.copy_from_slice(&data[12..20]);
+    let name = String::from_utf8_lossy(&data[HEADER_SIZE..]).into_owned();
+
+    Ok(KnownDevice {
+        address,
+        name: (!name.is_empty()).then_some(name),
+        class: (flags & FLAG_CLASS != 0).then_some(ClassOfDevice {
+            value: u32::from_le_bytes(class),
+        }),
+        last_connected: u64::from_le_bytes(last_connected),
+        link_key: flags & FLAG_LINK_KEY != 0,
+    })
+}
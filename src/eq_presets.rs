@@ -0,0 +1,150 @@
+// Equalizer presets stored in NVS, along with which preset is active.
+// The built-in presets from audio_eq can be used by name, but also overridden by saving a preset
+// with the same name.
+
+use anyhow::{bail, Result};
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+
+use crate::audio_eq::{builtin_preset, EqBand, FilterType, MAX_BANDS};
+
+const NVS_NAMESPACE: &str = "eq";
+const ACTIVE_PRESET_KEY: &str = "active";
+// NVS keys are at most 15 characters, and the preset name is used as the key
+const MAX_NAME_LEN: usize = 15;
+
+const FORMAT_VERSION: u8 = 1;
+const BAND_SIZE: usize = 13;
+
+pub const DEFAULT_PRESET: &str = "flat";
+
+pub struct EqPresetStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl EqPresetStore {
+    pub fn new(partition: EspNvsPartition<NvsDefault>) -> Result<Self> {
+        Ok(EqPresetStore {
+            nvs: EspNvs::new(partition, NVS_NAMESPACE, true)?,
+        })
+    }
+
+    pub fn load(&self, name: &str) -> Result<Option<Vec<EqBand>>> {
+        let mut buf = [0u8; 2 + MAX_BANDS * BAND_SIZE];
+
+        match self.nvs.get_raw(&preset_key(name)?, &mut buf)? {
+            Some(data) => Ok(Some(decode_bands(data)?)),
+            None => Ok(builtin_preset(name)),
+        }
+    }
+
+    pub fn save(&mut self, name: &str, bands: &[EqBand]) -> Result<()> {
+        if name == ACTIVE_PRESET_KEY {
+            bail!("Preset name {} is reserved", name);
+        }
+        self.nvs
+            .set_raw(&preset_key(name)?, &encode_bands(bands)?)?;
+        Ok(())
+    }
+
+    pub fn active_preset(&self) -> Result<String> {
+        let mut buf = [0u8; MAX_NAME_LEN];
+
+        match self.nvs.get_raw(ACTIVE_PRESET_KEY, &mut buf)? {
+            Some(name) => Ok(String::from_utf8_lossy(name).into_owned()),
+            None => Ok(DEFAULT_PRESET.to_string()),
+        }
+    }
+
+    pub fn set_active_preset(&mut self, name: &str) -> Result<()> {
+        if self.load(name)?.is_none() {
+            bail!("No equalizer preset {}", name);
+        }
+        self.nvs.set_raw(ACTIVE_PRESET_KEY, name.as_bytes())?;
+        Ok(())
+    }
+
+    // The bands of the active preset, falling back to flat if it has gone missing
+    pub fn load_active(&self) -> Result<Vec<EqBand>> {
+        let name = self.active_preset()?;
+
+        match self.load(&name)? {
+            Some(bands) => Ok(bands),
+            None => {
+                log::warn!("Active equalizer preset {} not found, using flat", name);
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+fn preset_key(name: &str) -> Result<String> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        bail!("Preset name must be 1 to {} bytes", MAX_NAME_LEN);
+    }
+    Ok(name.to_string())
+}
+
+// version, band count, then per band: type, frequency, gain and q as little endian f32
+fn encode_bands(bands: &[EqBand]) -> Result<Vec<u8>> {
+    if bands.len() > MAX_BANDS {
+        bail!("At most {} bands", MAX_BANDS);
+    }
+    let mut data = vec![FORMAT_VERSION, bands.len() as u8];
+
+    for band in bands {
+        band.validate()?;
+        data.push(match band.filter_type {
+            FilterType::Peaking => 0,
+            FilterType::LowShelf => 1,
+            FilterType::HighShelf => 2,
+        });
+        data.extend_from_slice(&band.frequency.to_le_bytes());
+        data.extend_from_slice(&band.gain_db.to_le_bytes());
+        data.extend_from_slice(&band.q.to_le_bytes());
+    }
+
+    Ok(data)
+}
+
+fn decode_bands(data: &[u8]) -> Result<Vec<EqBand>> {
+    if data.len() < 2 || data[0] != FORMAT_VERSION {
+        bail!("Unknown equalizer preset format");
+    }
+    let count = data[1] as usize;
+    if count > MAX_BANDS || data.len() != 2 + count * BAND_SIZE {
+        bail!("Corrupt equalizer preset");
+    }
+
+    let f32_at = |offset: usize| {
+        f32::from_le_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    };
+
+    let mut bands = Vec::new();
+    for index in 0..count {
+        let offset = 2 + index * BAND_SIZE;
+        let filter_type = match data[offset] {
+            0 => FilterType::Peaking,
+            1 => FilterType::LowShelf,
+            2 => FilterType::HighShelf,
+            t => bail!("Unknown filter type {}", t),
+        };
+
+        let band = EqBand {
+            filter_type,
+            frequency: f32_at(offset + 1),
+            gain_db: f32_at(offset + 5),
+            q: f32_at(offset + 9),
+        };
+        if let Err(e) = band.validate() {
+            bail!("Corrupt equalizer preset: {}", e);
+        }
+        bands.push(band);
+    }
+
+    Ok(bands)
+}
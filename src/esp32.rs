@@ -58,3 +58,8 @@ impl Esp32 {
         }
     }
 }
+
+// FreeRTOS priority of the calling thread; std threads start at the configured default
+pub fn set_current_thread_priority(priority: u32) {
+    unsafe { esp_idf_sys::vTaskPrioritySet(std::ptr::null_mut(), priority) };
+}
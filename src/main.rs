@@ -15,17 +15,40 @@ use crate::state_machine::StateMachine;
 // use log::info;
 
 mod audio;
+mod audio_dsp;
+mod audio_dynamics;
+mod audio_eq;
+mod audio_generator;
+mod audio_i2s;
+mod audio_meter;
+mod audio_mixer;
+mod audio_output;
+mod audio_resample;
+mod audio_sink;
+mod audio_tempo;
+mod audiobook;
 mod bluetooth_esp32;
 mod bluetooth_esp32_a2dp;
+mod bluetooth_esp32_avrcp;
 mod bluetooth_gap_esp32;
 mod bluetooth_gap_hal;
 mod bluetooth_hal;
 mod boot_state;
+mod console;
+mod cue_sheet;
+mod device_registry;
+mod eq_presets;
 mod esp32;
+mod ogg_chain;
 mod playback_state;
+mod playlist;
+mod prompts;
 mod sd_card;
+mod sink_selection;
 mod state_machine;
 mod uuids;
+mod vorbis_comments;
+mod wav;
 mod wifi_connect_state;
 
 fn print_memory(system: &mut System) {
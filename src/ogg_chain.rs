@@ -0,0 +1,163 @@
+// Finds the links of chained Ogg Vorbis files: several logical bitstreams one after the other,
+// each with its own headers, so the sample rate, channels and comments can change mid-file
+// (typically recordings of internet radio). Tremor decodes across links by itself, but doesn't
+// say where they are, so we find them by walking the Ogg pages.
+// See https://xiph.org/ogg/doc/framing.html
+
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+};
+
+use anyhow::{bail, Result};
+
+use crate::vorbis_comments::VorbisComments;
+
+const PAGE_HEADER_SIZE: usize = 27;
+const BEGINNING_OF_STREAM: u8 = 0x02;
+// Ogg pages are at most 64 kB, so the last page starts within this distance of the end
+const MAX_PAGE_SIZE: u64 = 65307;
+const NO_GRANULE_POSITION: u64 = u64::MAX;
+
+#[derive(Debug, Clone)]
+pub struct ChainLink {
+    // where the link starts, in playback time from the start of the file
+    pub start_ms: u64,
+    pub channels: u16,
+    pub rate: u32,
+    pub comments: Option<VorbisComments>,
+}
+
+struct PageHeader {
+    flags: u8,
+    granule_position: u64,
+    serial: u32,
+    header_len: u64,
+    body_len: u64,
+}
+
+// The links of the file, or an empty list if the file is not chained
+pub fn scan_links(filename: &str) -> Result<Vec<ChainLink>> {
+    let mut reader = BufReader::new(File::open(filename)?);
+
+    // Cheap check first, so that ordinary files aren't walked page by page: a file whose first
+    // and last pages belong to the same bitstream is not chained.
+    let first_serial = read_page_header(&mut reader)?.serial;
+    if last_page_serial(&mut reader)? == Some(first_serial) {
+        return Ok(Vec::new());
+    }
+
+    // (file offset, serial, channels, rate, last granule position) of each link
+    let mut links: Vec<(u64, u32, u16, u32, u64)> = Vec::new();
+    let mut offset = 0;
+
+    reader.seek(SeekFrom::Start(0))?;
+    loop {
+        let page = match read_page_header(&mut reader) {
+            Ok(page) => page,
+            Err(_) => break, // end of file, or garbage at the end which tremor will skip too
+        };
+
+        if page.flags & BEGINNING_OF_STREAM != 0 {
+            // the first page of a link holds just the identification header
+            let mut identification = vec![0u8; page.body_len as usize];
+            reader.read_exact(&mut identification)?;
+            if identification.len() < 16 || &identification[1..7] != b"vorbis" {
+                bail!("{}: link {} is not Vorbis", filename, links.len());
+            }
+            let channels = identification[11] as u16;
+            let rate = u32::from_le_bytes([
+                identification[12],
+                identification[13],
+                identification[14],
+                identification[15],
+            ]);
+            links.push((offset, page.serial, channels, rate, 0));
+        } else {
+            reader.seek(SeekFrom::Current(page.body_len as i64))?;
+        }
+
+        if let Some(link) = links.last_mut() {
+            if page.serial == link.1 && page.granule_position != NO_GRANULE_POSITION {
+                link.4 = page.granule_position;
+            }
+        }
+        offset += page.header_len + page.body_len;
+    }
+
+    if links.len() < 2 {
+        return Ok(Vec::new());
+    }
+
+    let mut chain = Vec::new();
+    let mut start_ms = 0;
+    for (offset, _, channels, rate, samples) in links {
+        reader.seek(SeekFrom::Start(offset))?;
+        let comments = match VorbisComments::read(&mut reader) {
+            Ok(comments) => Some(comments),
+            Err(e) => {
+                log::warn!("{}: no comments for link at {}: {}", filename, offset, e);
+                None
+            }
+        };
+
+        chain.push(ChainLink {
+            start_ms,
+            channels,
+            rate,
+            comments,
+        });
+        start_ms += samples * 1000 / rate.max(1) as u64;
+    }
+    log::info!("{} is a chain of {} links", filename, chain.len());
+
+    Ok(chain)
+}
+
+// Reads the page header and segment table, leaving the reader at the page body
+fn read_page_header<R: Read>(reader: &mut R) -> Result<PageHeader> {
+    let mut header = [0u8; PAGE_HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != b"OggS" {
+        bail!("Lost Ogg page sync");
+    }
+
+    let mut lacing = vec![0u8; header[26] as usize];
+    reader.read_exact(&mut lacing)?;
+
+    let mut granule = [0u8; 8];
+    granule.copy_from_slice(&header[6..14]);
+
+    Ok(PageHeader {
+        flags: header[5],
+        granule_position: u64::from_le_bytes(granule),
+        serial: u32::from_le_bytes([header[14], header[15], header[16], header[17]]),
+        header_len: (PAGE_HEADER_SIZE + lacing.len()) as u64,
+        body_len: lacing.iter().map(|l| *l as u64).sum(),
+    })
+}
+
+fn last_page_serial<R: Read + Seek>(reader: &mut R) -> Result<Option<u32>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let start = len.saturating_sub(MAX_PAGE_SIZE);
+
+    let mut tail = Vec::new();
+    reader.seek(SeekFrom::Start(start))?;
+    reader.take(MAX_PAGE_SIZE).read_to_end(&mut tail)?;
+
+    // the capture pattern could also occur inside compressed data, but then the serial number
+    // is most likely different, and all that happens is a full scan
+    let last_page = tail
+        .windows(4)
+        .rposition(|window| window == b"OggS")
+        .filter(|position| position + PAGE_HEADER_SIZE <= tail.len());
+
+    Ok(last_page.map(|position| {
+        u32::from_le_bytes([
+            tail[position + 14],
+            tail[position + 15],
+            tail[position + 16],
+            tail[position + 17],
+        ])
+    }))
+}
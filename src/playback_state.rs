@@ -1,12 +1,44 @@
+use std::{
+    path::Path,
+    sync::{Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
 use async_trait::async_trait;
+use futures::{
+    channel::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    executor::block_on,
+    future::{self, select, BoxFuture, Either},
+    FutureExt, StreamExt,
+};
 
 use crate::{
-    audio,
+    audio::{self, NowPlaying, PlayStatus, PlaybackCommand, PlaybackReport, PlaybackSettings},
+    audio_dsp::{DspStream, Volume, VolumeControl, MAX_VOLUME},
+    audio_dynamics::DynamicsSettings,
+    audio_eq::{builtin_preset, EqBand},
+    audio_generator,
+    audio_i2s::{I2sConfig, I2sSink},
+    audio_meter::MeterTap,
+    audio_mixer::Mixer,
+    audio_output::{DisconnectPolicy, Output, OutputStore},
+    audio_sink::SinkSelector,
     bluetooth_esp32::ESP32Bluetooth,
-    bluetooth_gap_hal::ScannedDevice,
-    bluetooth_hal::Bluetooth,
+    bluetooth_gap_hal::{IoCapability, PairingConfig, PairingEvent, ScannedDevice, ServiceUuid},
+    bluetooth_hal::{bdaddr_to_string, BDAddr, Bluetooth, BluetoothEvent, RemoteCommand},
     boot_state::Boot,
+    console,
+    device_registry::DeviceRegistry,
+    eq_presets::EqPresetStore,
+    playlist::Playlist,
+    prompts::Prompt,
     sd_card,
+    sink_selection::{DeviceMatch, SelectionPolicy},
     state_machine::{ConcreteState, StateEnum, StateExecutor, StateMachine},
     uuids::Bluetooth16bitUUIDEnum,
     wifi_connect_state::WifiConnect,
@@ -19,6 +51,58 @@ const PIN_SDCARD_SCLK: i32 = 18;
 const PIN_SDCARD_MOSI: i32 = 23;
 const PIN_SDCARD_MISO: i32 = 19;
 
+// The folder played is chosen with the console's play command, and is the whole card until then.
+// Folders below /sdcard/audiobooks play as audiobooks.
+const PLAYLIST_FOLDER: &str = "/sdcard";
+
+// How far fast forward and rewind jump
+const SEEK_STEP_MS: i64 = 10_000;
+
+// Between attempts to reconnect to a sink which went away, doubling up to the maximum
+const RECONNECT_DELAY_MS: u64 = 2_000;
+const RECONNECT_MAX_DELAY_MS: u64 = 60_000;
+
+// In percent, until a volume is chosen with the console or the sink. Full scale, so that sinks
+// without absolute volume play as loud as their own volume control is set. Sinks with absolute
+// volume are set to the player's volume when they connect.
+const DEFAULT_VOLUME: u8 = MAX_VOLUME;
+
+// Requests from whatever controls the player: the console, later buttons or web
+#[derive(Debug)]
+pub enum ControlRequest {
+    Output(Output),
+    // in percent
+    Volume(u8),
+    // A folder, absolute or below PLAYLIST_FOLDER; the whole of PLAYLIST_FOLDER if empty
+    Play(String),
+    // The diagnostic sequence of test signals, e.g. for checking a speaker
+    PlayTestSignals,
+    DisconnectPolicy(DisconnectPolicy),
+    // An equalizer preset, built in or saved, which is also used after a restart
+    Equalizer(String),
+    // Saves the bands as a preset, and uses it
+    SaveEqualizer(String, Vec<EqBand>),
+    // Compressor on or off, for quiet listening
+    NightMode(bool),
+    // Playback speed, 1.0 is normal speed
+    Speed(f32),
+    // Adds to the sinks which may be connected to, None allows any. Used from the next connection.
+    AllowSink(Option<DeviceMatch>),
+    // Adds to the sinks which are never connected to, None denies none
+    DenySink(Option<DeviceMatch>),
+    // The PIN for legacy pairing, None for the default. Used from the next start of Bluetooth.
+    Pin(Option<String>),
+    IoCapability(IoCapability),
+}
+
+// What the user answers a sink which asks to pair
+#[derive(Debug)]
+pub enum PairingReply {
+    Accept,
+    Reject,
+    Passkey(u32),
+}
+
 pub struct Playback {}
 
 impl<'a> From<ConcreteState<'a, WifiConnect>> for ConcreteState<'a, Playback> {
@@ -34,89 +118,1185 @@ impl<'a> From<ConcreteState<'a, WifiConnect>> for ConcreteState<'a, Playback> {
 impl<'a> StateExecutor<'a> for ConcreteState<'a, Playback> {
     async fn execute(self, machine: &mut StateMachine) -> StateEnum<'a> {
         log::info!("Initializing SD card");
-        sd_card::init(
+        let sd_card_mounted = match sd_card::init(
             PIN_SDCARD_CS,
             PIN_SDCARD_SCLK,
             PIN_SDCARD_MISO,
             PIN_SDCARD_MOSI,
-        )
-        .unwrap(); // TODO: Handle errors
+        ) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("SD card init failed, playing test signals: {}", e);
+                false
+            }
+        };
+
+        let mut output_store = open_output_store(machine);
+        let output = match output_store.as_ref().map(|store| store.load()) {
+            Some(Ok(output)) => output,
+            Some(Err(e)) => {
+                log::error!("Failed to load output, using Bluetooth: {}", e);
+                Output::Bluetooth
+            }
+            None => Output::Bluetooth,
+        };
+        log::info!("Output is {:?}", output);
+        let volume = match output_store.as_ref().map(|store| store.load_volume()) {
+            Some(Ok(Some(volume))) => volume,
+            Some(Ok(None)) | None => DEFAULT_VOLUME,
+            Some(Err(e)) => {
+                log::error!("Failed to load volume: {}", e);
+                DEFAULT_VOLUME
+            }
+        };
 
         let mut bluetooth = ESP32Bluetooth::new(true, true);
+        let mut i2s = I2sSink::new(I2sConfig::max98357a());
+
+        let mut registry = open_device_registry(machine);
+        let mut events = None;
+        let sink_address = if output == Output::Bluetooth {
+            bluetooth
+                .pre_init(&mut machine.esp32)
+                .expect("Bluetooth preinit failed");
+
+            bluetooth.init("Piccolo").unwrap(); // TODO: Error handling
+            events = match bluetooth.events() {
+                Ok(events) => Some(events),
+                Err(e) => {
+                    log::error!("No Bluetooth events: {}", e);
+                    None
+                }
+            };
 
-        log::info!("Bluetooth created 1");
+            if let Err(e) = bluetooth.gap_set_pairing(load_pairing(registry.as_ref())) {
+                log::error!("Failed to configure pairing: {}", e);
+            }
+            match bluetooth.gap_pairing_events() {
+                Ok(pairing_events) => start_pairing_handler(pairing_events),
+                Err(e) => log::error!("No pairing events: {}", e),
+            }
+            if let Err(e) = ESP32Bluetooth::avrcp_set_volume(volume) {
+                log::error!("Failed to set the volume: {}", e);
+            }
 
-        bluetooth
-            .pre_init(&mut machine.esp32)
-            .expect("Bluetooth preinit failed");
+            let policy = load_policy(registry.as_ref());
+            connect_to_speaker(&mut bluetooth, registry.as_mut(), &policy).await
+        } else {
+            None
+        };
+        let connected = sink_address.is_some();
 
-        bluetooth.init("Piccolo").unwrap(); // TODO: Error handling
+        if connected || output == Output::I2s {
+            let remote = if connected {
+                match bluetooth.avrcp_commands() {
+                    Ok(commands) => Some(commands),
+                    Err(e) => {
+                        log::error!("No AVRCP commands: {}", e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
 
-        log::info!("Starting scanning");
+            let mut sinks = SinkSelector::new();
+            if connected {
+                sinks.add(&mut bluetooth);
+            }
+            sinks.add(&mut i2s);
 
-        let mut discovery = bluetooth
-            .gap_start_discovery()
-            .expect("Bluetooth start discovery failed");
-        let mut found: Option<ScannedDevice> = None;
+            play(
+                machine,
+                sd_card_mounted,
+                output,
+                volume,
+                &mut sinks,
+                sink_address,
+                remote,
+                events,
+                output_store.as_mut(),
+                registry.as_mut(),
+            )
+            .await;
+        }
 
-        while found.is_none() && !discovery.is_closed() {
-            log::info!("Waiting for discovery");
-            match discovery.recv().await {
-                Ok(device) => {
-                    log::info!("Device is {:?}", device);
+        if output == Output::Bluetooth {
+            bluetooth.deinit().unwrap(); // TODO: Error handling
 
-                    if device
-                        .has_16bit_uuid(Bluetooth16bitUUIDEnum::AdvancedAudioDistribution as u16)
-                    {
-                        found.replace(device);
-                        log::info!("Found A2DP device")
-                    } else {
-                        log::info!("Ignoring device");
+            let mut machine = self.machine.lock().await;
+            bluetooth
+                .post_deinit(&mut machine.esp32)
+                .expect("Bluetooth post de_init failed");
+            drop(machine);
+        }
+
+        log::info!("Playback state completed, going to boot state");
+
+        StateEnum::Boot(ConcreteState::<Boot>::from(self))
+    }
+}
+
+// Connects to a known sink, most recently used first, and if none of them answers, to the best
+// A2DP sink that discovery finds, or the next best if that one doesn't answer either. The address
+// of the sink connected, None if there was none.
+async fn connect_to_speaker(
+    bluetooth: &mut ESP32Bluetooth,
+    registry: Option<&mut DeviceRegistry>,
+    policy: &SelectionPolicy,
+) -> Option<BDAddr> {
+    let known = match registry.as_deref().map(|registry| registry.devices()) {
+        Some(Ok(devices)) => devices,
+        Some(Err(e)) => {
+            log::error!("Failed to read known devices: {}", e);
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+
+    let mut connected = None;
+    for device in known {
+        if !policy.allows(&device.address, device.name.as_deref()) {
+            continue;
+        }
+        log::info!(
+            "Connecting to known device {} ({})",
+            bdaddr_to_string(device.address),
+            device.name.as_deref().unwrap_or("no name")
+        );
+        match bluetooth.a2dp_connect(&device.address).await {
+            Ok(()) => {
+                connected = Some((device.address, device.name, device.class));
+                break;
+            }
+            Err(e) => log::info!("Known device didn't connect: {}", e),
+        }
+    }
+
+    let (address, name, class) = match connected {
+        Some(device) => device,
+        None => {
+            let mut candidates = discover_speakers(bluetooth, policy).await;
+            if candidates.is_empty() {
+                log::info!("Bluetooth search timed out");
+                return None;
+            }
+
+            // the best first, and the others if it doesn't answer
+            loop {
+                let Some(dev) = policy.best(&candidates).cloned() else {
+                    log::info!("None of the devices found connected");
+                    return None;
+                };
+                log::info!(
+                    "Connecting to {} of {} devices",
+                    dev.name.as_deref().unwrap_or("unnamed"),
+                    candidates.len()
+                );
+                match bluetooth.a2dp_connect(&dev.address).await {
+                    Ok(()) => break (dev.address, dev.name, dev.class),
+                    Err(e) => {
+                        log::info!("Device didn't connect: {}", e);
+                        candidates.retain(|candidate| candidate.address != dev.address);
                     }
                 }
+            }
+        }
+    };
+    log::info!("Connected!");
+
+    if let Some(registry) = registry {
+        let authenticated = bluetooth
+            .gap_authentication(&address)
+            .is_some_and(|authentication| authentication.success);
+        let link_key = authenticated
+            || match bluetooth.gap_bonded_devices() {
+                Ok(bonded) => bonded.contains(&address),
                 Err(e) => {
-                    if !discovery.is_closed() {
-                        panic!("Error waiting for discovery: {e}");
-                    }
+                    log::warn!("Failed to get bonded devices: {}", e);
+                    false
+                }
+            };
+        if let Err(e) = registry.record_connection(&address, name.as_deref(), class, link_key) {
+            log::error!("Failed to remember device: {}", e);
+        }
+    }
+    Some(address)
+}
+
+// Runs an inquiry, and returns the A2DP sinks the policy accepts that were found within its
+// collection time. Devices which don't list all their services in EIR data are asked for them when
+// no other sink was found.
+async fn discover_speakers(
+    bluetooth: &mut ESP32Bluetooth,
+    policy: &SelectionPolicy,
+) -> Vec<ScannedDevice> {
+    log::info!("Starting scanning");
+
+    let mut discovery = bluetooth
+        .gap_start_discovery()
+        .expect("Bluetooth start discovery failed");
+    let deadline = Instant::now() + Duration::from_millis(policy.collect_ms);
+    let mut candidates: Vec<ScannedDevice> = Vec::new();
+    let mut unidentified: Vec<ScannedDevice> = Vec::new();
+
+    // Results arrive as devices answer, so the deadline is checked as they do; the inquiry ending
+    // ends the collection too
+    while !discovery.is_closed() {
+        log::info!("Waiting for discovery");
+        match discovery.recv().await {
+            Ok(device) => {
+                log::info!("Device is {:?}", device);
+
+                let a2dp =
+                    device.has_16bit_uuid(Bluetooth16bitUUIDEnum::AdvancedAudioDistribution as u16);
+                // the EIR data may not have room for all services, or not be sent at all
+                let services_known =
+                    !device.service_uuids.is_empty() && !device.service_uuids_incomplete;
+                if !a2dp && !services_known && policy.accepts(&device) {
+                    log::info!("Services not all in EIR data, asking the device later");
+                    unidentified.retain(|candidate| candidate.address != device.address);
+                    unidentified.push(device);
+                } else if !a2dp {
+                    log::info!("Ignoring device");
+                } else if !policy.accepts(&device) {
+                    log::info!("Device not accepted by the selection policy");
+                } else {
+                    log::info!("Found A2DP device");
+                    candidates.retain(|candidate| candidate.address != device.address);
+                    candidates.push(device);
+                }
+            }
+            Err(e) => {
+                if !discovery.is_closed() {
+                    panic!("Error waiting for discovery: {e}");
                 }
-            } // end of while not found loop
+            }
         }
 
-        match found {
-            Some(dev) => {
-                // async {
-                log::info!("Device is {}", &dev.name.unwrap());
-                log::info!("Cancelling discovery");
-                let cancel_result = bluetooth.gap_cancel_discovery();
-                if let Err(e) = cancel_result {
-                    log::info!("Ignoring cancel discovery error {e}");
+        if !candidates.is_empty() && Instant::now() >= deadline {
+            break;
+        }
+    }
+
+    log::info!("Cancelling discovery");
+    let cancel_result = bluetooth.gap_cancel_discovery();
+    if let Err(e) = cancel_result {
+        log::info!("Ignoring cancel discovery error {e}");
+    }
+
+    if candidates.is_empty() {
+        for device in unidentified {
+            match bluetooth.gap_get_remote_services(&device.address).await {
+                Ok(services) if is_audio_sink(&services) => {
+                    log::info!("Found A2DP device by its services");
+                    candidates.push(device);
                 }
+                Ok(services) => log::info!("Ignoring device with services {:?}", services),
+                Err(e) => log::info!("Failed to get services of device: {}", e),
+            }
+        }
+    }
 
-                log::info!("Discovery cancelled, connecting...");
-                bluetooth
-                    .a2dp_connect(&dev.address)
-                    .await
-                    .expect("a2dp_connect failed");
+    candidates
+}
 
-                log::info!("Connected!");
+fn is_audio_sink(services: &[ServiceUuid]) -> bool {
+    services.iter().any(|service| {
+        matches!(
+            service,
+            ServiceUuid::Known(
+                Bluetooth16bitUUIDEnum::AudioSink
+                    | Bluetooth16bitUUIDEnum::AdvancedAudioDistribution
+            )
+        )
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn play(
+    machine: &StateMachine<'_>,
+    sd_card_mounted: bool,
+    output: Output,
+    volume: u8,
+    sinks: &mut SinkSelector<'_>,
+    sink_address: Option<BDAddr>,
+    remote: Option<UnboundedReceiver<RemoteCommand>>,
+    events: Option<UnboundedReceiver<Result<BluetoothEvent>>>,
+    output_store: Option<&mut OutputStore>,
+    registry: Option<&mut DeviceRegistry>,
+) {
+    // The mixer plays for as long as we are connected, also when there is no music
+    let mixer = Mixer::new();
+    let mixer_control = mixer.control();
+    // The volume applies to the prompts too
+    let volume_processor = Volume::new(volume);
+    let volume_control = volume_processor.control();
+    let mut output_dsp = DspStream::new(Box::new(mixer));
+    output_dsp.add(Box::new(volume_processor));
+    let meter_tap = MeterTap::new(Box::new(output_dsp)).expect("Failed to start level meter");
+    // The meter is kept for whatever will show the levels (display, web dashboard)
+    let _level_meter = meter_tap.meter();
+    sinks
+        .start(output.sink_name(), Box::new(meter_tap))
+        .await
+        .expect("Failed to start playback");
+    mixer_control.play_prompt(Prompt::Connected.load());
+
+    let playlist = if sd_card_mounted {
+        load_selected_playlist()
+    } else {
+        Playlist::test_signals(audio_generator::diagnostic_sequence())
+    };
+    mixer_control.play_prompt(Prompt::PlaylistLoaded.load());
+    let mut eq_presets = open_eq_presets(machine);
+    // Changed while playing, and kept for the playlists played after
+    let settings = Mutex::new(PlaybackSettings {
+        equalizer: load_equalizer(eq_presets.as_ref()),
+        ..Default::default()
+    });
+    let (playback_control, mut playback_commands) = mpsc::unbounded();
+    let (control_requests, mut requests) = mpsc::unbounded();
+    console::connect(control_requests);
+    // Playlists chosen while playing, which replace the one playing
+    let (playlist_control, mut playlists) = mpsc::unbounded();
+    let now_playing = NowPlaying::new();
+    // Only with a Bluetooth sink, which is when there are remote commands
+    let _notifier = match remote
+        .as_ref()
+        .map(|_| ESP32Bluetooth::avrcp_follow(&now_playing))
+    {
+        Some(Ok(notifier)) => Some(notifier),
+        Some(Err(e)) => {
+            log::error!("No AVRCP notifications: {}", e);
+            None
+        }
+        None => None,
+    };
+
+    let playback = Box::pin(async {
+        let mut playlist = playlist;
+        loop {
+            let current_settings = settings
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone();
+            let report = audio::playback_task(
+                &mixer_control,
+                &playlist,
+                &current_settings,
+                &mut playback_commands,
+                &now_playing,
+            )
+            .await;
+            match report {
+                Ok(report) => log_report(&report),
+                Err(e) => log::error!("Playback failed: {}", e),
+            }
 
-                audio::playback_task(&mut bluetooth)
-                    .await
-                    .expect("Playback failed");
+            // A playlist which ends by itself ends playback, a replaced one stops first
+            let Ok(Some(next)) = playlists.try_next() else {
+                return;
+            };
+            playlist = next;
+            // commands meant for the playlist which ended, like the Stop that ended it
+            while let Ok(Some(command)) = playback_commands.try_next() {
+                log::info!("Dropping playback command {:?}", command);
+            }
+            mixer_control.play_prompt(Prompt::PlaylistLoaded.load());
+        }
+    });
+    let control = Box::pin(control_playback(
+        sinks,
+        output,
+        volume,
+        sink_address,
+        &mut requests,
+        remote,
+        events,
+        &playback_control,
+        &playlist_control,
+        volume_control,
+        &now_playing,
+        &settings,
+        output_store,
+        eq_presets.as_mut(),
+        registry,
+    ));
+    if let Either::Right(((), playback)) = select(playback, control).await {
+        playback.await;
+    }
+
+    if let Err(e) = sinks.stop().await {
+        log::error!("Failed to stop playback: {}", e);
+    }
+}
+
+// The folder chosen last, or all of PLAYLIST_FOLDER if that is gone
+fn load_selected_playlist() -> Playlist {
+    let folder = Playlist::selected_folder(PLAYLIST_FOLDER);
+
+    match Playlist::from_folder(&folder, false) {
+        Ok(playlist) => playlist,
+        Err(e) if folder != PLAYLIST_FOLDER => {
+            log::error!(
+                "Failed to read {}, playing {}: {}",
+                folder,
+                PLAYLIST_FOLDER,
+                e
+            );
+            Playlist::from_folder(PLAYLIST_FOLDER, false).expect("Failed to read playlist")
+        }
+        Err(e) => panic!("Failed to read playlist: {e}"),
+    }
+}
+
+fn log_report(report: &PlaybackReport) {
+    for (file, error) in &report.files_failed {
+        log::error!("Could not play {}: {}", file, error);
+    }
+    if report.corrupt_packets > 0 {
+        log::warn!(
+            "Skipped {} corrupt packets, check the SD card",
+            report.corrupt_packets
+        );
+    }
+}
+
+enum ControlEvent {
+    Request(Option<ControlRequest>),
+    Remote(Option<RemoteCommand>),
+    Bluetooth(Option<Result<BluetoothEvent>>),
+    ConnectionChanged,
+    Reconnected(Result<()>),
+}
+
+// Replaces the playlist with the requested folder, and remembers it for the next boot, or with
+// the test signals, which are not remembered.
+//
+// Moves playback to the requested outputs, and remembers them for the next boot. An output that
+// isn't running, like Bluetooth after booting to I2S, is reached by stopping playback so that the
+// state machine starts over with it.
+//
+// Also follows the Bluetooth sink: when it disconnects, the disconnect policy stored with the
+// output decides what happens, and when it connects again, playback goes back to it if that is the
+// output wanted. Headphones and speakers mostly reconnect to the device they last played from, but
+// not all do, so while waiting for it we also try to reconnect, less and less often.
+//
+// The buttons of the headphones pause by holding the stream, and skip and seek through the
+// playback task.
+//
+// The equalizer preset is chosen with the console, and remembered for the next boot. Night mode
+// and the speed are set with the console, and are back to normal after a restart.
+//
+// The sinks allowed and denied are set with the console, and used from the next connection. So
+// is how we pair, from the next start of Bluetooth.
+//
+// The volume is set with the console or the sink, and remembered for the next boot. Sinks with
+// absolute volume apply the volume themselves; while one is playing, the samples go
+// out at full scale and the volume is kept in sync with the sink in both directions. Other sinks
+// get the volume applied to the samples.
+#[allow(clippy::too_many_arguments)]
+async fn control_playback(
+    sinks: &mut SinkSelector<'_>,
+    mut wanted: Output,
+    mut volume: u8,
+    sink_address: Option<BDAddr>,
+    requests: &mut UnboundedReceiver<ControlRequest>,
+    mut remote: Option<UnboundedReceiver<RemoteCommand>>,
+    mut events: Option<UnboundedReceiver<Result<BluetoothEvent>>>,
+    playback_control: &UnboundedSender<PlaybackCommand>,
+    playlist_control: &UnboundedSender<Playlist>,
+    volume_control: VolumeControl,
+    now_playing: &NowPlaying,
+    settings: &Mutex<PlaybackSettings>,
+    mut output_store: Option<&mut OutputStore>,
+    mut eq_presets: Option<&mut EqPresetStore>,
+    mut registry: Option<&mut DeviceRegistry>,
+) {
+    let bluetooth = Output::Bluetooth.sink_name();
+    // the Bluetooth sink is only there if it was connected at the start
+    let has_bluetooth = sinks.has(bluetooth);
+    let mut connected = has_bluetooth;
+    // paused with the buttons, rather than because the sink went away
+    let mut user_paused = false;
+    // the connected sink has told us its volume, so it does absolute volume
+    let mut sink_volume = false;
+    let mut disconnect_policy = match output_store
+        .as_deref()
+        .map(|store| store.load_disconnect_policy())
+    {
+        Some(Ok(policy)) => policy,
+        Some(Err(e)) => {
+            log::error!("Failed to load disconnect policy: {}", e);
+            DisconnectPolicy::PauseAndWait
+        }
+        None => DisconnectPolicy::PauseAndWait,
+    };
+    // kept across events, as dropping it would lose the outcome of a connection being set up
+    let mut reconnecting: Option<BoxFuture<'static, Result<()>>> = None;
+    let mut reconnect_delay = Duration::from_millis(RECONNECT_DELAY_MS);
+
+    if connected {
+        // the sink answers with its volume if it does absolute volume, also when it has already
+        // answered before we were listening
+        report_volume(volume);
+    }
+
+    loop {
+        // full scale for a sink which applies the volume itself
+        volume_control.set(if sink_volume && wanted == Output::Bluetooth {
+            MAX_VOLUME
+        } else {
+            volume
+        });
+        now_playing.set_status(if sinks.active().is_some() {
+            PlayStatus::Playing
+        } else {
+            PlayStatus::Paused
+        });
+
+        let connection_change = async move {
+            if has_bluetooth {
+                ESP32Bluetooth::a2dp_wait_for_connection(!connected).await
+            } else {
+                future::pending().await
+            }
+        };
+        let remote_command = async {
+            match remote.as_mut() {
+                Some(remote) => remote.next().await,
+                None => future::pending().await,
+            }
+        };
+        let bluetooth_event = async {
+            match events.as_mut() {
+                Some(events) => events.next().await,
+                None => future::pending().await,
+            }
+        };
+
+        let reconnected = async {
+            match reconnecting.as_mut() {
+                Some(reconnecting) => reconnecting.await,
+                None => future::pending().await,
+            }
+        };
+
+        let event = match select(
+            select(requests.next(), Box::pin(remote_command)),
+            select(
+                Box::pin(bluetooth_event),
+                select(Box::pin(connection_change), Box::pin(reconnected)),
+            ),
+        )
+        .await
+        {
+            Either::Left((Either::Left((request, _)), _)) => ControlEvent::Request(request),
+            Either::Left((Either::Right((command, _)), _)) => ControlEvent::Remote(command),
+            Either::Right((Either::Left((event, _)), _)) => ControlEvent::Bluetooth(event),
+            Either::Right((Either::Right((Either::Left(((), _)), _)), _)) => {
+                ControlEvent::ConnectionChanged
+            }
+            Either::Right((Either::Right((Either::Right((result, _)), _)), _)) => {
+                ControlEvent::Reconnected(result)
             }
-            None => log::info!("Bluetooth search timed out"),
         };
 
-        bluetooth.deinit().unwrap(); // TODO: Error handling
+        match event {
+            ControlEvent::Request(Some(ControlRequest::Output(output))) => {
+                log::info!("Switching output to {:?}", output);
+                wanted = output;
 
-        let mut machine = self.machine.lock().await;
-        bluetooth
-            .post_deinit(&mut machine.esp32)
-            .expect("Bluetooth post de_init failed");
-        drop(machine);
+                if let Some(store) = output_store.as_deref_mut() {
+                    if let Err(e) = store.save(output) {
+                        log::error!("Failed to save output: {}", e);
+                    }
+                }
 
-        log::info!("Playback state completed, going to boot state");
+                if output == Output::Bluetooth && has_bluetooth && !connected {
+                    log::info!("Bluetooth sink not connected, switching when it is");
+                } else if let Err(e) = sinks.select(output.sink_name()).await {
+                    log::info!("{}, restarting playback", e);
+                    stop_playback(playback_control);
+                } else {
+                    user_paused = false;
+                }
+            }
+            ControlEvent::Request(Some(ControlRequest::Volume(new_volume))) => {
+                volume = new_volume.min(MAX_VOLUME);
+                log::info!("Volume {}%", volume);
+                save_volume(output_store.as_deref_mut(), volume);
+                if has_bluetooth {
+                    report_volume(volume);
+                }
+            }
+            ControlEvent::Request(Some(ControlRequest::Play(folder))) => {
+                let folder = if folder.is_empty() {
+                    PLAYLIST_FOLDER.to_string()
+                } else {
+                    Path::new(PLAYLIST_FOLDER)
+                        .join(folder)
+                        .to_string_lossy()
+                        .into_owned()
+                };
 
-        StateEnum::Boot(ConcreteState::<Boot>::from(self))
+                // a folder that can't be played leaves the current one playing
+                let playlist = match Playlist::from_folder(&folder, false) {
+                    Ok(playlist) if !playlist.is_empty() => playlist,
+                    Ok(_) => {
+                        log::error!("Nothing to play in {}", folder);
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to read {}: {}", folder, e);
+                        continue;
+                    }
+                };
+                log::info!("Playing {}", folder);
+                if let Err(e) = Playlist::select_folder(PLAYLIST_FOLDER, &folder) {
+                    log::error!("Failed to save the folder chosen: {}", e);
+                }
+                if playlist_control.unbounded_send(playlist).is_err() {
+                    log::error!("Playback has already ended");
+                }
+                stop_playback(playback_control);
+            }
+            ControlEvent::Request(Some(ControlRequest::DisconnectPolicy(policy))) => {
+                log::info!("Disconnect policy {:?}", policy);
+                disconnect_policy = policy;
+
+                if let Some(store) = output_store.as_deref_mut() {
+                    if let Err(e) = store.save_disconnect_policy(policy) {
+                        log::error!("Failed to save disconnect policy: {}", e);
+                    }
+                }
+            }
+            ControlEvent::Request(Some(ControlRequest::PlayTestSignals)) => {
+                log::info!("Playing test signals");
+                let playlist = Playlist::test_signals(audio_generator::diagnostic_sequence());
+                if playlist_control.unbounded_send(playlist).is_err() {
+                    log::error!("Playback has already ended");
+                }
+                stop_playback(playback_control);
+            }
+            ControlEvent::Request(Some(ControlRequest::Equalizer(name))) => {
+                if let Some(bands) = select_equalizer(eq_presets.as_deref_mut(), &name) {
+                    change_sound(
+                        settings,
+                        playback_control,
+                        PlaybackCommand::SetEqualizer(bands),
+                    );
+                }
+            }
+            ControlEvent::Request(Some(ControlRequest::SaveEqualizer(name, bands))) => {
+                let Some(store) = eq_presets.as_deref_mut() else {
+                    log::error!("No NVS, equalizer presets can't be saved");
+                    continue;
+                };
+                if let Err(e) = store.save(&name, &bands) {
+                    log::error!("Failed to save equalizer preset {}: {}", name, e);
+                    continue;
+                }
+                log::info!("Saved equalizer preset {}", name);
+                if let Some(bands) = select_equalizer(Some(store), &name) {
+                    change_sound(
+                        settings,
+                        playback_control,
+                        PlaybackCommand::SetEqualizer(bands),
+                    );
+                }
+            }
+            ControlEvent::Request(Some(ControlRequest::NightMode(on))) => {
+                log::info!("Night mode {}", if on { "on" } else { "off" });
+                let dynamics = if on {
+                    DynamicsSettings::night_mode()
+                } else {
+                    DynamicsSettings::default()
+                };
+                change_sound(
+                    settings,
+                    playback_control,
+                    PlaybackCommand::SetDynamics(dynamics),
+                );
+            }
+            ControlEvent::Request(Some(ControlRequest::Speed(speed))) => {
+                log::info!("Speed {}", speed);
+                change_sound(settings, playback_control, PlaybackCommand::SetSpeed(speed));
+            }
+            ControlEvent::Request(Some(ControlRequest::AllowSink(device))) => {
+                change_policy(registry.as_deref_mut(), |policy| match device {
+                    Some(device) => policy.allow.push(device),
+                    None => policy.allow.clear(),
+                });
+            }
+            ControlEvent::Request(Some(ControlRequest::DenySink(device))) => {
+                change_policy(registry.as_deref_mut(), |policy| match device {
+                    Some(device) => policy.deny.push(device),
+                    None => policy.deny.clear(),
+                });
+            }
+            ControlEvent::Request(Some(ControlRequest::Pin(pin))) => {
+                change_pairing(registry.as_deref_mut(), |pairing| pairing.legacy_pin = pin);
+            }
+            ControlEvent::Request(Some(ControlRequest::IoCapability(io_capability))) => {
+                change_pairing(registry.as_deref_mut(), |pairing| {
+                    pairing.io_capability = io_capability
+                });
+            }
+            ControlEvent::Request(None) => return,
+            ControlEvent::Remote(None) => remote = None,
+            ControlEvent::Remote(Some(RemoteCommand::Volume(new_volume))) => {
+                if new_volume != volume {
+                    save_volume(output_store.as_deref_mut(), new_volume);
+                }
+                volume = new_volume;
+                sink_volume = true;
+            }
+            ControlEvent::Remote(Some(command)) => {
+                log::info!("Remote command {:?}", command);
+
+                let playback_command = match command {
+                    // Headphones which don't know whether we are playing send play for both
+                    RemoteCommand::Play if sinks.active().is_none() => {
+                        if user_paused {
+                            user_paused = false;
+                            if wanted == Output::Bluetooth && has_bluetooth && !connected {
+                                log::info!("Bluetooth sink not connected, playing when it is");
+                            } else if let Err(e) = sinks.select(wanted.sink_name()).await {
+                                log::error!("Failed to resume: {}", e);
+                                stop_playback(playback_control);
+                            }
+                        }
+                        None
+                    }
+                    RemoteCommand::Play | RemoteCommand::Pause | RemoteCommand::Stop => {
+                        if sinks.active().is_some() {
+                            match sinks.pause().await {
+                                Ok(()) => user_paused = true,
+                                Err(e) => {
+                                    log::error!("Failed to pause: {}", e);
+                                    stop_playback(playback_control);
+                                }
+                            }
+                        }
+                        None
+                    }
+                    // in music, which has no chapters, these skip tracks
+                    RemoteCommand::Next => Some(PlaybackCommand::NextChapter),
+                    RemoteCommand::Previous => Some(PlaybackCommand::PreviousChapter),
+                    RemoteCommand::FastForward => Some(PlaybackCommand::SeekBy(SEEK_STEP_MS)),
+                    RemoteCommand::Rewind => Some(PlaybackCommand::SeekBy(-SEEK_STEP_MS)),
+                    RemoteCommand::Volume(_) => None,
+                };
+
+                if let Some(playback_command) = playback_command {
+                    if playback_control.unbounded_send(playback_command).is_err() {
+                        log::error!("Playback has already ended");
+                    }
+                }
+            }
+            ControlEvent::Bluetooth(None) => events = None,
+            // the stack logs errors itself
+            ControlEvent::Bluetooth(Some(Err(_))) => {}
+            ControlEvent::Bluetooth(Some(Ok(event))) => log::debug!("Bluetooth event {:?}", event),
+            ControlEvent::ConnectionChanged if connected => {
+                connected = false;
+                sink_volume = false;
+                if sinks.active() != Some(bluetooth) {
+                    continue;
+                }
+
+                log::warn!("Bluetooth sink disconnected, {:?}", disconnect_policy);
+                let result = match disconnect_policy {
+                    DisconnectPolicy::Stop => {
+                        stop_playback(playback_control);
+                        Ok(())
+                    }
+                    DisconnectPolicy::ContinueLocally => {
+                        sinks.select(Output::I2s.sink_name()).await
+                    }
+                    DisconnectPolicy::PauseAndWait => sinks.pause().await,
+                };
+                if let Err(e) = result {
+                    log::error!("Failed to leave the Bluetooth sink: {}", e);
+                    stop_playback(playback_control);
+                } else if let Some(address) = sink_address {
+                    if disconnect_policy != DisconnectPolicy::Stop {
+                        reconnect_delay = Duration::from_millis(RECONNECT_DELAY_MS);
+                        reconnecting = Some(reconnect(address, reconnect_delay));
+                    }
+                }
+            }
+            ControlEvent::ConnectionChanged => {
+                connected = true;
+                reconnecting = None;
+                log::info!("Bluetooth sink connected again");
+
+                let playing = sinks.active().is_some() || (sinks.is_paused() && !user_paused);
+                if wanted == Output::Bluetooth && playing {
+                    if let Err(e) = sinks.select(bluetooth).await {
+                        log::error!("Failed to go back to the Bluetooth sink: {}", e);
+                        stop_playback(playback_control);
+                    }
+                }
+            }
+            ControlEvent::Reconnected(result) => {
+                reconnecting = None;
+                match (result, sink_address) {
+                    // the connection changes, and playback follows, as when the sink reconnects
+                    (Ok(()), _) => log::info!("Reconnected to the Bluetooth sink"),
+                    (Err(e), Some(address))
+                        if !connected && disconnect_policy != DisconnectPolicy::Stop =>
+                    {
+                        log::info!("Bluetooth sink didn't reconnect: {}", e);
+                        reconnect_delay = (reconnect_delay * 2)
+                            .min(Duration::from_millis(RECONNECT_MAX_DELAY_MS));
+                        reconnecting = Some(reconnect(address, reconnect_delay));
+                    }
+                    (Err(e), _) => log::info!("Bluetooth sink didn't reconnect: {}", e),
+                }
+            }
+        }
+    }
+}
+
+// After the delay, tries to connect to the sink at address
+fn reconnect(address: BDAddr, delay: Duration) -> BoxFuture<'static, Result<()>> {
+    log::info!(
+        "Reconnecting to {} in {} s",
+        bdaddr_to_string(address),
+        delay.as_secs()
+    );
+    let (sender, receiver) = oneshot::channel();
+    if let Err(e) = thread::Builder::new()
+        .name("reconnect".to_owned())
+        .stack_size(2048)
+        .spawn(move || {
+            thread::sleep(delay);
+            let _ = sender.send(());
+        })
+    {
+        log::error!("Failed to start reconnect timer: {}", e);
+    }
+
+    async move {
+        // a timer which failed to start reconnects right away
+        let _ = receiver.await;
+        ESP32Bluetooth::a2dp_reconnect(&address).await
+    }
+    .boxed()
+}
+
+// The sink is set to it when it connects, if it does absolute volume
+fn report_volume(volume: u8) {
+    if let Err(e) = ESP32Bluetooth::avrcp_set_volume(volume) {
+        log::error!("Failed to set the volume of the sink: {}", e);
+    }
+}
+
+fn save_volume(output_store: Option<&mut OutputStore>, volume: u8) {
+    if let Some(store) = output_store {
+        if let Err(e) = store.save_volume(volume) {
+            log::error!("Failed to save volume: {}", e);
+        }
+    }
+}
+
+fn change_policy(registry: Option<&mut DeviceRegistry>, change: impl FnOnce(&mut SelectionPolicy)) {
+    let Some(registry) = registry else {
+        log::error!("No NVS, the sinks allowed can't be changed");
+        return;
+    };
+    let mut policy = match registry.load_policy() {
+        Ok(policy) => policy,
+        Err(e) => {
+            log::error!("Failed to load the sinks allowed: {}", e);
+            return;
+        }
+    };
+
+    change(&mut policy);
+    let list = |devices: &[DeviceMatch]| {
+        let devices: Vec<String> = devices.iter().map(DeviceMatch::to_string).collect();
+        devices.join(", ")
+    };
+    log::info!(
+        "Sinks allowed: {}; denied: {}",
+        if policy.allow.is_empty() {
+            "any".to_string()
+        } else {
+            list(&policy.allow)
+        },
+        if policy.deny.is_empty() {
+            "none".to_string()
+        } else {
+            list(&policy.deny)
+        }
+    );
+    if let Err(e) = registry.save_policy(&policy) {
+        log::error!("Failed to save the sinks allowed: {}", e);
+    }
+}
+
+fn change_pairing(registry: Option<&mut DeviceRegistry>, change: impl FnOnce(&mut PairingConfig)) {
+    let Some(registry) = registry else {
+        log::error!("No NVS, pairing can't be changed");
+        return;
+    };
+    let mut pairing = match registry.load_pairing() {
+        Ok(pairing) => pairing,
+        Err(e) => {
+            log::error!("Failed to load pairing: {}", e);
+            return;
+        }
+    };
+
+    change(&mut pairing);
+    match registry.save_pairing(&pairing) {
+        Ok(()) => log::info!(
+            "Pairing with IO capability {:?}, PIN {}, from the next start of Bluetooth",
+            pairing.io_capability,
+            pairing.legacy_pin.as_deref().unwrap_or("default")
+        ),
+        Err(e) => log::error!("Failed to save pairing: {}", e),
+    }
+}
+
+// The bands of the preset, which becomes the active one. Without NVS, only the built-in presets
+// can be used, and not remembered.
+fn select_equalizer(eq_presets: Option<&mut EqPresetStore>, name: &str) -> Option<Vec<EqBand>> {
+    let bands = match eq_presets.as_deref() {
+        Some(store) => store.load(name),
+        None => Ok(builtin_preset(name)),
+    };
+    let bands = match bands {
+        Ok(Some(bands)) => bands,
+        Ok(None) => {
+            log::error!("No equalizer preset {}", name);
+            return None;
+        }
+        Err(e) => {
+            log::error!("Failed to load equalizer preset {}: {}", name, e);
+            return None;
+        }
+    };
+
+    log::info!("Equalizer preset {}", name);
+    if let Some(store) = eq_presets {
+        if let Err(e) = store.set_active_preset(name) {
+            log::error!("Failed to save equalizer preset: {}", e);
+        }
+    }
+    Some(bands)
+}
+
+// Changes the sound of the playlist playing, and of the ones played after it
+fn change_sound(
+    settings: &Mutex<PlaybackSettings>,
+    playback_control: &UnboundedSender<PlaybackCommand>,
+    command: PlaybackCommand,
+) {
+    let mut current = settings.lock().unwrap_or_else(PoisonError::into_inner);
+    match &command {
+        PlaybackCommand::SetSpeed(speed) => current.speed = *speed,
+        PlaybackCommand::SetEqualizer(bands) => current.equalizer = bands.clone(),
+        PlaybackCommand::SetDynamics(dynamics) => current.dynamics = *dynamics,
+        _ => {}
+    }
+    drop(current);
+
+    if playback_control.unbounded_send(command).is_err() {
+        log::error!("Playback has already ended");
+    }
+}
+
+fn stop_playback(playback_control: &UnboundedSender<PlaybackCommand>) {
+    if playback_control
+        .unbounded_send(PlaybackCommand::Stop)
+        .is_err()
+    {
+        log::error!("Playback has already ended");
+    }
+}
+
+// Without NVS every boot starts with discovery
+fn open_device_registry(machine: &StateMachine) -> Option<DeviceRegistry> {
+    let Some(partition) = machine.nvs_partition.clone() else {
+        log::warn!("No NVS partition, devices won't be remembered");
+        return None;
+    };
+
+    match DeviceRegistry::new(partition) {
+        Ok(registry) => Some(registry),
+        Err(e) => {
+            log::error!("Failed to open device registry: {}", e);
+            None
+        }
+    }
+}
+
+// Without NVS the output is always Bluetooth
+fn open_output_store(machine: &StateMachine) -> Option<OutputStore> {
+    let Some(partition) = machine.nvs_partition.clone() else {
+        log::warn!("No NVS partition, output can't be changed");
+        return None;
+    };
+
+    match OutputStore::new(partition) {
+        Ok(store) => Some(store),
+        Err(e) => {
+            log::error!("Failed to open output store: {}", e);
+            None
+        }
+    }
+}
+
+// Without NVS, or with a broken list, any sink may be connected to
+fn load_policy(registry: Option<&DeviceRegistry>) -> SelectionPolicy {
+    match registry.map(|registry| registry.load_policy()) {
+        Some(Ok(policy)) => policy,
+        Some(Err(e)) => {
+            log::error!("Failed to load the sinks allowed: {}", e);
+            SelectionPolicy::default()
+        }
+        None => SelectionPolicy::default(),
+    }
+}
+
+// Without NVS, or when it can't be read, pairing is just works
+fn load_pairing(registry: Option<&DeviceRegistry>) -> PairingConfig {
+    match registry.map(|registry| registry.load_pairing()) {
+        Some(Ok(pairing)) => pairing,
+        Some(Err(e)) => {
+            log::error!("Failed to load pairing: {}", e);
+            PairingConfig::default()
+        }
+        None => PairingConfig::default(),
+    }
+}
+
+// Pairing which needs the user is answered on the console, also while connecting, so the handler
+// has a thread of its own. It ends when the events are replaced, with the next start of Bluetooth.
+fn start_pairing_handler(events: UnboundedReceiver<PairingEvent>) {
+    let (replies_sender, replies) = mpsc::unbounded();
+    console::connect_pairing(replies_sender);
+
+    if let Err(e) = thread::Builder::new()
+        .name("pairing".to_owned())
+        .stack_size(4096)
+        .spawn(move || block_on(handle_pairing(events, replies)))
+    {
+        log::error!("Failed to start pairing handler: {}", e);
+    }
+}
+
+// The sink waiting for an answer, and whether it wants a passkey rather than a yes or no
+type PairingQuestion = (BDAddr, bool);
+
+async fn handle_pairing(
+    mut events: UnboundedReceiver<PairingEvent>,
+    mut replies: UnboundedReceiver<PairingReply>,
+) {
+    let mut question: Option<PairingQuestion> = None;
+
+    loop {
+        match select(events.next(), replies.next()).await {
+            Either::Left((None, _)) | Either::Right((None, _)) => return,
+            Either::Left((Some(event), _)) => match event {
+                PairingEvent::ConfirmPasskey { address, passkey } => {
+                    log::warn!(
+                        "{} asks to pair: does it show {:06}? Answer pair yes or pair no",
+                        bdaddr_to_string(address),
+                        passkey
+                    );
+                    question = Some((address, false));
+                }
+                PairingEvent::EnterPasskey { address } => {
+                    log::warn!(
+                        "{} asks to pair: answer pair and the passkey it shows, or pair no",
+                        bdaddr_to_string(address)
+                    );
+                    question = Some((address, true));
+                }
+                PairingEvent::ShowPasskey { address, passkey } => log::warn!(
+                    "{} asks to pair: enter {:06} on it",
+                    bdaddr_to_string(address),
+                    passkey
+                ),
+                PairingEvent::Completed(authentication) => {
+                    // a question not answered in time is gone
+                    question = None;
+                    if authentication.success {
+                        log::info!("Paired with {}", bdaddr_to_string(authentication.address));
+                    } else {
+                        log::warn!(
+                            "Pairing with {} failed",
+                            bdaddr_to_string(authentication.address)
+                        );
+                    }
+                }
+            },
+            Either::Right((Some(reply), _)) => {
+                let result = match (question, reply) {
+                    (None, _) => {
+                        log::warn!("No sink is asking to pair");
+                        continue;
+                    }
+                    (Some((address, false)), PairingReply::Accept) => {
+                        ESP32Bluetooth::gap_answer_confirmation(&address, true)
+                    }
+                    (Some((address, false)), PairingReply::Reject) => {
+                        ESP32Bluetooth::gap_answer_confirmation(&address, false)
+                    }
+                    (Some((address, true)), PairingReply::Passkey(passkey)) => {
+                        ESP32Bluetooth::gap_answer_passkey(&address, Some(passkey))
+                    }
+                    (Some((address, true)), PairingReply::Reject) => {
+                        ESP32Bluetooth::gap_answer_passkey(&address, None)
+                    }
+                    (Some((_, false)), PairingReply::Passkey(_)) => {
+                        log::warn!("Answer pair yes or pair no");
+                        continue;
+                    }
+                    (Some((_, true)), PairingReply::Accept) => {
+                        log::warn!("Answer pair and the passkey the sink shows");
+                        continue;
+                    }
+                };
+                question = None;
+                if let Err(e) = result {
+                    log::error!("Failed to answer pairing: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// Without NVS only the built-in equalizer presets can be used
+fn open_eq_presets(machine: &StateMachine) -> Option<EqPresetStore> {
+    let Some(partition) = machine.nvs_partition.clone() else {
+        log::warn!("No NVS partition, equalizer presets can't be saved");
+        return None;
+    };
+
+    match EqPresetStore::new(partition) {
+        Ok(store) => Some(store),
+        Err(e) => {
+            log::error!("Failed to open equalizer presets: {}", e);
+            None
+        }
+    }
+}
+
+// A missing or broken preset store shouldn't stop playback, so fall back to no equalization
+fn load_equalizer(eq_presets: Option<&EqPresetStore>) -> Vec<EqBand> {
+    match eq_presets.map(|store| store.load_active()) {
+        Some(Ok(bands)) => bands,
+        Some(Err(e)) => {
+            log::error!("Failed to load equalizer preset: {}", e);
+            Vec::new()
+        }
+        None => Vec::new(),
     }
 }
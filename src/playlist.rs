@@ -0,0 +1,304 @@
+// What the playback task plays: the audio files of a folder, either as music (optionally shuffled)
+// or, for folders below audiobook::AUDIOBOOK_ROOT, as an audiobook. In music folders, a file
+// described by a CUE sheet is played as the tracks of the sheet. A playlist can also be a list
+// of built in test signals, which works without an SD card.
+//
+// The folder chosen to play is kept in a file in the root folder, so that e.g. a book being
+// listened to is what plays after a restart.
+
+use std::{path::Path, time::SystemTime};
+
+use anyhow::Result;
+
+use crate::{
+    audio_generator::TestSignal,
+    audiobook::Audiobook,
+    cue_sheet::{CueSheet, CueTrack},
+};
+
+const AUDIO_FILE_EXTENSIONS: [&str; 2] = ["ogg", "wav"];
+const SELECTION_FILENAME: &str = "playlist.txt";
+
+// Previous track within this time of the start of a track goes to the track before,
+// otherwise to the start of the current track.
+const RESTART_TRACK_THRESHOLD_MS: u64 = 3000;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PlaylistPosition {
+    pub index: usize,
+    pub position_ms: u64,
+}
+
+// For CUE tracks, positions are relative to the start of the track
+#[derive(Debug, Clone)]
+pub enum PlaylistEntry {
+    File(String),
+    CueTrack(CueTrack),
+    // Without a duration, the signal plays until skipped
+    TestSignal {
+        signal: TestSignal,
+        duration_ms: Option<u64>,
+    },
+}
+
+pub struct Playlist {
+    entries: Vec<PlaylistEntry>,
+    audiobook: Option<Audiobook>,
+}
+
+impl Playlist {
+    pub fn from_folder(folder: &str, shuffle: bool) -> Result<Self> {
+        let mut files = Vec::new();
+        let mut cue_sheets = Vec::new();
+
+        for entry in std::fs::read_dir(folder)? {
+            let path = entry?.path();
+            let extension_is = |known: &str| {
+                path.extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case(known))
+            };
+
+            if AUDIO_FILE_EXTENSIONS
+                .iter()
+                .any(|known| extension_is(known))
+            {
+                files.push(path.to_string_lossy().into_owned());
+            } else if extension_is("cue") {
+                cue_sheets.push(path.to_string_lossy().into_owned());
+            }
+        }
+        files.sort();
+
+        if Audiobook::is_audiobook_folder(folder) {
+            if shuffle {
+                log::info!("Not shuffling audiobook {}", folder);
+            }
+            return Ok(Playlist {
+                entries: files.iter().cloned().map(PlaylistEntry::File).collect(),
+                audiobook: Some(Audiobook::open(folder, files)?),
+            });
+        }
+
+        let cue_tracks = read_cue_tracks(&cue_sheets, &files);
+        let mut entries = Vec::new();
+        for file in files {
+            let tracks: Vec<_> = cue_tracks.iter().filter(|t| t.file == file).collect();
+
+            if tracks.is_empty() {
+                entries.push(PlaylistEntry::File(file));
+            } else {
+                entries.extend(tracks.into_iter().cloned().map(PlaylistEntry::CueTrack));
+            }
+        }
+        if shuffle {
+            shuffle_entries(&mut entries);
+        }
+
+        Ok(Playlist {
+            entries,
+            audiobook: None,
+        })
+    }
+
+    pub fn test_signals(signals: Vec<(TestSignal, Option<u64>)>) -> Self {
+        Playlist {
+            entries: signals
+                .into_iter()
+                .map(|(signal, duration_ms)| PlaylistEntry::TestSignal {
+                    signal,
+                    duration_ms,
+                })
+                .collect(),
+            audiobook: None,
+        }
+    }
+
+    // The folder last chosen with select_folder, or the root folder itself
+    pub fn selected_folder(root: &str) -> String {
+        match std::fs::read_to_string(Path::new(root).join(SELECTION_FILENAME)) {
+            Ok(contents) if !contents.trim().is_empty() => contents.trim().to_string(),
+            Ok(_) => root.to_string(),
+            Err(e) => {
+                log::info!("No folder chosen in {}: {}", root, e);
+                root.to_string()
+            }
+        }
+    }
+
+    pub fn select_folder(root: &str, folder: &str) -> Result<()> {
+        std::fs::write(
+            Path::new(root).join(SELECTION_FILENAME),
+            format!("{}\n", folder),
+        )?;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entry(&self, index: usize) -> &PlaylistEntry {
+        &self.entries[index]
+    }
+
+    pub fn audiobook(&self) -> Option<&Audiobook> {
+        self.audiobook.as_ref()
+    }
+
+    pub fn start(&self) -> Option<PlaylistPosition> {
+        if self.is_empty() {
+            return None;
+        }
+
+        match &self.audiobook {
+            Some(book) => Some(book.resume_position()),
+            None => Some(PlaylistPosition::default()),
+        }
+    }
+
+    pub fn next_track(&self, position: &PlaylistPosition) -> Option<PlaylistPosition> {
+        if position.index + 1 < self.entries.len() {
+            Some(PlaylistPosition {
+                index: position.index + 1,
+                position_ms: 0,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn previous_track(&self, position: &PlaylistPosition) -> Option<PlaylistPosition> {
+        let index = if position.position_ms > RESTART_TRACK_THRESHOLD_MS || position.index == 0 {
+            position.index
+        } else {
+            position.index - 1
+        };
+
+        Some(PlaylistPosition {
+            index,
+            position_ms: 0,
+        })
+    }
+
+    // Seeking stops at the start of the track, and past its end goes to the next track. Files
+    // without a known duration stop at their end by themselves.
+    pub fn seek(&self, position: &PlaylistPosition, delta_ms: i64) -> Option<PlaylistPosition> {
+        let position_ms = position.position_ms.saturating_add_signed(delta_ms);
+        let duration_ms = self.entries[position.index].duration_ms();
+        if duration_ms.is_some_and(|duration_ms| position_ms >= duration_ms) {
+            return self.next_track(position);
+        }
+
+        Some(PlaylistPosition {
+            index: position.index,
+            position_ms,
+        })
+    }
+
+    // Music has no chapters, so chapter navigation there moves between tracks
+    pub fn next_chapter(&self, position: &PlaylistPosition) -> Option<PlaylistPosition> {
+        match &self.audiobook {
+            Some(book) => book.next_chapter(position),
+            None => self.next_track(position),
+        }
+    }
+
+    pub fn previous_chapter(&self, position: &PlaylistPosition) -> Option<PlaylistPosition> {
+        match &self.audiobook {
+            Some(book) => book.previous_chapter(position),
+            None => self.previous_track(position),
+        }
+    }
+
+    // Remembers the position for next time. Only audiobooks have bookmarks.
+    pub fn store_position(&self, position: &PlaylistPosition) -> Result<()> {
+        match &self.audiobook {
+            Some(book) => book.save_bookmark(position),
+            None => Ok(()),
+        }
+    }
+}
+
+impl PlaylistEntry {
+    pub fn title(&self) -> String {
+        match self {
+            PlaylistEntry::File(file) => file_stem(file),
+            PlaylistEntry::CueTrack(track) => track
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("{} track {}", file_stem(&track.file), track.number)),
+            PlaylistEntry::TestSignal { signal, .. } => format!("{:?}", signal),
+        }
+    }
+
+    // Only CUE tracks and test signals know how long they are
+    pub fn duration_ms(&self) -> Option<u64> {
+        match self {
+            PlaylistEntry::File(_) => None,
+            PlaylistEntry::CueTrack(track) => track
+                .end_ms
+                .map(|end_ms| end_ms.saturating_sub(track.start_ms)),
+            PlaylistEntry::TestSignal { duration_ms, .. } => *duration_ms,
+        }
+    }
+
+    pub fn performer(&self) -> Option<&str> {
+        match self {
+            PlaylistEntry::CueTrack(track) => track.performer.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+// The tracks of all CUE sheets which refer to files we can play, sorted by file and start.
+// Tracks without a PERFORMER get the one of the album.
+fn read_cue_tracks(cue_sheets: &[String], files: &[String]) -> Vec<CueTrack> {
+    let mut tracks = Vec::new();
+
+    for cue_sheet in cue_sheets {
+        let sheet = match CueSheet::read_file(cue_sheet) {
+            Ok(sheet) => sheet,
+            Err(e) => {
+                log::error!("Failed to read CUE sheet {}: {}", cue_sheet, e);
+                continue;
+            }
+        };
+
+        for mut track in sheet.tracks {
+            if !files.contains(&track.file) {
+                log::warn!("{}: can't play {}, skipping", cue_sheet, track.file);
+                continue;
+            }
+            if track.performer.is_none() {
+                track.performer = sheet.performer.clone();
+            }
+            tracks.push(track);
+        }
+    }
+    tracks.sort_by(|a, b| (&a.file, a.start_ms).cmp(&(&b.file, b.start_ms)));
+
+    tracks
+}
+
+fn file_stem(path: &str) -> String {
+    Path::new(path).file_stem().map_or_else(
+        || path.to_string(),
+        |stem| stem.to_string_lossy().into_owned(),
+    )
+}
+
+// Fisher-Yates with a xorshift generator; this doesn't need to be a good random generator.
+fn shuffle_entries(entries: &mut [PlaylistEntry]) {
+    let mut state = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(1, |d| d.as_micros() as u32)
+        | 1;
+
+    for i in (1..entries.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+
+        entries.swap(i, state as usize % (i + 1));
+    }
+}
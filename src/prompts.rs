@@ -0,0 +1,75 @@
+// The voice prompts / notification sounds of the player. A prompt can be replaced by putting a
+// 16 bit, 44.1 kHz WAV file named after it in PROMPT_FOLDER on the SD card, e.g.
+// /sdcard/prompts/connected.wav. Without one, a chime built into the firmware is played.
+
+use std::f32::consts::PI;
+
+use crate::{
+    audio::{CHANNELS, SAMPLE_RATE},
+    audio_mixer::PromptClip,
+};
+
+const PROMPT_FOLDER: &str = "/sdcard/prompts";
+// The built in chimes are quieter than full scale so they don't startle
+const CHIME_LEVEL: f32 = 0.3 * 32767.0;
+const CHIME_NOTE_MS: u64 = 150;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prompt {
+    Connected,
+    // Nothing measures the battery yet
+    #[allow(dead_code)]
+    BatteryLow,
+    PlaylistLoaded,
+}
+
+impl Prompt {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Prompt::Connected => "connected",
+            Prompt::BatteryLow => "battery_low",
+            Prompt::PlaylistLoaded => "playlist_loaded",
+        }
+    }
+
+    // The notes of the built in chime, in Hz
+    fn chime_notes(&self) -> &'static [f32] {
+        match self {
+            Prompt::Connected => &[660.0, 880.0],
+            Prompt::BatteryLow => &[880.0, 660.0, 440.0],
+            Prompt::PlaylistLoaded => &[880.0],
+        }
+    }
+
+    // The clip from the SD card if there is one, otherwise the built in chime
+    pub fn load(&self) -> PromptClip {
+        let path = format!("{}/{}.wav", PROMPT_FOLDER, self.name());
+
+        if std::path::Path::new(&path).exists() {
+            match PromptClip::from_wav_file(&path) {
+                Ok(clip) => return clip,
+                Err(e) => log::error!("Failed to load prompt {}: {}", path, e),
+            }
+        }
+
+        chime(self.chime_notes())
+    }
+}
+
+// Sine notes with an exponential decay, like a soft bell
+fn chime(notes: &[f32]) -> PromptClip {
+    let note_frames = (CHIME_NOTE_MS * SAMPLE_RATE as u64 / 1000) as usize;
+    let mut samples = Vec::with_capacity(notes.len() * note_frames * CHANNELS);
+
+    for frequency in notes {
+        for frame in 0..note_frames {
+            let t = frame as f32 / SAMPLE_RATE as f32;
+            let envelope = (-t * 20.0).exp() * (frame as f32 / 64.0).min(1.0);
+            let value = (2.0 * PI * frequency * t).sin() * envelope * CHIME_LEVEL;
+
+            samples.extend([value as i16; CHANNELS]);
+        }
+    }
+
+    PromptClip::from_samples(samples)
+}
@@ -0,0 +1,153 @@
+// Which Bluetooth sinks we may connect to, so that the player doesn't pick the neighbour's speaker
+// just because it answered first. Discovery results are filtered by allow and deny lists, signal
+// strength and Class of Device, collected for a while, and the strongest one wins.
+
+use std::fmt;
+
+use crate::{
+    bluetooth_gap_hal::{AudioVideoMinor, MinorClass, ScannedDevice},
+    bluetooth_hal::BDAddr,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceMatch {
+    Address(BDAddr),
+    // case insensitive, * matches any text, e.g. "JBL*" or "*kitchen*"
+    Name(String),
+}
+
+impl DeviceMatch {
+    // An address, as 12 hex digits with or without colons, or else a name pattern
+    pub fn parse(text: &str) -> Self {
+        let digits = text.replace(':', "");
+        let is_address = digits.len() == 12
+            && digits.chars().all(|c| c.is_ascii_hexdigit())
+            && (digits.len() == text.len() || text.split(':').all(|part| part.len() == 2));
+        if !is_address {
+            return DeviceMatch::Name(text.to_string());
+        }
+
+        let mut address = BDAddr::default();
+        for (index, byte) in address.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&digits[index * 2..index * 2 + 2], 16).unwrap_or(0);
+        }
+        DeviceMatch::Address(address)
+    }
+
+    pub fn matches(&self, address: &BDAddr, name: Option<&str>) -> bool {
+        match self {
+            DeviceMatch::Address(a) => a == address,
+            DeviceMatch::Name(pattern) => {
+                name.is_some_and(|name| glob_match(&pattern.to_lowercase(), &name.to_lowercase()))
+            }
+        }
+    }
+}
+
+// What parse reads back
+impl fmt::Display for DeviceMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceMatch::Address(address) => {
+                let bytes: Vec<String> = address.iter().map(|b| format!("{b:02X}")).collect();
+                write!(f, "{}", bytes.join(":"))
+            }
+            DeviceMatch::Name(pattern) => write!(f, "{}", pattern),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SelectionPolicy {
+    // if not empty, only devices matching one of these
+    pub allow: Vec<DeviceMatch>,
+    pub deny: Vec<DeviceMatch>,
+    // dBm; devices further away, or which didn't report a signal strength, are skipped
+    pub min_rssi: Option<i8>,
+    // only devices whose Class of Device says they play audio, and if not empty, of one of these
+    // minors, e.g. only loudspeakers
+    pub audio_sinks_only: bool,
+    pub minor_classes: Vec<AudioVideoMinor>,
+    // how long to collect discovery results before picking the best; 0 takes the first match
+    pub collect_ms: u64,
+}
+
+impl Default for SelectionPolicy {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            min_rssi: None,
+            audio_sinks_only: true,
+            minor_classes: Vec::new(),
+            collect_ms: 5000,
+        }
+    }
+}
+
+impl SelectionPolicy {
+    // The allow and deny lists only, for devices known from before that haven't been scanned
+    pub fn allows(&self, address: &BDAddr, name: Option<&str>) -> bool {
+        if self.deny.iter().any(|m| m.matches(address, name)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|m| m.matches(address, name))
+    }
+
+    pub fn accepts(&self, device: &ScannedDevice) -> bool {
+        if !self.allows(&device.address, device.name.as_deref()) {
+            return false;
+        }
+
+        if let Some(min_rssi) = self.min_rssi {
+            if device.rssi.map_or(true, |rssi| rssi < min_rssi) {
+                return false;
+            }
+        }
+
+        if self.audio_sinks_only {
+            let Some(class) = &device.class else {
+                return false;
+            };
+            if !class.is_audio_sink() {
+                return false;
+            }
+            let wanted_minor = match class.minor_class() {
+                MinorClass::AudioVideo(minor) => self.minor_classes.contains(&minor),
+                _ => false,
+            };
+            if !self.minor_classes.is_empty() && !wanted_minor {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // The accepted device with the strongest signal; of equals, the first found
+    pub fn best<'a>(&self, candidates: &'a [ScannedDevice]) -> Option<&'a ScannedDevice> {
+        candidates
+            .iter()
+            .filter(|device| self.accepts(device))
+            .fold(None, |best: Option<&ScannedDevice>, device| match best {
+                Some(best) if best.rssi >= device.rssi => Some(best),
+                _ => Some(device),
+            })
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            // try the rest of the pattern at every position the star could end
+            text.char_indices()
+                .map(|(index, _)| index)
+                .chain(std::iter::once(text.len()))
+                .any(|index| glob_match(rest, &text[index..]))
+        }
+    }
+}
@@ -1,9 +1,12 @@
+use num_derive::FromPrimitive;
+
 #[allow(dead_code)]
 struct Bluetooth16bitUUID {
     value: u16,
 }
 
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
 pub enum Bluetooth16bitUUIDEnum {
     Headset = 0x1108,                   // Service Class and Profile
     AudioSink = 0x110b,                 //  AudioSink Service Class
@@ -0,0 +1,183 @@
+// Reads the Vorbis comment header (the "tags") of an Ogg Vorbis file.
+// librespot_tremor doesn't expose the comments, so we walk the Ogg pages ourselves until we
+// have the second packet of the first logical bitstream.
+// See https://xiph.org/vorbis/doc/Vorbis_I_spec.html section 5 and https://xiph.org/ogg/doc/framing.html
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+};
+
+use anyhow::{bail, Result};
+
+// Embedded cover art can make the comment header very large; don't try to hold that in RAM.
+const MAX_COMMENT_PACKET_SIZE: usize = 65536;
+
+#[derive(Debug, Default, Clone)]
+pub struct VorbisComments {
+    pub vendor: String,
+    pub comments: Vec<(String, String)>,
+}
+
+// A chapter marker as written by the Vorbis chapter extension, e.g.
+// CHAPTER001=00:00:00.000
+// CHAPTER001NAME=Introduction
+#[derive(Debug, Clone)]
+pub struct VorbisChapter {
+    pub start_ms: u64,
+    pub title: Option<String>,
+}
+
+impl VorbisComments {
+    pub fn read_file(filename: &str) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(filename)?);
+
+        VorbisComments::read(&mut reader).map_err(|e| anyhow::anyhow!("{}: {}", filename, e))
+    }
+
+    // Reads pages from the beginning of a logical bitstream until the comment header is complete
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut packet = Vec::new();
+        let mut packet_index = 0;
+
+        loop {
+            let (lacing, data) = read_page(reader)?;
+            let mut offset = 0;
+
+            for segment_len in lacing {
+                let segment_len = segment_len as usize;
+                packet.extend_from_slice(&data[offset..(offset + segment_len)]);
+                offset += segment_len;
+
+                if packet.len() > MAX_COMMENT_PACKET_SIZE {
+                    bail!("Vorbis header packet too large");
+                }
+
+                // a segment shorter than 255 bytes ends the packet
+                if segment_len < 255 {
+                    if packet_index == 1 {
+                        return VorbisComments::parse(&packet);
+                    }
+                    packet_index += 1;
+                    packet.clear();
+                }
+            }
+        }
+    }
+
+    pub fn parse(packet: &[u8]) -> Result<Self> {
+        if packet.len() < 7 || packet[0] != 3 || &packet[1..7] != b"vorbis" {
+            bail!("Not a Vorbis comment header");
+        }
+        let mut index = 7;
+
+        let vendor_len = read_u32_le(packet, &mut index)? as usize;
+        let vendor = String::from_utf8_lossy(read_bytes(packet, &mut index, vendor_len)?).into();
+
+        let count = read_u32_le(packet, &mut index)?;
+        let mut comments = Vec::new();
+
+        for _ in 0..count {
+            let len = read_u32_le(packet, &mut index)? as usize;
+            let comment = String::from_utf8_lossy(read_bytes(packet, &mut index, len)?);
+
+            match comment.split_once('=') {
+                Some((key, value)) => comments.push((key.to_uppercase(), value.to_string())),
+                None => log::warn!("Ignoring malformed Vorbis comment {}", comment),
+            }
+        }
+
+        Ok(VorbisComments { vendor, comments })
+    }
+
+    // Field names are case insensitive, and are stored in upper case.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = key.to_uppercase();
+
+        self.comments
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn chapters(&self) -> Vec<VorbisChapter> {
+        let mut chapters: Vec<(&str, VorbisChapter)> = Vec::new();
+
+        for (key, value) in &self.comments {
+            if let Some(id) = key.strip_prefix("CHAPTER") {
+                if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+                    continue;
+                }
+                match parse_timestamp(value) {
+                    Some(start_ms) => chapters.push((
+                        id,
+                        VorbisChapter {
+                            start_ms,
+                            title: self.get(&format!("CHAPTER{id}NAME")).map(str::to_string),
+                        },
+                    )),
+                    None => log::warn!("Ignoring chapter with bad timestamp {}={}", key, value),
+                }
+            }
+        }
+        chapters.sort_by_key(|(_, chapter)| chapter.start_ms);
+
+        chapters.into_iter().map(|(_, chapter)| chapter).collect()
+    }
+}
+
+// Reads one Ogg page, returning the segment table and the page data
+pub fn read_page<R: Read>(reader: &mut R) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut header = [0u8; 27];
+    reader.read_exact(&mut header)?;
+
+    if &header[0..4] != b"OggS" {
+        bail!("Lost Ogg page sync");
+    }
+
+    let mut lacing = vec![0u8; header[26] as usize];
+    reader.read_exact(&mut lacing)?;
+
+    let mut data = vec![0u8; lacing.iter().map(|l| *l as usize).sum()];
+    reader.read_exact(&mut data)?;
+
+    Ok((lacing, data))
+}
+
+// HH:MM:SS.mmm, as used by CHAPTERxx comments
+fn parse_timestamp(value: &str) -> Option<u64> {
+    let (hms, fraction) = value.trim().split_once('.').unwrap_or((value.trim(), "0"));
+    let mut parts = hms.split(':');
+
+    let hours: u32 = parts.next()?.parse().ok()?;
+    let minutes: u32 = parts.next()?.parse().ok()?;
+    let seconds: u32 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    // only millisecond resolution is kept, "5" means 500 ms
+    let fraction = format!("{:0<3}", &fraction[..fraction.len().min(3)]);
+    let millis: u64 = fraction.parse().ok()?;
+
+    Some(((hours as u64 * 60 + minutes as u64) * 60 + seconds as u64) * 1000 + millis)
+}
+
+fn read_u32_le(data: &[u8], index: &mut usize) -> Result<u32> {
+    let bytes = read_bytes(data, index, 4)?;
+
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_bytes<'a>(data: &'a [u8], index: &mut usize, len: usize) -> Result<&'a [u8]> {
+    // lengths come from the file, so don't trust them not to overflow
+    let end = index.checked_add(len);
+
+    match end.and_then(|end| data.get(*index..end)) {
+        Some(bytes) => {
+            *index += len;
+            Ok(bytes)
+        }
+        None => bail!("Vorbis comment header truncated"),
+    }
+}
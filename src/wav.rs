@@ -0,0 +1,236 @@
+// WAV (RIFF) files with 16 bit PCM samples at SAMPLE_RATE, mono or stereo. Used for prompts and
+// for albums ripped to one WAV file with a CUE sheet. Anything that would need resampling or
+// another sample format is refused rather than played wrong. Written files are always stereo.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    audio::{CHANNELS, SAMPLE_RATE},
+    bluetooth_hal::Stream,
+};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavFormat {
+    pub channels: usize,
+    // bytes from the start of the file to the first sample, and the number of sample bytes
+    pub data_offset: u64,
+    pub data_len: u64,
+}
+
+impl WavFormat {
+    pub fn frame_size(&self) -> usize {
+        2 * self.channels
+    }
+}
+
+// Reads the header up to the start of the data chunk, where the reader is left
+pub fn read_header<R: Read>(reader: &mut R) -> Result<WavFormat> {
+    let mut riff = [0u8; 12];
+    reader.read_exact(&mut riff)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        bail!("Not a WAV file");
+    }
+
+    let mut offset = 12u64;
+    let mut channels = None;
+
+    loop {
+        let mut chunk_header = [0u8; 8];
+        if reader.read_exact(&mut chunk_header).is_err() {
+            bail!("WAV file has no data");
+        }
+        let size = u32::from_le_bytes([
+            chunk_header[4],
+            chunk_header[5],
+            chunk_header[6],
+            chunk_header[7],
+        ]) as u64;
+        offset += 8;
+
+        match &chunk_header[0..4] {
+            b"fmt " => {
+                if !(16..=64).contains(&size) {
+                    bail!("Bad WAV format chunk size {}", size);
+                }
+                let mut fmt = vec![0u8; (size + (size & 1)) as usize];
+                reader.read_exact(&mut fmt)?;
+
+                let format = u16::from_le_bytes([fmt[0], fmt[1]]);
+                let channel_count = u16::from_le_bytes([fmt[2], fmt[3]]);
+                let rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+                let bits = u16::from_le_bytes([fmt[14], fmt[15]]);
+
+                if format != WAVE_FORMAT_PCM || bits != 16 || rate != SAMPLE_RATE {
+                    bail!(
+                        "Unsupported WAV format {}, {} bits, {} Hz",
+                        format,
+                        bits,
+                        rate
+                    );
+                }
+                if channel_count != 1 && channel_count != 2 {
+                    bail!("Unsupported WAV channel count {}", channel_count);
+                }
+                channels = Some(channel_count as usize);
+            }
+            b"data" => {
+                let Some(channels) = channels else {
+                    bail!("WAV data before format");
+                };
+                return Ok(WavFormat {
+                    channels,
+                    data_offset: offset,
+                    data_len: size,
+                });
+            }
+            _ => {
+                // chunks are padded to an even size
+                let skip = size + (size & 1);
+                if std::io::copy(&mut reader.by_ref().take(skip), &mut std::io::sink())? != skip {
+                    bail!("WAV file truncated");
+                }
+            }
+        }
+        offset += size + (size & 1);
+    }
+}
+
+// Converts whole frames of little endian samples to interleaved stereo
+pub fn convert_frames(data: &[u8], channels: usize, out: &mut Vec<i16>) {
+    for frame in data.chunks_exact(2 * channels) {
+        let left = i16::from_le_bytes([frame[0], frame[1]]);
+        let right = if channels == 2 {
+            i16::from_le_bytes([frame[2], frame[3]])
+        } else {
+            left
+        };
+        out.push(left);
+        out.push(right);
+    }
+}
+
+pub struct WavStream {
+    reader: BufReader<File>,
+    format: WavFormat,
+    // sample bytes left to read
+    remaining: u64,
+    bytes: Vec<u8>,
+    samples: Vec<i16>,
+}
+
+impl WavStream {
+    pub fn open(filename: &str) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(filename)?);
+        let format = read_header(&mut reader)?;
+
+        Ok(WavStream {
+            reader,
+            format,
+            remaining: format.data_len,
+            bytes: Vec::new(),
+            samples: Vec::new(),
+        })
+    }
+
+    pub fn seek(&mut self, position_ms: u64) -> Result<()> {
+        let frame_size = self.format.frame_size() as u64;
+        let frame = position_ms * SAMPLE_RATE as u64 / 1000;
+        let skip = (frame * frame_size).min(self.format.data_len / frame_size * frame_size);
+
+        self.reader
+            .seek(SeekFrom::Start(self.format.data_offset + skip))?;
+        self.remaining = self.format.data_len - skip;
+        Ok(())
+    }
+}
+
+impl Stream<i16> for WavStream {
+    fn read(&mut self, buf: &mut [i16]) -> Result<usize> {
+        let frame_size = self.format.frame_size();
+        let frames = (buf.len() / CHANNELS).min((self.remaining / frame_size as u64) as usize);
+
+        self.bytes.resize(frames * frame_size, 0);
+        let mut filled = 0;
+        while filled < self.bytes.len() {
+            match self.reader.read(&mut self.bytes[filled..])? {
+                0 => break, // the file is shorter than its header says
+                count => filled += count,
+            }
+        }
+        let filled = filled / frame_size * frame_size;
+        self.remaining = self.remaining.saturating_sub(filled as u64);
+
+        self.samples.clear();
+        convert_frames(
+            &self.bytes[..filled],
+            self.format.channels,
+            &mut self.samples,
+        );
+        buf[..self.samples.len()].copy_from_slice(&self.samples);
+
+        Ok(self.samples.len())
+    }
+}
+
+// Header of a stereo file with data_len bytes of samples
+pub fn write_header<W: Write>(writer: &mut W, data_len: u64) -> Result<()> {
+    let Ok(data_len) = u32::try_from(data_len) else {
+        bail!("Too much audio for a WAV file");
+    };
+    let block_align = 2 * CHANNELS as u16;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&WAVE_FORMAT_PCM.to_le_bytes())?;
+    writer.write_all(&(CHANNELS as u16).to_le_bytes())?;
+    writer.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    writer.write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&16u16.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+    Ok(())
+}
+
+// The sizes in the header are only right once finish() has been called
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    data_len: u64,
+}
+
+impl WavWriter {
+    pub fn create(path: &str) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_header(&mut writer, 0)?;
+
+        Ok(WavWriter {
+            writer,
+            data_len: 0,
+        })
+    }
+
+    // interleaved stereo
+    pub fn write(&mut self, samples: &[i16]) -> Result<()> {
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += 2 * samples.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.writer, self.data_len)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}